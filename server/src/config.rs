@@ -0,0 +1,507 @@
+use std::env;
+use std::fmt;
+use std::fs;
+use std::str::FromStr;
+
+use regex::Regex;
+use serde_derive::Deserialize;
+use url::Url;
+
+/// Where the config file is read from unless CONFIG_FILE is set
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+/**
+ * Everything the server can be configured with. It is read once at startup from a TOML file
+ * (see config.example.toml), then environment variables override single values.
+ * Handlers get it through `app_data` as `web::Data<Config>`.
+ */
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub elastic: ElasticConfig,
+    pub storage: StorageConfig,
+    pub uploads: UploadConfig,
+    pub tokens: TokenConfig,
+    pub keys: KeyConfig,
+    pub limits: LimitConfig,
+    pub accounts: AccountConfig,
+    pub admin: AdminConfig,
+    pub comments: CommentConfig,
+    pub retention: RetentionConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+    /// Origins the client is served from, they are allowed by the CORS policy
+    pub allowed_origins: Vec<String>,
+    pub cookie_domain: String,
+    /// Take the client address from X-Forwarded-For / Forwarded, only safe behind a reverse proxy that sets them
+    pub trust_proxy: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ElasticConfig {
+    pub url: String,
+    pub username: String,
+    pub password: String,
+    /// Every index name starts with this, so several instances can share a cluster
+    pub index_prefix: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentBackend {
+    #[default]
+    Elastic,
+    /// For local development only, nothing survives a restart
+    Memory,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct StorageConfig {
+    /// Where posts, comments and categories are stored
+    pub backend: ContentBackend,
+    pub database_dir: String,
+    pub database_file: String,
+    /// Served as /public, avatars are kept in its avatar subdirectory
+    pub public_dir: String,
+    /// Uploaded images are kept here while they are being processed
+    pub tmp_dir: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct UploadConfig {
+    /// Largest accepted avatar in bytes
+    pub max_avatar_size: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TokenConfig {
+    /// How long an access token (the identity cookie) stays valid
+    pub access_lifetime_minutes: i64,
+    /// How long a session lasts without being refreshed
+    pub refresh_lifetime_days: i64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct KeyConfig {
+    /// Directory with the key pairs tokens are signed and verified with
+    pub dir: String,
+    /// Sign with this key instead of the newest one
+    pub signing_kid: Option<String>,
+}
+
+/// How failed attempts slow a client down: after the free attempts, every attempt doubles the wait
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Backoff {
+    pub free_attempts: u32,
+    pub base_delay_seconds: i64,
+    pub max_delay_seconds: i64,
+    /// Attempts are forgotten after this long without a new one
+    pub reset_after_minutes: i64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LimitConfig {
+    /// Failed logins, counted per username and per address
+    pub login: Backoff,
+    /// Registrations, counted per address whether they succeed or not
+    pub register: Backoff,
+    /// Failed logins of one account before it is locked
+    pub lock_after: u32,
+    pub lock_minutes: i64,
+}
+
+/// What happens to the posts and comments of a deleted account
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeletionPolicy {
+    /// Keep them, shown as written by "[deleted]"
+    #[default]
+    Anonymize,
+    /// Anonymize and delete them
+    Remove,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct AccountConfig {
+    pub deletion_policy: DeletionPolicy,
+}
+
+/// The admin account that is created on first run
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct AdminConfig {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CommentConfig {
+    /// How deep replies can be nested (top level comments have depth 0)
+    pub max_depth: u32,
+}
+
+/// When deleted posts and comments are purged for good
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RetentionConfig {
+    /// Days after its deletion that content is purged, 0 keeps it forever
+    pub purge_after_days: u32,
+    /// How often the background purge runs
+    pub purge_interval_hours: u32,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            host: "0.0.0.0".to_string(),
+            port: 3000,
+            allowed_origins: vec![],
+            cookie_domain: String::new(),
+            trust_proxy: false,
+        }
+    }
+}
+
+impl Default for ElasticConfig {
+    fn default() -> ElasticConfig {
+        ElasticConfig {
+            url: String::new(),
+            username: String::new(),
+            password: String::new(),
+            index_prefix: "tidder".to_string(),
+        }
+    }
+}
+
+impl FromStr for ContentBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ContentBackend, String> {
+        match s {
+            "elastic" => Ok(ContentBackend::Elastic),
+            "memory" => Ok(ContentBackend::Memory),
+            other => Err(format!("unknown content store \"{}\", expected elastic or memory", other)),
+        }
+    }
+}
+
+impl FromStr for DeletionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<DeletionPolicy, String> {
+        match s {
+            "anonymize" => Ok(DeletionPolicy::Anonymize),
+            "remove" => Ok(DeletionPolicy::Remove),
+            other => Err(format!("unknown deletion policy \"{}\", expected anonymize or remove", other)),
+        }
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> StorageConfig {
+        StorageConfig {
+            backend: ContentBackend::Elastic,
+            database_dir: "database".to_string(),
+            database_file: "db.db".to_string(),
+            public_dir: "public".to_string(),
+            tmp_dir: "tmp".to_string(),
+        }
+    }
+}
+
+impl StorageConfig {
+    pub fn database_path(&self) -> String {
+        format!("{}/{}", self.database_dir, self.database_file)
+    }
+
+    pub fn avatar_dir(&self) -> String {
+        format!("{}/avatar", self.public_dir)
+    }
+
+    /// Personal data exports are private, so they are kept next to the database instead of the public files
+    pub fn export_dir(&self) -> String {
+        format!("{}/export", self.database_dir)
+    }
+}
+
+impl Default for UploadConfig {
+    fn default() -> UploadConfig {
+        UploadConfig { max_avatar_size: 5_000_000 }
+    }
+}
+
+impl UploadConfig {
+    /**
+     * Most bytes a multipart upload may have. Leaves room for the multipart headers,
+     * so a file right at the size limit is still rejected with a useful message.
+     */
+    pub fn multipart_limit(&self) -> usize {
+        self.max_avatar_size + 64 * 1024
+    }
+}
+
+impl Default for TokenConfig {
+    fn default() -> TokenConfig {
+        TokenConfig { access_lifetime_minutes: 15, refresh_lifetime_days: 30 }
+    }
+}
+
+impl Default for KeyConfig {
+    fn default() -> KeyConfig {
+        KeyConfig { dir: "keystore".to_string(), signing_kid: None }
+    }
+}
+
+impl Default for LimitConfig {
+    fn default() -> LimitConfig {
+        LimitConfig {
+            login: Backoff {
+                free_attempts: 5,
+                base_delay_seconds: 1,
+                max_delay_seconds: 900,
+                reset_after_minutes: 60,
+            },
+            register: Backoff {
+                free_attempts: 3,
+                base_delay_seconds: 60,
+                max_delay_seconds: 3600,
+                reset_after_minutes: 1440,
+            },
+            lock_after: 20,
+            lock_minutes: 30,
+        }
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Backoff {
+        LimitConfig::default().login
+    }
+}
+
+impl Default for CommentConfig {
+    fn default() -> CommentConfig {
+        CommentConfig { max_depth: 8 }
+    }
+}
+
+impl Default for RetentionConfig {
+    fn default() -> RetentionConfig {
+        RetentionConfig { purge_after_days: 0, purge_interval_hours: 24 }
+    }
+}
+
+/// Everything that is wrong with the configuration, so it can be fixed in one go
+#[derive(Debug)]
+pub struct ConfigError(Vec<String>);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Invalid configuration:")?;
+        for problem in &self.0 {
+            writeln!(f, "  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl Config {
+
+    /**
+     * Read the config file, apply the environment overrides and validate the result.
+     * The file is optional at its default location, everything can be set through the environment.
+     */
+    pub fn load() -> Result<Config, ConfigError> {
+        let (path, required) = match read_env("CONFIG_FILE") {
+            Some(path) => (path, true),
+            None => (DEFAULT_CONFIG_FILE.to_string(), false),
+        };
+
+        let mut config = match fs::read_to_string(&path) {
+            Ok(content) => toml::from_str::<Config>(&content)
+                .map_err(|e| ConfigError(vec![format!("{}: {}", path, e)]))?,
+            Err(_) if !required => Config::default(),
+            Err(e) => return Err(ConfigError(vec![format!("{}: {}", path, e)])),
+        };
+
+        let mut problems = config.apply_env();
+        problems.extend(config.validate());
+
+        match problems.is_empty() {
+            true => Ok(config),
+            false => Err(ConfigError(problems)),
+        }
+    }
+
+    /**
+     * Override values with the environment variables that are set
+     * @return The variables that couldn't be parsed
+     */
+    fn apply_env(&mut self) -> Vec<String> {
+        let mut problems = vec![];
+
+        set_from_env("HOST", &mut self.server.host, &mut problems);
+        set_from_env("PORT", &mut self.server.port, &mut problems);
+        set_from_env("COOKIE_DOMAIN", &mut self.server.cookie_domain, &mut problems);
+        set_from_env("TRUST_PROXY", &mut self.server.trust_proxy, &mut problems);
+        if let Some(origins) = read_env("CLIENT_URL") {
+            self.server.allowed_origins = origins.split(',')
+                .map(|origin| origin.trim().to_string())
+                .filter(|origin| !origin.is_empty())
+                .collect();
+        }
+
+        set_from_env("ELASTIC_URL", &mut self.elastic.url, &mut problems);
+        set_from_env("ELASTIC_USER", &mut self.elastic.username, &mut problems);
+        set_from_env("ELASTIC_PASS", &mut self.elastic.password, &mut problems);
+        set_from_env("ELASTIC_INDEX_PREFIX", &mut self.elastic.index_prefix, &mut problems);
+
+        set_from_env("CONTENT_STORE", &mut self.storage.backend, &mut problems);
+        set_from_env("DATABASE_DIR", &mut self.storage.database_dir, &mut problems);
+        set_from_env("PUBLIC_DIR", &mut self.storage.public_dir, &mut problems);
+        set_from_env("TMP_DIR", &mut self.storage.tmp_dir, &mut problems);
+
+        set_from_env("MAX_AVATAR_SIZE", &mut self.uploads.max_avatar_size, &mut problems);
+        set_from_env("ACCESS_TOKEN_MINUTES", &mut self.tokens.access_lifetime_minutes, &mut problems);
+        set_from_env("REFRESH_TOKEN_DAYS", &mut self.tokens.refresh_lifetime_days, &mut problems);
+
+        set_from_env("KEYSTORE_DIR", &mut self.keys.dir, &mut problems);
+        if let Some(kid) = read_env("SIGNING_KEY_ID") {
+            self.keys.signing_kid = Some(kid);
+        }
+
+        set_from_env("LOGIN_FREE_ATTEMPTS", &mut self.limits.login.free_attempts, &mut problems);
+        set_from_env("LOGIN_MAX_DELAY_SECONDS", &mut self.limits.login.max_delay_seconds, &mut problems);
+        set_from_env("REGISTER_FREE_ATTEMPTS", &mut self.limits.register.free_attempts, &mut problems);
+        set_from_env("ACCOUNT_LOCK_AFTER", &mut self.limits.lock_after, &mut problems);
+        set_from_env("ACCOUNT_LOCK_MINUTES", &mut self.limits.lock_minutes, &mut problems);
+
+        set_from_env("ACCOUNT_DELETION_POLICY", &mut self.accounts.deletion_policy, &mut problems);
+
+        set_from_env("ADMIN_USER", &mut self.admin.username, &mut problems);
+        set_from_env("ADMIN_PASS", &mut self.admin.password, &mut problems);
+
+        set_from_env("MAX_COMMENT_DEPTH", &mut self.comments.max_depth, &mut problems);
+
+        set_from_env("PURGE_AFTER_DAYS", &mut self.retention.purge_after_days, &mut problems);
+        set_from_env("PURGE_INTERVAL_HOURS", &mut self.retention.purge_interval_hours, &mut problems);
+
+        problems
+    }
+
+    /**
+     * Check the values that would otherwise only fail once they are used
+     * @return A description of every invalid value
+     */
+    fn validate(&self) -> Vec<String> {
+        let mut problems = vec![];
+
+        if self.server.allowed_origins.is_empty() {
+            problems.push("server.allowed_origins (CLIENT_URL) must contain at least one origin".to_string());
+        }
+        for origin in &self.server.allowed_origins {
+            match Url::parse(origin) {
+                Ok(url) if url.scheme() == "http" || url.scheme() == "https" => (),
+                _ => problems.push(format!("server.allowed_origins: \"{}\" is not an http(s) origin", origin)),
+            }
+        }
+        if self.server.cookie_domain.is_empty() {
+            problems.push("server.cookie_domain (COOKIE_DOMAIN) must be set".to_string());
+        }
+
+        // Elasticsearch settings only matter when it's used
+        if self.storage.backend == ContentBackend::Elastic {
+            if Url::parse(&self.elastic.url).is_err() {
+                problems.push(format!("elastic.url (ELASTIC_URL): \"{}\" is not a valid URL", self.elastic.url));
+            }
+            if self.elastic.username.is_empty() || self.elastic.password.is_empty() {
+                problems.push("elastic.username and elastic.password (ELASTIC_USER, ELASTIC_PASS) must be set".to_string());
+            }
+        }
+        let re_prefix = Regex::new(r"^[a-z0-9][a-z0-9_\-]*$").unwrap();
+        if !re_prefix.is_match(&self.elastic.index_prefix) {
+            problems.push("elastic.index_prefix (ELASTIC_INDEX_PREFIX) can only contain lowercase letters, numbers, _ and - and must start with a letter or number".to_string());
+        }
+
+        for (name, dir) in [
+            ("storage.database_dir", &self.storage.database_dir),
+            ("storage.database_file", &self.storage.database_file),
+            ("storage.public_dir", &self.storage.public_dir),
+            ("storage.tmp_dir", &self.storage.tmp_dir),
+            ("keys.dir", &self.keys.dir),
+        ] {
+            if dir.is_empty() {
+                problems.push(format!("{} must not be empty", name));
+            }
+        }
+
+        if self.uploads.max_avatar_size == 0 {
+            problems.push("uploads.max_avatar_size (MAX_AVATAR_SIZE) must be greater than 0".to_string());
+        }
+        if self.tokens.access_lifetime_minutes <= 0 {
+            problems.push("tokens.access_lifetime_minutes (ACCESS_TOKEN_MINUTES) must be greater than 0".to_string());
+        }
+        if self.tokens.refresh_lifetime_days <= 0 {
+            problems.push("tokens.refresh_lifetime_days (REFRESH_TOKEN_DAYS) must be greater than 0".to_string());
+        }
+
+        for (name, backoff) in [("limits.login", &self.limits.login), ("limits.register", &self.limits.register)] {
+            if backoff.base_delay_seconds <= 0 || backoff.max_delay_seconds < backoff.base_delay_seconds {
+                problems.push(format!("{}: base_delay_seconds must be greater than 0 and at most max_delay_seconds", name));
+            }
+            if backoff.reset_after_minutes <= 0 {
+                problems.push(format!("{}.reset_after_minutes must be greater than 0", name));
+            }
+        }
+        if self.limits.lock_after == 0 || self.limits.lock_minutes <= 0 {
+            problems.push("limits.lock_after and limits.lock_minutes (ACCOUNT_LOCK_AFTER, ACCOUNT_LOCK_MINUTES) must be greater than 0".to_string());
+        }
+
+        if self.retention.purge_interval_hours == 0 {
+            problems.push("retention.purge_interval_hours (PURGE_INTERVAL_HOURS) must be greater than 0".to_string());
+        }
+
+        if self.admin.username.is_empty() || self.admin.password.is_empty() {
+            problems.push("admin.username and admin.password (ADMIN_USER, ADMIN_PASS) must be set".to_string());
+        }
+
+        problems
+    }
+}
+
+/**
+ * Read an environment variable. Empty values count as unset, like the blank lines in .env.example.
+ */
+fn read_env(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.trim().is_empty())
+}
+
+/**
+ * Replace a value with the parsed environment variable, if it is set
+ * @param name The name of the environment variable
+ * @param target The value to replace
+ * @param problems Where to report a value that can't be parsed
+ */
+fn set_from_env<T: FromStr>(name: &str, target: &mut T, problems: &mut Vec<String>) {
+    if let Some(value) = read_env(name) {
+        match value.trim().parse::<T>() {
+            Ok(value) => *target = value,
+            Err(_) => problems.push(format!("{}: \"{}\" is not a valid value", name, value)),
+        }
+    }
+}
@@ -0,0 +1,199 @@
+use actix_web::{get, post, put, delete, web, HttpResponse};
+use serde_json::json;
+
+use crate::{AppConfig, DbPool, Store};
+use crate::error::AppError;
+use crate::model::api::{AuditQuery, BanQuery, BanRequest, EditWindowRequest, ModeratorRequest, PurgeQuery};
+use crate::service::{audit, database, retention};
+use crate::service::audit::Action;
+use crate::service::auth::{AdminUser, ADMIN};
+use crate::utils::form_validation::{validate_ban, validate_limit, validate_time};
+
+// Mounted under the admin scope in main.rs, which only lets admins through
+
+#[get("/settings")]
+pub async fn get_settings(pool: DbPool) -> Result<HttpResponse, AppError> {
+    let edit_window = database::get_setting(pool, database::COMMENT_EDIT_WINDOW).await?
+        .and_then(|minutes| minutes.parse::<u32>().ok());
+
+    Ok(HttpResponse::Ok().json(json!({ "settings": { "comment_edit_window": edit_window } })))
+}
+
+#[put("/settings/comment-edit-window")]
+pub async fn set_comment_edit_window(pool: DbPool, form: web::Form<EditWindowRequest>, admin: AdminUser) -> Result<HttpResponse, AppError> {
+    let minutes = form.minutes.map(|minutes| minutes.to_string());
+
+    database::set_setting(pool.clone(), database::COMMENT_EDIT_WINDOW, minutes).await?;
+
+    audit::record(pool, &admin.id, Action::UpdateSetting, database::COMMENT_EDIT_WINDOW, None, Some(json!({ "value": form.minutes }))).await?;
+
+    Ok(HttpResponse::Ok().json(json!({ "status": "ok", "comment_edit_window": form.minutes })))
+}
+
+#[get("/categories/{id}/moderators")]
+pub async fn get_moderators(pool: DbPool, store: Store, category_id: web::Path<String>) -> Result<HttpResponse, AppError> {
+    let category = store.get_category_by_id(&category_id).await?;
+    let moderators = database::find_moderators_by_category_id(pool, category.id).await?;
+
+    let moderators = moderators.into_iter()
+        .map(|user| json!({ "id": user.id, "username": user.username }))
+        .collect::<Vec<_>>();
+
+    Ok(HttpResponse::Ok().json(json!({ "moderators": moderators })))
+}
+
+#[post("/categories/{id}/moderators")]
+pub async fn grant_moderator(pool: DbPool, store: Store, category_id: web::Path<String>, form: web::Form<ModeratorRequest>, admin: AdminUser) -> Result<HttpResponse, AppError> {
+    let category = store.get_category_by_id(&category_id).await?;
+    let user = database::find_user_by_username(pool.clone(), form.username.clone()).await?;
+
+    database::grant_moderator(pool.clone(), user.id.clone(), category.id.clone(), admin.id.clone()).await?;
+
+    audit::record(pool, &admin.id, Action::GrantModerator, &user.id, None, Some(json!({ "category_id": category.id }))).await?;
+
+    Ok(HttpResponse::Created().json(json!({ "status": "ok", "user_id": user.id })))
+}
+
+#[delete("/categories/{id}/moderators/{user_id}")]
+pub async fn revoke_moderator(pool: DbPool, path: web::Path<(String, String)>, admin: AdminUser) -> Result<HttpResponse, AppError> {
+    let (category_id, user_id) = path.into_inner();
+
+    database::revoke_moderator(pool.clone(), user_id.clone(), category_id.clone()).await?;
+
+    audit::record(pool, &admin.id, Action::RevokeModerator, &user_id, None, Some(json!({ "category_id": category_id }))).await?;
+
+    Ok(HttpResponse::Ok().json(json!({ "status": "ok" })))
+}
+
+/**
+ * Bans that are in effect, or every ban with `all=true`, optionally of one user
+ */
+#[get("/bans")]
+pub async fn get_bans(pool: DbPool, query: web::Query<BanQuery>) -> Result<HttpResponse, AppError> {
+    let bans = database::find_bans(pool, query.user_id.clone(), !query.all.unwrap_or(false)).await?;
+
+    Ok(HttpResponse::Ok().json(json!({ "bans": bans })))
+}
+
+/**
+ * Ban or suspend a user. A site wide ban also ends every session of the user,
+ * so the account is locked out as soon as its current access token expires.
+ */
+#[post("/bans")]
+pub async fn issue_ban(pool: DbPool, store: Store, form: web::Form<BanRequest>, admin: AdminUser) -> Result<HttpResponse, AppError> {
+    let form = form.into_inner();
+    validate_ban(&form)?;
+
+    let user = database::find_user_by_username(pool.clone(), form.username.clone()).await?;
+    if user.role.eq(ADMIN) {
+        return Err(AppError::Forbidden("Admins can't be banned".to_string()));
+    }
+
+    if let Some(category_id) = &form.category_id {
+        store.get_category_by_id(category_id).await?;
+    }
+
+    let reason = form.reason.trim().to_string();
+    let ban_id = database::save_ban(pool.clone(), user.id.clone(), form.category_id.clone(), reason.clone(), admin.id.clone(), form.duration_hours).await?;
+
+    if form.category_id.is_none() {
+        database::revoke_user_sessions(pool.clone(), user.id.clone(), None).await?;
+    }
+
+    let details = json!({ "ban_id": ban_id, "category_id": form.category_id, "duration_hours": form.duration_hours });
+    audit::record(pool, &admin.id, Action::IssueBan, &user.id, Some(reason), Some(details)).await?;
+
+    Ok(HttpResponse::Created().json(json!({ "status": "ok", "ban_id": ban_id, "user_id": user.id })))
+}
+
+#[delete("/bans/{id}")]
+pub async fn lift_ban(pool: DbPool, ban_id: web::Path<String>, admin: AdminUser) -> Result<HttpResponse, AppError> {
+    database::lift_ban(pool.clone(), ban_id.to_string(), admin.id.clone()).await?;
+
+    audit::record(pool, &admin.id, Action::LiftBan, &ban_id, None, None).await?;
+
+    Ok(HttpResponse::Ok().json(json!({ "status": "ok" })))
+}
+
+/**
+ * The audit log, newest first, filtered by `actor_id`, `action`, `target_type`, `target_id` and a `since`/`until` time range
+ */
+#[get("/audit")]
+pub async fn get_audit_log(pool: DbPool, query: web::Query<AuditQuery>) -> Result<HttpResponse, AppError> {
+    let limit = validate_limit(query.limit)?;
+
+    let mut query = query.into_inner();
+    query.since = validate_time(query.since, "since")?;
+    query.until = validate_time(query.until, "until")?;
+
+    let page = audit::list(pool, query, limit).await?;
+
+    Ok(HttpResponse::Ok().json(json!({ "entries": page.items, "next_cursor": page.next_cursor })))
+}
+
+/**
+ * Purge deleted content now instead of waiting for the background purge.
+ * With `dry_run` nothing is removed, the report tells what would be.
+ */
+#[post("/purge")]
+pub async fn purge_deleted(store: Store, pool: DbPool, config: AppConfig, query: web::Query<PurgeQuery>, admin: AdminUser) -> Result<HttpResponse, AppError> {
+    let days = config.retention.purge_after_days;
+    if days == 0 {
+        return Err(AppError::Conflict("Deleted content is kept forever, set retention.purge_after_days to purge it".to_string()));
+    }
+
+    let report = retention::purge(store.as_ref(), pool.clone(), days, query.dry_run.unwrap_or(false)).await?;
+
+    if !report.dry_run {
+        let details = json!({
+            "posts": report.posts.len(),
+            "orphaned_comments": report.orphaned_comments.len(),
+            "comments": report.comments.len(),
+            "scrubbed_comments": report.scrubbed_comments.len(),
+        });
+        audit::record(pool, &admin.id, Action::PurgeDeleted, &report.deleted_before, None, Some(details)).await?;
+    }
+
+    Ok(HttpResponse::Ok().json(json!({ "report": report })))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{test, App, http::StatusCode};
+    use serde_json::Value;
+
+    use super::*;
+    use crate::test_utils::TestApp;
+
+    #[actix_web::test]
+    async fn audit_log_is_filtered_by_time() {
+        let t = TestApp::new();
+        let app = test::init_service(App::new().configure(|cfg| t.configure(cfg)).service(get_audit_log)).await;
+
+        {
+            let conn = t.pool.get().unwrap();
+            for (target_id, created_at) in [("old", "2025-06-01 12:00:00"), ("new", "2026-02-01 12:00:00")] {
+                conn.execute(
+                    "INSERT INTO audit_log (actor_id, action, target_type, target_id, created_at) VALUES ('admin', 'ban.issue', 'user', ?, ?)",
+                    [target_id, created_at]
+                ).unwrap();
+            }
+        }
+
+        let targets = |body: Value| body["entries"].as_array().unwrap().iter()
+            .map(|entry| entry["target_id"].as_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+
+        let req = test::TestRequest::get().uri("/audit?since=2026-01-01T00:00:00Z").to_request();
+        assert_eq!(targets(test::call_and_read_body_json(&app, req).await), ["new"]);
+
+        let req = test::TestRequest::get().uri("/audit?until=2026-01-01").to_request();
+        assert_eq!(targets(test::call_and_read_body_json(&app, req).await), ["old"]);
+
+        let req = test::TestRequest::get().uri("/audit?since=2025-01-01%2000:00:00&until=2027-01-01T01:00:00%2B01:00").to_request();
+        assert_eq!(targets(test::call_and_read_body_json(&app, req).await), ["new", "old"]);
+
+        let req = test::TestRequest::get().uri("/audit?since=last%20week").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -1,131 +1,319 @@
-use std::env;
-
-use actix_web::{ HttpResponse, get, post, cookie, web};
-use regex::{Regex, RegexSet};
+use actix_web::{ HttpResponse, HttpRequest, get, post, cookie::{self, Cookie}, http::header, web};
 use serde_json::json;
-use serde_derive::{Serialize};
 use bcrypt::{DEFAULT_COST, hash};
 
-use crate::DbPool;
+use crate::{AppConfig, DbPool, Keys};
+use crate::error::AppError;
 use crate::model::api::UserRequest;
-use crate::service::{security, database};
-
-#[derive(Debug, Serialize)]
-struct Claims {
-    sub: String, 
-    iss: String,
-    exp: usize,
-    iat: usize,
-}
+use crate::config::Config;
+use crate::service::{security, database, throttle};
+use crate::service::security::SessionTokens;
+use crate::utils::form_validation::{validate_username, validate_password};
+
+const IDENTITY_COOKIE: &str = "identity";
+const REFRESH_COOKIE: &str = "refresh";
+const REFRESH_COOKIE_PATH: &str = "/api";
 
+/**
+ * The public key of the current signing key. Only works until the next key rotation, use the JWKS instead.
+ */
 #[get("/api/pubkey")]
-pub async fn pubkey() -> HttpResponse {
+pub async fn pubkey(keys: Keys) -> HttpResponse {
     HttpResponse::Ok()
         .content_type("text/plain")
-        .body(include_str!("../security/keystore/pubkey.pem"))
+        .body(keys.signing_public_pem().to_string())
+}
+
+/**
+ * Every public key that is currently accepted, tokens name theirs in the `kid` header
+ */
+#[get("/.well-known/jwks.json")]
+pub async fn jwks(keys: Keys) -> HttpResponse {
+    HttpResponse::Ok().json(keys.jwks())
 }
 
 #[get("/api/users")]
-pub async fn get_users(pool: DbPool) -> HttpResponse {
-    let users = match database::find_users(pool).await {
-        Ok(users) => users,
-        Err(e) => return HttpResponse::InternalServerError().json(json!({ "status": "error", "message": e.to_string() })),
-    };
+pub async fn get_users(pool: DbPool) -> Result<HttpResponse, AppError> {
+    let users = database::find_users(pool).await?;
 
-    HttpResponse::Ok().json(json!({ "users": users }))
+    Ok(HttpResponse::Ok().json(json!({ "users": users })))
 }
 
 #[post("/api/register")]
-pub async fn register(pool: DbPool, form: web::Form<UserRequest>) -> HttpResponse {
+pub async fn register(pool: DbPool, config: AppConfig, keys: Keys, form: web::Form<UserRequest>, req: HttpRequest) -> Result<HttpResponse, AppError> {
 
-    let re_username = Regex::new(r"^[a-zA-Z0-9_]+$").unwrap();
-    let re_password = RegexSet::new(&[r"[A-Z]", r"[a-z]", r"\d", r#"[!@#$%^&*(),.?\":{}|<>]"#]).unwrap();
+    // Every registration counts, successful or not, since each one hashes a password
+    let ip = throttle::client_ip(&req, &config);
+    throttle::check_register(pool.clone(), &config.limits, &ip).await?;
+    throttle::record_registration(pool.clone(), &config.limits, &ip).await?;
 
     // Validate username
-    let username = match re_username.is_match(&form.username) {
-        true => &form.username,
-        false => return HttpResponse::BadRequest().json(json!({ "status": "error", "message": "Username can only contain letters, numbers and underscores" })),
-    };
+    validate_username(&form.username)?;
     
     // Validate and hash password
-    let password = match 
-        &form.password.len() >= &8 && 
-        &form.password.len() <= &64 &&
-        re_password.is_match(&form.password) {
-            true => hash(&form.password, DEFAULT_COST).expect("Failed to hash password"),
-            false => return HttpResponse::BadRequest().json(json!({ "status": "error", "message": "Password must be between 8 and 64 characters long and contain at least one uppercase letter, one lowercase letter, one digit and one special character" })),
-    };
+    validate_password(&form.password)?;
+    let password = hash(&form.password, DEFAULT_COST)?;
 
-    let user_id = match database::save_user(pool.clone(), username.to_string(), password).await {
-        Ok(id) => id,
-        Err(e) => {
-            if e.eq("UNIQUE constraint failed: users.username") || e.eq("UNIQUE constraint failed: users.username_lower") {
-                return HttpResponse::Conflict().json(json!({ "status": "error", "message": "Username already exists" }));
-            } else {
-                eprintln!("Error saving user: {}", e);
-                return HttpResponse::InternalServerError().json(json!({ "status": "error", "message": "Something went wrong" }));
-            }
-        }
-    }; 
+    let user_id = database::save_user(pool.clone(), form.username.to_string(), password).await?;
     
-    let token = match security::login(pool, &form.into_inner()).await {
-        Ok(token) => token,
-        Err(_) => return HttpResponse::Unauthorized().json(json!({ "status": "error", "message": "Invalid credentials" })),
-    };
+    let user = database::find_user_by_id(pool.clone(), user_id.clone()).await?;
+    let session = security::start_session(pool, &keys, &config.tokens, &user, user_agent(&req)).await?;
 
-    // Create a secure cookie with the JWT token
-    let cookie = cookie::Cookie::build("identity", &token)
-        .path("/")
-        .secure(true)
-        .http_only(true)
-        .same_site(cookie::SameSite::Strict)
-        .max_age(cookie::time::Duration::days(365))
-        .finish();
+    let (identity_cookie, refresh_cookie) = session_cookies(&config, session);
 
-    HttpResponse::Created()
-        .cookie(cookie)
-        .json(json!({ "status": "ok", "user_id": user_id }))
+    Ok(HttpResponse::Created()
+        .cookie(identity_cookie)
+        .cookie(refresh_cookie)
+        .json(json!({ "status": "ok", "user_id": user_id })))
     
 }
 
 #[post("/api/login")]
-pub async fn login(pool: DbPool, form: web::Form<UserRequest>) -> HttpResponse {
+pub async fn login(pool: DbPool, config: AppConfig, keys: Keys, form: web::Form<UserRequest>, req: HttpRequest) -> Result<HttpResponse, AppError> {
 
     let form = form.into_inner();
 
-    let token = match security::login(pool, &form).await {
-        Ok(token) => token,
-        Err(_) => return HttpResponse::Unauthorized().json(json!({ "status": "error", "message": "Invalid credentials" })),
+    let ip = throttle::client_ip(&req, &config);
+    throttle::check_login(pool.clone(), &config.limits, &form.username, &ip).await?;
+
+    let user = match security::login(pool.clone(), &form).await {
+        Ok(user) => user,
+        Err(e @ AppError::Unauthorized(_)) => {
+            throttle::record_login_failure(pool, &config.limits, &form.username, &ip).await?;
+            return Err(e);
+        },
+        Err(e) => return Err(e),
     };
+    throttle::clear_login_failures(pool.clone(), &form.username).await?;
 
-    // Create a secure cookie with the JWT token
-    let cookie = cookie::Cookie::build("identity", &token)
-        .domain(env::var("COOKIE_DOMAIN").expect("COOKIE_DOMAIN not set"))
-        .path("/")
-        .secure(true)
-        .http_only(true)
-        .same_site(cookie::SameSite::Strict)
-        .max_age(cookie::time::Duration::days(365))
-        .finish();
+    let session = security::start_session(pool, &keys, &config.tokens, &user, user_agent(&req)).await?;
 
-    HttpResponse::Ok()
-        .cookie(cookie)
-        .json(json!({ "status": "ok", "username": form.username }))
+    let (identity_cookie, refresh_cookie) = session_cookies(&config, session);
+
+    Ok(HttpResponse::Ok()
+        .cookie(identity_cookie)
+        .cookie(refresh_cookie)
+        .json(json!({ "status": "ok", "username": form.username })))
+}
+
+/**
+ * Get a new access token when the identity cookie has expired. The refresh token is replaced as well.
+ */
+#[post("/api/refresh")]
+pub async fn refresh(pool: DbPool, config: AppConfig, keys: Keys, req: HttpRequest) -> Result<HttpResponse, AppError> {
+
+    let refresh_token = match req.cookie(REFRESH_COOKIE) {
+        Some(cookie) => cookie.value().to_string(),
+        None => return Err(AppError::Unauthorized("Unauthorized".to_string())),
+    };
+
+    let session = security::refresh_session(pool, &keys, &config.tokens, &refresh_token).await?;
+
+    let (identity_cookie, refresh_cookie) = session_cookies(&config, session);
+
+    Ok(HttpResponse::Ok()
+        .cookie(identity_cookie)
+        .cookie(refresh_cookie)
+        .json(json!({ "status": "ok" })))
 }
 
 #[post("/api/logout")]
-pub async fn logout() -> HttpResponse {
-    // Clear the cookie
-    let identity_cookie = cookie::Cookie::build("identity", "")
-        .domain(env::var("COOKIE_DOMAIN").expect("COOKIE_DOMAIN not set"))
-        .path("/")
+pub async fn logout(pool: DbPool, config: AppConfig, req: HttpRequest) -> Result<HttpResponse, AppError> {
+
+    // End the session on the server, so the refresh token can't be used anymore
+    if let Some(cookie) = req.cookie(REFRESH_COOKIE) {
+        security::end_session(pool, cookie.value()).await?;
+    }
+
+    let (identity_cookie, refresh_cookie) = clear_session_cookies(&config);
+
+    Ok(HttpResponse::Ok()
+        .cookie(identity_cookie)
+        .cookie(refresh_cookie)
+        .json(json!({ "status": "ok" })))
+}
+
+/**
+ * The identity cookie holds the access token and is sent everywhere.
+ * The refresh cookie is only sent to the API, which needs it to refresh and to log out.
+ */
+fn session_cookies(config: &Config, session: SessionTokens) -> (Cookie<'static>, Cookie<'static>) {
+    (
+        build_cookie(config, IDENTITY_COOKIE, session.access_token, "/", cookie::time::Duration::minutes(config.tokens.access_lifetime_minutes)),
+        build_cookie(config, REFRESH_COOKIE, session.refresh_token, REFRESH_COOKIE_PATH, cookie::time::Duration::days(config.tokens.refresh_lifetime_days)),
+    )
+}
+
+/**
+ * Cookies that replace the session cookies with expired ones, which logs the browser out
+ */
+pub fn clear_session_cookies(config: &Config) -> (Cookie<'static>, Cookie<'static>) {
+    (
+        build_cookie(config, IDENTITY_COOKIE, String::new(), "/", cookie::time::Duration::MIN),
+        build_cookie(config, REFRESH_COOKIE, String::new(), REFRESH_COOKIE_PATH, cookie::time::Duration::MIN),
+    )
+}
+
+fn build_cookie(config: &Config, name: &'static str, value: String, path: &'static str, max_age: cookie::time::Duration) -> Cookie<'static> {
+    Cookie::build(name, value)
+        .domain(config.server.cookie_domain.clone())
+        .path(path)
         .secure(true)
         .http_only(true)
         .same_site(cookie::SameSite::Strict)
-        .max_age(cookie::time::Duration::MIN)
-        .finish();
+        .max_age(max_age)
+        .finish()
+}
 
-    HttpResponse::Ok()
-        .cookie(identity_cookie)
-        .json(json!({ "status": "ok" }))
-}
\ No newline at end of file
+/// The device a session is started from, cut to a sane length
+fn user_agent(req: &HttpRequest) -> Option<String> {
+    req.headers().get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.chars().take(256).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{test, App, http::StatusCode};
+
+    use super::*;
+    use crate::controller::user::get_self;
+    use crate::test_utils::{TestApp, PASSWORD};
+
+    fn session_cookie(resp: &actix_web::dev::ServiceResponse, name: &'static str) -> Cookie<'static> {
+        resp.response().cookies()
+            .find(|cookie| cookie.name() == name)
+            .map(|cookie| Cookie::new(name, cookie.value().to_string()))
+            .unwrap_or_else(|| panic!("No {} cookie", name))
+    }
+
+    #[actix_web::test]
+    async fn register_and_login_start_sessions() {
+        let t = TestApp::new();
+        let app = test::init_service(App::new().configure(|cfg| t.configure(cfg)).service(register).service(login).service(get_self)).await;
+
+        let req = test::TestRequest::post().uri("/api/register")
+            .set_form([("username", "alice"), ("password", PASSWORD)])
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let identity = session_cookie(&resp, IDENTITY_COOKIE);
+
+        let req = test::TestRequest::get().uri("/api/user/me").cookie(identity).to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["user"]["username"], "alice");
+
+        let req = test::TestRequest::post().uri("/api/login")
+            .set_form([("username", "alice"), ("password", "Wr0ng!pass")])
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+
+        let req = test::TestRequest::post().uri("/api/login")
+            .set_form([("username", "alice"), ("password", PASSWORD)])
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        session_cookie(&resp, IDENTITY_COOKIE);
+        session_cookie(&resp, REFRESH_COOKIE);
+    }
+
+    #[actix_web::test]
+    async fn refresh_rotates_the_refresh_token() {
+        let t = TestApp::new();
+        let alice = t.user("alice", "user").await;
+        let app = test::init_service(App::new().configure(|cfg| t.configure(cfg)).service(refresh).service(get_self)).await;
+
+        let req = test::TestRequest::post().uri("/api/refresh").cookie(alice.refresh()).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let identity = session_cookie(&resp, IDENTITY_COOKIE);
+        let refresh_token = session_cookie(&resp, REFRESH_COOKIE);
+        assert_ne!(refresh_token.value(), alice.tokens.refresh_token);
+
+        let req = test::TestRequest::get().uri("/api/user/me").cookie(identity).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        let req = test::TestRequest::post().uri("/api/refresh").cookie(refresh_token).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn reused_refresh_token_ends_the_session() {
+        let t = TestApp::new();
+        let alice = t.user("alice", "user").await;
+        let app = test::init_service(App::new().configure(|cfg| t.configure(cfg)).service(refresh).service(get_self)).await;
+
+        let req = test::TestRequest::post().uri("/api/refresh").cookie(alice.refresh()).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let identity = session_cookie(&resp, IDENTITY_COOKIE);
+        let refresh_token = session_cookie(&resp, REFRESH_COOKIE);
+
+        // The old token again, as if it was stolen
+        let req = test::TestRequest::post().uri("/api/refresh").cookie(alice.refresh()).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+
+        // Both the legitimate refresh token and the access tokens of the session stop working
+        let req = test::TestRequest::post().uri("/api/refresh").cookie(refresh_token).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+
+        let req = test::TestRequest::get().uri("/api/user/me").cookie(identity).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn logout_ends_the_session() {
+        let t = TestApp::new();
+        let alice = t.user("alice", "user").await;
+        let app = test::init_service(App::new().configure(|cfg| t.configure(cfg)).service(logout).service(refresh).service(get_self)).await;
+
+        let req = test::TestRequest::post().uri("/api/logout").cookie(alice.refresh()).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        let req = test::TestRequest::get().uri("/api/user/me").cookie(alice.identity()).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+
+        let req = test::TestRequest::post().uri("/api/refresh").cookie(alice.refresh()).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn failed_logins_back_off() {
+        let t = TestApp::new();
+        t.user("alice", "user").await;
+        let app = test::init_service(App::new().configure(|cfg| t.configure(cfg)).service(login)).await;
+
+        for _ in 0..t.config.limits.login.free_attempts {
+            let req = test::TestRequest::post().uri("/api/login")
+                .set_form([("username", "alice"), ("password", "Wr0ng!pass")])
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+        }
+
+        // Even the right password has to wait now, and the wait says for how long
+        let req = test::TestRequest::post().uri("/api/login")
+            .set_form([("username", "alice"), ("password", PASSWORD)])
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(resp.headers().contains_key(header::RETRY_AFTER));
+    }
+
+    #[actix_web::test]
+    async fn reserved_usernames_are_refused() {
+        let t = TestApp::new();
+        let app = test::init_service(App::new().configure(|cfg| t.configure(cfg)).service(register)).await;
+
+        for username in ["me", "Me"] {
+            let req = test::TestRequest::post().uri("/api/register")
+                .set_form([("username", username), ("password", PASSWORD)])
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), StatusCode::BAD_REQUEST, "registered {}", username);
+        }
+
+        let req = test::TestRequest::post().uri("/api/register")
+            .set_form([("username", "meg"), ("password", PASSWORD)])
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CREATED);
+    }
+}
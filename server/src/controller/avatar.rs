@@ -11,7 +11,7 @@ use actix_web::{post, HttpResponse, HttpRequest, web::Query, get};
 use serde_derive::Deserialize;
 use serde_json::json;
 
-use crate::{service::{security, database}, DbPool, utils::convert};
+use crate::{error::AppError, service::{security, database, auth::AuthUser}, DbPool, AppConfig, utils::convert};
 // use crate::utils::convert;
 
 #[derive(Debug, MultipartForm)]
@@ -21,49 +21,36 @@ struct UploadForm {
 }
 
 #[post("/api/upload/avatar")]
-async fn upload_avatar(pool: DbPool, MultipartForm(form): MultipartForm<UploadForm>, req: HttpRequest) -> HttpResponse {
-
-    let user_id = match security::verify_user(&req) {
-        Ok((user_id, _)) => user_id,
-        Err(_) => return HttpResponse::Unauthorized().json(json!({ "status": "error", "message": "Unauthorized" })),
-    };
+async fn upload_avatar(pool: DbPool, config: AppConfig, MultipartForm(form): MultipartForm<UploadForm>, user: AuthUser, req: HttpRequest) -> Result<HttpResponse, AppError> {
 
     let filename = uuid::Uuid::new_v4().to_string();
     let file = form.file;
     
-    let (filename, file) = match security::validate_image(file, filename) {
-        Ok(data) => data,
-        Err(msg) => return HttpResponse::UnsupportedMediaType().json(json!({ "status": "error", "message": msg })),
-    };
+    let (filename, file) = security::validate_image(file, filename, &config.uploads, &config.storage.tmp_dir)?;
     
     
     // save the new avatar to the avatar directory
-    let avatar_dir = "public/avatar";
+    let avatar_dir = config.storage.avatar_dir();
     let avatar_path = format!("{}/{}", avatar_dir, &filename);
-    fs::write(&avatar_path, file).unwrap();
+    fs::write(&avatar_path, file)?;
 
     // delete the old avatar if it wasn't overwritten
-    let old_avatar = database::find_user_by_id(pool.clone(), user_id.clone()).await.expect("Failed to fetch user").avatar_url;
-    match old_avatar {
-        Some(old_avatar) => {
-            let old_avatar = old_avatar.split("/").last().unwrap();
-            let old_avatar_path = format!("{}/{}", avatar_dir, old_avatar);
-            if old_avatar_path != avatar_path {
-                match fs::remove_file(old_avatar_path) {
-                    Ok(_) => (),
-                    Err(_) => (),
-                }
-            }
-        },
-        None => (),
+    let old_avatar = database::find_user_by_id(pool.clone(), user.id.clone()).await?.avatar_url;
+    if let Some(old_avatar) = old_avatar {
+        let old_avatar = old_avatar.split("/").last().unwrap();
+        let old_avatar_path = format!("{}/{}", avatar_dir, old_avatar);
+        if old_avatar_path != avatar_path {
+            let _ = fs::remove_file(old_avatar_path);
+        }
     }
 
     // generate the url for the avatar   
-    let avatar_url = req.url_for("avatars", [format!("{}", filename)]).unwrap();
+    let avatar_url = req.url_for("avatars", [filename.to_string()])
+        .map_err(|e| AppError::Internal(format!("Failed to generate avatar url: {}", e)))?;
     let avatar_url = convert::url_to_string(avatar_url);
-    database::update_user_avatar(pool, user_id, avatar_url).await.expect("Failed to update user avatar");
+    database::update_user_avatar(pool, user.id, avatar_url).await?;
 
-    HttpResponse::Ok().into()
+    Ok(HttpResponse::Ok().into())
 }
 
 #[derive(Debug, Deserialize)]
@@ -72,12 +59,12 @@ struct AvatarQuery {
 }
 
 #[get("/api/avatar")]
-async fn get_avatar_urls(pool: DbPool, query: Query<AvatarQuery>) -> HttpResponse {
+async fn get_avatar_urls(pool: DbPool, query: Query<AvatarQuery>) -> Result<HttpResponse, AppError> {
     
     let user_ids = query.user_ids.split(",");
     let user_ids = user_ids.map(|id| id.parse::<String>().unwrap()).collect::<Vec<_>>();
     
-    let urls = database::find_avatars_by_user_ids(pool, user_ids).await.expect("Failed to fetch avatar urls");
+    let urls = database::find_avatars_by_user_ids(pool, user_ids).await?;
     let urls = urls.into_iter().map(|(user_id, avatar_url)| {
         json!({
             "user_id": user_id,
@@ -85,5 +72,5 @@ async fn get_avatar_urls(pool: DbPool, query: Query<AvatarQuery>) -> HttpRespons
         })
     }).collect::<Vec<_>>();
 
-    HttpResponse::Ok().json(json!({ "urls": urls }))
+    Ok(HttpResponse::Ok().json(json!({ "urls": urls })))
 }
\ No newline at end of file
@@ -1,46 +1,72 @@
-use actix_web::{get, HttpResponse, Responder, web, HttpRequest};
+use actix_web::{get, post, put, HttpResponse, web};
 use serde_json::json;
 
-use crate::{service::{elastic, security}, model::api::QueryParams};
+use crate::{DbPool, Store, error::AppError, service::{audit, audit::Action, ban, category, security, auth::{AuthUser, OptionalUser}, ranking::Ranking}, model::api::{QueryParams, CreateCategoryRequest, UpdateCategoryRequest}, utils::form_validation::{validate_limit, validate_category_name, validate_category}, utils::sanitize::render_markdown};
 
 #[get("/api/category")]
-pub async fn get_categories() -> impl Responder {
-    let categories = elastic::get_categories().await;
-    HttpResponse::Ok().json(json!({ "categories": categories }))
+pub async fn get_categories(store: Store) -> Result<HttpResponse, AppError> {
+    let categories = store.get_categories().await?;
+    Ok(HttpResponse::Ok().json(json!({ "categories": categories })))
 }
 
 #[get("/api/category/{category_id}")]
-pub async fn get_category_by_id(category_id: web::Path<String>) -> impl Responder {
-   let category_id = match category_id.parse::<String>() {
-         Ok(category_id) => category_id,
-         Err(_) => return HttpResponse::BadRequest().json(json!({ "status": "error", "message": "Category not found" })),
-   };
-
-   match elastic::get_category_by_id(&category_id).await {
-      Ok(category) => HttpResponse::Ok().json(json!({ "category": category })),
-      Err(_) => HttpResponse::InternalServerError().json(json!({ "status": "error", "message": "Failed to fetch category" })),
-   }
+pub async fn get_category_by_id(store: Store, category_id: web::Path<String>) -> Result<HttpResponse, AppError> {
+   let category = store.get_category_by_id(&category_id).await?;
+   let rules_html = render_markdown(&category.rules);
+   Ok(HttpResponse::Ok().json(json!({ "category": category, "rules_html": rules_html })))
+}
+
+#[post("/api/category")]
+pub async fn create_category(store: Store, pool: DbPool, form: web::Form<CreateCategoryRequest>, user: AuthUser) -> Result<HttpResponse, AppError> {
+    let form = form.into_inner();
+
+    ban::check(pool.clone(), &user.id, None).await?;
+
+    validate_category_name(&form.name)?;
+    let description = form.description.unwrap_or_default();
+    let rules = form.rules.unwrap_or_default();
+    let post_types = validate_category(&description, &rules, form.post_types.as_deref())?;
+
+    let category = category::create_category(store.as_ref(), pool, user.id, form.name, description, rules, post_types).await?;
+
+    Ok(HttpResponse::Created().json(json!({ "category": category })))
+}
+
+/**
+ * Change the description, rules and allowed post types of a category. The name stays, posts and links refer to it.
+ */
+#[put("/api/category/{category_id}")]
+pub async fn update_category(store: Store, pool: DbPool, category_id: web::Path<String>, form: web::Form<UpdateCategoryRequest>, user: AuthUser) -> Result<HttpResponse, AppError> {
+    let category = store.get_category_by_id(&category_id).await?;
+
+    // Only the owner and admins can change a category
+    if !user.is_admin() && category.owner_id.as_ref() != Some(&user.id) {
+        return Err(AppError::Forbidden("Forbidden".to_string()));
+    }
+
+    let form = form.into_inner();
+    let post_types = validate_category(&form.description, &form.rules, form.post_types.as_deref())?;
+
+    store.update_category(category.id.clone(), form.description.clone(), form.rules.clone(), post_types.clone()).await?;
+
+    let details = json!({
+        "before": { "description": category.description, "rules": category.rules, "post_types": category.post_types },
+        "after": { "description": form.description, "rules": form.rules, "post_types": post_types },
+    });
+    audit::record(pool, &user.id, Action::UpdateCategory, &category.id, None, Some(details)).await?;
+
+    Ok(HttpResponse::Ok().json(json!({ "status": "ok", "message": "Category updated" })))
 }
 
 #[get("/api/category/{id}/posts")]
-pub async fn get_posts_by_category_id(id: web::Path<String>, query: web::Query<QueryParams>, req: HttpRequest) -> impl Responder {
-    // Convert the id to an integer
-    let id = match id.parse::<String>() {
-        Ok(id) => id,
-        Err(_) => return HttpResponse::BadRequest().json(json!({ "status": "error", "message": "Category not found" })),
-    };
-
-    let is_admin = match security::verify_user(&req) {
-        Ok((_, role)) => match role.eq("admin") {
-            true => true,
-            false => false,
-        },
-        Err(_) => false,
-    };
-
-    let show_all = is_admin && query.show_all.unwrap_or(false);
-
-    let posts = elastic::get_posts_by_category_id(id, &show_all).await;
-
-    HttpResponse::Ok().json(json!({ "posts": posts }))
-}
\ No newline at end of file
+pub async fn get_posts_by_category_id(store: Store, pool: DbPool, id: web::Path<String>, query: web::Query<QueryParams>, user: OptionalUser) -> Result<HttpResponse, AppError> {
+    let show_all = security::will_show_all(pool, &query, &user, Some(id.as_str())).await?;
+
+    let ranking = Ranking::from_query(&query.sort, &query.t)?;
+
+    let limit = validate_limit(query.limit)?;
+
+    let page = store.get_posts_by_category_id(id.into_inner(), &show_all, &ranking, limit, &query.cursor).await?;
+
+    Ok(HttpResponse::Ok().json(json!({ "posts": page.items, "next_cursor": page.next_cursor })))
+}
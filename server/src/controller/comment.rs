@@ -1,31 +1,208 @@
-use actix_web::{delete, web, HttpResponse, Responder, HttpRequest};
+use actix_web::{delete, get, post, put, web, HttpResponse};
 use serde_json::json;
-use crate::service::{elastic, security};
+use crate::{DbPool, Store};
+use crate::error::AppError;
+use crate::model::api::{CreateCommentRequest, UpdateCommentRequest, ModerationQuery, ReportRequest, VoteRequest};
+use crate::model::data::{Deletion, DeletionKind, Restoration, Revision};
+use crate::service::{audit, ban, vote, database, report, security};
+use crate::service::audit::Action;
+use crate::service::store::Index;
+use crate::service::auth::{AuthUser, AdminUser};
+use crate::utils::form_validation::{validate_new_comment, validate_vote, validate_report, validate_moderation_reason};
 
 
+/**
+ * Delete a comment. The author deletes it, anyone else who moderates the category of its post removes it
+ * with an optional `reason`.
+ */
 #[delete("/api/comment/{id}")]
-pub async fn unpublish_comment(comment_id: web::Path<String>, req: HttpRequest) -> impl Responder {
-    // XXX: Bad Practice! Should be moved to a middleware
-    let (user_id, role) = match security::verify_user(&req) {
-        Ok(claims) => claims,
-        Err(_) => return HttpResponse::Unauthorized().json(json!({ "status": "error", "message": "Unauthorized" })),
-    };
+pub async fn unpublish_comment(store: Store, pool: DbPool, comment_id: web::Path<String>, query: web::Query<ModerationQuery>, user: AuthUser) -> Result<HttpResponse, AppError> {
+    let comment = store.get_comment_source_by_id(comment_id.to_string()).await?;
 
-    let comment = match elastic::get_comment_by_id(comment_id.to_string(), &false).await {
-        Ok(comment) => comment,
-        Err(msg) => return HttpResponse::NotFound().json(json!({ "status": "error", "message": msg })),
-    };
+    // Deleted comments stay in their thread, so they can still be found
+    if comment.deleted {
+        return Err(AppError::Conflict("Comment is already deleted".to_string()));
+    }
+
+    // Authors deleting their own comments aren't moderation
+    if comment.author_id.eq(&user.id) {
+        store.delete_post(Index::Comment, comment_id.to_string(), Deletion::new(DeletionKind::Author, Some(user.id), None)).await?;
+
+        return Ok(HttpResponse::Ok().json(json!({ "status": "ok" })));
+    }
+
+    let post = store.get_post_source_by_id(comment.post_id.clone()).await?;
+    if !security::can_moderate(pool.clone(), &user, &post.category_id).await? {
+        return Err(AppError::Forbidden("Forbidden".to_string()));
+    }
+
+    let reason = validate_moderation_reason(query.into_inner().reason)?;
+    store.delete_post(Index::Comment, comment_id.to_string(), Deletion::new(DeletionKind::Moderator, Some(user.id.clone()), reason.clone())).await?;
+
+    audit::record(pool, &user.id, Action::RemoveComment, &comment_id, reason, None).await?;
+
+    Ok(HttpResponse::Ok().json(json!({ "status": "ok" })))
+}
+
+/**
+ * Bring back a deleted comment. What a moderator removed can be restored by the moderators of the category
+ * of its post, what the author deleted only by admins.
+ */
+#[post("/api/comment/{id}/restore")]
+pub async fn restore_comment(store: Store, pool: DbPool, comment_id: web::Path<String>, user: AuthUser) -> Result<HttpResponse, AppError> {
+    let comment = store.get_comment_source_by_id(comment_id.to_string()).await?;
+
+    if !comment.deleted {
+        return Err(AppError::Conflict("Comment is not deleted".to_string()));
+    }
+    if comment.purged {
+        return Err(AppError::Conflict("Comment was purged and can't be restored".to_string()));
+    }
+
+    let post = store.get_post_source_by_id(comment.post_id.clone()).await?;
+    if !security::can_restore(pool.clone(), &user, &post.category_id, comment.deletion.as_ref()).await? {
+        return Err(AppError::Forbidden("Forbidden".to_string()));
+    }
+
+    store.restore_post(Index::Comment, comment_id.to_string(), Restoration::new(user.id.clone())).await?;
+
+    audit::record(pool, &user.id, Action::RestoreComment, &comment_id, None, Some(json!({ "deletion": comment.deletion }))).await?;
+
+    Ok(HttpResponse::Ok().json(json!({ "status": "ok" })))
+}
+
+#[post("/api/comment/{id}/vote")]
+pub async fn vote_comment(store: Store, pool: DbPool, comment_id: web::Path<String>, form: web::Form<VoteRequest>, user: AuthUser) -> Result<HttpResponse, AppError> {
+    let value = validate_vote(&form)?;
+
+    let comment = store.get_comment_by_id(comment_id.to_string(), &false).await?;
+
+    // Deleted comments can't be voted on
+    if comment.deleted {
+        return Err(AppError::NotFound("Comment not found".to_string()));
+    }
 
-    // Check if the user is the author of the post
-    if !role.eq("admin") && !comment.author_id.eq(&user_id) {
-        return HttpResponse::Unauthorized().json(json!({ "status": "error", "message": "Unauthorized" }));
+    let post = store.get_post_source_by_id(comment.post_id.clone()).await?;
+    ban::check(pool.clone(), &user.id, Some(&post.category_id)).await?;
+
+    let (upvotes, downvotes) = vote::cast_vote(store.as_ref(), pool, user.id, comment.author_id, Index::Comment, comment_id.to_string(), value).await?;
+
+    Ok(HttpResponse::Ok().json(json!({ "status": "ok", "vote": value, "upvotes": upvotes, "downvotes": downvotes })))
+}
+
+/**
+ * Report a comment to the moderators of its post's category, once per user
+ */
+#[post("/api/comment/{id}/report")]
+pub async fn report_comment(store: Store, pool: DbPool, comment_id: web::Path<String>, form: web::Form<ReportRequest>, user: AuthUser) -> Result<HttpResponse, AppError> {
+    validate_report(&form)?;
+
+    let reports = report::report(store.as_ref(), pool, user.id, Index::Comment, comment_id.into_inner(), form.reason.trim().to_string()).await?;
+
+    Ok(HttpResponse::Created().json(json!({ "status": "ok", "reports": reports })))
+}
+
+#[put("/api/comment/{id}")]
+pub async fn update_comment(store: Store, pool: DbPool, comment_id: web::Path<String>, form: web::Form<UpdateCommentRequest>, user: AuthUser) -> Result<HttpResponse, AppError> {
+    let comment = store.get_comment_source_by_id(comment_id.to_string()).await?;
+
+    // Only the author can edit a comment, and only while it exists
+    if comment.deleted {
+        return Err(AppError::NotFound("Comment not found".to_string()));
+    }
+    if !comment.author_id.eq(&user.id) {
+        return Err(AppError::Forbidden("Forbidden".to_string()));
+    }
+
+    let post = store.get_post_source_by_id(comment.post_id.clone()).await?;
+    ban::check(pool.clone(), &user.id, Some(&post.category_id)).await?;
+
+    // Refuse the edit if the admins have set an edit window and it has passed
+    let edit_window = database::get_setting(pool, database::COMMENT_EDIT_WINDOW).await?
+        .and_then(|minutes| minutes.parse::<i64>().ok());
+    if let (Some(minutes), Ok(created_at)) = (edit_window, chrono::DateTime::parse_from_rfc3339(&comment.created_at)) {
+        if chrono::Utc::now() > created_at + chrono::Duration::minutes(minutes) {
+            return Err(AppError::Forbidden(format!("Comments can only be edited within {} minutes", minutes)));
+        }
     }
 
-    // Delete the comment
-    match elastic::delete_post(elastic::Index::Comment, comment_id.to_string()).await {
-        Ok(_) => (),
-        Err(msg) => return HttpResponse::InternalServerError().json(json!({ "status": "error", "message": msg })),
+    // Validate the form the same way as a new comment
+    let edit = CreateCommentRequest {
+        body: form.into_inner().body,
+        parent_id: None,
+    };
+    validate_new_comment(&edit)?;
+
+    let now = chrono::Utc::now().to_rfc3339();
+    let revision = Revision {
+        target_id: comment_id.to_string(),
+        title: None,
+        body: comment.body,
+        editor_id: user.id,
+        created_at: comment.edited_at.unwrap_or(comment.created_at),
+        replaced_at: now.clone(),
     };
 
-    HttpResponse::Ok().json(json!({ "status": "ok" }))
-}
\ No newline at end of file
+    store.index_revision(Index::Comment, revision).await?;
+    store.update_comment(comment_id.to_string(), edit.body, now.clone()).await?;
+
+    Ok(HttpResponse::Ok().json(json!({ "status": "ok", "edited_at": now })))
+}
+
+#[get("/api/comment/{id}/revisions")]
+pub async fn get_comment_revisions(store: Store, comment_id: web::Path<String>, _admin: AdminUser) -> Result<HttpResponse, AppError> {
+
+    let comment = store.get_comment_source_by_id(comment_id.to_string()).await?;
+    let revisions = store.get_revisions(Index::Comment, comment_id.to_string()).await?;
+
+    Ok(HttpResponse::Ok().json(json!({ "comment": comment, "revisions": revisions })))
+}
+#[cfg(test)]
+mod tests {
+    use actix_web::{test, App, http::StatusCode};
+
+    use super::*;
+    use crate::service::auth::ADMIN;
+    use crate::service::store::ContentStore;
+    use crate::test_utils::TestApp;
+
+    #[actix_web::test]
+    async fn restoring_a_comment_depends_on_who_deleted_it() {
+        let t = TestApp::new();
+        let owner = t.user("owner", "user").await;
+        let author = t.user("author", "user").await;
+        let admin = t.user("admin", ADMIN).await;
+        let category = t.category(&owner, "rust").await;
+        let post_id = t.post(&owner, &category, "Thread").await;
+        let deleted = t.comment(&author, &post_id, None, "Deleted by the author").await;
+        let removed = t.comment(&author, &post_id, None, "Removed by a moderator").await;
+
+        let app = test::init_service(App::new()
+            .configure(|cfg| t.configure(cfg))
+            .service(unpublish_comment)
+            .service(restore_comment)
+        ).await;
+
+        for (id, user) in [(&deleted, &author), (&removed, &owner)] {
+            let req = test::TestRequest::delete().uri(&format!("/api/comment/{}", id)).cookie(user.identity()).to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        }
+
+        let attempts = [
+            (&deleted, &author, StatusCode::FORBIDDEN),
+            (&deleted, &owner, StatusCode::FORBIDDEN),
+            (&deleted, &admin, StatusCode::OK),
+            (&removed, &author, StatusCode::FORBIDDEN),
+            (&removed, &owner, StatusCode::OK),
+            (&removed, &owner, StatusCode::CONFLICT),
+        ];
+        for (id, user, status) in attempts {
+            let req = test::TestRequest::post().uri(&format!("/api/comment/{}/restore", id)).cookie(user.identity()).to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), status, "{} restoring {}", user.user.username, id);
+        }
+
+        let comment = t.store.get_comment_source_by_id(deleted.clone()).await.unwrap();
+        assert!(!comment.deleted);
+        assert_eq!(comment.restoration.unwrap().restored_by, admin.user.id);
+    }
+}
@@ -4,4 +4,5 @@ pub mod category;
 pub mod search;
 pub mod comment;
 pub mod avatar;
-pub mod user;
\ No newline at end of file
+pub mod user;
+pub mod admin;pub mod report;
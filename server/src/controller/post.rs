@@ -1,134 +1,120 @@
-use actix_web::{get, post, delete, web, HttpResponse, Responder, HttpRequest};
+use actix_web::{get, post, put, delete, web, HttpResponse};
 use serde_json::json;
-use crate::DbPool;
-use crate::model::api::{CreatePostRequest, CreateCommentRequest, QueryParams};
-use crate::model::data::{Post, Comment};
-use crate::service::security::verify_user;
-use crate::service::{elastic, security, database};
-use crate::utils::form_validation::{validate_new_post, validate_new_comment};
+use crate::{AppConfig, DbPool, Store};
+use crate::error::AppError;
+use crate::model::api::{CreatePostRequest, UpdatePostRequest, CreateCommentRequest, ModerationQuery, QueryParams, ReportRequest, VoteRequest};
+use crate::model::data::{Post, PostType, Comment, Deletion, DeletionKind, Restoration, Revision, User};
+use crate::service::{audit, ban, category, report, security, database, vote};
+use crate::service::audit::Action;
+use crate::service::auth::{AuthUser, OptionalUser};
+use crate::service::store::Index;
+use crate::service::ranking::Ranking;
+use crate::utils::comment_tree::build_tree;
+use crate::utils::diff::line_diff;
+use crate::utils::form_validation::{validate_new_post, validate_new_comment, validate_reply, validate_vote, validate_limit, validate_report, validate_moderation_reason};
 
 #[get("/api/post/popular")]
-pub async fn get_popular_posts(query: web::Query<QueryParams>, req: HttpRequest) -> impl Responder {
+pub async fn get_popular_posts(store: Store, pool: DbPool, query: web::Query<QueryParams>, user: OptionalUser) -> Result<HttpResponse, AppError> {
 
-    let show_all = security::will_show_all(query, &req);
+    let ranking = Ranking::from_query(&query.sort, &query.t)?;
+
+    let limit = validate_limit(query.limit)?;
+    let cursor = query.cursor.clone();
+
+    // The feed spans every category, so only admins see deleted posts in it
+    let show_all = security::will_show_all(pool, &query, &user, None).await?;
 
     // Fetch the posts from the database and return a JSON response
-    let data = elastic::get_posts(&show_all).await;
+    let page = store.get_posts(&show_all, &ranking, limit, &cursor).await?;
     
-    match data {
-        Ok(data) => HttpResponse::Ok().json(json!({ "posts": data })),
-        Err(msg) => HttpResponse::InternalServerError().json(json!({ "status": "error", "message": msg })),
-    }
+    Ok(HttpResponse::Ok().json(json!({ "posts": page.items, "next_cursor": page.next_cursor })))
 }
 
 #[get("/api/post/me")]
-pub async fn get_own_posts(req: HttpRequest) -> impl Responder {
+pub async fn get_own_posts(store: Store, query: web::Query<QueryParams>, user: AuthUser) -> Result<HttpResponse, AppError> {
 
-    // XXX: Bad Practice! Should be moved to a middleware
-    let (user_id, _) = match security::verify_user(&req) {
-        Ok(claims) => claims,
-        Err(_) => return HttpResponse::Unauthorized().json(json!({ "status": "error", "message": "Unauthorized" })),
-    };
+    let limit = validate_limit(query.limit)?;
 
-    let posts = match elastic::get_posts_by_user_id(user_id.clone()).await {
-        Ok(posts) => posts,
-        Err(_) => return HttpResponse::InternalServerError().json(json!({ "status": "error", "message": "Failed to fetch posts" })),
-    };
+    let page = store.get_posts_by_user_id(user.id.clone(), limit, &query.cursor).await?;
 
-    HttpResponse::Ok().json(json!({ "posts": posts }))
+    Ok(HttpResponse::Ok().json(json!({ "posts": page.items, "next_cursor": page.next_cursor })))
 }
 
 #[get("/api/post/{id}")]
-pub async fn get_post_by_id(id: web::Path<String>, req: HttpRequest, query: web::Query<QueryParams>) -> impl Responder {
+pub async fn get_post_by_id(store: Store, pool: DbPool, id: web::Path<String>, user: OptionalUser, query: web::Query<QueryParams>) -> Result<HttpResponse, AppError> {
 
-    // Convert the id to an integer
-    let id = match id.parse::<String>() {
-        Ok(id) => id,
-        Err(_) => return HttpResponse::BadRequest().json(json!({ "status": "error", "message": "Post not found" })),
-    };
-    
-    let show_all = security::will_show_all(query, &req);
+    let category_id = requested_category_id(&store, &query, &id).await?;
+    let show_all = security::will_show_all(pool, &query, &user, category_id.as_deref()).await?;
 
-    let post = match elastic::get_post_by_id(id, &show_all).await {
-        Ok(post) => post,
-        Err(_) => return HttpResponse::InternalServerError().json(json!({ "status": "error", "message": "Failed to fetch post" })),
-    };
+    let post = store.get_post_by_id(id.into_inner(), &show_all).await?;
 
     // only post author and admins can view unpublished posts
     if !post.published {
-        match verify_user(&req) {
-            Ok((user_id, role)) => {
-                if !post.author_id.eq(&user_id) && !role.eq("admin")  {
-                    return HttpResponse::NotFound().json(json!({ "status": "error", "message": "Not Found" }));
-                }
-            },
-            Err(_) => {
-                return HttpResponse::NotFound().json(json!({ "status": "error", "message": "Not Found" }));
-            }
+        match &user.0 {
+            Some(user) if post.author_id.eq(&user.id) || user.is_admin() => (),
+            _ => return Err(AppError::NotFound("Post not found".to_string())),
         }
     }
     
-    let category = match elastic::get_category_by_id(&post.category_id).await {
-        Ok(category) => category,
-        Err(msg) => return HttpResponse::InternalServerError().json(json!({ "status": "error", "message": msg })),
-    };
+    let category = store.get_category_by_id(&post.category_id).await?;
 
-    HttpResponse::Ok().json(json!({ "category": category, "post": post }))
+    Ok(HttpResponse::Ok().json(json!({ "category": category, "post": post })))
 
 }
 
 #[get("/api/post/{id}/comment")]
-pub async fn get_comments_by_post_id(id: web::Path<String>, query: web::Query<QueryParams>, req: HttpRequest) -> impl Responder {
+pub async fn get_comments_by_post_id(store: Store, pool: DbPool, id: web::Path<String>, query: web::Query<QueryParams>, user: OptionalUser) -> Result<HttpResponse, AppError> {
 
-    let show_all = security::will_show_all(query, &req);
+    // Comments are ranked by "best" unless another sort is requested
+    let ranking = match &query.sort {
+        Some(_) => Ranking::from_query(&query.sort, &query.t)?,
+        None => Ranking::Best,
+    };
+
+    let limit = validate_limit(query.limit)?;
+    let cursor = query.cursor.clone();
+
+    let category_id = requested_category_id(&store, &query, &id).await?;
+    let show_all = security::will_show_all(pool, &query, &user, category_id.as_deref()).await?;
 
-    let comments = elastic::get_comments_by_post_id(id.to_string(), &show_all).await;
+    let page = store.get_comments_by_post_id(id.to_string(), &show_all, &ranking, limit, &cursor).await?;
 
-    HttpResponse::Ok().json(json!({ "comments": comments }))
+    Ok(HttpResponse::Ok().json(json!({ "comments": build_tree(page.items, &ranking), "next_cursor": page.next_cursor })))
 }
 
 #[post("/api/post")]
-pub async fn create_post(pool: DbPool, form: web::Form<CreatePostRequest>, query: web::Query<QueryParams>, req: HttpRequest) -> HttpResponse {
+pub async fn create_post(store: Store, pool: DbPool, form: web::Form<CreatePostRequest>, query: web::Query<QueryParams>, user: AuthUser) -> Result<HttpResponse, AppError> {
     
-    // XXX: Bad Practice! Should be moved to a middleware
-    let (user_id, _) = match security::verify_user(&req) {
-        Ok(claims) => claims,
-        Err(_) => return HttpResponse::Unauthorized().json(json!({ "status": "error", "message": "Unauthorized" })),
-    };
-
-    let user = match database::find_user_by_id(pool, user_id.clone()).await {
-        Ok(user) => user,
-        Err(_) => return HttpResponse::Unauthorized().json(json!({ "status": "error", "message": "User not found" })),
-    };
+    let user = find_author(pool.clone(), user.id.clone()).await?;
 
     // Validate the form
-    match validate_new_post(&form) {
-        Ok(_) => (),
-        Err(msg) => return HttpResponse::BadRequest().json(json!({ "status": "error", "message": msg })),
-    }
+    validate_new_post(&form)?;
 
-    // Get the category or create a new one
+    // Banned users can't post, and site wide banned ones can't create categories
+    ban::check(pool.clone(), &user.id, form.category_id.as_deref()).await?;
+
+    // Get the category or create a new one that allows every post type
     let category = match (&form.new_category, &form.category_id) {
-        (Some(new_category), _) => match elastic::index_category(new_category.clone()).await {
-            Ok(category) => category,
-            Err(msg) => return HttpResponse::InternalServerError().json(json!({ "status": "error", "message": msg })),
-        },
-        (_, Some(category_id)) => match elastic::get_category_by_id(category_id).await {
-            Ok(category) => category,
-            Err(msg) => return HttpResponse::InternalServerError().json(json!({ "status": "error", "message": msg })),
-        },
-        _ => return HttpResponse::InternalServerError().json(json!({ "status": "error", "message": "Failed to create category" })),
+        (Some(new_category), _) => category::create_category(store.as_ref(), pool, user.id.clone(), new_category.clone(), String::new(), String::new(), PostType::all()).await?,
+        (_, Some(category_id)) => store.get_category_by_id(category_id).await?,
+        _ => return Err(AppError::BadRequest("Category is required".to_string())),
     };
 
+    let post_type = form.post_type.unwrap_or_default();
+    if !category.allows(&post_type) {
+        return Err(AppError::BadRequest(format!("{} doesn't allow this type of post", category.name)));
+    }
+
     let now = chrono::Utc::now().to_rfc3339();
     let is_draft = query.draft.unwrap_or(false);
 
     let post = Post {
         id: None,
-        author_id: user_id,
+        author_id: user.id,
         author_name: user.username,
         category_id: category.id,
         category_name: category.name,
+        post_type,
         title: form.title.clone(),
         body: form.body.clone(),
         upvotes: 0,
@@ -137,106 +123,624 @@ pub async fn create_post(pool: DbPool, form: web::Form<CreatePostRequest>, query
         created_at: now.clone(),
         updated_at: now,
         deleted: false,
+        deletion: None,
+        restoration: None,
+        locked: false,
     };
 
-    match elastic::index_post(post).await {
-        Ok(post) => HttpResponse::Created().json(json!({ "category_id": post.category_id, "post_id": post.id })),
-        Err(msg) => HttpResponse::InternalServerError().json(json!({ "status": "error", "message": msg })),
-    }
+    let post = store.index_post(post).await?;
+
+    Ok(HttpResponse::Created().json(json!({ "category_id": post.category_id, "post_id": post.id })))
 }
 
-#[post("api/post/{id}/publish")]
-pub async fn publish_post(id: web::Path<String>, req: HttpRequest) -> HttpResponse {
-    
-    // XXX: Bad Practice! Should be moved to a middleware
-    let (user_id, _) = match security::verify_user(&req) {
-        Ok(user_id) => user_id,
-        Err(_) => return HttpResponse::Unauthorized().json(json!({ "status": "error", "message": "Unauthorized" })),
+#[put("/api/post/{id}")]
+pub async fn update_post(store: Store, pool: DbPool, id: web::Path<String>, form: web::Form<UpdatePostRequest>, user: AuthUser) -> Result<HttpResponse, AppError> {
+
+    // The stored post is needed unrendered, since it becomes the previous revision
+    let post = store.get_post_source_by_id(id.clone()).await?;
+
+    // Only the author can edit a post, and only while it exists
+    if post.deleted {
+        return Err(AppError::NotFound("Post not found".to_string()));
+    }
+    if !post.author_id.eq(&user.id) {
+        return Err(AppError::Forbidden("Forbidden".to_string()));
+    }
+    ban::check(pool, &user.id, Some(&post.category_id)).await?;
+
+    // Validate the form the same way as a new post in the post's category
+    let form = form.into_inner();
+    let edit = CreatePostRequest {
+        new_category: None,
+        category_id: Some(post.category_id.clone()),
+        post_type: Some(post.post_type),
+        title: form.title,
+        body: form.body,
     };
+    validate_new_post(&edit)?;
 
-    let post = match elastic::get_post_by_id(id.clone(), &false).await {
-        Ok(post) => post,
-        Err(_) => return HttpResponse::NotFound().json(json!({ "status": "error", "message": "Post not found" })),
+    let now = chrono::Utc::now().to_rfc3339();
+    let revision = Revision {
+        target_id: id.to_string(),
+        title: Some(post.title),
+        body: post.body,
+        editor_id: user.id,
+        created_at: post.updated_at,
+        replaced_at: now.clone(),
     };
 
-    // Check if the user the author of the post
-    if !post.author_id.eq(&user_id) {
-        return HttpResponse::Forbidden().json(json!({ "status": "error", "message": "Forbidden" }));
+    store.index_revision(Index::Post, revision).await?;
+    store.update_post(id.to_string(), edit.title, edit.body, now).await?;
+
+    Ok(HttpResponse::Ok().json(json!({ "status": "success", "message": "Post updated" })))
+}
+
+/// Only the latest versions of a post are compared with the ones before them, older ones come without a diff
+const DIFFED_VERSIONS: usize = 20;
+
+#[get("/api/post/{id}/revisions")]
+pub async fn get_post_revisions(store: Store, pool: DbPool, id: web::Path<String>, user: AuthUser) -> Result<HttpResponse, AppError> {
+
+    let post = store.get_post_source_by_id(id.clone()).await?;
+
+    // Only the author and the moderators of the category can see the edit history
+    if !post.author_id.eq(&user.id) && !security::can_moderate(pool, &user, &post.category_id).await? {
+        return Err(AppError::Forbidden("Forbidden".to_string()));
     }
 
-    match elastic::publish_post(id.to_string()).await {
-        Ok(_) => HttpResponse::Ok().json(json!({ "status": "success", "message": "Post published" })),
-        Err(msg) => HttpResponse::InternalServerError().json(json!({ "status": "error", "message": msg })),
+    let revisions = store.get_revisions(Index::Post, id.to_string()).await?;
+
+    // Every earlier version followed by the current one, each compared with the version before it
+    let mut versions: Vec<(Option<String>, String, String)> = revisions.into_iter()
+        .map(|revision| (revision.title, revision.body, revision.created_at))
+        .collect();
+    versions.push((Some(post.title.clone()), post.body.clone(), post.updated_at.clone()));
+
+    let first_diffed = versions.len().saturating_sub(DIFFED_VERSIONS);
+    let versions = versions.iter().enumerate().map(|(i, (title, body, created_at))| {
+        let (previous_title, previous_body) = match i {
+            0 => (None, ""),
+            _ => (versions[i - 1].0.clone(), versions[i - 1].1.as_str()),
+        };
+
+        let diff = match i >= first_diffed {
+            true => Some(line_diff(previous_body, body)),
+            false => None,
+        };
+
+        json!({
+            "version": i + 1,
+            "title": title,
+            "body": body,
+            "created_at": created_at,
+            "title_changed": i > 0 && previous_title.ne(title),
+            "diff": diff,
+        })
+    }).collect::<Vec<_>>();
+
+    Ok(HttpResponse::Ok().json(json!({ "post_id": id.to_string(), "versions": versions })))
+}
+
+#[post("api/post/{id}/publish")]
+pub async fn publish_post(store: Store, id: web::Path<String>, user: AuthUser) -> Result<HttpResponse, AppError> {
+    
+    let post = store.get_post_by_id(id.clone(), &false).await?;
+
+    // Check if the user the author of the post
+    if !post.author_id.eq(&user.id) {
+        return Err(AppError::Forbidden("Forbidden".to_string()));
     }
+
+    store.publish_post(id.to_string()).await?;
+
+    Ok(HttpResponse::Ok().json(json!({ "status": "success", "message": "Post published" })))
 }
 
 #[post("/api/post/{id}/comment")]
-pub async fn create_comment(pool: DbPool, id: web::Path<String>, form: web::Form<CreateCommentRequest>, req: HttpRequest) -> HttpResponse {
+pub async fn create_comment(store: Store, pool: DbPool, config: AppConfig, id: web::Path<String>, form: web::Form<CreateCommentRequest>, user: AuthUser) -> Result<HttpResponse, AppError> {
     
-    // XXX: Bad Practice! Should be moved to a middleware
-    let (user_id, _) = match security::verify_user(&req) {
-        Ok(claims) => claims,
-        Err(_) => return HttpResponse::Unauthorized().json(json!({ "status": "error", "message": "Unauthorized" })),
-    };
-
-    let user = match database::find_user_by_id(pool, user_id.clone()).await {
-        Ok(user) => user,
-        Err(_) => return HttpResponse::Unauthorized().json(json!({ "status": "error", "message": "User not found" })),
-    };
+    let author = find_author(pool.clone(), user.id.clone()).await?;
 
     // Validate the form
-    match validate_new_comment(&form) {
-        Ok(_) => (),
-        Err(msg) => return HttpResponse::BadRequest().json(json!({ "status": "error", "message": msg })),
-    }
+    validate_new_comment(&form)?;
 
     // Get the post
-    let post = match elastic::get_post_by_id(id.clone(), &false).await {
-        Ok(post) => post,
-        Err(_) => return HttpResponse::NotFound().json(json!({ "status": "error", "message": "Post not found" })),
+    let post = store.get_post_by_id(id.clone(), &false).await?;
+
+    ban::check(pool.clone(), &user.id, Some(&post.category_id)).await?;
+
+    // Moderators can still answer in a locked thread
+    if post.locked && !security::can_moderate(pool, &user, &post.category_id).await? {
+        return Err(AppError::Forbidden("This thread is locked".to_string()));
+    }
+
+    let post_id = post.id.unwrap_or(id.into_inner());
+
+    // Replies must point to a comment on the same post and respect the maximum depth
+    let (parent_id, root_id, depth) = match form.parent_id.as_ref().filter(|id| !id.is_empty()) {
+        Some(parent_id) => {
+            let parent = match store.get_comment_by_id(parent_id.clone(), &false).await {
+                Ok(parent) => parent,
+                Err(AppError::NotFound(_)) => return Err(AppError::BadRequest("Parent comment not found".to_string())),
+                Err(e) => return Err(e),
+            };
+
+            let depth = validate_reply(&parent, &post_id, config.comments.max_depth)?;
+
+            (Some(parent_id.clone()), parent.root_id.or(parent.id), depth)
+        },
+        None => (None, None, 0),
     };
 
     let now = chrono::Utc::now().to_rfc3339();
     let comment = Comment {
         id: None,
-        author_id: user_id,
-        author_name: user.username,
-        post_id: post.id.unwrap(),
+        author_id: author.id,
+        author_name: author.username,
+        post_id,
+        parent_id,
+        root_id,
+        depth,
         body: form.body.clone(),
         deleted: false,
+        deletion: None,
+        restoration: None,
+        purged: false,
         upvotes: 0,
         downvotes: 0,
         created_at: now.clone(),
         updated_at: now,
+        edited_at: None,
     };
 
-    match elastic::index_comment(comment).await {
-        Ok(comment) => HttpResponse::Created().json(json!({ "post_id": comment.post_id, "comment_id": comment.id })),
-        Err(msg) => HttpResponse::InternalServerError().json(json!({ "status": "error", "message": msg })),
-    }
+    let comment = store.index_comment(comment).await?;
+
+    Ok(HttpResponse::Created().json(json!({ "post_id": comment.post_id, "comment_id": comment.id })))
 }
 
+/**
+ * Delete a post. The author deletes it, anyone else who moderates its category removes it with an optional `reason`.
+ */
 #[delete("/api/post/{id}")]
-pub async fn unpublish_post(id: web::Path<String>, req: HttpRequest) -> HttpResponse {
+pub async fn unpublish_post(store: Store, pool: DbPool, id: web::Path<String>, query: web::Query<ModerationQuery>, user: AuthUser) -> Result<HttpResponse, AppError> {
     
-    // XXX: Bad Practice! Should be moved to a middleware
-    let (user_id, role) = match security::verify_user(&req) {
-        Ok(user_id) => user_id,
-        Err(_) => return HttpResponse::Unauthorized().json(json!({ "status": "error", "message": "Unauthorized" })),
-    };
+    let post = store.get_post_by_id(id.clone(), &false).await?;
+
+    // Authors deleting their own posts aren't moderation
+    if post.author_id.eq(&user.id) {
+        store.delete_post(Index::Post, id.clone(), Deletion::new(DeletionKind::Author, Some(user.id), None)).await?;
+
+        return Ok(HttpResponse::Ok().json(json!({ "status": "success", "message": "Post deleted" })));
+    }
+
+    if !security::can_moderate(pool.clone(), &user, &post.category_id).await? {
+        return Err(AppError::Forbidden("Forbidden".to_string()));
+    }
+
+    let reason = validate_moderation_reason(query.into_inner().reason)?;
+    store.delete_post(Index::Post, id.clone(), Deletion::new(DeletionKind::Moderator, Some(user.id.clone()), reason.clone())).await?;
+
+    audit::record(pool, &user.id, Action::RemovePost, &id, reason, None).await?;
+
+    Ok(HttpResponse::Ok().json(json!({ "status": "success", "message": "Post removed" })))
+}
+
+/**
+ * Bring back a deleted post. What a moderator removed can be restored by the moderators of its category,
+ * what the author deleted only by admins.
+ */
+#[post("/api/post/{id}/restore")]
+pub async fn restore_post(store: Store, pool: DbPool, id: web::Path<String>, user: AuthUser) -> Result<HttpResponse, AppError> {
+
+    let post = store.get_post_source_by_id(id.clone()).await?;
+
+    if !post.deleted {
+        return Err(AppError::Conflict("Post is not deleted".to_string()));
+    }
+    if !security::can_restore(pool.clone(), &user, &post.category_id, post.deletion.as_ref()).await? {
+        return Err(AppError::Forbidden("Forbidden".to_string()));
+    }
+
+    store.restore_post(Index::Post, id.clone(), Restoration::new(user.id.clone())).await?;
+
+    audit::record(pool, &user.id, Action::RestorePost, &id, None, Some(json!({ "deletion": post.deletion }))).await?;
+
+    Ok(HttpResponse::Ok().json(json!({ "status": "success", "message": "Post restored" })))
+}
+
+/**
+ * Report a post to the moderators of its category, once per user
+ */
+#[post("/api/post/{id}/report")]
+pub async fn report_post(store: Store, pool: DbPool, id: web::Path<String>, form: web::Form<ReportRequest>, user: AuthUser) -> Result<HttpResponse, AppError> {
+
+    validate_report(&form)?;
+
+    let reports = report::report(store.as_ref(), pool, user.id, Index::Post, id.into_inner(), form.reason.trim().to_string()).await?;
+
+    Ok(HttpResponse::Created().json(json!({ "status": "ok", "reports": reports })))
+}
+
+/**
+ * Stop new comments on a post, only moderators of its category can
+ */
+#[post("/api/post/{id}/lock")]
+pub async fn lock_post(store: Store, pool: DbPool, id: web::Path<String>, user: AuthUser) -> Result<HttpResponse, AppError> {
+    set_locked(store, pool, id.into_inner(), user, true).await?;
+
+    Ok(HttpResponse::Ok().json(json!({ "status": "success", "message": "Post locked" })))
+}
+
+#[delete("/api/post/{id}/lock")]
+pub async fn unlock_post(store: Store, pool: DbPool, id: web::Path<String>, user: AuthUser) -> Result<HttpResponse, AppError> {
+    set_locked(store, pool, id.into_inner(), user, false).await?;
+
+    Ok(HttpResponse::Ok().json(json!({ "status": "success", "message": "Post unlocked" })))
+}
+
+#[post("/api/post/{id}/vote")]
+pub async fn vote_post(store: Store, pool: DbPool, id: web::Path<String>, form: web::Form<VoteRequest>, user: AuthUser) -> Result<HttpResponse, AppError> {
+
+    let value = validate_vote(&form)?;
+
+    let post = store.get_post_by_id(id.clone(), &false).await?;
 
-    let post = match elastic::get_post_by_id(id.clone(), &false).await {
-        Ok(post) => post,
-        Err(_) => return HttpResponse::NotFound().json(json!({ "status": "error", "message": "Post not found" })),
+    // Only published posts that still exist can be voted on
+    if post.deleted || !post.published {
+        return Err(AppError::NotFound("Post not found".to_string()));
+    }
+    ban::check(pool.clone(), &user.id, Some(&post.category_id)).await?;
+
+    let (upvotes, downvotes) = vote::cast_vote(store.as_ref(), pool, user.id, post.author_id, Index::Post, id.to_string(), value).await?;
+
+    Ok(HttpResponse::Ok().json(json!({ "status": "ok", "vote": value, "upvotes": upvotes, "downvotes": downvotes })))
+}
+
+/**
+ * Look up the user behind a token. A token can outlive its account, so a missing user isn't a 404 here.
+ */
+async fn find_author(pool: DbPool, user_id: String) -> Result<User, AppError> {
+    match database::find_user_by_id(pool, user_id).await {
+        Ok(user) => Ok(user),
+        Err(AppError::NotFound(_)) => Err(AppError::Unauthorized("User not found".to_string())),
+        Err(e) => Err(e),
+    }
+}
+
+async fn set_locked(store: Store, pool: DbPool, post_id: String, user: AuthUser, locked: bool) -> Result<(), AppError> {
+    let post = store.get_post_source_by_id(post_id.clone()).await?;
+
+    if !security::can_moderate(pool.clone(), &user, &post.category_id).await? {
+        return Err(AppError::Forbidden("Forbidden".to_string()));
+    }
+
+    store.lock_post(post_id.clone(), locked).await?;
+
+    let action = match locked {
+        true => Action::LockPost,
+        false => Action::UnlockPost,
     };
+    audit::record(pool, &user.id, action, &post_id, None, None).await
+}
 
-    // Check if the user is an admin or the author of the post
-    if !role.eq("admin") && !post.author_id.eq(&user_id) {
-        return HttpResponse::Forbidden().json(json!({ "status": "error", "message": "Forbidden" }));
+/**
+ * The category of a post, but only when deleted content is asked for, since that is when moderators
+ * have to be told apart. Otherwise the extra lookup is skipped.
+ */
+async fn requested_category_id(store: &Store, query: &QueryParams, post_id: &str) -> Result<Option<String>, AppError> {
+    match query.show_all.unwrap_or(false) {
+        true => Ok(Some(store.get_post_source_by_id(post_id.to_string()).await?.category_id)),
+        false => Ok(None),
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
 
-    match elastic::delete_post(elastic::Index::Post, id.clone()).await {
-        Ok(_) => HttpResponse::Ok().json(json!({ "status": "success", "message": "Post deleted" })),
-        Err(msg) => HttpResponse::InternalServerError().json(json!({ "status": "error", "message": msg })),
+    use actix_web::{test, App, http::StatusCode};
+    use serde_json::Value;
+
+    use super::*;
+    use crate::service::auth::ADMIN;
+    use crate::service::store::ContentStore;
+    use crate::test_utils::TestApp;
+
+    #[actix_web::test]
+    async fn concurrent_votes_all_count_once() {
+        let t = TestApp::new();
+        let author = t.user("author", "user").await;
+        let category = t.category(&author, "rust").await;
+        let post_id = t.post(&author, &category, "Votes").await;
+
+        let mut voters = Vec::new();
+        for i in 0..8 {
+            voters.push(t.user(&format!("voter{}", i), "user").await);
+        }
+
+        let app = Rc::new(test::init_service(App::new().configure(|cfg| t.configure(cfg)).service(vote_post)).await);
+
+        // Every voter upvotes three times at once, only one of each should count
+        let mut requests = Vec::new();
+        for voter in &voters {
+            for _ in 0..3 {
+                let app = app.clone();
+                let req = test::TestRequest::post().uri(&format!("/api/post/{}/vote", post_id))
+                    .cookie(voter.identity())
+                    .set_form([("direction", "up")])
+                    .to_request();
+                requests.push(actix_web::rt::spawn(async move { test::call_service(app.as_ref(), req).await.status() }));
+            }
+        }
+        for request in requests {
+            assert_eq!(request.await.unwrap(), StatusCode::OK);
+        }
+
+        let post = t.store.get_post_by_id(post_id.clone(), &false).await.unwrap();
+        assert_eq!((post.upvotes, post.downvotes), (8, 0));
+
+        // Half of them change their mind, again all at once
+        let mut requests = Vec::new();
+        for voter in &voters[..4] {
+            let app = app.clone();
+            let req = test::TestRequest::post().uri(&format!("/api/post/{}/vote", post_id))
+                .cookie(voter.identity())
+                .set_form([("direction", "down")])
+                .to_request();
+            requests.push(actix_web::rt::spawn(async move { test::call_service(app.as_ref(), req).await.status() }));
+        }
+        for request in requests {
+            assert_eq!(request.await.unwrap(), StatusCode::OK);
+        }
+
+        let post = t.store.get_post_by_id(post_id, &false).await.unwrap();
+        assert_eq!((post.upvotes, post.downvotes), (4, 4));
     }
-}
\ No newline at end of file
+
+    #[actix_web::test]
+    async fn vote_can_be_cleared() {
+        let t = TestApp::new();
+        let author = t.user("author", "user").await;
+        let voter = t.user("voter", "user").await;
+        let category = t.category(&author, "rust").await;
+        let post_id = t.post(&author, &category, "Votes").await;
+        let app = test::init_service(App::new().configure(|cfg| t.configure(cfg)).service(vote_post)).await;
+
+        for (direction, upvotes, downvotes) in [("down", 0, 1), ("down", 0, 1), ("up", 1, 0), ("clear", 0, 0)] {
+            let req = test::TestRequest::post().uri(&format!("/api/post/{}/vote", post_id))
+                .cookie(voter.identity())
+                .set_form([("direction", direction)])
+                .to_request();
+            let body: Value = test::call_and_read_body_json(&app, req).await;
+            assert_eq!((body["upvotes"].as_u64(), body["downvotes"].as_u64()), (Some(upvotes), Some(downvotes)));
+        }
+    }
+
+    #[actix_web::test]
+    async fn comments_come_back_as_a_tree() {
+        let t = TestApp::new();
+        let author = t.user("author", "user").await;
+        let category = t.category(&author, "rust").await;
+        let post_id = t.post(&author, &category, "Threads").await;
+        let app = test::init_service(App::new()
+            .configure(|cfg| t.configure(cfg))
+            .service(create_comment)
+            .service(get_comments_by_post_id)
+        ).await;
+
+        let comment = |body: &str, parent_id: Option<&str>| {
+            let mut form = vec![("body", body.to_string())];
+            if let Some(parent_id) = parent_id {
+                form.push(("parent_id", parent_id.to_string()));
+            }
+            test::TestRequest::post().uri(&format!("/api/post/{}/comment", post_id))
+                .cookie(author.identity())
+                .set_form(form)
+                .to_request()
+        };
+
+        let first: Value = test::call_and_read_body_json(&app, comment("First", None)).await;
+        let first_id = first["comment_id"].as_str().unwrap().to_string();
+        let reply: Value = test::call_and_read_body_json(&app, comment("Reply", Some(&first_id))).await;
+        let reply_id = reply["comment_id"].as_str().unwrap().to_string();
+        let nested: Value = test::call_and_read_body_json(&app, comment("Nested", Some(&reply_id))).await;
+        let second: Value = test::call_and_read_body_json(&app, comment("Second", None)).await;
+
+        let req = test::TestRequest::post().uri(&format!("/api/post/{}/comment", post_id))
+            .cookie(author.identity())
+            .set_form([("body", "Lost"), ("parent_id", "missing")])
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::BAD_REQUEST);
+
+        let req = test::TestRequest::get().uri(&format!("/api/post/{}/comment?sort=new", post_id)).to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        let roots = body["comments"].as_array().unwrap();
+
+        assert_eq!(roots.len(), 2);
+        assert_eq!(roots[0]["id"], second["comment_id"]);
+        assert_eq!(roots[1]["id"], first["comment_id"]);
+
+        let reply = &roots[1]["replies"][0];
+        assert_eq!(reply["id"], reply_id.as_str());
+        assert_eq!(reply["depth"], 1);
+
+        let nested_reply = &reply["replies"][0];
+        assert_eq!(nested_reply["id"], nested["comment_id"]);
+        assert_eq!(nested_reply["depth"], 2);
+        assert_eq!(nested_reply["root_id"], first_id.as_str());
+    }
+
+    #[actix_web::test]
+    async fn edits_keep_a_history_for_the_author_and_moderators() {
+        let t = TestApp::new();
+        let author = t.user("author", "user").await;
+        let moderator = t.user("moderator", "user").await;
+        let stranger = t.user("stranger", "user").await;
+        let category = t.category(&author, "rust").await;
+        database::grant_moderator(t.pool.clone(), moderator.user.id.clone(), category.id.clone(), author.user.id.clone()).await.unwrap();
+        let post_id = t.post(&author, &category, "First title").await;
+        let app = test::init_service(App::new()
+            .configure(|cfg| t.configure(cfg))
+            .service(update_post)
+            .service(get_post_revisions)
+        ).await;
+
+        for (title, body) in [("First title", "line one\nline two"), ("Second title", "line one\nline 2")] {
+            let req = test::TestRequest::put().uri(&format!("/api/post/{}", post_id))
+                .cookie(author.identity())
+                .set_form([("title", title), ("body", body)])
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        }
+
+        // Nobody else can edit the post
+        let req = test::TestRequest::put().uri(&format!("/api/post/{}", post_id))
+            .cookie(moderator.identity())
+            .set_form([("title", "Taken over"), ("body", "Not my post at all")])
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+
+        let req = test::TestRequest::get().uri(&format!("/api/post/{}/revisions", post_id)).cookie(author.identity()).to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        let versions = body["versions"].as_array().unwrap();
+
+        assert_eq!(versions.len(), 3);
+        assert_eq!(versions[1]["title_changed"], false);
+        assert_eq!(versions[2]["title"], "Second title");
+        assert_eq!(versions[2]["title_changed"], true);
+        assert_eq!(versions[2]["diff"], json!([
+            { "op": "equal", "line": "line one" },
+            { "op": "delete", "line": "line two" },
+            { "op": "insert", "line": "line 2" },
+        ]));
+
+        let req = test::TestRequest::get().uri(&format!("/api/post/{}/revisions", post_id)).cookie(moderator.identity()).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        let req = test::TestRequest::get().uri(&format!("/api/post/{}/revisions", post_id)).cookie(stranger.identity()).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn own_posts_are_paged_with_a_cursor() {
+        let t = TestApp::new();
+        let author = t.user("author", "user").await;
+        let category = t.category(&author, "rust").await;
+        for i in 0..5 {
+            t.post(&author, &category, &format!("Post {}", i)).await;
+        }
+        let app = test::init_service(App::new().configure(|cfg| t.configure(cfg)).service(get_own_posts)).await;
+
+        let mut titles = Vec::new();
+        let mut cursor: Option<String> = None;
+        let mut pages = 0;
+        loop {
+            let uri = match &cursor {
+                Some(cursor) => format!("/api/post/me?limit=2&cursor={}", cursor),
+                None => "/api/post/me?limit=2".to_string(),
+            };
+            let req = test::TestRequest::get().uri(&uri).cookie(author.identity()).to_request();
+            let body: Value = test::call_and_read_body_json(&app, req).await;
+
+            pages += 1;
+            titles.extend(body["posts"].as_array().unwrap().iter().map(|post| post["title"].as_str().unwrap().to_string()));
+            cursor = body["next_cursor"].as_str().map(|cursor| cursor.to_string());
+            if cursor.is_none() {
+                break;
+            }
+        }
+
+        assert_eq!(pages, 3);
+        assert_eq!(titles, vec!["Post 4", "Post 3", "Post 2", "Post 1", "Post 0"]);
+
+        let req = test::TestRequest::get().uri("/api/post/me?cursor=garbage").cookie(author.identity()).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn comments_are_paged_by_thread_with_every_reply() {
+        let t = TestApp::new();
+        let author = t.user("author", "user").await;
+        let category = t.category(&author, "rust").await;
+        let post_id = t.post(&author, &category, "Threads").await;
+        let app = test::init_service(App::new()
+            .configure(|cfg| t.configure(cfg))
+            .service(create_comment)
+            .service(get_comments_by_post_id)
+        ).await;
+
+        // Three threads, the oldest one busy
+        let mut root_ids = Vec::new();
+        for i in 0..3 {
+            let req = test::TestRequest::post().uri(&format!("/api/post/{}/comment", post_id))
+                .cookie(author.identity())
+                .set_form([("body", format!("Root {}", i))])
+                .to_request();
+            let body: Value = test::call_and_read_body_json(&app, req).await;
+            root_ids.push(body["comment_id"].as_str().unwrap().to_string());
+        }
+        for i in 0..30 {
+            let req = test::TestRequest::post().uri(&format!("/api/post/{}/comment", post_id))
+                .cookie(author.identity())
+                .set_form([("body", format!("Reply {}", i)), ("parent_id", root_ids[0].clone())])
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CREATED);
+        }
+
+        let req = test::TestRequest::get().uri(&format!("/api/post/{}/comment?sort=new&limit=2", post_id)).to_request();
+        let first: Value = test::call_and_read_body_json(&app, req).await;
+        let roots = first["comments"].as_array().unwrap();
+        assert_eq!(roots.len(), 2);
+        assert_eq!(roots[0]["id"], root_ids[2].as_str());
+        assert_eq!(roots[1]["id"], root_ids[1].as_str());
+
+        let cursor = first["next_cursor"].as_str().unwrap();
+        let req = test::TestRequest::get().uri(&format!("/api/post/{}/comment?sort=new&limit=2&cursor={}", post_id, cursor)).to_request();
+        let second: Value = test::call_and_read_body_json(&app, req).await;
+        let roots = second["comments"].as_array().unwrap();
+        assert_eq!(roots.len(), 1);
+        assert_eq!(roots[0]["id"], root_ids[0].as_str());
+        assert_eq!(roots[0]["replies"].as_array().unwrap().len(), 30);
+        assert!(second["next_cursor"].is_null());
+    }
+
+    #[actix_web::test]
+    async fn restoring_a_post_depends_on_who_deleted_it() {
+        let t = TestApp::new();
+        let owner = t.user("owner", "user").await;
+        let author = t.user("author", "user").await;
+        let admin = t.user("admin", ADMIN).await;
+        let category = t.category(&owner, "rust").await;
+        let deleted = t.post(&author, &category, "Deleted by the author").await;
+        let removed = t.post(&author, &category, "Removed by a moderator").await;
+
+        let app = test::init_service(App::new()
+            .configure(|cfg| t.configure(cfg))
+            .service(unpublish_post)
+            .service(restore_post)
+        ).await;
+
+        for (id, user) in [(&deleted, &author), (&removed, &owner)] {
+            let req = test::TestRequest::delete().uri(&format!("/api/post/{}", id)).cookie(user.identity()).to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        }
+
+        // Only admins bring back what authors deleted, moderators bring back what they removed
+        let attempts = [
+            (&deleted, &author, StatusCode::FORBIDDEN),
+            (&deleted, &owner, StatusCode::FORBIDDEN),
+            (&deleted, &admin, StatusCode::OK),
+            (&deleted, &admin, StatusCode::CONFLICT),
+            (&removed, &author, StatusCode::FORBIDDEN),
+            (&removed, &owner, StatusCode::OK),
+        ];
+        for (id, user, status) in attempts {
+            let req = test::TestRequest::post().uri(&format!("/api/post/{}/restore", id)).cookie(user.identity()).to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), status, "{} restoring {}", user.user.username, id);
+        }
+
+        let post = t.store.get_post_source_by_id(removed.clone()).await.unwrap();
+        assert!(!post.deleted);
+        assert_eq!(post.restoration.unwrap().restored_by, owner.user.id);
+    }
+}
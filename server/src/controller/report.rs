@@ -0,0 +1,48 @@
+use actix_web::{get, post, web, HttpResponse};
+use serde_json::json;
+
+use crate::{DbPool, Store};
+use crate::error::AppError;
+use crate::model::api::{ReportQuery, ResolveReportRequest};
+use crate::service::{database, report, security};
+use crate::service::auth::AuthUser;
+use crate::utils::form_validation::{validate_limit, validate_moderation_reason};
+
+/**
+ * The moderation queue: reported posts and comments, most reported first. Open items unless
+ * another `status` is asked for, filtered by `type` and `category_id`.
+ */
+#[get("/api/report")]
+pub async fn get_reports(pool: DbPool, query: web::Query<ReportQuery>, user: AuthUser) -> Result<HttpResponse, AppError> {
+    let limit = validate_limit(query.limit)?;
+
+    let page = report::list(pool, &user, &query, limit).await?;
+
+    Ok(HttpResponse::Ok().json(json!({ "reports": page.items, "next_cursor": page.next_cursor })))
+}
+
+/**
+ * A queue item with every report and its reason
+ */
+#[get("/api/report/{target_id}")]
+pub async fn get_report(pool: DbPool, target_id: web::Path<String>, user: AuthUser) -> Result<HttpResponse, AppError> {
+    let item = database::find_report_item(pool.clone(), target_id.to_string()).await?;
+
+    if !security::can_moderate(pool.clone(), &user, &item.category_id).await? {
+        return Err(AppError::Forbidden("Forbidden".to_string()));
+    }
+
+    let reports = database::find_reports_by_target_id(pool, target_id.into_inner()).await?;
+
+    Ok(HttpResponse::Ok().json(json!({ "report": item, "reports": reports })))
+}
+
+#[post("/api/report/{target_id}/resolve")]
+pub async fn resolve_report(store: Store, pool: DbPool, target_id: web::Path<String>, form: web::Form<ResolveReportRequest>, user: AuthUser) -> Result<HttpResponse, AppError> {
+    let form = form.into_inner();
+    let reason = validate_moderation_reason(form.reason)?;
+
+    report::resolve(store.as_ref(), pool, &user, target_id.into_inner(), form.action, reason).await?;
+
+    Ok(HttpResponse::Ok().json(json!({ "status": "ok" })))
+}
@@ -1,19 +1,18 @@
 use actix_web::{get, web::Query, HttpResponse};
 use serde_json::json;
-use crate::{service::elastic, model::api::QueryParams};
+use crate::{Searcher, error::AppError, model::api::QueryParams, utils::form_validation::validate_limit};
 
 #[get("/api/search")]
-pub async fn search(query: Query<QueryParams>) -> HttpResponse {
+pub async fn search(index: Searcher, params: Query<QueryParams>) -> Result<HttpResponse, AppError> {
 
-    let query = match &query.q {
+    let query = match &params.q {
         Some(query) => query,
-        None => return HttpResponse::BadRequest().json(json!({ "status": "error", "message": "Missing query parameter" })),
+        None => return Err(AppError::BadRequest("Missing query parameter".to_string())),
     };
 
-    let data = elastic::search(&query).await;
+    let limit = validate_limit(params.limit)?;
+
+    let (categories, posts, next_cursor) = index.search(query, limit, &params.cursor).await?;
     
-    match data {
-        Ok(data) => HttpResponse::Ok().json(json!({ "categories": data.0, "posts": data.1 })),
-        Err(msg) => HttpResponse::InternalServerError().json(json!({ "status": "error", "message": msg })),
-    }
-}
\ No newline at end of file
+    Ok(HttpResponse::Ok().json(json!({ "categories": categories, "posts": posts, "next_cursor": next_cursor })))
+}
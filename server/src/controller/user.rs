@@ -1,24 +1,329 @@
-use actix_web::{ HttpResponse, get, HttpRequest};
+use std::fs;
+
+use actix_files::NamedFile;
+use actix_web::{ HttpResponse, HttpRequest, get, put, delete, web};
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType};
 use serde_json::json;
+use bcrypt::{DEFAULT_COST, hash};
 
-use crate::DbPool;
-use crate::service::{security, database};
+use crate::{AppConfig, DbPool, Store};
+use crate::config::DeletionPolicy;
+use crate::controller::auth;
+use crate::error::AppError;
+use crate::model::api::{ChangePasswordRequest, ChangeUsernameRequest, DeleteAccountRequest, ExportQuery, QueryParams};
+use crate::model::data::{ExportStatus, User};
+use crate::service::{database, export, security, throttle};
+use crate::service::auth::AuthUser;
+use crate::utils::form_validation::{validate_username, validate_password, validate_limit};
 
 #[get("/api/user/me")]
-pub async fn get_self(pool: DbPool, req: HttpRequest) -> HttpResponse {
-    let (user_id, _) = match security::verify_user(&req) {
-        Ok(claims) => claims,
-        Err(_) => return HttpResponse::Unauthorized().json(json!({ "status": "error", "message": "Unauthorized" })),
+pub async fn get_self(pool: DbPool, user: AuthUser) -> Result<HttpResponse, AppError> {
+    let moderates = database::find_moderated_category_ids(pool.clone(), user.id.clone()).await?;
+    let user = database::find_user_by_id(pool, user.id).await?;
+
+    Ok(HttpResponse::Ok().json(json!({ "user": {
+        "id": user.id,
+        "username": user.username,
+        "avatar_url": user.avatar_url,
+        "moderates": moderates,
+        "post_karma": user.post_karma,
+        "comment_karma": user.comment_karma,
+    }})))
+}
+
+/**
+ * The devices the user is logged in on
+ */
+#[get("/api/user/me/sessions")]
+pub async fn get_sessions(pool: DbPool, user: AuthUser) -> Result<HttpResponse, AppError> {
+    let sessions = database::find_sessions_by_user_id(pool, user.id).await?;
+
+    let sessions = sessions.into_iter().map(|session| json!({
+        "id": session.id,
+        "user_agent": session.user_agent,
+        "created_at": session.created_at,
+        "last_used_at": session.last_used_at,
+        "expires_at": session.expires_at,
+        "current": session.id.eq(&user.session_id),
+    })).collect::<Vec<_>>();
+
+    Ok(HttpResponse::Ok().json(json!({ "sessions": sessions })))
+}
+
+/**
+ * Log out a device. Its refresh token stops working right away, its access token once it expires.
+ */
+#[delete("/api/user/me/sessions/{id}")]
+pub async fn revoke_session(pool: DbPool, session_id: web::Path<String>, user: AuthUser) -> Result<HttpResponse, AppError> {
+    database::revoke_session(pool, user.id, session_id.into_inner()).await?;
+
+    Ok(HttpResponse::Ok().json(json!({ "status": "ok", "message": "Session revoked" })))
+}
+
+/**
+ * Change the password. Every other device is logged out, this one stays logged in.
+ */
+#[put("/api/user/me/password")]
+pub async fn change_password(pool: DbPool, config: AppConfig, user: AuthUser, form: web::Form<ChangePasswordRequest>, req: HttpRequest) -> Result<HttpResponse, AppError> {
+    let account = database::find_user_by_id(pool.clone(), user.id.clone()).await?;
+
+    let ip = throttle::client_ip(&req, &config);
+    security::confirm_password(pool.clone(), &config.limits, &account, &form.current_password, &ip).await?;
+
+    validate_password(&form.new_password)?;
+    let password = hash(&form.new_password, DEFAULT_COST)?;
+
+    database::update_user_password(pool.clone(), user.id.clone(), password).await?;
+    database::revoke_user_sessions(pool, user.id, Some(user.session_id)).await?;
+
+    Ok(HttpResponse::Ok().json(json!({ "status": "ok", "message": "Password changed" })))
+}
+
+/**
+ * Change the username. Posts and comments show the new name as well.
+ */
+#[put("/api/user/me/username")]
+pub async fn change_username(pool: DbPool, store: Store, user: AuthUser, form: web::Form<ChangeUsernameRequest>) -> Result<HttpResponse, AppError> {
+    validate_username(&form.username)?;
+
+    database::update_username(pool, user.id.clone(), form.username.clone()).await?;
+    store.rename_author(user.id, form.username.clone()).await?;
+
+    Ok(HttpResponse::Ok().json(json!({ "status": "ok", "username": form.username })))
+}
+
+/**
+ * Delete the account. Posts and comments are kept without the author or removed, depending on
+ * `accounts.deletion_policy`. Admin accounts can't delete themselves, since the admin is recreated on startup.
+ */
+#[delete("/api/user/me")]
+pub async fn delete_account(pool: DbPool, config: AppConfig, store: Store, user: AuthUser, form: web::Form<DeleteAccountRequest>, req: HttpRequest) -> Result<HttpResponse, AppError> {
+    if user.is_admin() {
+        return Err(AppError::Forbidden("Admin accounts can't be deleted".to_string()));
+    }
+
+    let account = database::find_user_by_id(pool.clone(), user.id.clone()).await?;
+
+    let ip = throttle::client_ip(&req, &config);
+    security::confirm_password(pool.clone(), &config.limits, &account, &form.password, &ip).await?;
+
+    store.remove_author(user.id.clone(), config.accounts.deletion_policy == DeletionPolicy::Remove).await?;
+    export::remove_all(pool.clone(), &config.storage, user.id.clone()).await?;
+    database::delete_user(pool, user.id, throttle::account_key(&account.username)).await?;
+
+    // The avatar is only served from disk, it's gone for good once the file is
+    if let Some(avatar_url) = account.avatar_url {
+        let avatar = avatar_url.split("/").last().unwrap_or_default();
+        let _ = fs::remove_file(format!("{}/{}", config.storage.avatar_dir(), avatar));
+    }
+
+    let (identity_cookie, refresh_cookie) = auth::clear_session_cookies(&config);
+
+    Ok(HttpResponse::Ok()
+        .cookie(identity_cookie)
+        .cookie(refresh_cookie)
+        .json(json!({ "status": "ok", "message": "Account deleted" })))
+}
+
+/**
+ * Export everything the user has: the profile, every post and comment including drafts and deleted ones,
+ * the votes and the avatar. The archive is built in the background, poll until the export is ready and
+ * follow `download_url`. Ask for a `fresh` export to replace a finished one.
+ */
+#[get("/api/user/me/export")]
+pub async fn get_export(store: Store, pool: DbPool, config: AppConfig, query: web::Query<ExportQuery>, user: AuthUser) -> Result<HttpResponse, AppError> {
+    let export = export::request(store.into_inner(), pool, config.storage.clone(), user.id, query.fresh.unwrap_or(false)).await?;
+
+    let download_url = match export.is(ExportStatus::Ready) {
+        true => Some(format!("/api/user/me/export/{}", export.id)),
+        false => None,
     };
 
-    let user = match database::find_user_by_id(pool, user_id).await {
-        Ok(user) => user,
-        Err(e) => return HttpResponse::InternalServerError().json(json!({ "status": "error", "message": e.to_string() })),
+    let mut response = match export.is(ExportStatus::Pending) {
+        true => HttpResponse::Accepted(),
+        false => HttpResponse::Ok(),
     };
 
-    HttpResponse::Ok().json(json!({ "user": {
+    Ok(response.json(json!({ "export": export, "download_url": download_url })))
+}
+
+#[get("/api/user/me/export/{id}")]
+pub async fn download_export(pool: DbPool, config: AppConfig, export_id: web::Path<String>, user: AuthUser, req: HttpRequest) -> Result<HttpResponse, AppError> {
+    let path = export::find_archive(pool, &config.storage, &user.id, export_id.into_inner()).await?;
+
+    let file = NamedFile::open(path)?.set_content_disposition(ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters: vec![DispositionParam::Filename("tidder-export.zip".to_string())],
+    });
+
+    Ok(file.into_response(&req))
+}
+
+/**
+ * The public profile of a user, with the first page of their published posts and of their comments.
+ * Use the `posts` and `comments` endpoints with the cursors for the following pages.
+ */
+#[get("/api/user/{username}")]
+pub async fn get_profile(store: Store, pool: DbPool, username: web::Path<String>, query: web::Query<QueryParams>) -> Result<HttpResponse, AppError> {
+    let limit = validate_limit(query.limit)?;
+
+    let user = database::find_user_by_username(pool.clone(), username.into_inner()).await?;
+    let profile = public_profile(pool, &user).await?;
+
+    let posts = store.get_published_posts_by_author_id(user.id.clone(), limit, &None).await?;
+    let comments = store.get_comments_by_author_id(user.id, limit, &None).await?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "user": profile,
+        "posts": posts.items,
+        "posts_next_cursor": posts.next_cursor,
+        "comments": comments.items,
+        "comments_next_cursor": comments.next_cursor,
+    })))
+}
+
+#[get("/api/user/{username}/posts")]
+pub async fn get_profile_posts(store: Store, pool: DbPool, username: web::Path<String>, query: web::Query<QueryParams>) -> Result<HttpResponse, AppError> {
+    let limit = validate_limit(query.limit)?;
+
+    let user = database::find_user_by_username(pool, username.into_inner()).await?;
+    let page = store.get_published_posts_by_author_id(user.id, limit, &query.cursor).await?;
+
+    Ok(HttpResponse::Ok().json(json!({ "posts": page.items, "next_cursor": page.next_cursor })))
+}
+
+#[get("/api/user/{username}/comments")]
+pub async fn get_profile_comments(store: Store, pool: DbPool, username: web::Path<String>, query: web::Query<QueryParams>) -> Result<HttpResponse, AppError> {
+    let limit = validate_limit(query.limit)?;
+
+    let user = database::find_user_by_username(pool, username.into_inner()).await?;
+    let page = store.get_comments_by_author_id(user.id, limit, &query.cursor).await?;
+
+    Ok(HttpResponse::Ok().json(json!({ "comments": page.items, "next_cursor": page.next_cursor })))
+}
+
+/// What anyone may know about a user
+async fn public_profile(pool: DbPool, user: &User) -> Result<serde_json::Value, AppError> {
+    let moderates = database::find_moderated_category_ids(pool, user.id.clone()).await?;
+
+    Ok(json!({
         "id": user.id,
         "username": user.username,
         "avatar_url": user.avatar_url,
-    }}))
-}
\ No newline at end of file
+        "role": user.role,
+        "moderates": moderates,
+        "created_at": user.created_at,
+        "post_karma": user.post_karma,
+        "comment_karma": user.comment_karma,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{test, App, http::StatusCode};
+    use serde_json::Value;
+
+    use super::*;
+    use crate::controller::post::{create_comment, report_post};
+    use crate::model::data::{Deletion, DeletionKind};
+    use crate::service::store::{ContentStore, Index};
+    use crate::test_utils::{TestApp, PASSWORD};
+
+    #[actix_web::test]
+    async fn deleting_an_account_takes_its_reports_and_failed_logins() {
+        let t = TestApp::new();
+        let owner = t.user("owner", "user").await;
+        let reporter = t.user("reporter", "user").await;
+        let category = t.category(&owner, "rust").await;
+        let post_id = t.post(&owner, &category, "Reported").await;
+
+        let app = test::init_service(App::new()
+            .configure(|cfg| t.configure(cfg))
+            .service(report_post)
+            .service(delete_account)
+        ).await;
+
+        let req = test::TestRequest::post().uri(&format!("/api/post/{}/report", post_id))
+            .cookie(reporter.identity())
+            .set_form([("reason", "Personal details of someone")])
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CREATED);
+
+        throttle::record_login_failure(t.pool.clone(), &t.config.limits, "reporter", "127.0.0.1").await.unwrap();
+
+        let req = test::TestRequest::delete().uri("/api/user/me")
+            .cookie(reporter.identity())
+            .set_form([("password", PASSWORD)])
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        let conn = t.pool.get().unwrap();
+        let reports: u32 = conn.query_row("SELECT COUNT(*) FROM reports", [], |row| row.get(0)).unwrap();
+        let report_count: u32 = conn.query_row("SELECT report_count FROM report_items WHERE target_id = ?", [&post_id], |row| row.get(0)).unwrap();
+        let attempts: u32 = conn.query_row("SELECT COUNT(*) FROM login_attempts WHERE key = ?", [throttle::account_key("reporter")], |row| row.get(0)).unwrap();
+        assert_eq!((reports, report_count, attempts), (0, 0, 0));
+    }
+
+    #[actix_web::test]
+    async fn profile_shows_only_visible_content() {
+        let t = TestApp::new();
+        let author = t.user("author", "user").await;
+        let category = t.category(&author, "rust").await;
+        let kept = t.post(&author, &category, "Kept").await;
+        let removed = t.post(&author, &category, "Removed").await;
+        database::add_karma(t.pool.clone(), author.user.id.clone(), 3, 2).await.unwrap();
+
+        let app = test::init_service(App::new()
+            .configure(|cfg| t.configure(cfg))
+            .service(create_comment)
+            .service(get_profile)
+        ).await;
+
+        for post_id in [&kept, &removed] {
+            let req = test::TestRequest::post().uri(&format!("/api/post/{}/comment", post_id))
+                .cookie(author.identity())
+                .set_form([("body", "Comment")])
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CREATED);
+        }
+
+        t.store.delete_post(Index::Post, removed, Deletion::new(DeletionKind::Moderator, Some(author.user.id.clone()), None)).await.unwrap();
+
+        let req = test::TestRequest::get().uri("/api/user/Author").to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+
+        assert_eq!(body["user"]["username"], "author");
+        assert_eq!(body["user"]["post_karma"], 3);
+        assert_eq!(body["user"]["comment_karma"], 2);
+        assert_eq!(body["posts"].as_array().unwrap().len(), 1);
+        assert_eq!(body["posts"][0]["title"], "Kept");
+        assert_eq!(body["comments"].as_array().unwrap().len(), 1);
+        assert_eq!(body["comments"][0]["post_id"], kept.as_str());
+
+        let req = test::TestRequest::get().uri("/api/user/nobody").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn profile_posts_are_paged_with_a_cursor() {
+        let t = TestApp::new();
+        let author = t.user("author", "user").await;
+        let category = t.category(&author, "rust").await;
+        for i in 0..3 {
+            t.post(&author, &category, &format!("Post {}", i)).await;
+        }
+        let app = test::init_service(App::new().configure(|cfg| t.configure(cfg)).service(get_profile_posts)).await;
+
+        let req = test::TestRequest::get().uri("/api/user/author/posts?limit=2").to_request();
+        let first: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(first["posts"].as_array().unwrap().len(), 2);
+        assert_eq!(first["posts"][0]["title"], "Post 2");
+
+        let cursor = first["next_cursor"].as_str().unwrap();
+        let req = test::TestRequest::get().uri(&format!("/api/user/author/posts?limit=2&cursor={}", cursor)).to_request();
+        let second: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(second["posts"].as_array().unwrap().len(), 1);
+        assert_eq!(second["posts"][0]["title"], "Post 0");
+        assert!(second["next_cursor"].is_null());
+    }
+}
@@ -0,0 +1,118 @@
+use std::fmt;
+
+use actix_web::{HttpResponse, ResponseError, http::{header, StatusCode}, error::BlockingError};
+use r2d2_sqlite::rusqlite;
+use serde_json::json;
+
+/**
+ * Every error a request can end in. Each variant maps to one HTTP status code and is
+ * returned to the client as `{ "status": "error", "message": ... }`.
+ * The message of `Internal` is only logged, clients get a generic message instead.
+ */
+#[derive(Debug)]
+pub enum AppError {
+    BadRequest(String),
+    Unauthorized(String),
+    Forbidden(String),
+    NotFound(String),
+    Conflict(String),
+    UnsupportedMediaType(String),
+    /// The message and how many seconds the client has to wait
+    TooManyRequests(String, u64),
+    Internal(String),
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::BadRequest(msg)
+            | AppError::Unauthorized(msg)
+            | AppError::Forbidden(msg)
+            | AppError::NotFound(msg)
+            | AppError::Conflict(msg)
+            | AppError::UnsupportedMediaType(msg)
+            | AppError::TooManyRequests(msg, _)
+            | AppError::Internal(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl ResponseError for AppError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            AppError::TooManyRequests(_, _) => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let message = match self {
+            AppError::Internal(msg) => {
+                eprintln!("Internal server error: {}", msg);
+                "Internal server error".to_string()
+            },
+            e => e.to_string(),
+        };
+
+        let mut response = HttpResponse::build(self.status_code());
+        if let AppError::TooManyRequests(_, retry_after) = self {
+            response.insert_header((header::RETRY_AFTER, retry_after.to_string()));
+        }
+
+        response.json(json!({ "status": "error", "message": message }))
+    }
+}
+
+impl From<elasticsearch::Error> for AppError {
+    fn from(e: elasticsearch::Error) -> AppError {
+        AppError::Internal(format!("Elasticsearch: {}", e))
+    }
+}
+
+impl From<serde_json::Error> for AppError {
+    fn from(e: serde_json::Error) -> AppError {
+        AppError::Internal(format!("Malformed document: {}", e))
+    }
+}
+
+impl From<rusqlite::Error> for AppError {
+    fn from(e: rusqlite::Error) -> AppError {
+        AppError::Internal(format!("Database: {}", e))
+    }
+}
+
+impl From<r2d2::Error> for AppError {
+    fn from(e: r2d2::Error) -> AppError {
+        AppError::Internal(format!("Database pool: {}", e))
+    }
+}
+
+impl From<BlockingError> for AppError {
+    fn from(e: BlockingError) -> AppError {
+        AppError::Internal(format!("Blocking task: {}", e))
+    }
+}
+
+impl From<std::io::Error> for AppError {
+    fn from(e: std::io::Error) -> AppError {
+        AppError::Internal(format!("IO: {}", e))
+    }
+}
+
+impl From<bcrypt::BcryptError> for AppError {
+    fn from(e: bcrypt::BcryptError) -> AppError {
+        AppError::Internal(format!("bcrypt: {}", e))
+    }
+}
+
+impl From<zip::result::ZipError> for AppError {
+    fn from(e: zip::result::ZipError) -> AppError {
+        AppError::Internal(format!("Zip: {}", e))
+    }
+}
@@ -1,5 +1,6 @@
 use actix_web::{App, HttpServer, http, web, delete, HttpResponse, middleware};
 use actix_cors::Cors;
+use actix_multipart::form::MultipartFormConfig;
 use actix_files as a_fs;
 use controller::post::{get_own_posts, publish_post};
 use http::header;
@@ -7,22 +8,42 @@ use r2d2::Pool;
 use r2d2_sqlite::SqliteConnectionManager;
 use std::fs;
 use std::env;
+use std::sync::Arc;
 use dotenv::dotenv;
+use service::store::{ContentStore, SearchIndex};
+use service::{elastic::ElasticStore, memory::MemoryStore};
+use service::auth::RequireRole;
+use service::keys::{self, KeyStore};
+use config::{Config, ContentBackend};
 
 // routes
 use crate::controller::{
     auth::{
         pubkey, 
+        jwks,
         register, 
         login, 
-        logout
+        logout,
+        refresh
     },
     user::{
-        get_self
+        get_self,
+        get_sessions,
+        revoke_session,
+        change_password,
+        change_username,
+        delete_account,
+        get_export,
+        download_export,
+        get_profile,
+        get_profile_posts,
+        get_profile_comments
     },
     category::{
         get_categories,
         get_category_by_id,
+        create_category,
+        update_category,
         get_posts_by_category_id,
     },
     post::{
@@ -31,10 +52,27 @@ use crate::controller::{
         get_comments_by_post_id,
         create_post,
         create_comment,
-        unpublish_post
+        unpublish_post,
+        restore_post,
+        lock_post,
+        report_post,
+        unlock_post,
+        vote_post,
+        update_post,
+        get_post_revisions
     },
     comment::{
-        unpublish_comment
+        unpublish_comment,
+        restore_comment,
+        vote_comment,
+        update_comment,
+        get_comment_revisions,
+        report_comment
+    },
+    report::{
+        get_reports,
+        get_report,
+        resolve_report
     },
     search::{
         search
@@ -43,61 +81,157 @@ use crate::controller::{
         upload_avatar,
         get_avatar_urls
     },
+    admin::{
+        get_settings,
+        set_comment_edit_window,
+        get_moderators,
+        grant_moderator,
+        revoke_moderator,
+        get_bans,
+        issue_ban,
+        lift_ban,
+        get_audit_log,
+        purge_deleted
+    },
 };
 
+mod config;
+mod error;
 mod model;
 mod controller;
 mod service;
 mod utils;
+#[cfg(test)]
+mod test_utils;
 
 type DbPool = web::Data<Pool<SqliteConnectionManager>>;
+type AppConfig = web::Data<Config>;
+type Keys = web::Data<KeyStore>;
+type Store = web::Data<dyn ContentStore>;
+type Searcher = web::Data<dyn SearchIndex>;
 
 #[delete("/api/flush")]
-pub async fn flush() -> HttpResponse {
-    service::elastic::flush_data().await;
-    HttpResponse::Ok().into()
+pub async fn flush(store: Store) -> Result<HttpResponse, error::AppError> {
+    store.flush_data().await?;
+    Ok(HttpResponse::Ok().into())
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv().ok();
-   
-    let db_file = "db.db";
+
+    // Read and check the configuration before anything else, so a bad value doesn't fail halfway through startup
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprint!("{}", e);
+            std::process::exit(1);
+        },
+    };
+
+    // Admin commands run instead of the server
+    let command = env::args().nth(1);
+    match command.as_deref() {
+        Some("keygen") => {
+            match keys::generate(&config.keys.dir) {
+                Ok(kid) => println!("Created key {} in {}, it is used for signing after a restart", kid, config.keys.dir),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                },
+            }
+            return Ok(());
+        },
+        // Needs the database and the content store, so it runs once they are open
+        Some("recompute-karma") => (),
+        Some(command) => {
+            eprintln!("Unknown command: {} (available: keygen, recompute-karma)", command);
+            std::process::exit(1);
+        },
+        None => (),
+    }
+
+    let keys = match KeyStore::load(&config.keys) {
+        Ok(keys) => web::Data::new(keys),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        },
+    };
     
     // Create the database directory if it doesn't exist
-    let db_dir = "database";
-    if !fs::metadata(db_dir).is_ok() {
-        fs::create_dir(db_dir).expect("Failed to create database directory");
+    let db_dir = config.storage.database_dir.as_str();
+    if fs::metadata(db_dir).is_err() {
+        fs::create_dir_all(db_dir).expect("Failed to create database directory");
     }
 
     // Create the tmp folder if it doesn't exist (used to store uploaded images temporarily while they are being processed)
-    let tmp_dir = "tmp";
-    if !fs::metadata(tmp_dir).is_ok() {
-        fs::create_dir(tmp_dir).expect("Failed to create tmp directory");
+    let tmp_dir = config.storage.tmp_dir.as_str();
+    if fs::metadata(tmp_dir).is_err() {
+        fs::create_dir_all(tmp_dir).expect("Failed to create tmp directory");
     }
 
     // Create the avatar directory if it doesn't exist
-    let avatar_dir = "public/avatar"; 
-    if !fs::metadata(avatar_dir).is_ok() {
-        fs::create_dir_all(avatar_dir).expect(format!("Failed to create {} directory", avatar_dir).as_str());
+    let avatar_dir = config.storage.avatar_dir(); 
+    if fs::metadata(&avatar_dir).is_err() {
+        fs::create_dir_all(&avatar_dir).unwrap_or_else(|_| panic!("Failed to create {} directory", avatar_dir));
+    }
+
+    // Create the export directory if it doesn't exist
+    let export_dir = config.storage.export_dir();
+    if fs::metadata(&export_dir).is_err() {
+        fs::create_dir_all(&export_dir).unwrap_or_else(|_| panic!("Failed to create {} directory", export_dir));
     }
 
     // Open a connection pool to the database
-    let manager: SqliteConnectionManager = SqliteConnectionManager::file(format!("{}/{}", db_dir, db_file));
+    let manager: SqliteConnectionManager = SqliteConnectionManager::file(config.storage.database_path());
     let pool: Pool<SqliteConnectionManager> = r2d2::Pool::builder()
         .build(manager) 
         .expect("Failed to create pool.");
 
-    // Initialize the database (create tables etc.)
-    service::database::init(pool.clone()).await.unwrap();
+    // Initialize the database (migrate the schema etc.), the error is already logged
+    if service::database::init(pool.clone(), config.admin.clone()).await.is_err() {
+        std::process::exit(1);
+    }
+
+    // Pick the content store (the memory store runs without an Elasticsearch cluster)
+    let (store, searcher): (Arc<dyn ContentStore>, Arc<dyn SearchIndex>) = match config.storage.backend {
+        ContentBackend::Memory => {
+            let store = Arc::new(MemoryStore::new());
+            (store.clone(), store)
+        },
+        ContentBackend::Elastic => {
+            let store = Arc::new(ElasticStore::new(&config.elastic));
+            (store.clone(), store)
+        },
+    };
+
+    if command.as_deref() == Some("recompute-karma") {
+        match service::karma::recompute(store.as_ref(), web::Data::new(pool.clone())).await {
+            Ok(users) => println!("Recomputed karma, {} users have some", users),
+            Err(e) => {
+                eprintln!("Failed to recompute karma: {}", e);
+                std::process::exit(1);
+            },
+        }
+        return Ok(());
+    }
+
+    // Purge deleted content in the background, unless it is kept forever
+    if config.retention.purge_after_days > 0 {
+        actix_web::rt::spawn(service::retention::run(store.clone(), web::Data::new(pool.clone()), config.retention.clone()));
+    }
+
+    let bind_address = (config.server.host.clone(), config.server.port);
+    let config = web::Data::new(config);
 
     // Start the HTTP server
     HttpServer::new(move || {
 
         // CORS
-        let cors = Cors::default()
-            .allowed_origin(env::var("CLIENT_URL").expect("CLIENT_URL must be set").as_str())
-            .allowed_methods(vec!["GET", "POST", "DELETE"])
+        let cors = config.server.allowed_origins.iter()
+            .fold(Cors::default(), |cors, origin| cors.allowed_origin(origin))
+            .allowed_methods(vec!["GET", "POST", "PUT", "DELETE"])
             .allowed_headers(vec![
                 header::AUTHORIZATION, 
                 header::ACCEPT, 
@@ -120,33 +254,81 @@ async fn main() -> std::io::Result<()> {
         App::new()
             .wrap(cors)
             .wrap(security_headers)
+            .app_data(config.clone())
+            .app_data(keys.clone())
             .app_data(web::Data::new(pool.clone()))
-            .service(a_fs::Files::new("/public", "./public").show_files_listing())
-            .service(web::resource("/public/avatar/{filename}").name("avatars").route(web::get().to(|| HttpResponse::Ok())))
+            .app_data(web::Data::from(store.clone()))
+            .app_data(web::Data::from(searcher.clone()))
+            .app_data(MultipartFormConfig::default().total_limit(config.uploads.multipart_limit()))
+            .service(a_fs::Files::new("/public", &config.storage.public_dir).show_files_listing())
+            .service(web::resource("/public/avatar/{filename}").name("avatars").route(web::get().to(HttpResponse::Ok)))
             .service(login)
             .service(register)
             .service(logout) 
+            .service(refresh)
             .service(pubkey) 
+            .service(jwks)
             .service(get_popular_posts)
             .service(get_own_posts)
             .service(get_post_by_id)
             .service(get_posts_by_category_id)
             .service(get_categories)
             .service(get_category_by_id)
+            .service(create_category)
+            .service(update_category)
             .service(get_comments_by_post_id)
             .service(get_avatar_urls)
             .service(search)
             .service(get_self)
+            .service(get_sessions)
+            .service(revoke_session)
+            .service(change_password)
+            .service(change_username)
+            .service(delete_account)
+            .service(get_export)
+            .service(download_export)
+            .service(get_profile)
+            .service(get_profile_posts)
+            .service(get_profile_comments)
             .service(create_post)
             .service(create_comment)
             .service(publish_post)
             .service(unpublish_post)
+            .service(restore_post)
+            .service(lock_post)
+            .service(report_post)
+            .service(report_comment)
+            .service(get_reports)
+            .service(get_report)
+            .service(resolve_report)
+            .service(unlock_post)
             .service(unpublish_comment)
+            .service(restore_comment)
+            .service(vote_post)
+            .service(update_post)
+            .service(get_post_revisions)
+            .service(vote_comment)
+            .service(update_comment)
+            .service(get_comment_revisions)
+            .service(
+                web::scope("/api/admin")
+                    .wrap(RequireRole::admin())
+                    .service(get_settings)
+                    .service(set_comment_edit_window)
+                    .service(get_moderators)
+                    .service(grant_moderator)
+                    .service(revoke_moderator)
+                    .service(get_bans)
+                    .service(issue_ban)
+                    .service(lift_ban)
+                    .service(get_audit_log)
+                    .service(purge_deleted)
+            )
             .service(upload_avatar)
             // .service(get_users) // Dev endpoint, remove in production
             // .service(flush) // Dev endpoint, remove in production
     })
-    .bind("0.0.0.0:3000")?
+    .bind(bind_address)?
     .run()
     .await
 }
\ No newline at end of file
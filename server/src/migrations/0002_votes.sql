@@ -0,0 +1,9 @@
+-- One row per user and post/comment, so nobody can vote twice on the same item
+CREATE TABLE IF NOT EXISTS votes (
+    user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    target_id TEXT NOT NULL,
+    target_type TEXT NOT NULL,
+    value INTEGER NOT NULL CHECK (value IN (-1, 1)),
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user_id, target_id)
+);
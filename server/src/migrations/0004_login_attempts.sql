@@ -0,0 +1,8 @@
+-- Recent login and registration attempts per account and address, for brute force protection.
+-- Times are unix timestamps, since the backoff is computed from them.
+CREATE TABLE IF NOT EXISTS login_attempts (
+    key TEXT PRIMARY KEY,
+    attempts INTEGER NOT NULL,
+    last_attempt_at INTEGER NOT NULL,
+    locked_until INTEGER
+);
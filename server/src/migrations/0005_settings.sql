@@ -0,0 +1,5 @@
+-- Site wide settings that admins can change at runtime
+CREATE TABLE IF NOT EXISTS settings (
+    key TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+);
@@ -0,0 +1,10 @@
+-- Users that moderate a category besides the admins, who moderate every category
+CREATE TABLE category_moderators (
+    user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    category_id TEXT NOT NULL,
+    granted_by TEXT NOT NULL,
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user_id, category_id)
+);
+
+CREATE INDEX category_moderators_category_id ON category_moderators (category_id);
@@ -0,0 +1,24 @@
+-- The moderation queue, one row per reported post or comment. The category is copied,
+-- so moderators can be shown the reports of their categories only.
+CREATE TABLE report_items (
+    target_id TEXT PRIMARY KEY,
+    target_type TEXT NOT NULL,
+    category_id TEXT NOT NULL,
+    report_count INTEGER NOT NULL DEFAULT 0,
+    status TEXT NOT NULL DEFAULT 'open' CHECK (status IN ('open', 'dismissed', 'removed')),
+    first_reported_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    last_reported_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    resolved_by TEXT,
+    resolved_at DATETIME
+);
+
+CREATE INDEX report_items_status_category_id ON report_items (status, category_id);
+
+-- The single reports, one per user and item
+CREATE TABLE reports (
+    user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    target_id TEXT NOT NULL REFERENCES report_items(target_id) ON DELETE CASCADE,
+    reason TEXT NOT NULL,
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user_id, target_id)
+);
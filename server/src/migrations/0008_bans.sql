@@ -0,0 +1,14 @@
+-- Suspensions expire, bans don't. Without a category they apply to the whole site.
+CREATE TABLE bans (
+    id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    category_id TEXT,
+    reason TEXT NOT NULL,
+    issued_by TEXT NOT NULL,
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    expires_at DATETIME,
+    lifted_by TEXT,
+    lifted_at DATETIME
+);
+
+CREATE INDEX bans_user_id ON bans (user_id);
@@ -0,0 +1,12 @@
+-- Personal data exports. They are built in the background, the archive can be downloaded until it expires.
+CREATE TABLE exports (
+    id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    status TEXT NOT NULL DEFAULT 'pending',
+    error TEXT,
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+    completed_at DATETIME,
+    expires_at DATETIME
+);
+
+CREATE INDEX exports_user_id ON exports (user_id);
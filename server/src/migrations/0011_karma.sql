@@ -0,0 +1,4 @@
+-- How well received the posts and comments of a user are: the upvotes minus the downvotes they got.
+-- Kept up to date as votes arrive. Votes from before this migration are counted by `tidder recompute-karma`.
+ALTER TABLE users ADD COLUMN post_karma INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE users ADD COLUMN comment_karma INTEGER NOT NULL DEFAULT 0;
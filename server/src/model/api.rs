@@ -1,6 +1,6 @@
 use serde_derive::{Deserialize, Serialize};
 
-use crate::model::data::Post;
+use crate::model::data::{PostType, ReportStatus};
 
 //////////////////
 // REQUEST DTOs //
@@ -12,10 +12,130 @@ pub struct UserRequest {
     pub password: String,
 }
  
+#[derive(Debug, Deserialize)]
+pub struct ChangePasswordRequest {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChangeUsernameRequest {
+    pub username: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteAccountRequest {
+    pub password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportQuery {
+    /// Start a new export instead of returning the last one
+    pub fresh: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ModeratorRequest {
+    pub username: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateCategoryRequest {
+    pub name: String,
+    pub description: Option<String>,
+    pub rules: Option<String>,
+    /// Comma separated, every type is allowed when left out
+    pub post_types: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateCategoryRequest {
+    pub description: String,
+    pub rules: String,
+    /// Comma separated, every type is allowed when left out
+    pub post_types: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReportRequest {
+    pub reason: String,
+}
+
+/// What a moderator does with a reported post or comment
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportAction {
+    /// Keep it, the reports were unfounded
+    Dismiss,
+    /// Delete it
+    Remove,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResolveReportRequest {
+    pub action: ReportAction,
+    /// Kept with the removal when the reported post or comment is removed
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReportQuery {
+    pub status: Option<ReportStatus>,
+    #[serde(rename = "type")]
+    pub target_type: Option<String>,
+    pub category_id: Option<String>,
+    pub limit: Option<u32>,
+    pub cursor: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BanRequest {
+    pub username: String,
+    /// Only ban from this category instead of the whole site
+    pub category_id: Option<String>,
+    pub reason: String,
+    /// Suspend for this long instead of banning for good
+    pub duration_hours: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BanQuery {
+    pub user_id: Option<String>,
+    /// Include bans that expired or were lifted
+    pub all: Option<bool>,
+}
+
+/// Why a moderator removes a post or comment
+#[derive(Debug, Deserialize)]
+pub struct ModerationQuery {
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuditQuery {
+    pub actor_id: Option<String>,
+    pub action: Option<String>,
+    pub target_type: Option<String>,
+    pub target_id: Option<String>,
+    /// Only entries from this time on, in RFC 3339 or as `YYYY-MM-DD HH:MM:SS` in UTC
+    pub since: Option<String>,
+    /// Only entries before this time
+    pub until: Option<String>,
+    pub limit: Option<u32>,
+    pub cursor: Option<String>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct CreatePostRequest {
     pub new_category: Option<String>,
     pub category_id: Option<String>,
+    pub post_type: Option<PostType>,
+    pub title: String,
+    pub body: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdatePostRequest {
     pub title: String,
     pub body: String,
 }
@@ -23,15 +143,23 @@ pub struct CreatePostRequest {
 #[derive(Debug, Deserialize)]
 pub struct CreateCommentRequest {
     pub body: String,
+    pub parent_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateCommentRequest {
+    pub body: String,
 }
 
-//////////////////////
-/// RESPONSES DTOs ///
-//////////////////////
+#[derive(Debug, Deserialize)]
+pub struct EditWindowRequest {
+    /// Minutes after creation during which comments can be edited. Leave out to allow edits at any time.
+    pub minutes: Option<u32>,
+}
 
-#[derive(Debug, Deserialize, Serialize)]
-pub struct GetPostsResponse {
-    pub posts: Vec<Post>,
+#[derive(Debug, Deserialize)]
+pub struct VoteRequest {
+    pub direction: String,
 }
 
 // Other (should probably be moved to another file)
@@ -41,6 +169,10 @@ pub struct QueryParams {
    pub q: Option<String>,
    pub show_all: Option<bool>,
    pub draft: Option<bool>,
+   pub sort: Option<String>,
+   pub t: Option<String>,
+   pub limit: Option<u32>,
+   pub cursor: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -51,4 +183,12 @@ pub struct Claims {
     pub iat: usize,
     pub username: String,
     pub role: String,
-}
\ No newline at end of file
+    /// The session the token was issued for
+    pub sid: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PurgeQuery {
+    /// Only report what would be purged
+    pub dry_run: Option<bool>,
+}
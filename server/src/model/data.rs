@@ -1,16 +1,114 @@
+use std::str::FromStr;
+
 use serde_derive::{Deserialize, Serialize};
-use serde_json::Value;
-use r2d2_sqlite::rusqlite::Row;
+use r2d2_sqlite::rusqlite::{Result, Row};
+
+use crate::error::AppError;
 
 use crate::utils::sanitize::{sanitize_post, sanitize_comment};
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Category {
     pub id: String,
     pub name: String,
+    /// Categories from before owners were introduced have none
+    pub owner_id: Option<String>,
+    pub description: String,
+    /// Markdown, rendered like a post body when the category is requested on its own
+    pub rules: String,
+    pub post_types: Vec<PostType>,
+    pub created_at: Option<String>,
     pub posts: Option<u64>,
 }
 
+impl Category {
+    /**
+     * The form of a name that has to be unique: lowercase, without surrounding spaces and with runs of spaces collapsed.
+     * "Rust", " rust" and "RUST" are the same category, "Rust Games" is another one.
+     */
+    pub fn normalize_name(name: &str) -> String {
+        name.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase()
+    }
+
+    pub fn allows(&self, post_type: &PostType) -> bool {
+        self.post_types.contains(post_type)
+    }
+}
+
+/// What a post consists of. Text posts have a Markdown body, link posts a URL.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PostType {
+    #[default]
+    Text,
+    Link,
+}
+
+impl PostType {
+    pub fn all() -> Vec<PostType> {
+        vec![PostType::Text, PostType::Link]
+    }
+}
+
+impl FromStr for PostType {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<PostType, AppError> {
+        match s.trim() {
+            "text" => Ok(PostType::Text),
+            "link" => Ok(PostType::Link),
+            other => Err(AppError::BadRequest(format!("Unknown post type \"{}\", expected text or link", other))),
+        }
+    }
+}
+
+/// Who took down a post or comment, the two are restored under different rules
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeletionKind {
+    /// Deleted by the author, only admins can restore it
+    Author,
+    /// Removed by a moderator, any moderator of the category can restore it
+    Moderator,
+}
+
+/// How a post or comment was deleted
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Deletion {
+    pub kind: DeletionKind,
+    /// Empty when the author's account was deleted along with the content
+    pub deleted_by: Option<String>,
+    pub reason: Option<String>,
+    pub deleted_at: String,
+}
+
+impl Deletion {
+    pub fn new(kind: DeletionKind, deleted_by: Option<String>, reason: Option<String>) -> Deletion {
+        Deletion {
+            kind,
+            deleted_by,
+            reason,
+            deleted_at: chrono::Utc::now().to_rfc3339(),
+        }
+    }
+}
+
+/// Who brought back a deleted post or comment
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Restoration {
+    pub restored_by: String,
+    pub restored_at: String,
+}
+
+impl Restoration {
+    pub fn new(restored_by: String) -> Restoration {
+        Restoration {
+            restored_by,
+            restored_at: chrono::Utc::now().to_rfc3339(),
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Post {
     pub id: Option<String>,
@@ -18,37 +116,29 @@ pub struct Post {
     pub author_id: String,
     pub category_id: String,
     pub category_name: String,
+    /// Posts from before post types were introduced are text posts
+    #[serde(default)]
+    pub post_type: PostType,
     pub title: String,
     pub body: String,
     pub upvotes: u32,
     pub downvotes: u32,
     pub published: bool,
     pub deleted: bool,
+    /// The latest deletion, posts deleted before this was recorded have none
+    #[serde(default)]
+    pub deletion: Option<Deletion>,
+    /// Set when the post was restored after its latest deletion
+    #[serde(default)]
+    pub restoration: Option<Restoration>,
+    /// Locked threads take no new comments, except from moderators
+    #[serde(default)]
+    pub locked: bool,
     pub created_at: String,
     pub updated_at: String,
 }
 
 impl Post {
-    pub fn from_json(source: &Value) -> Post {
-        let id = source.get("_id").unwrap().as_str().unwrap().to_string();
-        let source = source.get("_source").unwrap();
-
-        Post {
-            id: Some(id),
-            title: source.get("title").unwrap().as_str().unwrap().to_string(),
-            body: source.get("body").unwrap().as_str().unwrap().to_string(),
-            author_name: source.get("author_name").unwrap().as_str().unwrap().to_string(),
-            author_id: source.get("author_id").unwrap().as_str().unwrap().to_string(),
-            category_id: source.get("category_id").unwrap().as_str().unwrap().to_string(),
-            category_name: source.get("category_name").unwrap().as_str().unwrap().to_string(),
-            created_at: source.get("created_at").unwrap().as_str().unwrap().to_string(),
-            updated_at: source.get("updated_at").unwrap().as_str().unwrap().to_string(),
-            upvotes: source.get("upvotes").unwrap().as_u64().unwrap() as u32,
-            downvotes: source.get("downvotes").unwrap().as_u64().unwrap() as u32,
-            published: source.get("published").unwrap().as_bool().unwrap(),
-            deleted: source.get("deleted").unwrap().as_bool().unwrap(),
-        }
-    }
     pub fn sanitize(&mut self, show_all: &bool) -> Post {
         sanitize_post(self, show_all)
     }
@@ -60,37 +150,79 @@ pub struct Comment {
     pub author_name: String,
     pub author_id: String,
     pub post_id: String,
+    // Comments from before threading have no parent and are top level
+    pub parent_id: Option<String>,
+    pub root_id: Option<String>,
+    #[serde(default)]
+    pub depth: u32,
     pub body: String,
     pub deleted: bool,
+    /// The latest deletion, comments deleted before this was recorded have none
+    #[serde(default)]
+    pub deletion: Option<Deletion>,
+    /// Set when the comment was restored after its latest deletion
+    #[serde(default)]
+    pub restoration: Option<Restoration>,
+    /// The text of a deleted comment with replies is purged for good, it only keeps its place in the thread
+    #[serde(default)]
+    pub purged: bool,
     pub upvotes: u32,
     pub downvotes: u32,
     pub created_at: String,
     pub updated_at: String,
+    pub edited_at: Option<String>,
 }
 
 impl Comment {
-    pub fn from_json(source: &Value) -> Comment {
-        let id = source.get("_id").unwrap().as_str().unwrap().to_string();
-        let source = source.get("_source").unwrap();
-
-        Comment {
-            id: Some(id),
-            author_name: source.get("author_name").unwrap().as_str().unwrap().to_string(),
-            author_id: source.get("author_id").unwrap().as_str().unwrap().to_string(),
-            post_id: source.get("post_id").unwrap().as_str().unwrap().to_string(),
-            body: source.get("body").unwrap().as_str().unwrap().to_string(),
-            upvotes: source.get("upvotes").unwrap().as_u64().unwrap() as u32,
-            downvotes: source.get("downvotes").unwrap().as_u64().unwrap() as u32,
-            created_at: source.get("created_at").unwrap().as_str().unwrap().to_string(),
-            updated_at: source.get("updated_at").unwrap().as_str().unwrap().to_string(),
-            deleted: source.get("deleted").unwrap().as_bool().unwrap(),
-        }
-    }
     pub fn sanitize(&mut self, show_all: &bool) -> Comment {
         sanitize_comment(self, show_all)
     }
 }
 
+/// An earlier version of a post or comment, stored when it is edited
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Revision {
+    pub target_id: String,
+    pub title: Option<String>,
+    pub body: String,
+    pub editor_id: String,
+    /// When this version was written
+    pub created_at: String,
+    /// When this version was replaced by the next one
+    pub replaced_at: String,
+}
+
+/// One page of a listing. `next_cursor` is set when there are more items after this page.
+#[derive(Debug, Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<String>,
+}
+
+/// What a purge of deleted content removed, or would remove in a dry run
+#[derive(Debug, Serialize)]
+pub struct PurgeReport {
+    pub dry_run: bool,
+    /// Content deleted before this time is purged
+    pub deleted_before: String,
+    /// Deleted posts that were removed
+    pub posts: Vec<String>,
+    /// Comments of the removed posts, deleted or not
+    pub orphaned_comments: Vec<String>,
+    /// Deleted comments that were removed
+    pub comments: Vec<String>,
+    /// Deleted comments that still have replies, only their text and author were removed
+    pub scrubbed_comments: Vec<String>,
+}
+
+/// A comment together with its replies, as returned by the comments endpoint
+#[derive(Debug, Serialize)]
+pub struct CommentNode {
+    #[serde(flatten)]
+    pub comment: Comment,
+    pub replies: Vec<CommentNode>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct User {
     pub id: String,
@@ -100,18 +232,269 @@ pub struct User {
     pub avatar_url: Option<String>,
     pub created_at: String,
     pub role: String,
+    /// Upvotes minus downvotes on the user's posts
+    pub post_karma: i64,
+    /// Upvotes minus downvotes on the user's comments
+    pub comment_karma: i64,
 }
 
 impl User {
-    pub fn from_db(row: &Row) -> User {
-        User {
-            id: row.get(0).unwrap(),
-            username: row.get(1).unwrap(),
-            username_lower: row.get(2).unwrap(),
-            password: row.get(3).unwrap(),
-            avatar_url: row.get(4).unwrap_or(None),
-            created_at: row.get(5).unwrap(),
-            role: row.get(6).unwrap(),
+    pub fn from_db(row: &Row) -> Result<User> {
+        Ok(User {
+            id: row.get(0)?,
+            username: row.get(1)?,
+            username_lower: row.get(2)?,
+            password: row.get(3)?,
+            avatar_url: row.get(4)?,
+            created_at: row.get(5)?,
+            role: row.get(6)?,
+            post_karma: row.get(7)?,
+            comment_karma: row.get(8)?,
+        })
+    }
+}
+
+/// A logged in device, without its refresh token hash
+#[derive(Debug)]
+pub struct Session {
+    pub id: String,
+    pub user_id: String,
+    pub user_agent: Option<String>,
+    pub created_at: String,
+    pub last_used_at: String,
+    pub expires_at: String,
+}
+
+impl Session {
+    pub fn from_db(row: &Row) -> Result<Session> {
+        Ok(Session {
+            id: row.get(0)?,
+            user_id: row.get(1)?,
+            user_agent: row.get(2)?,
+            created_at: row.get(3)?,
+            last_used_at: row.get(4)?,
+            expires_at: row.get(5)?,
+        })
+    }
+}
+
+/// Recent attempts of one account or address, see `service::throttle`
+#[derive(Debug)]
+pub struct LoginAttempts {
+    pub attempts: u32,
+    pub last_attempt_at: i64,
+    pub locked_until: Option<i64>,
+}
+
+impl LoginAttempts {
+    pub fn from_db(row: &Row) -> Result<LoginAttempts> {
+        Ok(LoginAttempts {
+            attempts: row.get(0)?,
+            last_attempt_at: row.get(1)?,
+            locked_until: row.get(2)?,
+        })
+    }
+}
+
+/// Where a reported post or comment is in the moderation queue
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportStatus {
+    Open,
+    Dismissed,
+    Removed,
+}
+
+impl ReportStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReportStatus::Open => "open",
+            ReportStatus::Dismissed => "dismissed",
+            ReportStatus::Removed => "removed",
+        }
+    }
+}
+
+/// A reported post or comment with the number of reports it got
+#[derive(Debug, Serialize)]
+pub struct ReportItem {
+    pub target_id: String,
+    pub target_type: String,
+    pub category_id: String,
+    pub report_count: u32,
+    pub status: String,
+    pub first_reported_at: String,
+    pub last_reported_at: String,
+    pub resolved_by: Option<String>,
+    pub resolved_at: Option<String>,
+}
+
+impl ReportItem {
+    pub fn from_db(row: &Row) -> Result<ReportItem> {
+        Ok(ReportItem {
+            target_id: row.get(0)?,
+            target_type: row.get(1)?,
+            category_id: row.get(2)?,
+            report_count: row.get(3)?,
+            status: row.get(4)?,
+            first_reported_at: row.get(5)?,
+            last_reported_at: row.get(6)?,
+            resolved_by: row.get(7)?,
+            resolved_at: row.get(8)?,
+        })
+    }
+}
+
+/// A single report, the reporter's name is gone once their account is
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub user_id: String,
+    pub username: Option<String>,
+    pub reason: String,
+    pub created_at: String,
+}
+
+impl Report {
+    pub fn from_db(row: &Row) -> Result<Report> {
+        Ok(Report {
+            user_id: row.get(0)?,
+            username: row.get(1)?,
+            reason: row.get(2)?,
+            created_at: row.get(3)?,
+        })
+    }
+}
+
+/// A ban of a user from the whole site or from one category
+#[derive(Debug, Serialize)]
+pub struct Ban {
+    pub id: String,
+    pub user_id: String,
+    /// Banned from the whole site when there is none
+    pub category_id: Option<String>,
+    pub reason: String,
+    pub issued_by: String,
+    pub created_at: String,
+    /// Permanent when there is none, a suspension otherwise
+    pub expires_at: Option<String>,
+    pub lifted_by: Option<String>,
+    pub lifted_at: Option<String>,
+}
+
+impl Ban {
+    pub fn from_db(row: &Row) -> Result<Ban> {
+        Ok(Ban {
+            id: row.get(0)?,
+            user_id: row.get(1)?,
+            category_id: row.get(2)?,
+            reason: row.get(3)?,
+            issued_by: row.get(4)?,
+            created_at: row.get(5)?,
+            expires_at: row.get(6)?,
+            lifted_by: row.get(7)?,
+            lifted_at: row.get(8)?,
+        })
+    }
+}
+
+/// One privileged action in the audit log
+#[derive(Debug, Serialize)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub actor_id: String,
+    pub action: String,
+    pub target_type: String,
+    pub target_id: String,
+    pub reason: Option<String>,
+    /// Whatever else describes the action, like the new values of an edit
+    pub details: Option<serde_json::Value>,
+    pub created_at: String,
+}
+
+impl AuditEntry {
+    pub fn from_db(row: &Row) -> Result<AuditEntry> {
+        let details: Option<String> = row.get(6)?;
+
+        Ok(AuditEntry {
+            id: row.get(0)?,
+            actor_id: row.get(1)?,
+            action: row.get(2)?,
+            target_type: row.get(3)?,
+            target_id: row.get(4)?,
+            reason: row.get(5)?,
+            details: details.and_then(|details| serde_json::from_str(&details).ok()),
+            created_at: row.get(7)?,
+        })
+    }
+}
+
+/// A vote of a user, as it is stored
+#[derive(Debug, Serialize)]
+pub struct Vote {
+    pub target_id: String,
+    pub target_type: String,
+    pub value: i8,
+    pub created_at: String,
+}
+
+impl Vote {
+    pub fn from_db(row: &Row) -> Result<Vote> {
+        Ok(Vote {
+            target_id: row.get(0)?,
+            target_type: row.get(1)?,
+            value: row.get(2)?,
+            created_at: row.get(3)?,
+        })
+    }
+}
+
+/// Where a personal data export is
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportStatus {
+    Pending,
+    Ready,
+    Failed,
+    /// Was ready, but can't be downloaded anymore. Never stored, exports past `expires_at` are read as expired.
+    Expired,
+}
+
+impl ExportStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ExportStatus::Pending => "pending",
+            ExportStatus::Ready => "ready",
+            ExportStatus::Failed => "failed",
+            ExportStatus::Expired => "expired",
         }
     }
 }
+
+/// A personal data export, the archive can be downloaded once it is ready
+#[derive(Debug, Serialize, Clone)]
+pub struct Export {
+    pub id: String,
+    pub user_id: String,
+    pub status: String,
+    pub error: Option<String>,
+    pub created_at: String,
+    pub completed_at: Option<String>,
+    pub expires_at: Option<String>,
+}
+
+impl Export {
+    pub fn from_db(row: &Row) -> Result<Export> {
+        Ok(Export {
+            id: row.get(0)?,
+            user_id: row.get(1)?,
+            status: row.get(2)?,
+            error: row.get(3)?,
+            created_at: row.get(4)?,
+            completed_at: row.get(5)?,
+            expires_at: row.get(6)?,
+        })
+    }
+
+    pub fn is(&self, status: ExportStatus) -> bool {
+        self.status.eq(status.as_str())
+    }
+}
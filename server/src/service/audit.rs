@@ -0,0 +1,122 @@
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::DbPool;
+use crate::error::AppError;
+use crate::model::api::AuditQuery;
+use crate::model::data::{AuditEntry, Page};
+use crate::service::database;
+use crate::utils::cursor;
+
+/// The privileged actions that end up in the audit log
+#[derive(Debug, Clone, Copy)]
+pub enum Action {
+    RemovePost,
+    RemoveComment,
+    RestorePost,
+    RestoreComment,
+    LockPost,
+    UnlockPost,
+    DismissReport,
+    IssueBan,
+    LiftBan,
+    GrantModerator,
+    RevokeModerator,
+    UpdateCategory,
+    UpdateSetting,
+    PurgeDeleted,
+}
+
+impl Action {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Action::RemovePost => "post.remove",
+            Action::RemoveComment => "comment.remove",
+            Action::RestorePost => "post.restore",
+            Action::RestoreComment => "comment.restore",
+            Action::LockPost => "post.lock",
+            Action::UnlockPost => "post.unlock",
+            Action::DismissReport => "report.dismiss",
+            Action::IssueBan => "ban.issue",
+            Action::LiftBan => "ban.lift",
+            Action::GrantModerator => "moderator.grant",
+            Action::RevokeModerator => "moderator.revoke",
+            Action::UpdateCategory => "category.update",
+            Action::UpdateSetting => "setting.update",
+            Action::PurgeDeleted => "content.purge",
+        }
+    }
+
+    /// What the target id of the action refers to
+    fn target_type(&self) -> &'static str {
+        match self {
+            Action::RemovePost | Action::RestorePost | Action::LockPost | Action::UnlockPost => "post",
+            Action::RemoveComment | Action::RestoreComment => "comment",
+            Action::DismissReport => "report",
+            Action::IssueBan | Action::GrantModerator | Action::RevokeModerator => "user",
+            Action::LiftBan => "ban",
+            Action::UpdateCategory => "category",
+            Action::UpdateSetting => "setting",
+            Action::PurgeDeleted => "retention",
+        }
+    }
+}
+
+/// Where a page of the audit log continues
+#[derive(Debug, Serialize, Deserialize)]
+struct Cursor {
+    before: i64,
+}
+
+/**
+ * Add an entry to the audit log, after the action succeeded
+ * @param details Anything else worth keeping about the action, stored as JSON
+ */
+pub async fn record(pool: DbPool, actor_id: &str, action: Action, target_id: &str, reason: Option<String>, details: Option<Value>) -> Result<(), AppError> {
+    let details = details.map(|details| details.to_string());
+
+    database::save_audit_entry(pool, actor_id.to_string(), action.as_str(), action.target_type(), target_id.to_string(), reason, details).await
+}
+
+/**
+ * A page of the audit log, newest first
+ * @param limit The validated page size
+ */
+pub async fn list(pool: DbPool, query: AuditQuery, limit: u32) -> Result<Page<AuditEntry>, AppError> {
+    let before = match &query.cursor {
+        Some(cursor) => Some(cursor::decode::<Cursor>(cursor)?.before),
+        None => None,
+    };
+
+    // One more than asked for tells if there is another page
+    let mut items = database::find_audit_entries(pool, query, before, limit + 1).await?;
+
+    let next_cursor = match items.len() > limit as usize {
+        true => {
+            items.truncate(limit as usize);
+            items.last().map(|entry| cursor::encode(&Cursor { before: entry.id }))
+        },
+        false => None,
+    };
+
+    Ok(Page { items, next_cursor })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TestApp;
+
+    #[actix_web::test]
+    async fn entries_cannot_be_changed_or_removed() {
+        let t = TestApp::new();
+        record(t.pool.clone(), "admin", Action::IssueBan, "alice", Some("Spam".to_string()), None).await.unwrap();
+
+        let conn = t.pool.get().unwrap();
+        assert!(conn.execute("UPDATE audit_log SET reason = 'Nothing happened'", []).is_err());
+        assert!(conn.execute("DELETE FROM audit_log", []).is_err());
+
+        let reason: String = conn.query_row("SELECT reason FROM audit_log", [], |row| row.get(0)).unwrap();
+        assert_eq!(reason, "Spam");
+    }
+}
@@ -0,0 +1,288 @@
+use std::future::{ready, Future, Ready};
+use std::ops::Deref;
+use std::pin::Pin;
+use std::rc::Rc;
+
+use actix_web::{web, Error, FromRequest, HttpMessage, HttpRequest};
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform};
+
+use crate::DbPool;
+use crate::error::AppError;
+use crate::service::{ban, database};
+use crate::service::keys::KeyStore;
+
+pub const ADMIN: &str = "admin";
+
+/// What the extractors and the guard resolve to, they have to look things up in the database
+type LocalBoxFuture<T> = Pin<Box<dyn Future<Output = T>>>;
+
+/// The user behind the `identity` cookie. Responds with 401 when the cookie is missing or invalid
+/// or its session is over, and with 403 when the user is banned from the site.
+#[derive(Debug, Clone)]
+pub struct AuthUser {
+    pub id: String,
+    pub role: String,
+    /// The session the access token was issued for
+    pub session_id: String,
+}
+
+impl AuthUser {
+    pub fn is_admin(&self) -> bool {
+        self.role.eq(ADMIN)
+    }
+}
+
+/// The user behind the `identity` cookie, if there is a valid one
+#[derive(Debug, Clone)]
+pub struct OptionalUser(pub Option<AuthUser>);
+
+/// A logged in admin. Responds with 401 without a valid cookie and 403 for everyone else.
+#[derive(Debug, Clone)]
+pub struct AdminUser(pub AuthUser);
+
+impl Deref for AdminUser {
+    type Target = AuthUser;
+
+    fn deref(&self) -> &AuthUser {
+        &self.0
+    }
+}
+
+/**
+ * Decode the identity cookie of a request. This only proves the token was issued by us, see `verify_user`.
+ */
+fn authenticate(req: &HttpRequest) -> Result<AuthUser, AppError> {
+    let cookie = match req.cookie("identity") {
+        Some(cookie) => cookie,
+        None => return Err(AppError::Unauthorized("Unauthorized".to_string())),
+    };
+
+    let keys = match req.app_data::<web::Data<KeyStore>>() {
+        Some(keys) => keys,
+        None => return Err(AppError::Internal("Key store is not configured".to_string())),
+    };
+
+    let claims = keys.verify(cookie.value())?;
+
+    Ok(AuthUser {
+        id: claims.sub,
+        role: claims.role,
+        session_id: claims.sid,
+    })
+}
+
+/**
+ * The user behind a request. Besides the token, the session it was issued for has to be live
+ * and the user must not be banned from the site, so logging out, revoking a session or a ban take
+ * effect right away instead of when the access token expires. The user is kept in the request
+ * extensions, so this only happens once no matter how many guards and extractors ask for it.
+ */
+async fn verify_user(req: HttpRequest) -> Result<AuthUser, AppError> {
+    if let Some(user) = req.extensions().get::<AuthUser>() {
+        return Ok(user.clone());
+    }
+
+    let user = authenticate(&req)?;
+
+    let pool = match req.app_data::<DbPool>() {
+        Some(pool) => pool.clone(),
+        None => return Err(AppError::Internal("Database is not configured".to_string())),
+    };
+
+    if !database::is_session_active(pool.clone(), user.id.clone(), user.session_id.clone()).await? {
+        return Err(AppError::Unauthorized("Session expired".to_string()));
+    }
+    ban::check(pool, &user.id, None).await?;
+
+    req.extensions_mut().insert(user.clone());
+
+    Ok(user)
+}
+
+impl FromRequest for AuthUser {
+    type Error = AppError;
+    type Future = LocalBoxFuture<Result<AuthUser, AppError>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        Box::pin(verify_user(req.clone()))
+    }
+}
+
+impl FromRequest for OptionalUser {
+    type Error = AppError;
+    type Future = LocalBoxFuture<Result<OptionalUser, AppError>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move { Ok(OptionalUser(verify_user(req).await.ok())) })
+    }
+}
+
+impl FromRequest for AdminUser {
+    type Error = AppError;
+    type Future = LocalBoxFuture<Result<AdminUser, AppError>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move {
+            let user = verify_user(req).await?;
+            match user.is_admin() {
+                true => Ok(AdminUser(user)),
+                false => Err(AppError::Forbidden("Forbidden".to_string())),
+            }
+        })
+    }
+}
+
+/**
+ * Route guard that only lets users with the given role through, e.g.
+ * `web::scope("/api/admin").wrap(RequireRole::admin())`.
+ * Handlers behind it don't have to check the role themselves.
+ */
+pub struct RequireRole {
+    role: &'static str,
+}
+
+impl RequireRole {
+    pub fn admin() -> RequireRole {
+        RequireRole { role: ADMIN }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequireRole
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RequireRoleMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequireRoleMiddleware { service: Rc::new(service), role: self.role }))
+    }
+}
+
+pub struct RequireRoleMiddleware<S> {
+    service: Rc<S>,
+    role: &'static str,
+}
+
+impl<S, B> Service<ServiceRequest> for RequireRoleMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let role = self.role;
+
+        Box::pin(async move {
+            let allowed = verify_user(req.request().clone()).await.and_then(|user| match user.role.eq(role) {
+                true => Ok(()),
+                false => Err(AppError::Forbidden("Forbidden".to_string())),
+            });
+
+            match allowed {
+                Ok(_) => Ok(service.call(req).await?.map_into_left_body()),
+                Err(e) => Ok(req.error_response(e).map_into_right_body()),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{test, web, App, http::StatusCode};
+
+    use super::*;
+    use crate::controller::admin::get_bans;
+    use crate::controller::user::get_self;
+    use crate::test_utils::TestApp;
+
+    #[actix_web::test]
+    async fn rejects_requests_without_a_valid_cookie() {
+        let t = TestApp::new();
+        let app = test::init_service(App::new().configure(|cfg| t.configure(cfg)).service(get_self)).await;
+
+        let req = test::TestRequest::get().uri("/api/user/me").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+
+        let req = test::TestRequest::get().uri("/api/user/me")
+            .cookie(actix_web::cookie::Cookie::new("identity", "not a token"))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn revoked_session_is_rejected_right_away() {
+        let t = TestApp::new();
+        let alice = t.user("alice", "user").await;
+        let app = test::init_service(App::new().configure(|cfg| t.configure(cfg)).service(get_self)).await;
+
+        let req = test::TestRequest::get().uri("/api/user/me").cookie(alice.identity()).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        let sessions = database::find_sessions_by_user_id(t.pool.clone(), alice.user.id.clone()).await.unwrap();
+        database::revoke_session(t.pool.clone(), alice.user.id.clone(), sessions[0].id.clone()).await.unwrap();
+
+        let req = test::TestRequest::get().uri("/api/user/me").cookie(alice.identity()).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn banned_user_is_rejected_right_away() {
+        let t = TestApp::new();
+        let admin = t.user("admin", ADMIN).await;
+        let alice = t.user("alice", "user").await;
+        let app = test::init_service(App::new().configure(|cfg| t.configure(cfg)).service(get_self)).await;
+
+        // A ban from a single category doesn't lock anyone out of their account
+        database::save_ban(t.pool.clone(), alice.user.id.clone(), Some("category".to_string()), "Spam".to_string(), admin.user.id.clone(), None).await.unwrap();
+
+        let req = test::TestRequest::get().uri("/api/user/me").cookie(alice.identity()).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        database::save_ban(t.pool.clone(), alice.user.id.clone(), None, "Spam".to_string(), admin.user.id.clone(), None).await.unwrap();
+
+        let req = test::TestRequest::get().uri("/api/user/me").cookie(alice.identity()).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn require_role_only_lets_admins_through() {
+        let t = TestApp::new();
+        let admin = t.user("admin", ADMIN).await;
+        let alice = t.user("alice", "user").await;
+        let app = test::init_service(App::new()
+            .configure(|cfg| t.configure(cfg))
+            .service(web::scope("/api/admin").wrap(RequireRole::admin()).service(get_bans))
+        ).await;
+
+        let req = test::TestRequest::get().uri("/api/admin/bans").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+
+        let req = test::TestRequest::get().uri("/api/admin/bans").cookie(alice.identity()).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+
+        let req = test::TestRequest::get().uri("/api/admin/bans").cookie(admin.identity()).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+        let sessions = database::find_sessions_by_user_id(t.pool.clone(), admin.user.id.clone()).await.unwrap();
+        database::revoke_session(t.pool.clone(), admin.user.id.clone(), sessions[0].id.clone()).await.unwrap();
+
+        let req = test::TestRequest::get().uri("/api/admin/bans").cookie(admin.identity()).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,30 @@
+use crate::DbPool;
+use crate::error::AppError;
+use crate::model::data::Ban;
+use crate::service::database;
+
+/**
+ * Refuse a user that is banned. Site wide bans always count, category bans only for the category.
+ * @param category_id The category the user wants to post, comment or vote in, if any
+ * @return Forbidden with the reason and the end of the ban
+ */
+pub async fn check(pool: DbPool, user_id: &str, category_id: Option<&str>) -> Result<(), AppError> {
+    let bans = database::find_active_bans(pool, user_id.to_string(), category_id.map(|id| id.to_string())).await?;
+
+    match bans.first() {
+        Some(ban) => Err(AppError::Forbidden(message(ban))),
+        None => Ok(()),
+    }
+}
+
+fn message(ban: &Ban) -> String {
+    let scope = match ban.category_id {
+        Some(_) => "from this category",
+        None => "from the site",
+    };
+
+    match &ban.expires_at {
+        Some(expires_at) => format!("You are suspended {} until {} UTC: {}", scope, expires_at, ban.reason),
+        None => format!("You are banned {}: {}", scope, ban.reason),
+    }
+}
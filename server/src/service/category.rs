@@ -0,0 +1,63 @@
+use serde_json::json;
+
+use crate::DbPool;
+use crate::error::AppError;
+use crate::model::data::{Category, PostType};
+use crate::service::{audit, database};
+use crate::service::audit::Action;
+use crate::service::store::ContentStore;
+
+/**
+ * Create a category owned by a user. The owner moderates it from the start, the grant is audited like any other.
+ * @return Conflict if a category with the same normalized name exists
+ */
+pub async fn create_category(store: &dyn ContentStore, pool: DbPool, owner_id: String, name: String, description: String, rules: String, post_types: Vec<PostType>) -> Result<Category, AppError> {
+    let category = Category {
+        id: String::new(),
+        name: name.split_whitespace().collect::<Vec<_>>().join(" "),
+        owner_id: Some(owner_id.clone()),
+        description,
+        rules,
+        post_types,
+        created_at: Some(chrono::Utc::now().to_rfc3339()),
+        posts: None,
+    };
+
+    let category = store.index_category(category).await?;
+    database::grant_moderator(pool.clone(), owner_id.clone(), category.id.clone(), owner_id.clone()).await?;
+
+    audit::record(pool, &owner_id, Action::GrantModerator, &owner_id, None, Some(json!({ "category_id": category.id }))).await?;
+
+    Ok(category)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::model::api::AuditQuery;
+    use crate::service::audit;
+    use crate::test_utils::TestApp;
+
+    #[actix_web::test]
+    async fn owner_grant_is_audited() {
+        let t = TestApp::new();
+        let owner = t.user("owner", "user").await;
+        let category = t.category(&owner, "rust").await;
+
+        let query = AuditQuery {
+            actor_id: Some(owner.user.id.clone()),
+            action: None,
+            target_type: None,
+            target_id: None,
+            since: None,
+            until: None,
+            limit: None,
+            cursor: None,
+        };
+        let page = audit::list(t.pool.clone(), query, 10).await.unwrap();
+
+        assert_eq!(page.items.len(), 1);
+        assert_eq!(page.items[0].action, "moderator.grant");
+        assert_eq!(page.items[0].target_id, owner.user.id);
+        assert_eq!(page.items[0].details.as_ref().unwrap()["category_id"], category.id);
+    }
+}
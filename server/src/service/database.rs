@@ -1,35 +1,31 @@
-use std::env;
-
 use actix_web::web::block;
 use r2d2::Pool;
-use r2d2_sqlite::rusqlite::ToSql;
+use r2d2_sqlite::rusqlite::{self, ToSql, TransactionBehavior};
 use r2d2_sqlite::{rusqlite::params, SqliteConnectionManager};
 use uuid;
 
 use crate::DbPool;
-use crate::model::data::User;
+use crate::config::AdminConfig;
+use crate::error::AppError;
+use crate::model::api::AuditQuery;
+use crate::model::data::{AuditEntry, Ban, Export, ExportStatus, LoginAttempts, Report, ReportItem, Session, User, Vote};
+use crate::service::migrations;
 
+/// Setting key for how many minutes after creation a comment can still be edited
+pub const COMMENT_EDIT_WINDOW: &str = "comment_edit_window_minutes";
 
-pub async fn init(pool: Pool<SqliteConnectionManager>) -> Result<(), String> {
-    let result = block(move || {
-        let conn = pool.get()
-            .expect("couldn't get db connection from pool");
 
-        // Create tables
-        match conn.execute(
-            "CREATE TABLE IF NOT EXISTS users (
-                id TEXT PRIMARY KEY,
-                username TEXT NOT NULL UNIQUE,
-                username_lower TEXT NOT NULL UNIQUE,
-                password TEXT NOT NULL,
-                avatar_url TEXT,
-                created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
-                role TEXT NOT NULL DEFAULT 'user'
-            )", 
-            params![]
-        ) {
-            Ok(_) => {
-                println!("Database initialized");
+/**
+ * Migrate the database to the current schema and create the admin account if it doesn't exist yet
+ */
+pub async fn init(pool: Pool<SqliteConnectionManager>, admin: AdminConfig) -> Result<(), AppError> {
+    block(move || {
+        let mut conn = pool.get()?;
+
+        // Create or update the tables
+        match migrations::run(&mut conn) {
+            Ok(version) => {
+                println!("Database initialized at schema version {}", version);
             },
             Err(e) => {
                 eprintln!("Error initializing database: {}", e);
@@ -37,11 +33,16 @@ pub async fn init(pool: Pool<SqliteConnectionManager>) -> Result<(), String> {
             },
         }
 
+        // Exports that were being built when the server stopped will never finish
+        conn.execute(
+            "UPDATE exports SET status = ?, error = 'Interrupted by a restart', completed_at = CURRENT_TIMESTAMP WHERE status = ?",
+            params![ExportStatus::Failed.as_str(), ExportStatus::Pending.as_str()]
+        )?;
+
         // Create default admin user
         let id = uuid::Uuid::new_v4().to_string();
-        let username = env::var("ADMIN_USER").expect("ADMIN_USER not set");
-        let password = env::var("ADMIN_PASS").expect("ADMIN_PASS not set");
-        let password = bcrypt::hash(password, bcrypt::DEFAULT_COST).expect("Failed to hash password");
+        let username = admin.username;
+        let password = bcrypt::hash(admin.password, bcrypt::DEFAULT_COST)?;
 
         match conn.execute(
             "INSERT INTO users (id, username, username_lower, password, role) VALUES (?, ?, ?, ?, ?)", 
@@ -52,30 +53,22 @@ pub async fn init(pool: Pool<SqliteConnectionManager>) -> Result<(), String> {
                 Ok(())
             },
             Err(e) => {
-                if e.to_string().eq("UNIQUE constraint failed: users.username") || e.to_string().eq("UNIQUE constraint failed: users.username_lower") {
+                if is_unique_violation(&e) {
                     Ok(())
                 } else {
                     eprintln!("Error creating default admin user: {}", e);
-                    Err(e)
+                    Err(e.into())
                 }
             },
         }
 
-    }).await.map_err(|e| {
-        panic!("Error initializing database: {}", e)
-    });
-
-    match result {
-        Ok(_) => Ok(()),
-        Err(e) => Err(e),
-    }
+    }).await?
 }
 
-pub async fn save_user(pool: DbPool, username: String, password: String) -> Result<String, String> {
+pub async fn save_user(pool: DbPool, username: String, password: String) -> Result<String, AppError> {
     
-    let result = block(move || {
-        let conn = pool.get()
-            .expect("couldn't get db connection from pool");
+    block(move || {
+        let conn = pool.get()?;
 
         let id = uuid::Uuid::new_v4().to_string();
 
@@ -84,116 +77,71 @@ pub async fn save_user(pool: DbPool, username: String, password: String) -> Resu
             params![id, username, username.to_lowercase(), password]
         ) {
             Ok(_) => Ok(id),
-            Err(e) => Err(e)
+            Err(e) if is_unique_violation(&e) => Err(AppError::Conflict("Username already exists".to_string())),
+            Err(e) => Err(e.into()),
         }
 
-    }).await.map_err(|e| {
-        eprintln!("{}", e);
-        "Error saving user".to_string()
-    }).unwrap();
-
-    match result {
-        Ok(id) => Ok(id),
-        Err(e) => Err(e.to_string()),
-    }
+    }).await?
 }
 
-pub async fn find_users(pool: DbPool) -> Result<Vec<User>, String> {
+/// Only the dev endpoint `get_users` lists every user, it isn't mounted by default
+#[allow(dead_code)]
+pub async fn find_users(pool: DbPool) -> Result<Vec<User>, AppError> {
         
-        let result = block(move || {
-            let conn = pool.get()
-                .expect("couldn't get db connection from pool");
-    
-            let mut stmt = conn.prepare("SELECT * FROM users").unwrap();
-            let user_iter = stmt.query_map(params![], |row| {
-                Ok(User::from_db(row))
-            }).unwrap();
-    
-            let mut users = Vec::new();
-            for user in user_iter {
-                users.push(user.unwrap());
-            }
+        block(move || {
+            let conn = pool.get()?;
     
-            Ok::<Vec<User>, String>(users)
+            let mut stmt = conn.prepare("SELECT * FROM users")?;
+            let users = stmt.query_map(params![], User::from_db)?
+                .collect::<Result<Vec<User>, _>>()?;
     
-        }).await.map_err(|e| {
-            eprintln!("{}", e);
-            "Error finding users".to_string()
-        });
+            Ok(users)
     
-        match result {
-            Ok(result) => match result {
-                Ok(users) => Ok(users),
-                Err(e) => Err(e.to_string()),
-            },
-            Err(e) => Err(e),
-        }
+        }).await?
 }
 
-pub async fn find_user_by_id(pool: DbPool, id: String) -> Result<User, String> {
+pub async fn find_user_by_id(pool: DbPool, id: String) -> Result<User, AppError> {
     
         // Open a connection on a separate thread and return the result to the main thread
-        let result = block(move || {
-            let conn = pool.get()
-                .expect("couldn't get db connection from pool");
+        block(move || {
+            let conn = pool.get()?;
     
             // Query for the user
-            conn.query_row(
+            match conn.query_row(
                 "SELECT * FROM users WHERE id = ?", 
                 params![id],
-                |row| {
-                    Ok(User::from_db(row))
-                }
-            )
-        // Handle any errors that may occur
-        }).await.map_err(|e| {
-            eprintln!("{}", e);
-            "Error finding user".to_string()
-        });
-    
-        match result {
-            Ok(result) => match result {
+                User::from_db
+            ) {
                 Ok(user) => Ok(user),
-                Err(e) => Err(e.to_string()),
-            },
-            Err(e) => Err(e),
-        }
+                Err(rusqlite::Error::QueryReturnedNoRows) => Err(AppError::NotFound("User not found".to_string())),
+                Err(e) => Err(e.into()),
+            }
 
+        }).await?
 }
 
-pub async fn find_user_by_username(pool: DbPool, username: String) -> Result<User, String> {
+pub async fn find_user_by_username(pool: DbPool, username: String) -> Result<User, AppError> {
 
-    let result = block(move || {
-        let conn = pool.get()
-            .expect("couldn't get db connection from pool");
+    block(move || {
+        let conn = pool.get()?;
 
-        conn.query_row(
+        match conn.query_row(
             "SELECT * FROM users WHERE username_lower = ?", 
             params![username.to_lowercase()],
-            |row| {
-                Ok(User::from_db(row))
-            }
-        )
-
-    }).await.map_err(|e| {
-        eprintln!("{}", e);
-        "Error finding user".to_string()
-    });
-
-    match result {
-        Ok(result) => match result {
+            User::from_db
+        ) {
             Ok(user) => Ok(user),
-            Err(e) => Err(e.to_string()),
-        },
-        Err(e) => Err(e),
-    }
+            Err(rusqlite::Error::QueryReturnedNoRows) => Err(AppError::NotFound("User not found".to_string())),
+            Err(e) => Err(e.into()),
+        }
+
+    }).await?
 }
 
-pub async fn find_avatars_by_user_ids(pool: DbPool, ids: Vec<String>) -> Result<Vec<(String, Option<String>)>, String> {
+pub async fn find_avatars_by_user_ids(pool: DbPool, ids: Vec<String>) -> Result<Vec<(String, Option<String>)>, AppError> {
     
-        let result = block(move || {
-            let conn = pool.get()
-                .expect("couldn't get db connection from pool");
+        block(move || {
+            let conn = pool.get()?;
     
             let params: Vec<&dyn ToSql> = ids.iter().map(|x| x as &dyn ToSql).collect();
             let query = format!(
@@ -201,58 +149,977 @@ pub async fn find_avatars_by_user_ids(pool: DbPool, ids: Vec<String>) -> Result<
                 ids.iter().map(|_| "?").collect::<Vec<_>>().join(",")
             );
 
-            let mut stmt = conn.prepare(&query).unwrap();
+            let mut stmt = conn.prepare(&query)?;
             
-            let url_iter = stmt.query_map(params.as_slice(), |row| {
-                let user = User::from_db(row);
+            let urls = stmt.query_map(params.as_slice(), |row| {
+                let user = User::from_db(row)?;
                 Ok((user.id, user.avatar_url))
-            }).unwrap();
-    
-            let mut urls = Vec::new();
-            for url in url_iter {
-                match url {
-                    Ok(url) => urls.push(url),
-                    Err(e) => return Err(e.to_string()),
-                }
-            }
+            })?.collect::<Result<Vec<_>, _>>()?;
     
-            Ok::<Vec<(String, Option<String>)>, String>(urls)
+            Ok(urls)
     
-        }).await.map_err(|e| {
-            eprintln!("{}", e);
-            "Error finding users".to_string()
-        });
-    
-        match result {
-            Ok(result) => match result {
-                Ok(urls) => Ok(urls),
-                Err(e) => Err(e.to_string()),
-            },
-            Err(e) => Err(e),
-        }
+        }).await?
 }
 
-pub async fn update_user_avatar(pool: DbPool, user_id: String, avatar_url: String) -> Result<(), String> {
+pub async fn update_user_avatar(pool: DbPool, user_id: String, avatar_url: String) -> Result<(), AppError> {
     
-    let result = block(move || {
-        let conn = pool.get()
-            .expect("couldn't get db connection from pool");
+    block(move || {
+        let conn = pool.get()?;
 
-        match conn.execute(
+        conn.execute(
             "UPDATE users SET avatar_url = ? WHERE id = ?", 
             params![avatar_url, user_id]
+        )?;
+
+        Ok(())
+
+    }).await?
+}
+
+pub async fn update_user_password(pool: DbPool, user_id: String, password: String) -> Result<(), AppError> {
+
+    block(move || {
+        let conn = pool.get()?;
+
+        match conn.execute(
+            "UPDATE users SET password = ? WHERE id = ?", 
+            params![password, user_id]
+        )? {
+            0 => Err(AppError::NotFound("User not found".to_string())),
+            _ => Ok(()),
+        }
+
+    }).await?
+}
+
+/**
+ * Change the username of a user, the same rules as for registering apply
+ * @return Conflict if another user already has the name, in any case
+ */
+pub async fn update_username(pool: DbPool, user_id: String, username: String) -> Result<(), AppError> {
+
+    block(move || {
+        let conn = pool.get()?;
+
+        match conn.execute(
+            "UPDATE users SET username = ?, username_lower = ? WHERE id = ?", 
+            params![username, username.to_lowercase(), user_id]
         ) {
+            Ok(0) => Err(AppError::NotFound("User not found".to_string())),
             Ok(_) => Ok(()),
-            Err(e) => Err(e)
+            Err(e) if is_unique_violation(&e) => Err(AppError::Conflict("Username already exists".to_string())),
+            Err(e) => Err(e.into()),
+        }
+
+    }).await?
+}
+
+/**
+ * Delete a user with their sessions, votes, reports and failed logins. The vote counters of posts and comments
+ * are left as they are, the reports are taken out of the moderation queue counts.
+ * @param login_key The key the failed logins of the account are counted under, so whoever takes the name next doesn't inherit them
+ */
+pub async fn delete_user(pool: DbPool, user_id: String, login_key: String) -> Result<(), AppError> {
+
+    block(move || {
+        let mut conn = pool.get()?;
+
+        let tx = conn.transaction()?;
+
+        tx.execute(
+            "DELETE FROM used_refresh_tokens WHERE session_id IN (SELECT id FROM sessions WHERE user_id = ?)", 
+            params![user_id]
+        )?;
+        tx.execute("DELETE FROM sessions WHERE user_id = ?", params![user_id])?;
+        tx.execute("DELETE FROM votes WHERE user_id = ?", params![user_id])?;
+        tx.execute("DELETE FROM category_moderators WHERE user_id = ?", params![user_id])?;
+        tx.execute("DELETE FROM bans WHERE user_id = ?", params![user_id])?;
+        tx.execute("DELETE FROM exports WHERE user_id = ?", params![user_id])?;
+        tx.execute(
+            "UPDATE report_items SET report_count = report_count - 1 WHERE target_id IN (SELECT target_id FROM reports WHERE user_id = ?)",
+            params![user_id]
+        )?;
+        tx.execute("DELETE FROM reports WHERE user_id = ?", params![user_id])?;
+        tx.execute("DELETE FROM login_attempts WHERE key = ?", params![login_key])?;
+
+        if tx.execute("DELETE FROM users WHERE id = ?", params![user_id])? == 0 { return Err(AppError::NotFound("User not found".to_string())) }
+
+        tx.commit()?;
+        Ok(())
+
+    }).await?
+}
+
+/**
+ * Make a user moderator of a category
+ * @param granted_by The admin that granted it
+ * @return Conflict if the user already moderates the category
+ */
+pub async fn grant_moderator(pool: DbPool, user_id: String, category_id: String, granted_by: String) -> Result<(), AppError> {
+
+    block(move || {
+        let conn = pool.get()?;
+
+        match conn.execute(
+            "INSERT INTO category_moderators (user_id, category_id, granted_by) VALUES (?, ?, ?)", 
+            params![user_id, category_id, granted_by]
+        ) {
+            Ok(_) => Ok(()),
+            Err(e) if is_unique_violation(&e) => Err(AppError::Conflict("User already moderates this category".to_string())),
+            Err(e) => Err(e.into()),
+        }
+
+    }).await?
+}
+
+pub async fn revoke_moderator(pool: DbPool, user_id: String, category_id: String) -> Result<(), AppError> {
+
+    block(move || {
+        let conn = pool.get()?;
+
+        match conn.execute(
+            "DELETE FROM category_moderators WHERE user_id = ? AND category_id = ?", 
+            params![user_id, category_id]
+        )? {
+            0 => Err(AppError::NotFound("Moderator not found".to_string())),
+            _ => Ok(()),
+        }
+
+    }).await?
+}
+
+/**
+ * The moderators of a category, without the admins
+ */
+pub async fn find_moderators_by_category_id(pool: DbPool, category_id: String) -> Result<Vec<User>, AppError> {
+
+    block(move || {
+        let conn = pool.get()?;
+
+        let mut stmt = conn.prepare(
+            "SELECT users.* FROM users JOIN category_moderators ON category_moderators.user_id = users.id WHERE category_moderators.category_id = ? ORDER BY users.username_lower"
+        )?;
+
+        let users = stmt.query_map(params![category_id], User::from_db)?
+            .collect::<Result<Vec<User>, rusqlite::Error>>()?;
+
+        Ok(users)
+
+    }).await?
+}
+
+/**
+ * The ids of the categories a user moderates
+ */
+pub async fn find_moderated_category_ids(pool: DbPool, user_id: String) -> Result<Vec<String>, AppError> {
+
+    block(move || {
+        let conn = pool.get()?;
+
+        let mut stmt = conn.prepare("SELECT category_id FROM category_moderators WHERE user_id = ?")?;
+
+        let category_ids = stmt.query_map(params![user_id], |row| row.get(0))?
+            .collect::<Result<Vec<String>, rusqlite::Error>>()?;
+
+        Ok(category_ids)
+
+    }).await?
+}
+
+pub async fn is_moderator(pool: DbPool, user_id: String, category_id: String) -> Result<bool, AppError> {
+
+    block(move || {
+        let conn = pool.get()?;
+
+        let is_moderator = conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM category_moderators WHERE user_id = ? AND category_id = ?)", 
+            params![user_id, category_id],
+            |row| row.get(0)
+        )?;
+
+        Ok(is_moderator)
+
+    }).await?
+}
+
+/**
+ * Report a post or comment. The reports of an item are counted in the moderation queue,
+ * and an item that was dismissed before is opened again.
+ * @return Conflict if the user already reported the item, otherwise the number of reports of the item
+ */
+pub async fn save_report(pool: DbPool, user_id: String, target_id: String, target_type: &'static str, category_id: String, reason: String) -> Result<u32, AppError> {
+
+    block(move || {
+        let mut conn = pool.get()?;
+
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+        match tx.execute(
+            "INSERT INTO reports (user_id, target_id, reason) VALUES (?, ?, ?)", 
+            params![user_id, target_id, reason]
+        ) {
+            Ok(_) => (),
+            Err(e) if is_unique_violation(&e) => return Err(AppError::Conflict("You already reported this".to_string())),
+            Err(e) => return Err(e.into()),
+        }
+
+        tx.execute(
+            "INSERT INTO report_items (target_id, target_type, category_id, report_count) VALUES (?, ?, ?, 1)
+            ON CONFLICT (target_id) DO UPDATE SET report_count = report_count + 1, last_reported_at = CURRENT_TIMESTAMP, status = 'open', resolved_by = NULL, resolved_at = NULL", 
+            params![target_id, target_type, category_id]
+        )?;
+
+        let report_count = tx.query_row(
+            "SELECT report_count FROM report_items WHERE target_id = ?", 
+            params![target_id],
+            |row| row.get(0)
+        )?;
+
+        tx.commit()?;
+        Ok(report_count)
+
+    }).await?
+}
+
+/**
+ * A page of the moderation queue, most reported first
+ * @param category_ids Only items in these categories, items in any category when left out
+ */
+pub async fn find_report_items(pool: DbPool, status: Option<&'static str>, target_type: Option<&'static str>, category_ids: Option<Vec<String>>, limit: u32, offset: u32) -> Result<Vec<ReportItem>, AppError> {
+
+    block(move || {
+        let conn = pool.get()?;
+
+        let mut conditions = vec![];
+        let mut params: Vec<&dyn ToSql> = vec![];
+
+        if let Some(status) = &status {
+            conditions.push("status = ?".to_string());
+            params.push(status);
+        }
+        if let Some(target_type) = &target_type {
+            conditions.push("target_type = ?".to_string());
+            params.push(target_type);
+        }
+        if let Some(category_ids) = &category_ids {
+            conditions.push(format!("category_id IN ({})", category_ids.iter().map(|_| "?").collect::<Vec<_>>().join(",")));
+            params.extend(category_ids.iter().map(|id| id as &dyn ToSql));
+        }
+        params.push(&limit);
+        params.push(&offset);
+
+        let query = format!(
+            "SELECT target_id, target_type, category_id, report_count, status, first_reported_at, last_reported_at, resolved_by, resolved_at FROM report_items {} ORDER BY report_count DESC, last_reported_at DESC LIMIT ? OFFSET ?",
+            match conditions.is_empty() {
+                true => String::new(),
+                false => format!("WHERE {}", conditions.join(" AND ")),
+            }
+        );
+
+        let mut stmt = conn.prepare(&query)?;
+
+        let items = stmt.query_map(params.as_slice(), ReportItem::from_db)?
+            .collect::<Result<Vec<ReportItem>, rusqlite::Error>>()?;
+
+        Ok(items)
+
+    }).await?
+}
+
+pub async fn find_report_item(pool: DbPool, target_id: String) -> Result<ReportItem, AppError> {
+
+    block(move || {
+        let conn = pool.get()?;
+
+        match conn.query_row(
+            "SELECT target_id, target_type, category_id, report_count, status, first_reported_at, last_reported_at, resolved_by, resolved_at FROM report_items WHERE target_id = ?", 
+            params![target_id],
+            ReportItem::from_db
+        ) {
+            Ok(item) => Ok(item),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Err(AppError::NotFound("Report not found".to_string())),
+            Err(e) => Err(e.into()),
+        }
+
+    }).await?
+}
+
+/**
+ * The reports of a post or comment, newest first
+ */
+pub async fn find_reports_by_target_id(pool: DbPool, target_id: String) -> Result<Vec<Report>, AppError> {
+
+    block(move || {
+        let conn = pool.get()?;
+
+        let mut stmt = conn.prepare(
+            "SELECT reports.user_id, users.username, reports.reason, reports.created_at FROM reports LEFT JOIN users ON users.id = reports.user_id WHERE reports.target_id = ? ORDER BY reports.created_at DESC"
+        )?;
+
+        let reports = stmt.query_map(params![target_id], Report::from_db)?
+            .collect::<Result<Vec<Report>, rusqlite::Error>>()?;
+
+        Ok(reports)
+
+    }).await?
+}
+
+/**
+ * Take an item out of the moderation queue
+ * @return Conflict if it was resolved in the meantime
+ */
+pub async fn resolve_report_item(pool: DbPool, target_id: String, status: &'static str, resolved_by: String) -> Result<(), AppError> {
+
+    block(move || {
+        let conn = pool.get()?;
+
+        match conn.execute(
+            "UPDATE report_items SET status = ?, resolved_by = ?, resolved_at = CURRENT_TIMESTAMP WHERE target_id = ? AND status = 'open'", 
+            params![status, resolved_by, target_id]
+        )? {
+            0 => Err(AppError::Conflict("Report already resolved".to_string())),
+            _ => Ok(()),
+        }
+
+    }).await?
+}
+
+/**
+ * Ban a user from the whole site or from a category
+ * @param duration_hours How long a suspension lasts, a ban without one is permanent
+ * @return The id of the ban
+ */
+pub async fn save_ban(pool: DbPool, user_id: String, category_id: Option<String>, reason: String, issued_by: String, duration_hours: Option<u32>) -> Result<String, AppError> {
+
+    block(move || {
+        let conn = pool.get()?;
+
+        let id = uuid::Uuid::new_v4().to_string();
+        conn.execute(
+            "INSERT INTO bans (id, user_id, category_id, reason, issued_by, expires_at) VALUES (?, ?, ?, ?, ?, CASE WHEN ? IS NULL THEN NULL ELSE datetime('now', ?) END)", 
+            params![id, user_id, category_id, reason, issued_by, duration_hours, duration_hours.map(|hours| format!("+{} hours", hours))]
+        )?;
+
+        Ok(id)
+
+    }).await?
+}
+
+/**
+ * The bans that keep a user from acting, the ones without an end first
+ * @param category_id Bans from this category count as well as site wide ones, only site wide ones without it
+ */
+pub async fn find_active_bans(pool: DbPool, user_id: String, category_id: Option<String>) -> Result<Vec<Ban>, AppError> {
+
+    block(move || {
+        let conn = pool.get()?;
+
+        let mut stmt = conn.prepare(
+            "SELECT id, user_id, category_id, reason, issued_by, created_at, expires_at, lifted_by, lifted_at FROM bans
+            WHERE user_id = ? AND lifted_at IS NULL AND (expires_at IS NULL OR expires_at > datetime('now')) AND (category_id IS NULL OR category_id = ?)
+            ORDER BY expires_at IS NOT NULL, expires_at DESC"
+        )?;
+
+        let bans = stmt.query_map(params![user_id, category_id], Ban::from_db)?
+            .collect::<Result<Vec<Ban>, rusqlite::Error>>()?;
+
+        Ok(bans)
+
+    }).await?
+}
+
+/**
+ * Bans for the admins, newest first
+ * @param active_only Leave out bans that expired or were lifted
+ */
+pub async fn find_bans(pool: DbPool, user_id: Option<String>, active_only: bool) -> Result<Vec<Ban>, AppError> {
+
+    block(move || {
+        let conn = pool.get()?;
+
+        let mut stmt = conn.prepare(
+            "SELECT id, user_id, category_id, reason, issued_by, created_at, expires_at, lifted_by, lifted_at FROM bans
+            WHERE (? IS NULL OR user_id = ?) AND (NOT ? OR (lifted_at IS NULL AND (expires_at IS NULL OR expires_at > datetime('now'))))
+            ORDER BY created_at DESC"
+        )?;
+
+        let bans = stmt.query_map(params![user_id, user_id, active_only], Ban::from_db)?
+            .collect::<Result<Vec<Ban>, rusqlite::Error>>()?;
+
+        Ok(bans)
+
+    }).await?
+}
+
+/**
+ * End a ban before it expires
+ * @return NotFound if there is no such ban or it has already ended
+ */
+pub async fn lift_ban(pool: DbPool, ban_id: String, lifted_by: String) -> Result<(), AppError> {
+
+    block(move || {
+        let conn = pool.get()?;
+
+        match conn.execute(
+            "UPDATE bans SET lifted_by = ?, lifted_at = CURRENT_TIMESTAMP WHERE id = ? AND lifted_at IS NULL AND (expires_at IS NULL OR expires_at > datetime('now'))", 
+            params![lifted_by, ban_id]
+        )? {
+            0 => Err(AppError::NotFound("Ban not found".to_string())),
+            _ => Ok(()),
         }
 
-    }).await.map_err(|e| {
-        eprintln!("{}", e);
-        "Error updating avatar url".to_string()
-    }).unwrap();
+    }).await?
+}
+
+pub async fn save_audit_entry(pool: DbPool, actor_id: String, action: &'static str, target_type: &'static str, target_id: String, reason: Option<String>, details: Option<String>) -> Result<(), AppError> {
+
+    block(move || {
+        let conn = pool.get()?;
+
+        conn.execute(
+            "INSERT INTO audit_log (actor_id, action, target_type, target_id, reason, details) VALUES (?, ?, ?, ?, ?, ?)", 
+            params![actor_id, action, target_type, target_id, reason, details]
+        )?;
+
+        Ok(())
 
-    match result {
-        Ok(_) => Ok(()),
-        Err(e) => Err(e.to_string()),
-    }
-}
\ No newline at end of file
+    }).await?
+}
+
+/**
+ * A page of the audit log, newest first. Every filter that is given has to match.
+ * @param before Only entries older than this id, to continue from the previous page
+ */
+pub async fn find_audit_entries(pool: DbPool, query: AuditQuery, before: Option<i64>, limit: u32) -> Result<Vec<AuditEntry>, AppError> {
+
+    block(move || {
+        let conn = pool.get()?;
+
+        let mut stmt = conn.prepare(
+            "SELECT id, actor_id, action, target_type, target_id, reason, details, created_at FROM audit_log
+            WHERE (?1 IS NULL OR actor_id = ?1) AND (?2 IS NULL OR action = ?2) AND (?3 IS NULL OR target_type = ?3) AND (?4 IS NULL OR target_id = ?4)
+            AND (?5 IS NULL OR created_at >= ?5) AND (?6 IS NULL OR created_at < ?6) AND (?7 IS NULL OR id < ?7)
+            ORDER BY id DESC LIMIT ?8"
+        )?;
+
+        let entries = stmt.query_map(
+            params![query.actor_id, query.action, query.target_type, query.target_id, query.since, query.until, before, limit],
+            AuditEntry::from_db
+        )?.collect::<Result<Vec<AuditEntry>, rusqlite::Error>>()?;
+
+        Ok(entries)
+
+    }).await?
+}
+
+/**
+ * Record a user's vote on a post or comment and return the vote it replaced.
+ * A value of 0 clears the vote. The read and the write happen in one transaction,
+ * so two concurrent requests from the same user can't both count.
+ * @return The previous vote (-1, 0 or 1)
+ */
+pub async fn set_vote(pool: DbPool, user_id: String, target_id: String, target_type: &'static str, value: i8) -> Result<i8, AppError> {
+
+    block(move || {
+        let mut conn = pool.get()?;
+
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+        let previous: i8 = match tx.query_row(
+            "SELECT value FROM votes WHERE user_id = ? AND target_id = ?", 
+            params![user_id, target_id],
+            |row| row.get(0)
+        ) {
+            Ok(previous) => previous,
+            Err(rusqlite::Error::QueryReturnedNoRows) => 0,
+            Err(e) => return Err(e.into()),
+        };
+
+        if value == 0 {
+            tx.execute(
+                "DELETE FROM votes WHERE user_id = ? AND target_id = ?", 
+                params![user_id, target_id]
+            )?;
+        } else {
+            tx.execute(
+                "INSERT INTO votes (user_id, target_id, target_type, value) VALUES (?, ?, ?, ?)
+                ON CONFLICT (user_id, target_id) DO UPDATE SET value = excluded.value, created_at = CURRENT_TIMESTAMP", 
+                params![user_id, target_id, target_type, value]
+            )?;
+        }
+
+        tx.commit()?;
+        Ok(previous)
+
+    }).await?
+}
+
+/**
+ * Delete every vote on posts and comments that were purged
+ */
+pub async fn delete_votes(pool: DbPool, target_ids: Vec<String>) -> Result<(), AppError> {
+
+    block(move || {
+        let mut conn = pool.get()?;
+
+        let tx = conn.transaction()?;
+        for target_id in &target_ids {
+            tx.execute("DELETE FROM votes WHERE target_id = ?", params![target_id])?;
+        }
+        tx.commit()?;
+
+        Ok(())
+
+    }).await?
+}
+
+/**
+ * Move the karma of a user after a vote on one of their posts or comments
+ */
+pub async fn add_karma(pool: DbPool, user_id: String, post_delta: i64, comment_delta: i64) -> Result<(), AppError> {
+
+    block(move || {
+        let conn = pool.get()?;
+
+        conn.execute(
+            "UPDATE users SET post_karma = post_karma + ?, comment_karma = comment_karma + ? WHERE id = ?",
+            params![post_delta, comment_delta, user_id]
+        )?;
+
+        Ok(())
+
+    }).await?
+}
+
+/**
+ * Replace the karma of every user. Users that aren't in `karma` have none.
+ * @param karma (user id, post karma, comment karma)
+ * @return How many users have karma
+ */
+pub async fn replace_karma(pool: DbPool, karma: Vec<(String, i64, i64)>) -> Result<usize, AppError> {
+
+    block(move || {
+        let mut conn = pool.get()?;
+
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        tx.execute("UPDATE users SET post_karma = 0, comment_karma = 0", params![])?;
+
+        let mut updated = 0;
+        for (user_id, post_karma, comment_karma) in &karma {
+            // Content can outlive the account of its author
+            updated += tx.execute(
+                "UPDATE users SET post_karma = ?, comment_karma = ? WHERE id = ?",
+                params![post_karma, comment_karma, user_id]
+            )?;
+        }
+        tx.commit()?;
+
+        Ok(updated)
+
+    }).await?
+}
+
+/**
+ * Store a new session and clean up the ones that have expired
+ * @param token_hash Hash of the session's first refresh token
+ * @param lifetime_days How long the session lasts without being refreshed
+ * @return The id of the session
+ */
+pub async fn create_session(pool: DbPool, user_id: String, token_hash: String, user_agent: Option<String>, lifetime_days: i64) -> Result<String, AppError> {
+
+    block(move || {
+        let conn = pool.get()?;
+
+        conn.execute("DELETE FROM sessions WHERE expires_at < datetime('now')", params![])?;
+
+        let id = uuid::Uuid::new_v4().to_string();
+        conn.execute(
+            "INSERT INTO sessions (id, user_id, token_hash, user_agent, expires_at) VALUES (?, ?, ?, ?, datetime('now', ?))", 
+            params![id, user_id, token_hash, user_agent, format!("+{} days", lifetime_days)]
+        )?;
+
+        Ok(id)
+
+    }).await?
+}
+
+/**
+ * Replace the refresh token of a session and extend it.
+ * A token that was already replaced means it was stolen (or the client is confused),
+ * so the whole session is revoked and both holders have to log in again.
+ * @return The refreshed session
+ */
+pub async fn rotate_session(pool: DbPool, token_hash: String, new_token_hash: String, lifetime_days: i64) -> Result<Session, AppError> {
+
+    block(move || {
+        let mut conn = pool.get()?;
+
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+        let reused_session: Option<String> = match tx.query_row(
+            "SELECT session_id FROM used_refresh_tokens WHERE token_hash = ?", 
+            params![token_hash],
+            |row| row.get(0)
+        ) {
+            Ok(session_id) => Some(session_id),
+            Err(rusqlite::Error::QueryReturnedNoRows) => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        if let Some(session_id) = reused_session {
+            tx.execute(
+                "UPDATE sessions SET revoked_at = CURRENT_TIMESTAMP WHERE id = ? AND revoked_at IS NULL", 
+                params![session_id]
+            )?;
+            tx.commit()?;
+            return Err(AppError::Unauthorized("Refresh token reuse detected, please log in again".to_string()));
+        }
+
+        let session = match tx.query_row(
+            "SELECT id, user_id, user_agent, created_at, last_used_at, expires_at FROM sessions WHERE token_hash = ? AND revoked_at IS NULL AND expires_at > datetime('now')", 
+            params![token_hash],
+            Session::from_db
+        ) {
+            Ok(session) => session,
+            Err(rusqlite::Error::QueryReturnedNoRows) => return Err(AppError::Unauthorized("Session expired".to_string())),
+            Err(e) => return Err(e.into()),
+        };
+
+        tx.execute(
+            "INSERT INTO used_refresh_tokens (token_hash, session_id) VALUES (?, ?)", 
+            params![token_hash, session.id]
+        )?;
+        tx.execute(
+            "UPDATE sessions SET token_hash = ?, last_used_at = CURRENT_TIMESTAMP, expires_at = datetime('now', ?) WHERE id = ?", 
+            params![new_token_hash, format!("+{} days", lifetime_days), session.id]
+        )?;
+
+        tx.commit()?;
+        Ok(session)
+
+    }).await?
+}
+
+/**
+ * Revoke the session a refresh token belongs to, if there is one
+ */
+pub async fn revoke_session_by_token(pool: DbPool, token_hash: String) -> Result<(), AppError> {
+
+    block(move || {
+        let conn = pool.get()?;
+
+        conn.execute(
+            "UPDATE sessions SET revoked_at = CURRENT_TIMESTAMP WHERE token_hash = ? AND revoked_at IS NULL", 
+            params![token_hash]
+        )?;
+
+        Ok(())
+
+    }).await?
+}
+
+/**
+ * Revoke one of a user's sessions
+ */
+pub async fn revoke_session(pool: DbPool, user_id: String, session_id: String) -> Result<(), AppError> {
+
+    block(move || {
+        let conn = pool.get()?;
+
+        match conn.execute(
+            "UPDATE sessions SET revoked_at = CURRENT_TIMESTAMP WHERE id = ? AND user_id = ? AND revoked_at IS NULL", 
+            params![session_id, user_id]
+        )? {
+            0 => Err(AppError::NotFound("Session not found".to_string())),
+            _ => Ok(()),
+        }
+
+    }).await?
+}
+
+/**
+ * Check that a session of a user is neither revoked nor expired. A deleted account has no sessions left.
+ */
+pub async fn is_session_active(pool: DbPool, user_id: String, session_id: String) -> Result<bool, AppError> {
+
+    block(move || {
+        let conn = pool.get()?;
+
+        let active: bool = conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM sessions WHERE id = ? AND user_id = ? AND revoked_at IS NULL AND expires_at > datetime('now'))",
+            params![session_id, user_id],
+            |row| row.get(0)
+        )?;
+
+        Ok(active)
+
+    }).await?
+}
+
+/**
+ * Log out every device of a user
+ * @param keep The session to stay logged in, if any
+ */
+pub async fn revoke_user_sessions(pool: DbPool, user_id: String, keep: Option<String>) -> Result<(), AppError> {
+
+    block(move || {
+        let conn = pool.get()?;
+
+        conn.execute(
+            "UPDATE sessions SET revoked_at = CURRENT_TIMESTAMP WHERE user_id = ? AND id IS NOT ? AND revoked_at IS NULL", 
+            params![user_id, keep]
+        )?;
+
+        Ok(())
+
+    }).await?
+}
+
+/**
+ * The sessions of a user that can still be refreshed, most recently used first
+ */
+pub async fn find_sessions_by_user_id(pool: DbPool, user_id: String) -> Result<Vec<Session>, AppError> {
+
+    block(move || {
+        let conn = pool.get()?;
+
+        let mut stmt = conn.prepare(
+            "SELECT id, user_id, user_agent, created_at, last_used_at, expires_at FROM sessions WHERE user_id = ? AND revoked_at IS NULL AND expires_at > datetime('now') ORDER BY last_used_at DESC"
+        )?;
+        let sessions = stmt.query_map(params![user_id], Session::from_db)?
+            .collect::<Result<Vec<Session>, _>>()?;
+
+        Ok(sessions)
+
+    }).await?
+}
+
+pub async fn find_login_attempts(pool: DbPool, keys: Vec<String>) -> Result<Vec<LoginAttempts>, AppError> {
+
+    block(move || {
+        let conn = pool.get()?;
+
+        let params: Vec<&dyn ToSql> = keys.iter().map(|x| x as &dyn ToSql).collect();
+        let query = format!(
+            "SELECT attempts, last_attempt_at, locked_until FROM login_attempts WHERE key IN ({})",
+            keys.iter().map(|_| "?").collect::<Vec<_>>().join(",")
+        );
+
+        let mut stmt = conn.prepare(&query)?;
+        let attempts = stmt.query_map(params.as_slice(), LoginAttempts::from_db)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(attempts)
+
+    }).await?
+}
+
+/**
+ * Count an attempt. The count starts over when the last attempt is older than `reset_after` seconds,
+ * and entries of the same kind that old are removed.
+ * @param key What is counted, e.g. `login:user:<name>`. The part before the first colon is its kind.
+ * @param lock Lock the key for the given number of seconds once it reaches the given number of attempts
+ */
+pub async fn record_login_attempt(pool: DbPool, key: String, now: i64, reset_after: i64, lock: Option<(u32, i64)>) -> Result<(), AppError> {
+
+    block(move || {
+        let mut conn = pool.get()?;
+
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+        let kind = format!("{}:%", key.split(':').next().unwrap_or_default());
+        tx.execute(
+            "DELETE FROM login_attempts WHERE key LIKE ? AND last_attempt_at < ? AND IFNULL(locked_until, 0) < ?", 
+            params![kind, now - reset_after, now]
+        )?;
+
+        tx.execute(
+            "INSERT INTO login_attempts (key, attempts, last_attempt_at) VALUES (?1, 1, ?2)
+            ON CONFLICT (key) DO UPDATE SET attempts = attempts + 1, last_attempt_at = ?2", 
+            params![key, now]
+        )?;
+
+        if let Some((lock_after, lock_seconds)) = lock {
+            tx.execute(
+                "UPDATE login_attempts SET locked_until = ? WHERE key = ? AND attempts >= ? AND IFNULL(locked_until, 0) < ?", 
+                params![now + lock_seconds, key, lock_after, now]
+            )?;
+        }
+
+        tx.commit()?;
+        Ok(())
+
+    }).await?
+}
+
+pub async fn clear_login_attempts(pool: DbPool, key: String) -> Result<(), AppError> {
+
+    block(move || {
+        let conn = pool.get()?;
+
+        conn.execute("DELETE FROM login_attempts WHERE key = ?", params![key])?;
+
+        Ok(())
+
+    }).await?
+}
+
+pub async fn get_setting(pool: DbPool, key: &'static str) -> Result<Option<String>, AppError> {
+
+    block(move || {
+        let conn = pool.get()?;
+
+        match conn.query_row(
+            "SELECT value FROM settings WHERE key = ?", 
+            params![key],
+            |row| row.get(0)
+        ) {
+            Ok(value) => Ok(Some(value)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+
+    }).await?
+}
+
+/**
+ * Store a setting, or remove it when the value is None
+ */
+pub async fn set_setting(pool: DbPool, key: &'static str, value: Option<String>) -> Result<(), AppError> {
+
+    block(move || {
+        let conn = pool.get()?;
+
+        match value {
+            Some(value) => conn.execute(
+                "INSERT INTO settings (key, value) VALUES (?, ?) ON CONFLICT (key) DO UPDATE SET value = excluded.value", 
+                params![key, value]
+            )?,
+            None => conn.execute(
+                "DELETE FROM settings WHERE key = ?", 
+                params![key]
+            )?,
+        };
+
+        Ok(())
+
+    }).await?
+}
+
+/**
+ * Check if an insert failed because a UNIQUE column already has the value
+ */
+fn is_unique_violation(e: &rusqlite::Error) -> bool {
+    matches!(e, rusqlite::Error::SqliteFailure(err, _) if err.code == rusqlite::ErrorCode::ConstraintViolation)
+}
+
+/**
+ * Every vote of a user, newest first
+ */
+pub async fn find_votes_by_user_id(pool: DbPool, user_id: String) -> Result<Vec<Vote>, AppError> {
+
+    block(move || {
+        let conn = pool.get()?;
+
+        let mut stmt = conn.prepare(
+            "SELECT target_id, target_type, value, created_at FROM votes WHERE user_id = ? ORDER BY created_at DESC"
+        )?;
+        let votes = stmt.query_map(params![user_id], Vote::from_db)?
+            .collect::<Result<Vec<Vote>, _>>()?;
+
+        Ok(votes)
+
+    }).await?
+}
+
+/**
+ * Start a personal data export
+ * @return The id of the export
+ */
+pub async fn save_export(pool: DbPool, user_id: String) -> Result<String, AppError> {
+
+    block(move || {
+        let conn = pool.get()?;
+
+        let id = uuid::Uuid::new_v4().to_string();
+        conn.execute(
+            "INSERT INTO exports (id, user_id, status) VALUES (?, ?, ?)",
+            params![id, user_id, ExportStatus::Pending.as_str()]
+        )?;
+
+        Ok(id)
+
+    }).await?
+}
+
+/**
+ * Every export of a user, the latest first. Exports past their expiry are read as expired.
+ */
+pub async fn find_exports_by_user_id(pool: DbPool, user_id: String) -> Result<Vec<Export>, AppError> {
+
+    block(move || {
+        let conn = pool.get()?;
+
+        let mut stmt = conn.prepare(
+            "SELECT id, user_id, CASE WHEN expires_at <= datetime('now') THEN 'expired' ELSE status END, error, created_at, completed_at, expires_at FROM exports WHERE user_id = ? ORDER BY created_at DESC, rowid DESC"
+        )?;
+        let exports = stmt.query_map(params![user_id], Export::from_db)?
+            .collect::<Result<Vec<Export>, _>>()?;
+
+        Ok(exports)
+
+    }).await?
+}
+
+pub async fn find_export(pool: DbPool, export_id: String) -> Result<Export, AppError> {
+
+    block(move || {
+        let conn = pool.get()?;
+
+        match conn.query_row(
+            "SELECT id, user_id, CASE WHEN expires_at <= datetime('now') THEN 'expired' ELSE status END, error, created_at, completed_at, expires_at FROM exports WHERE id = ?",
+            params![export_id],
+            Export::from_db
+        ) {
+            Ok(export) => Ok(export),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Err(AppError::NotFound("Export not found".to_string())),
+            Err(e) => Err(e.into()),
+        }
+
+    }).await?
+}
+
+/**
+ * Mark an export as ready, or as failed with the error
+ * @param lifetime_days How long a ready export can be downloaded
+ */
+pub async fn finish_export(pool: DbPool, export_id: String, error: Option<String>, lifetime_days: u32) -> Result<(), AppError> {
+
+    block(move || {
+        let conn = pool.get()?;
+
+        let (status, expires_in) = match error {
+            Some(_) => (ExportStatus::Failed, None),
+            None => (ExportStatus::Ready, Some(format!("+{} days", lifetime_days))),
+        };
+        conn.execute(
+            "UPDATE exports SET status = ?, error = ?, completed_at = CURRENT_TIMESTAMP, expires_at = CASE WHEN ? IS NULL THEN NULL ELSE datetime('now', ?) END WHERE id = ?",
+            params![status.as_str(), error, expires_in, expires_in, export_id]
+        )?;
+
+        Ok(())
+
+    }).await?
+}
+
+pub async fn delete_export(pool: DbPool, export_id: String) -> Result<(), AppError> {
+
+    block(move || {
+        let conn = pool.get()?;
+
+        conn.execute("DELETE FROM exports WHERE id = ?", params![export_id])?;
+
+        Ok(())
+
+    }).await?
+}
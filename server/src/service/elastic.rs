@@ -1,614 +1,1534 @@
-use std::env;
-
-use actix_web::{http::StatusCode};
+use async_trait::async_trait;
 use url::Url;
-use serde_json::json;
-use base64::{Engine as _, engine::general_purpose::STANDARD};
+use serde::de::{DeserializeOwned, IgnoredAny};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use base64::{Engine as _, engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD}};
+use ring::digest;
 use elasticsearch::{
     http::transport::{TransportBuilder, SingleNodeConnectionPool},
-    params::Refresh,
+    http::response::Response,
+    params::{Conflicts, Refresh},
     DeleteByQueryParts,
     Elasticsearch,
     SearchParts,
     UpdateParts,
+    UpdateByQueryParts,
     IndexParts,
+    CreateParts,
     CountParts,
     GetParts,
+    OpenPointInTimeParts,
 };
 
-use crate::model::data::{Category, Post, Comment};
+use crate::config::ElasticConfig;
+use crate::error::AppError;
+use crate::model::data::{Category, Post, PostType, Comment, Deletion, DeletionKind, Restoration, Revision, Page};
+use crate::service::ranking::Ranking;
+use crate::service::store::{ContentStore, SearchIndex, Index, DELETED_AUTHOR};
+use crate::utils::cursor;
+
+/// Names of the indices, they all start with the configured prefix
+struct Indices {
+    category: String,
+    comment: String,
+    post: String,
+    post_revision: String,
+    comment_revision: String,
+}
 
-pub enum Index {
-    Post,
-    Comment,
+impl Indices {
+    fn new(prefix: &str) -> Indices {
+        Indices {
+            category: format!("{}_category", prefix),
+            comment: format!("{}_comment", prefix),
+            post: format!("{}_post", prefix),
+            post_revision: format!("{}_post_revision", prefix),
+            comment_revision: format!("{}_comment_revision", prefix),
+        }
+    }
 }
 
-const CATEGORY_INDEX: &str = "tidder_category";
-const COMMENT_INDEX: &str = "tidder_comment";
-const POST_INDEX: &str = "tidder_post";
+/// How long a paginated listing stays consistent between two page requests
+const PIT_KEEP_ALIVE: &str = "5m";
 
-fn client() -> Elasticsearch {
+/// How many pages of comments a profile listing looks through for comments on visible posts
+const AUTHOR_COMMENT_ROUNDS: u32 = 10;
 
-    let url = env::var("ELASTIC_URL").expect("Missing ELASTIC_URL");
-    let url = Url::parse(url.as_str()).expect("Invalid URL");
-    let conn_pool = SingleNodeConnectionPool::new(url);
+/// How many authors are summed up per request when karma is recomputed
+const KARMA_PAGE_SIZE: u32 = 1000;
 
-    let username = env::var("ELASTIC_USER").expect("Missing ELASTIC_USER");
-    let password = env::var("ELASTIC_PASS").expect("Missing ELASTIC_PASS");
-    let encoded = STANDARD.encode(format!("{}:{}", username, password));
+/// How many hits are fetched per request when a search is read to the end
+const SEARCH_ALL_PAGE_SIZE: u32 = 1000;
 
-    let header_name = elasticsearch::http::headers::AUTHORIZATION;
-    let header_value = elasticsearch::http::headers::HeaderValue::from_str(
-        &format!("Basic {}", encoded)
-    ).expect("Invalid header value");
+/// Where a paginated listing continues: the point in time it reads from,
+/// the sort values of the last hit and the time the listing started
+#[derive(Debug, Serialize, Deserialize)]
+struct Cursor {
+    pit: String,
+    after: Value,
+    now: i64,
+}
+
+/// A document as returned by a get request or as a search hit
+#[derive(Debug, Deserialize)]
+struct Hit<T> {
+    #[serde(rename = "_id")]
+    id: String,
+    #[serde(rename = "_index", default)]
+    index: String,
+    #[serde(rename = "_source")]
+    source: T,
+    /// Sort values of a search hit, a listing continues after the last of them
+    #[serde(default)]
+    sort: Option<Value>,
+}
 
-    let transport = TransportBuilder::new(conn_pool)
-        .header(header_name, header_value)
-        .disable_proxy()
-        .build()
-        .expect("Failed to create transport");
+#[derive(Debug, Deserialize)]
+struct SearchResponse<T> {
+    hits: Hits<T>,
+    pit_id: Option<String>,
+}
 
-    Elasticsearch::new(transport)
+#[derive(Debug, Deserialize)]
+struct Hits<T> {
+    hits: Vec<Hit<T>>,
 }
 
-pub async fn get_categories() -> Vec<Category> {
+/// Search hits without their source, when only the ids are needed
+#[derive(Debug, Deserialize)]
+struct IdsResponse {
+    hits: IdHits,
+}
 
-    let client = client();
+#[derive(Debug, Deserialize)]
+struct IdHits {
+    hits: Vec<IdHit>,
+}
 
-    let response = client
-        .search(SearchParts::Index(&[CATEGORY_INDEX]))
-        .body(json!({
-            "size": 10000,
-            "query": {
-                "match_all": {}
-            },
-        }))
-        .send().await;
-    
-    let mut categories = match response {
-        Ok(response) => {
-            let body = response.json::<serde_json::Value>().await.unwrap();
-            let categories: Vec<Category> = body.get("hits").unwrap().get("hits").unwrap().as_array().unwrap().iter().map(|hit| {
-                let source = hit.get("_source").unwrap();
-                let id = hit.get("_id").unwrap().as_str().unwrap().to_string();
-                
-                Category {
-                    id,
-                    name: source.get("name").unwrap().as_str().unwrap().to_string(),
-                    posts: None,
-                }
-            }).collect();
+#[derive(Debug, Deserialize)]
+struct IdHit {
+    #[serde(rename = "_id")]
+    id: String,
+}
 
-            categories
-        },
-        _ => return vec![]
-    };
+/// The karma of a page of authors, from a composite aggregation
+#[derive(Debug, Deserialize)]
+struct KarmaResponse {
+    aggregations: KarmaAggregations,
+}
 
-    for category in categories.iter_mut() {
-        let count = count_posts_by_category_id(category.id.to_string()).await;
-        match count {
-            Ok(count) => {
-                category.posts = Some(count);
-            },
-            _ => return vec![]
+#[derive(Debug, Deserialize)]
+struct KarmaAggregations {
+    authors: KarmaBuckets,
+}
+
+#[derive(Debug, Deserialize)]
+struct KarmaBuckets {
+    /// Where the next page starts, missing after the last one
+    after_key: Option<Value>,
+    buckets: Vec<KarmaBucket>,
+}
+
+#[derive(Debug, Deserialize)]
+struct KarmaBucket {
+    key: AuthorKey,
+    upvotes: Sum,
+    downvotes: Sum,
+}
+
+#[derive(Debug, Deserialize)]
+struct AuthorKey {
+    author_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Sum {
+    value: f64,
+}
+
+/// The comments that have replies, from a terms aggregation on the parent id
+#[derive(Debug, Deserialize)]
+struct RepliedResponse {
+    aggregations: RepliedAggregations,
+}
+
+#[derive(Debug, Deserialize)]
+struct RepliedAggregations {
+    parents: ParentBuckets,
+}
+
+#[derive(Debug, Deserialize)]
+struct ParentBuckets {
+    buckets: Vec<ParentBucket>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ParentBucket {
+    key: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct PointInTime {
+    id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct CountResponse {
+    count: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct IndexResponse {
+    #[serde(rename = "_id")]
+    id: String,
+}
+
+/// The document after an update, as returned when `_source` is requested
+#[derive(Debug, Deserialize)]
+struct UpdateResponse<T> {
+    get: UpdatedSource<T>,
+}
+
+#[derive(Debug, Deserialize)]
+struct UpdatedSource<T> {
+    #[serde(rename = "_source")]
+    source: T,
+}
+
+/// A category as it is stored. Categories from before owners were introduced only have a name.
+#[derive(Debug, Deserialize, Serialize)]
+struct CategorySource {
+    name: String,
+    /// Missing on categories from before their ids were derived from the name
+    #[serde(default)]
+    name_normalized: Option<String>,
+    #[serde(default)]
+    owner_id: Option<String>,
+    #[serde(default)]
+    description: String,
+    #[serde(default)]
+    rules: String,
+    #[serde(default = "PostType::all")]
+    post_types: Vec<PostType>,
+    #[serde(default)]
+    created_at: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Votes {
+    upvotes: u32,
+    downvotes: u32,
+}
+
+impl From<Hit<CategorySource>> for Category {
+    fn from(hit: Hit<CategorySource>) -> Category {
+        Category {
+            id: hit.id,
+            name: hit.source.name,
+            owner_id: hit.source.owner_id,
+            description: hit.source.description,
+            rules: hit.source.rules,
+            post_types: hit.source.post_types,
+            created_at: hit.source.created_at,
+            posts: None,
         }
     }
+}
 
-    categories
+impl From<Hit<Post>> for Post {
+    fn from(hit: Hit<Post>) -> Post {
+        Post {
+            id: Some(hit.id),
+            ..hit.source
+        }
+    }
+}
 
+impl From<Hit<Comment>> for Comment {
+    fn from(hit: Hit<Comment>) -> Comment {
+        Comment {
+            id: Some(hit.id),
+            ..hit.source
+        }
+    }
 }
 
-pub async fn get_category_by_id(category_id: &String) -> Result<Category, &'static str> {
-    let client = client();
+/**
+ * Check the status of a response. A 404 becomes `AppError::NotFound` with the given message,
+ * any other error status an internal error.
+ */
+fn check(response: Result<Response, elasticsearch::Error>, not_found: &str) -> Result<Response, AppError> {
+    let response = response?;
+    let status = response.status_code();
 
-    let response = client
-        .get(GetParts::IndexId(CATEGORY_INDEX, category_id))
-        .send().await;
-    
-    match response {
-        Ok(response) => {
-            let body = response.json::<serde_json::Value>().await.unwrap();
-            let source = body.get("_source").unwrap();
-            Ok(Category {
-                id: category_id.to_string(),
-                name: source.get("name").unwrap().as_str().unwrap().to_string(),
-                posts: None,
-            })
-        },
-        Err(_) => Err("Internal server error")
+    if status.as_u16() == 404 {
+        return Err(AppError::NotFound(not_found.to_string()));
+    }
+    if !status.is_success() {
+        return Err(AppError::Internal(format!("Elasticsearch responded with status {}", status.as_u16())));
     }
+
+    Ok(response)
 }
 
-pub async fn get_category_by_name(category_name: String) -> Result<Category, &'static str> {
+/**
+ * The id of a category, derived from its normalized name so that a name can only be taken once
+ */
+fn category_id(normalized_name: &str) -> String {
+    let digest = digest::digest(&digest::SHA256, normalized_name.as_bytes());
+    URL_SAFE_NO_PAD.encode(&digest.as_ref()[..15])
+}
 
-    let client = client();
+/**
+ * Check the status of a response and deserialize its body
+ */
+async fn read<T: DeserializeOwned>(response: Result<Response, elasticsearch::Error>, not_found: &str) -> Result<T, AppError> {
+    Ok(check(response, not_found)?.json::<T>().await?)
+}
 
-    let response = client
-        .search(SearchParts::Index(&[CATEGORY_INDEX]))
-        .body(json!({
-            "query": {
-                "match": {
-                    "name": category_name
-                }
+/// Content store backed by Elasticsearch. The client keeps its connections open, so one store is shared by all workers.
+pub struct ElasticStore {
+    client: Elasticsearch,
+    indices: Indices,
+}
+
+impl ElasticStore {
+
+    pub fn new(config: &ElasticConfig) -> ElasticStore {
+
+        // The config is validated at startup, so the URL is known to parse
+        let url = Url::parse(config.url.as_str()).expect("Invalid URL");
+        let conn_pool = SingleNodeConnectionPool::new(url);
+
+        let encoded = STANDARD.encode(format!("{}:{}", config.username, config.password));
+
+        let header_name = elasticsearch::http::headers::AUTHORIZATION;
+        let header_value = elasticsearch::http::headers::HeaderValue::from_str(
+            &format!("Basic {}", encoded)
+        ).expect("Invalid header value");
+
+        let transport = TransportBuilder::new(conn_pool)
+            .header(header_name, header_value)
+            .disable_proxy()
+            .build()
+            .expect("Failed to create transport");
+
+        ElasticStore {
+            client: Elasticsearch::new(transport),
+            indices: Indices::new(&config.index_prefix),
+        }
+    }
+
+    /**
+     * The index of posts or comments and the message for when a document isn't in it
+     */
+    fn content_index(&self, index: &Index) -> (&str, &'static str) {
+        match index {
+            Index::Post => (self.indices.post.as_str(), "Post not found"),
+            Index::Comment => (self.indices.comment.as_str(), "Comment not found"),
+        }
+    }
+
+    /// The revision index of posts or comments
+    fn revision_index(&self, index: &Index) -> &str {
+        match index {
+            Index::Post => self.indices.post_revision.as_str(),
+            Index::Comment => self.indices.comment_revision.as_str(),
+        }
+    }
+
+    /**
+     * Decode the cursor of a listing request
+     * @return The cursor, if any, and the time the listing started in milliseconds
+     */
+    fn start_listing(cursor: &Option<String>) -> Result<(Option<Cursor>, i64), AppError> {
+        match cursor {
+            Some(cursor) => {
+                let cursor: Cursor = cursor::decode(cursor)?;
+                let now = cursor.now;
+                Ok((Some(cursor), now))
+            },
+            None => Ok((None, chrono::Utc::now().timestamp_millis())),
+        }
+    }
+
+    /**
+     * Fetch one page of a listing. Every page of a listing searches the same point in time
+     * and continues after the sort values of the previous page, so posts that are created
+     * or voted on while a client is paging don't show up twice or go missing.
+     * @return The hits of the page and the cursor for the next page
+     */
+    async fn search_page<T: DeserializeOwned>(&self, indices: &[&str], query: Value, sort: Value, limit: u32, cursor: Option<Cursor>, now: i64) -> Result<(Vec<Hit<T>>, Option<String>), AppError> {
+        let (hits, next) = self.fetch_page(indices, query, sort, limit, cursor, now).await?;
+        Ok((hits, next.map(|next| cursor::encode(&next))))
+    }
+
+    /**
+     * Every hit of a search, read a page at a time from one point in time,
+     * so nothing is cut off at the result window of the index
+     */
+    async fn search_all<T: DeserializeOwned>(&self, indices: &[&str], query: Value, sort: Value) -> Result<Vec<Hit<T>>, AppError> {
+        let now = chrono::Utc::now().timestamp_millis();
+
+        let mut hits = vec![];
+        let mut cursor = None;
+        loop {
+            let (page, next) = self.fetch_page(indices, query.clone(), sort.clone(), SEARCH_ALL_PAGE_SIZE, cursor, now).await?;
+            hits.extend(page);
+
+            match next {
+                Some(next) => cursor = Some(next),
+                None => break,
             }
-        }))
-        .send().await;
-    
-    match response {
-        Ok(response) => {
-            let body = response.json::<serde_json::Value>().await.unwrap();
-            let hits = body.get("hits").unwrap().get("hits").unwrap().as_array().unwrap();
-            if hits.len() > 0 {
-                let source = hits[0].get("_source").unwrap();
-                let id = hits[0].get("_id").unwrap().as_str().unwrap().to_string();
-
-                let category = Category {
-                    id,
-                    name: source.get("name").unwrap().as_str().unwrap().to_string(),
-                    posts: None,
-                };
-                Ok(category)
-            } else {
-                Err("Not found")
+        }
+
+        Ok(hits)
+    }
+
+    /**
+     * One page of a search from a point in time, see `search_page`
+     * @return The hits of the page and where the next page starts
+     */
+    async fn fetch_page<T: DeserializeOwned>(&self, indices: &[&str], query: Value, sort: Value, limit: u32, cursor: Option<Cursor>, now: i64) -> Result<(Vec<Hit<T>>, Option<Cursor>), AppError> {
+
+        let client = &self.client;
+
+        let (pit, after) = match cursor {
+            Some(cursor) => (cursor.pit, Some(cursor.after)),
+            None => {
+                let response = client
+                    .open_point_in_time(OpenPointInTimeParts::Index(indices))
+                    .keep_alive(PIT_KEEP_ALIVE)
+                    .send().await;
+
+                // The indices are created with the first document, until then there is nothing to list
+                match read::<PointInTime>(response, "Index not found").await {
+                    Ok(pit) => (pit.id, None),
+                    Err(AppError::NotFound(_)) => return Ok((vec![], None)),
+                    Err(e) => return Err(e),
+                }
             }
-        },
-        Err(_) => Err("Internal server error")
+        };
+
+        // Ask for one hit more than the limit to find out if there is a next page
+        let mut body = json!({
+            "size": limit + 1,
+            "query": query,
+            "sort": sort,
+            "pit": {
+                "id": pit,
+                "keep_alive": PIT_KEEP_ALIVE,
+            },
+        });
+        if let Some(after) = after {
+            body["search_after"] = after;
+        }
+
+        let response = client
+            .search(SearchParts::None)
+            .body(body)
+            .send().await;
+
+        // The point in time is gone once it hasn't been used for the keep alive period
+        let body = match read::<SearchResponse<T>>(response, "Cursor expired").await {
+            Err(AppError::NotFound(msg)) => return Err(AppError::BadRequest(msg)),
+            result => result?,
+        };
+
+        // Elasticsearch may hand out a new id for the point in time
+        let pit = body.pit_id.unwrap_or(pit);
+        let mut hits = body.hits.hits;
+
+        let next_cursor = if hits.len() > limit as usize {
+            hits.truncate(limit as usize);
+            let after = hits.last().and_then(|hit| hit.sort.clone())
+                .ok_or_else(|| AppError::Internal("Search hit without sort values".to_string()))?;
+            Some(Cursor { pit, after, now })
+        } else {
+            self.close_point_in_time(pit).await;
+            None
+        };
+
+        Ok((hits, next_cursor))
     }
-}
 
-pub async fn count_posts_by_category_id(category_id: String) -> Result<u64, &'static str> {
-    
-        let client = client();
-    
+    /**
+     * Run a script on every post and comment of an author. Author names are copied into
+     * the documents, so they have to be rewritten when an account changes.
+     */
+    async fn update_by_author(&self, author_id: String, script: Value) -> Result<(), AppError> {
+
+        let client = &self.client;
+
         let response = client
-            .count(CountParts::Index(&[POST_INDEX]))
+            .update_by_query(UpdateByQueryParts::Index(&[self.indices.post.as_str(), self.indices.comment.as_str()]))
             .body(json!({
                 "query": {
-                    "bool": {
-                        "must": [
-                            { "match": { "category_id": category_id } },
-                            { "match": { "published": true } },
-                            { "match": { "deleted": false } }
-                        ]
+                    "term": {
+                        "author_id.keyword": author_id
                     }
-                }
+                },
+                "script": script
             }))
-            .send()
-            .await;
-        
-        match response {
-            Ok(response) => {
-                let body = response.json::<serde_json::Value>().await.unwrap();
-                let count = body.get("count").unwrap().as_u64().unwrap();
-                Ok(count)
-            },
-            Err(_) => Err("Internal server error")
+            .ignore_unavailable(true)
+            .send().await;
+
+        // Nothing was ever posted
+        match check(response, "Index not found") {
+            Ok(_) | Err(AppError::NotFound(_)) => Ok(()),
+            Err(e) => Err(e),
         }
-}
+    }
 
-pub async fn index_category(category_name: String) -> Result<Category, &'static str> {
-    
-        let client = client();
+    /**
+     * The ids of the first documents that match a query, an index that doesn't exist yet has none
+     */
+    async fn search_ids(&self, index: &str, query: Value, sort: Value, size: u32) -> Result<Vec<String>, AppError> {
 
-        // check if category exists
-        let category = get_category_by_name(category_name.clone()).await;
+        let client = &self.client;
 
-        match category {
-            Ok(_) => return Err("Category already exists"),
-            _ => {}
-        }
-    
         let response = client
-            .index(IndexParts::IndexId(CATEGORY_INDEX, ""))
-            .body(json!({ "name": category_name }))
-            .refresh(Refresh::True)
+            .search(SearchParts::Index(&[index]))
+            .body(json!({
+                "size": size,
+                "_source": false,
+                "query": query,
+                "sort": sort,
+            }))
             .send().await;
-    
-        match response {
-            Ok(response) => {
-                let body = response.json::<serde_json::Value>().await.unwrap();
-                Ok(Category {
-                    id: body.get("_id").unwrap().as_str().unwrap().to_string(),
-                    name: category_name,
-                    posts: None,
-                })
-            },
-            Err(_) => Err("Internal server error")
+
+        match read::<IdsResponse>(response, "Index not found").await {
+            Ok(body) => Ok(body.hits.hits.into_iter().map(|hit| hit.id).collect()),
+            Err(AppError::NotFound(_)) => Ok(vec![]),
+            Err(e) => Err(e),
         }
-}
+    }
 
-pub async fn index_post(post: Post) -> Result<Post, &'static str> {
+    /**
+     * Permanently remove the documents that match a query. The indices are refreshed,
+     * so the removed documents are gone from the next search.
+     */
+    async fn delete_by_query(&self, indices: &[&str], query: Value) -> Result<(), AppError> {
 
-    let client = client();
+        let client = &self.client;
 
-    let response = client
-        .index(IndexParts::IndexId(POST_INDEX, ""))
-        .body(json!(post))
-        .refresh(Refresh::True)
-        .send().await;
+        let response = client
+            .delete_by_query(DeleteByQueryParts::Index(indices))
+            .body(json!({
+                "query": query
+            }))
+            .conflicts(Conflicts::Proceed)
+            .ignore_unavailable(true)
+            .refresh(true)
+            .send().await;
 
-    match response {
-        Ok(response) => {
-            let body = response.json::<serde_json::Value>().await.unwrap();
-            let id = body.get("_id").unwrap().as_str().unwrap().to_string();
-            Ok(Post {
-                id: Some(id),
-                ..post
-            })
-        },
-        Err(_) => Err("Internal server error")
+        match check(response, "Index not found") {
+            Ok(_) | Err(AppError::NotFound(_)) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn close_point_in_time(&self, pit: String) {
+        let result = self.client
+            .close_point_in_time()
+            .body(json!({ "id": pit }))
+            .send().await;
+
+        if let Err(e) = result {
+            eprintln!("Failed to close point in time: {}", e);
+        }
     }
 }
 
-pub async fn publish_post(post_id: String) -> Result<(), &'static str> {
+#[async_trait]
+impl SearchIndex for ElasticStore {
 
-    let client = client();
+    async fn search(&self, query: &str, limit: u32, cursor: &Option<String>) -> Result<(Vec<Category>, Vec<Post>, Option<String>), AppError> {
 
-    let response = client
-        .update(UpdateParts::IndexId(POST_INDEX, &post_id))
-        .body(json!({
-            "doc": {
-                "published": true
+            let (cursor, now) = ElasticStore::start_listing(cursor)?;
+
+            // Match categories, and only posts that are published and not deleted
+            let query = json!({
+                "bool": {
+                    "must": {
+                        "multi_match": {
+                            "query": query,
+
+                            "fields": [
+                                "title",
+                                "body",
+                                "author_name",
+                                "name",
+                            ],
+                            "fuzziness": "2",
+                        }
+                    },
+                    "filter": {
+                        "bool": {
+                            "should": [
+                                { "term": { "_index": self.indices.category.as_str() } },
+                                { "bool": { "must": [
+                                    { "match": { "published": true } },
+                                    { "match": { "deleted": false } },
+                                ] } },
+                            ],
+                            "minimum_should_match": 1,
+                        }
+                    }
+                }
+            });
+
+            let (hits, next_cursor) = self.search_page::<Value>(&[self.indices.post.as_str(), self.indices.category.as_str()], query, json!([{ "_score": "desc" }]), limit, cursor, now).await?;
+
+            let mut categories = Vec::new();
+            let mut posts = Vec::new();
+            for hit in hits {
+                // Hits from both indices come back together, so the source is only read once the index is known
+                if hit.index == self.indices.category {
+                    let source: CategorySource = serde_json::from_value(hit.source)?;
+                    categories.push(Category::from(Hit { id: hit.id, index: hit.index, source, sort: hit.sort }));
+                    continue;
+                }
+
+                let source: Post = serde_json::from_value(hit.source)?;
+                let post = Post::from(Hit { id: hit.id, index: hit.index, source, sort: hit.sort }).sanitize(&false);
+
+                if post.deleted || !post.published {
+                    continue;
+                }
+
+                posts.push(post);
             }
-        }))
-        .send().await;
 
-    match response {
-        Ok(_) => Ok(()),
-        Err(_) => Err("Internal server error")
+            Ok((categories, posts, next_cursor))
     }
 }
 
-pub async fn delete_post(index: Index, post_id: String) -> Result<(), &'static str> {
+#[async_trait]
+impl ContentStore for ElasticStore {
 
-    let client = client();
+    async fn get_categories(&self) -> Result<Vec<Category>, AppError> {
 
-    let index = match index {
-        Index::Post => POST_INDEX,
-        Index::Comment => COMMENT_INDEX,
-    };
+        let client = &self.client;
 
-    let response = client
-        .update(UpdateParts::IndexId(index, &post_id))
-        .body(json!({
-            "doc": {
-                "deleted": true
-            }
-        }))
-        .send().await;
+        let response = client
+            .search(SearchParts::Index(&[self.indices.category.as_str()]))
+            .body(json!({
+                "size": 10000,
+                "query": {
+                    "match_all": {}
+                },
+            }))
+            .send().await;
 
-    match response {
-        Ok(_) => Ok(()),
-        Err(_) => Err("Internal server error")
-    }
-}
+        let mut categories: Vec<Category> = match read::<SearchResponse<CategorySource>>(response, "Index not found").await {
+            Ok(body) => body.hits.hits.into_iter().map(Category::from).collect(),
+            // No category has been created yet
+            Err(AppError::NotFound(_)) => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
 
-pub async fn index_comment(comment: Comment) -> Result<Comment, &'static str> {
+        for category in categories.iter_mut() {
+            category.posts = Some(self.count_posts_by_category_id(category.id.to_string()).await?);
+        }
 
-    let client = client();
+        Ok(categories)
+    }
 
-    let response = client
-        .index(IndexParts::IndexId(COMMENT_INDEX, ""))
-        .body(json!(comment))
-        .refresh(Refresh::True)
-        .send().await;
+    async fn get_category_by_id(&self, category_id: &str) -> Result<Category, AppError> {
+        let client = &self.client;
 
-    match response {
-        Ok(response) => {
-            let body = response.json::<serde_json::Value>().await.unwrap();
-            let id = body.get("_id").unwrap().as_str().unwrap().to_string();
-            Ok(Comment {
-                id: Some(id),
-                ..comment
-            })
-        },
-        Err(_) => Err("Internal server error")
+        let response = client
+            .get(GetParts::IndexId(self.indices.category.as_str(), category_id))
+            .send().await;
+
+        let hit = read::<Hit<CategorySource>>(response, "Category not found").await?;
+        Ok(Category::from(hit))
     }
-}
 
-pub async fn search(query: &String) -> Result<(Vec<Category>, Vec<Post>), &'static str> {
-    
-        let client = client();
-    
+    /**
+     * A category is stored under an id derived from its normalized name, so it is looked up directly.
+     * Categories from before that have random ids, they are all read and their normalized names compared here.
+     */
+    async fn get_category_by_name(&self, category_name: String) -> Result<Category, AppError> {
+
+        let client = &self.client;
+
+        let normalized = Category::normalize_name(&category_name);
+
+        match self.get_category_by_id(&category_id(&normalized)).await {
+            Err(AppError::NotFound(_)) => (),
+            result => return result,
+        }
+
+        // No more categories without a normalized name are ever created, so there are only as many as before
         let response = client
-            .search(SearchParts::Index(&[POST_INDEX, CATEGORY_INDEX]))
+            .search(SearchParts::Index(&[self.indices.category.as_str()]))
             .body(json!({
                 "size": 10000,
                 "query": {
-                    "multi_match": {
-                        "query": query,
-
-                        "fields": [
-                            "title", 
-                            "body",
-                            "author_name",
-                            "name",
-                        ],
-                        "fuzziness": "2",
+                    "bool": {
+                        "must_not": { "exists": { "field": "name_normalized" } }
                     }
-                },
+                }
             }))
             .send().await;
-    
-        match response {
-            Ok(response) => {
-                let body = response.json::<serde_json::Value>().await.unwrap();
-                let hits = body.get("hits").unwrap().get("hits").unwrap().as_array().unwrap();
-                let mut categories = Vec::new();
-                let mut posts = Vec::new();
-                for hit in hits {
-                    let source = hit.get("_source").unwrap();
-                    let id = hit.get("_id").unwrap().as_str().unwrap().to_string();
-    
-                    if hit.get("_index").unwrap().as_str().unwrap() == "tidder_category" {
-                        let category = Category {
-                            id,
-                            name: source.get("name").unwrap().as_str().unwrap().to_string(),
-                            posts: None,
-                        };
-                        categories.push(category);
-                        continue;
-                    }
-                    
-                    let post = Post::from_json(hit).sanitize(&false);
 
-                    if post.deleted || !post.published {
-                        continue;
-                    }
+        let body = read::<SearchResponse<CategorySource>>(response, "Category not found").await?;
 
-                    posts.push(post);
-                }
-    
-                Ok((categories, posts))
-            },
-            Err(_) => Err("Internal server error")
+        match body.hits.hits.into_iter().find(|hit| Category::normalize_name(&hit.source.name).eq(&normalized)) {
+            Some(hit) => Ok(Category::from(hit)),
+            None => Err(AppError::NotFound("Category not found".to_string())),
         }
-}
+    }
 
-pub async fn flush_data() -> () {
-    let client = client();
+    async fn count_posts_by_category_id(&self, category_id: String) -> Result<u64, AppError> {
+
+            let client = &self.client;
+
+            let response = client
+                .count(CountParts::Index(&[self.indices.post.as_str()]))
+                .body(json!({
+                    "query": {
+                        "bool": {
+                            "must": [
+                                { "match": { "category_id": category_id } },
+                                { "match": { "published": true } },
+                                { "match": { "deleted": false } }
+                            ]
+                        }
+                    }
+                }))
+                .send()
+                .await;
+
+            // There are no posts at all until the index is created with the first one
+            match read::<CountResponse>(response, "Index not found").await {
+                Ok(body) => Ok(body.count),
+                Err(AppError::NotFound(_)) => Ok(0),
+                Err(e) => Err(e),
+            }
+    }
+
+    async fn index_category(&self, category: Category) -> Result<Category, AppError> {
+
+            let client = &self.client;
 
-    let result = client
-        .delete_by_query(DeleteByQueryParts::Index(&[POST_INDEX]))
-        .body(json!({
-            "query": {
-                "match_all": {}
+            // Categories from before ids were derived from the name are only found by looking
+            match self.get_category_by_name(category.name.clone()).await {
+                Ok(_) => return Err(AppError::Conflict("Category already exists".to_string())),
+                Err(AppError::NotFound(_)) => (),
+                Err(e) => return Err(e),
+            }
+
+            let normalized = Category::normalize_name(&category.name);
+            let id = category_id(&normalized);
+
+            let source = CategorySource {
+                name: category.name.clone(),
+                name_normalized: Some(normalized),
+                owner_id: category.owner_id.clone(),
+                description: category.description.clone(),
+                rules: category.rules.clone(),
+                post_types: category.post_types.clone(),
+                created_at: category.created_at.clone(),
+            };
+
+            // Creating a document fails if the id is taken, so of two requests for the same name only one gets it
+            let response = client
+                .create(CreateParts::IndexId(self.indices.category.as_str(), &id))
+                .body(json!(source))
+                .refresh(Refresh::True)
+                .send().await;
+
+            if let Ok(response) = &response {
+                if response.status_code().as_u16() == 409 {
+                    return Err(AppError::Conflict("Category already exists".to_string()));
+                }
             }
-        }))
-        .send().await;
 
-    // Just to mute compiler warnings
-    if result.is_err() {
-        return ();
+            let body = read::<IndexResponse>(response, "Index not found").await?;
+
+            Ok(Category {
+                id: body.id,
+                ..category
+            })
     }
 
-    let result = client
-        .delete_by_query(DeleteByQueryParts::Index(&[CATEGORY_INDEX]))
-        .body(json!({
-            "query": {
-                "match_all": {}
-            }
-        }))
-        .send().await;
+    async fn update_category(&self, category_id: String, description: String, rules: String, post_types: Vec<PostType>) -> Result<(), AppError> {
+
+        let client = &self.client;
+
+        let response = client
+            .update(UpdateParts::IndexId(self.indices.category.as_str(), &category_id))
+            .body(json!({
+                "doc": {
+                    "description": description,
+                    "rules": rules,
+                    "post_types": post_types,
+                }
+            }))
+            .refresh(Refresh::True)
+            .send().await;
 
-    // Just to mute compiler warnings
-    if result.is_err() {
-        return ();
+        check(response, "Category not found")?;
+        Ok(())
     }
 
-    let result = client
-        .delete_by_query(DeleteByQueryParts::Index(&[COMMENT_INDEX]))
-        .body(json!({
-            "query": {
-                "match_all": {}
-            }
-        }))
-        .send().await;
-    
-    // Just to mute compiler warnings
-    match result {
-        Ok(_) => (),
-        Err(_) => ()
-    }
-}
-
-
-///NOTE:########################################################//
-// NOTE:# Sanitize text body in all functions below this point #//
-///NOTE:########################################################//
-
-pub async fn get_posts_by_category_id(category_id: String, show_all: &bool) -> Vec<Post> {
-
-    let client = client();
-
-    // If show_all is true, we don't need to match by deleted
-    let query = if *show_all {
-        json!([
-            { "match": { "category_id": category_id } },
-        ])
-    } else {
-        json!([
-            { "match": { "category_id": category_id } },
-            { "match": { "published": true } },
-            { "match": { "deleted": false } }
-        ])
-    };
-
-    let response = client
-        .search(SearchParts::Index(&[POST_INDEX]))
-        .body(json!({
-            "size": 10000,
-            "sort": [
-                { "upvotes": "desc" },
-                { "downvotes": "asc" },
-                { "created_at": "desc" },
-            ],
-            "query": {
-                // match by category_id and published
-                "bool": {
-                    "must": query
+    async fn index_post(&self, post: Post) -> Result<Post, AppError> {
+
+        let client = &self.client;
+
+        let response = client
+            .index(IndexParts::IndexId(self.indices.post.as_str(), ""))
+            .body(json!(post))
+            .refresh(Refresh::True)
+            .send().await;
+
+        let body = read::<IndexResponse>(response, "Index not found").await?;
+
+        Ok(Post {
+            id: Some(body.id),
+            ..post
+        })
+    }
+
+    async fn publish_post(&self, post_id: String) -> Result<(), AppError> {
+
+        let client = &self.client;
+
+        let response = client
+            .update(UpdateParts::IndexId(self.indices.post.as_str(), &post_id))
+            .body(json!({
+                "doc": {
+                    "published": true
                 }
-            }
-        }))
-        .send().await;
-    
-    match response {
-        Ok(response) => {
-
-            let posts = response.json::<serde_json::Value>().await.unwrap();
-            let posts: Vec<Post> = posts.get("hits").unwrap().get("hits").unwrap().as_array().unwrap().iter().map(|hit| {
-                Post::from_json(hit).sanitize(show_all)
-            }).collect();
+            }))
+            .send().await;
 
-            posts
-        },
-        _ => return vec![]
+        check(response, "Post not found")?;
+        Ok(())
     }
-}
 
-pub async fn get_posts(show_all: &bool) -> Result<Vec<Post>, &'static str> {
-    
-        let client = client();
+    async fn lock_post(&self, post_id: String, locked: bool) -> Result<(), AppError> {
 
-        let query = if *show_all {
-            json!({
-                "match_all": {}
-            })
-        } else {
-            json!({
-                "bool": {
-                    "must": [
-                        { "match": { "deleted": false } },
-                        { "match": { "published": true } },
-                    ]
+        let client = &self.client;
+
+        let response = client
+            .update(UpdateParts::IndexId(self.indices.post.as_str(), &post_id))
+            .body(json!({
+                "doc": {
+                    "locked": locked
                 }
-            })
-        };
-    
+            }))
+            .send().await;
+
+        check(response, "Post not found")?;
+        Ok(())
+    }
+
+    async fn update_post(&self, post_id: String, title: String, body: String, updated_at: String) -> Result<(), AppError> {
+
+        let client = &self.client;
+
+        let response = client
+            .update(UpdateParts::IndexId(self.indices.post.as_str(), &post_id))
+            .body(json!({
+                "doc": {
+                    "title": title,
+                    "body": body,
+                    "updated_at": updated_at,
+                }
+            }))
+            .refresh(Refresh::True)
+            .send().await;
+
+        check(response, "Post not found")?;
+        Ok(())
+    }
+
+    async fn update_comment(&self, comment_id: String, body: String, edited_at: String) -> Result<(), AppError> {
+
+        let client = &self.client;
+
         let response = client
-            .search(SearchParts::Index(&[POST_INDEX]))
+            .update(UpdateParts::IndexId(self.indices.comment.as_str(), &comment_id))
+            .body(json!({
+                "doc": {
+                    "body": body,
+                    "updated_at": edited_at,
+                    "edited_at": edited_at,
+                }
+            }))
+            .refresh(Refresh::True)
+            .send().await;
+
+        check(response, "Comment not found")?;
+        Ok(())
+    }
+
+    /**
+     * Store the version of a post or comment that is about to be replaced by an edit
+     */
+    async fn index_revision(&self, index: Index, revision: Revision) -> Result<(), AppError> {
+
+        let client = &self.client;
+
+        let index = self.revision_index(&index);
+
+        let response = client
+            .index(IndexParts::Index(index))
+            .body(json!(revision))
+            .refresh(Refresh::True)
+            .send().await;
+
+        check(response, "Index not found")?;
+        Ok(())
+    }
+
+    /**
+     * Get the earlier versions of a post or comment, oldest first
+     */
+    async fn get_revisions(&self, index: Index, target_id: String) -> Result<Vec<Revision>, AppError> {
+
+        let client = &self.client;
+
+        let index = self.revision_index(&index);
+
+        let response = client
+            .search(SearchParts::Index(&[index]))
             .body(json!({
                 "size": 10000,
-                "sort": [
-                    { "upvotes": "desc" },
-                    { "downvotes": "asc" },
-                    { "created_at": "desc" },
-                ],
-                "query": query,
+                "sort": [{ "replaced_at": "asc" }],
+                "query": {
+                    "term": {
+                        "target_id.keyword": target_id
+                    }
+                }
             }))
             .send().await;
-        
-        match response {
-            Ok(response) => {
-
-                let body = response.json::<serde_json::Value>().await.unwrap();
-                let posts: Vec<Post> = body.get("hits").unwrap().get("hits").unwrap().as_array().unwrap().iter().map(|hit| {
-                    Post::from_json(hit).sanitize(show_all)
-                }).collect();
-    
-                Ok(posts)
-            },
-            Err(_) => Err("Internal server error")
+
+        // The index doesn't exist until the first edit
+        match read::<SearchResponse<Revision>>(response, "Index not found").await {
+            Ok(body) => Ok(body.hits.hits.into_iter().map(|hit| hit.source).collect()),
+            Err(AppError::NotFound(_)) => Ok(vec![]),
+            Err(e) => Err(e),
         }
-}
+    }
 
-pub async fn get_post_by_id(id: String, show_all: &bool) -> Result<Post, (StatusCode, &'static str)> {
+    async fn delete_post(&self, index: Index, post_id: String, deletion: Deletion) -> Result<(), AppError> {
 
-    let client = client();
+        let client = &self.client;
 
-    let response = client
-        .get(GetParts::IndexId(POST_INDEX, &id))
-        .send().await;
-    
-    match response {
-        Ok(response) => {
-            let source = response.json::<serde_json::Value>().await.unwrap();
-            Ok(Post::from_json(&source).sanitize(show_all))
-        },
-        Err(_) => Err((StatusCode::NOT_FOUND, "Not Found"))
+        let (index, not_found) = self.content_index(&index);
+
+        let response = client
+            .update(UpdateParts::IndexId(index, &post_id))
+            .body(json!({
+                "doc": {
+                    "deleted": true,
+                    "deletion": deletion,
+                    "restoration": null
+                }
+            }))
+            .send().await;
+
+        check(response, not_found)?;
+        Ok(())
     }
-}
 
-pub async fn get_posts_by_user_id(user_id: String) -> Result<Vec<Post>, &'static str> {
-    
-    let client = client();
+    async fn restore_post(&self, index: Index, post_id: String, restoration: Restoration) -> Result<(), AppError> {
 
-    let response = client
-        .search(SearchParts::Index(&[POST_INDEX]))
-        .body(json!({
-            "size": 10000,
-            "sort": [{ "created_at": "desc" }],
-            "query": {
-                "match": {
-                    "author_id": user_id
+        let client = &self.client;
+
+        let (index, not_found) = self.content_index(&index);
+
+        let response = client
+            .update(UpdateParts::IndexId(index, &post_id))
+            .body(json!({
+                "doc": {
+                    "deleted": false,
+                    "restoration": restoration
                 }
+            }))
+            .send().await;
+
+        check(response, not_found)?;
+        Ok(())
+    }
+
+    /**
+     * Deleted posts or comments, oldest deletions first. Documents from before deletions were recorded
+     * have no deletion time, their last update counts instead.
+     */
+    async fn find_deleted(&self, index: Index, deleted_before: i64, limit: u32) -> Result<Vec<String>, AppError> {
+
+        let (index, _) = self.content_index(&index);
+
+        let before = json!({ "lt": deleted_before, "format": "epoch_millis" });
+
+        let query = json!({
+            "bool": {
+                "must": { "match": { "deleted": true } },
+                "must_not": { "match": { "purged": true } },
+                "should": [
+                    { "range": { "deletion.deleted_at": before } },
+                    {
+                        "bool": {
+                            "must_not": { "exists": { "field": "deletion.deleted_at" } },
+                            "must": { "range": { "updated_at": before } },
+                        }
+                    },
+                ],
+                "minimum_should_match": 1,
             }
-        }))
-        .send().await;
-    
-    match response {
-        Ok(response) => {
-
-            let body = response.json::<serde_json::Value>().await.unwrap();
-            let posts: Vec<Post> = body.get("hits").unwrap().get("hits").unwrap().as_array().unwrap().iter().map(|hit| {
-                Post::from_json(hit).sanitize(&false)
-            }).collect();
+        });
+
+        let sort = json!([
+            { "deletion.deleted_at": { "order": "asc", "missing": "_first", "unmapped_type": "date" } },
+            { "updated_at": "asc" },
+        ]);
+
+        self.search_ids(index, query, sort, limit).await
+    }
+
+    async fn find_comment_ids_by_post_ids(&self, post_ids: Vec<String>) -> Result<Vec<String>, AppError> {
+        if post_ids.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let query = json!({
+            "terms": {
+                "post_id.keyword": post_ids
+            }
+        });
+
+        // A post can have more comments than a single search returns, every one of them has to go
+        let hits = self.search_all::<IgnoredAny>(&[self.indices.comment.as_str()], query, json!([{ "created_at": "asc" }])).await?;
+
+        Ok(hits.into_iter().map(|hit| hit.id).collect())
+    }
+
+    async fn find_replied_comment_ids(&self, comment_ids: Vec<String>) -> Result<Vec<String>, AppError> {
+        if comment_ids.is_empty() {
+            return Ok(vec![]);
+        }
 
-            Ok(posts)
-        },
-        Err(_) => Err("Internal server error")
-    }
-}
-
-pub async fn get_comments_by_post_id(post_id: String, show_all: &bool) -> Vec<Comment> {
-    
-    let client = client();
-
-    let response = client
-        .search(SearchParts::Index(&[COMMENT_INDEX]))
-        .body(json!({
-            "size": 10000,
-            "sort": [
-                { "published": "desc"},
-                { "upvotes": "desc" },
-                { "downvotes": "asc" },
-                { "created_at": "asc" },
-            ],
-            "query": {
-                "match": {
-                    "post_id": post_id
+        let client = &self.client;
+
+        // One bucket per comment with replies, no matter how many replies each of them has
+        let response = client
+            .search(SearchParts::Index(&[self.indices.comment.as_str()]))
+            .body(json!({
+                "size": 0,
+                "query": {
+                    "terms": {
+                        "parent_id.keyword": comment_ids
+                    }
+                },
+                "aggs": {
+                    "parents": {
+                        "terms": {
+                            "field": "parent_id.keyword",
+                            "size": comment_ids.len(),
+                        }
+                    }
                 }
+            }))
+            .send().await;
+
+        let body = match read::<RepliedResponse>(response, "Index not found").await {
+            Ok(body) => body,
+            Err(AppError::NotFound(_)) => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+
+        Ok(body.aggregations.parents.buckets.into_iter().map(|bucket| bucket.key).collect())
+    }
+
+    async fn purge(&self, index: Index, ids: Vec<String>) -> Result<(), AppError> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+
+        // Revisions first, so an interrupted purge leaves none without their post or comment
+        self.delete_by_query(&[self.revision_index(&index)], json!({ "terms": { "target_id.keyword": ids } })).await?;
+
+        let (index, _) = self.content_index(&index);
+        self.delete_by_query(&[index], json!({ "ids": { "values": ids } })).await
+    }
+
+    async fn scrub_comments(&self, comment_ids: Vec<String>) -> Result<(), AppError> {
+        if comment_ids.is_empty() {
+            return Ok(());
+        }
+
+        let client = &self.client;
+
+        let response = client
+            .update_by_query(UpdateByQueryParts::Index(&[self.indices.comment.as_str()]))
+            .body(json!({
+                "query": {
+                    "ids": { "values": comment_ids }
+                },
+                "script": {
+                    "lang": "painless",
+                    "source": "ctx._source.body = ''; ctx._source.author_id = ''; ctx._source.author_name = params.author_name; ctx._source.purged = true; if (ctx._source.deletion != null && ctx._source.deletion.kind == 'author') { ctx._source.deletion.deleted_by = null; }",
+                    "params": {
+                        "author_name": DELETED_AUTHOR,
+                    }
+                }
+            }))
+            .refresh(true)
+            .send().await;
+
+        check(response, "Comment not found")?;
+
+        self.delete_by_query(&[self.indices.comment_revision.as_str()], json!({ "terms": { "target_id.keyword": comment_ids } })).await
+    }
+
+    /**
+     * Atomically adjust the vote counters of a post or comment.
+     * The increment runs as a script on the Elasticsearch node, and version conflicts
+     * are retried there, so concurrent votes are never lost.
+     * @return The new (upvotes, downvotes)
+     */
+    async fn update_votes(&self, index: Index, id: String, upvotes: i64, downvotes: i64) -> Result<(u32, u32), AppError> {
+
+        let client = &self.client;
+
+        let (index, not_found) = self.content_index(&index);
+
+        let response = client
+            .update(UpdateParts::IndexId(index, &id))
+            .body(json!({
+                "script": {
+                    "lang": "painless",
+                    "source": "ctx._source.upvotes = Math.max(0, ctx._source.upvotes + params.upvotes); ctx._source.downvotes = Math.max(0, ctx._source.downvotes + params.downvotes);",
+                    "params": {
+                        "upvotes": upvotes,
+                        "downvotes": downvotes,
+                    }
+                }
+            }))
+            .retry_on_conflict(10)
+            ._source(&["upvotes", "downvotes"])
+            .send().await;
+
+        let votes = read::<UpdateResponse<Votes>>(response, not_found).await?.get.source;
+        Ok((votes.upvotes, votes.downvotes))
+    }
+
+    /**
+     * Sum the votes per author with a composite aggregation, a page of authors at a time
+     */
+    async fn sum_karma(&self, index: Index) -> Result<Vec<(String, i64)>, AppError> {
+
+        let client = &self.client;
+
+        let (index, _) = self.content_index(&index);
+
+        let mut karma = vec![];
+        let mut after: Option<Value> = None;
+        loop {
+            let mut authors = json!({
+                "size": KARMA_PAGE_SIZE,
+                "sources": [{ "author_id": { "terms": { "field": "author_id.keyword" } } }],
+            });
+            if let Some(after) = after {
+                authors["after"] = after;
             }
-        }))
-        .send().await;
-    
-    match response {
-        Ok(response) => {
-
-            let body = response.json::<serde_json::Value>().await.unwrap();
-            let comments: Vec<Comment> = body.get("hits").unwrap().get("hits").unwrap().as_array().unwrap().iter().map(|hit| {
-                Comment::from_json(hit).sanitize(show_all)
+
+            let response = client
+                .search(SearchParts::Index(&[index]))
+                .body(json!({
+                    "size": 0,
+                    "aggs": {
+                        "authors": {
+                            "composite": authors,
+                            "aggs": {
+                                "upvotes": { "sum": { "field": "upvotes" } },
+                                "downvotes": { "sum": { "field": "downvotes" } },
+                            }
+                        }
+                    }
+                }))
+                .send().await;
+
+            // Nobody has karma until the index is created with the first post or comment
+            let authors = match read::<KarmaResponse>(response, "Index not found").await {
+                Ok(body) => body.aggregations.authors,
+                Err(AppError::NotFound(_)) => break,
+                Err(e) => return Err(e),
+            };
+
+            // Scrubbed comments have no author any more
+            karma.extend(authors.buckets.into_iter()
+                .filter(|bucket| !bucket.key.author_id.is_empty())
+                .map(|bucket| (bucket.key.author_id, (bucket.upvotes.value - bucket.downvotes.value) as i64)));
+
+            after = match authors.after_key {
+                Some(after_key) => Some(after_key),
+                None => break,
+            };
+        }
+
+        Ok(karma)
+    }
+
+    async fn index_comment(&self, comment: Comment) -> Result<Comment, AppError> {
+
+        let client = &self.client;
+
+        let response = client
+            .index(IndexParts::IndexId(self.indices.comment.as_str(), ""))
+            .body(json!(comment))
+            .refresh(Refresh::True)
+            .send().await;
+
+        let body = read::<IndexResponse>(response, "Index not found").await?;
+
+        Ok(Comment {
+            id: Some(body.id),
+            ..comment
+        })
+    }
+
+    async fn rename_author(&self, author_id: String, author_name: String) -> Result<(), AppError> {
+        self.update_by_author(author_id, json!({
+            "lang": "painless",
+            "source": "ctx._source.author_name = params.author_name;",
+            "params": {
+                "author_name": author_name,
+            }
+        })).await
+    }
+
+    async fn remove_author(&self, author_id: String, remove_content: bool) -> Result<(), AppError> {
+        self.update_by_author(author_id, json!({
+            "lang": "painless",
+            "source": "ctx._source.author_id = ''; ctx._source.author_name = params.author_name; if (params.remove_content && !ctx._source.deleted) { ctx._source.deleted = true; ctx._source.deletion = params.deletion; ctx._source.restoration = null; }",
+            "params": {
+                "author_name": DELETED_AUTHOR,
+                "remove_content": remove_content,
+                "deletion": Deletion::new(DeletionKind::Author, None, None),
+            }
+        })).await
+    }
+
+    async fn flush_data(&self) -> Result<(), AppError> {
+        let client = &self.client;
+
+        let indices: [&[&str]; 4] = [
+            &[self.indices.post.as_str()],
+            &[self.indices.category.as_str()],
+            &[self.indices.post_revision.as_str(), self.indices.comment_revision.as_str()],
+            &[self.indices.comment.as_str()],
+        ];
+
+        // Indices that don't exist yet are fine, only a failed request stops the flush
+        for indices in indices {
+            client
+                .delete_by_query(DeleteByQueryParts::Index(indices))
+                .body(json!({
+                    "query": {
+                        "match_all": {}
+                    }
+                }))
+                .send().await?;
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Get a post the way it is stored, without rendering or sanitizing the body
+     */
+    async fn get_post_source_by_id(&self, id: String) -> Result<Post, AppError> {
+
+        let client = &self.client;
+
+        let response = client
+            .get(GetParts::IndexId(self.indices.post.as_str(), &id))
+            .send().await;
+
+        let hit = read::<Hit<Post>>(response, "Post not found").await?;
+        Ok(Post::from(hit))
+    }
+
+    /**
+     * Get a comment the way it is stored, without rendering or sanitizing the body
+     */
+    async fn get_comment_source_by_id(&self, comment_id: String) -> Result<Comment, AppError> {
+
+        let client = &self.client;
+
+        let response = client
+            .get(GetParts::IndexId(self.indices.comment.as_str(), &comment_id))
+            .send().await;
+
+        let hit = read::<Hit<Comment>>(response, "Comment not found").await?;
+        Ok(Comment::from(hit))
+    }
+
+    async fn get_post_sources_by_author_id(&self, author_id: String, limit: u32, cursor: &Option<String>) -> Result<Page<Post>, AppError> {
+
+        let (cursor, now) = ElasticStore::start_listing(cursor)?;
+
+        let query = json!({
+            "term": {
+                "author_id.keyword": author_id
+            }
+        });
+
+        let (hits, next_cursor) = self.search_page::<Post>(&[self.indices.post.as_str()], query, json!([{ "created_at": "asc" }]), limit, cursor, now).await?;
+
+        Ok(Page { items: hits.into_iter().map(Post::from).collect(), next_cursor })
+    }
+
+    async fn get_comment_sources_by_author_id(&self, author_id: String, limit: u32, cursor: &Option<String>) -> Result<Page<Comment>, AppError> {
+
+        let (cursor, now) = ElasticStore::start_listing(cursor)?;
+
+        let query = json!({
+            "term": {
+                "author_id.keyword": author_id
+            }
+        });
+
+        let (hits, next_cursor) = self.search_page::<Comment>(&[self.indices.comment.as_str()], query, json!([{ "created_at": "asc" }]), limit, cursor, now).await?;
+
+        Ok(Page { items: hits.into_iter().map(Comment::from).collect(), next_cursor })
+    }
+
+
+    // NOTE:########################################################//
+    // NOTE:# Sanitize text body in all functions below this point #//
+    // NOTE:########################################################//
+
+    async fn get_posts_by_category_id(&self, category_id: String, show_all: &bool, ranking: &Ranking, limit: u32, cursor: &Option<String>) -> Result<Page<Post>, AppError> {
+
+        let (cursor, now) = ElasticStore::start_listing(cursor)?;
+
+        // If show_all is true, we don't need to match by deleted
+        let mut must = if *show_all {
+            vec![
+                json!({ "match": { "category_id": category_id } }),
+            ]
+        } else {
+            vec![
+                json!({ "match": { "category_id": category_id } }),
+                json!({ "match": { "published": true } }),
+                json!({ "match": { "deleted": false } }),
+            ]
+        };
+
+        if let Some(filter) = ranking.filter(now) {
+            must.push(filter);
+        }
+
+        let query = json!({
+            // match by category_id and published
+            "bool": {
+                "must": must
+            }
+        });
+
+        let (hits, next_cursor) = self.search_page::<Post>(&[self.indices.post.as_str()], query, ranking.sort(now), limit, cursor, now).await?;
+
+        let posts: Vec<Post> = hits.into_iter().map(|hit| {
+            Post::from(hit).sanitize(show_all)
+        }).collect();
+
+        Ok(Page { items: posts, next_cursor })
+    }
+
+    async fn get_posts(&self, show_all: &bool, ranking: &Ranking, limit: u32, cursor: &Option<String>) -> Result<Page<Post>, AppError> {
+
+            let (cursor, now) = ElasticStore::start_listing(cursor)?;
+
+            let mut must = if *show_all {
+                vec![]
+            } else {
+                vec![
+                    json!({ "match": { "deleted": false } }),
+                    json!({ "match": { "published": true } }),
+                ]
+            };
+
+            if let Some(filter) = ranking.filter(now) {
+                must.push(filter);
+            }
+
+            // A bool query without clauses matches everything
+            let query = json!({
+                "bool": {
+                    "must": must
+                }
+            });
+
+            let (hits, next_cursor) = self.search_page::<Post>(&[self.indices.post.as_str()], query, ranking.sort(now), limit, cursor, now).await?;
+
+            let posts: Vec<Post> = hits.into_iter().map(|hit| {
+                Post::from(hit).sanitize(show_all)
             }).collect();
 
-            comments
-        },
-        _ => vec![]
+            Ok(Page { items: posts, next_cursor })
     }
-}
 
-pub async fn get_comment_by_id(comment_id: String, show_all: &bool) -> Result<Comment, &'static str> {
-    
-        let client = client();
-    
+    async fn get_post_by_id(&self, id: String, show_all: &bool) -> Result<Post, AppError> {
+
+        let client = &self.client;
+
         let response = client
-            .get(GetParts::IndexId(COMMENT_INDEX, &comment_id))
+            .get(GetParts::IndexId(self.indices.post.as_str(), &id))
             .send().await;
-        
-        match response {
-            Ok(response) => {
-                let source = response.json::<serde_json::Value>().await.unwrap();
-                Ok(Comment::from_json(&source).sanitize(show_all))
-            },
-            Err(_) => Err("Not Found")
+
+        let hit = read::<Hit<Post>>(response, "Post not found").await?;
+        Ok(Post::from(hit).sanitize(show_all))
+    }
+
+    async fn get_posts_by_user_id(&self, user_id: String, limit: u32, cursor: &Option<String>) -> Result<Page<Post>, AppError> {
+
+        let (cursor, now) = ElasticStore::start_listing(cursor)?;
+
+        let query = json!({
+            "match": {
+                "author_id": user_id
+            }
+        });
+
+        let (hits, next_cursor) = self.search_page::<Post>(&[self.indices.post.as_str()], query, json!([{ "created_at": "desc" }]), limit, cursor, now).await?;
+
+        let posts: Vec<Post> = hits.into_iter().map(|hit| {
+            Post::from(hit).sanitize(&false)
+        }).collect();
+
+        Ok(Page { items: posts, next_cursor })
+    }
+
+    async fn get_published_posts_by_author_id(&self, author_id: String, limit: u32, cursor: &Option<String>) -> Result<Page<Post>, AppError> {
+
+        let (cursor, now) = ElasticStore::start_listing(cursor)?;
+
+        let query = json!({
+            "bool": {
+                "must": [
+                    { "term": { "author_id.keyword": author_id } },
+                    { "match": { "published": true } },
+                    { "match": { "deleted": false } },
+                ]
+            }
+        });
+
+        let (hits, next_cursor) = self.search_page::<Post>(&[self.indices.post.as_str()], query, json!([{ "created_at": "desc" }]), limit, cursor, now).await?;
+
+        let posts: Vec<Post> = hits.into_iter().map(|hit| {
+            Post::from(hit).sanitize(&false)
+        }).collect();
+
+        Ok(Page { items: posts, next_cursor })
+    }
+
+    /**
+     * Comments don't know if their post is still visible, so the comments of each page are checked against
+     * their posts. Pages are fetched until one has visible comments, up to `AUTHOR_COMMENT_ROUNDS` of them,
+     * so a page can have fewer comments than asked for.
+     */
+    async fn get_comments_by_author_id(&self, author_id: String, limit: u32, cursor: &Option<String>) -> Result<Page<Comment>, AppError> {
+
+        let query = json!({
+            "bool": {
+                "must": [
+                    { "term": { "author_id.keyword": author_id } },
+                    { "match": { "deleted": false } },
+                ]
+            }
+        });
+
+        let mut comments = vec![];
+        let mut next_cursor = cursor.clone();
+        for _ in 0..AUTHOR_COMMENT_ROUNDS {
+            let (cursor, now) = ElasticStore::start_listing(&next_cursor)?;
+
+            let (hits, cursor) = self.search_page::<Comment>(&[self.indices.comment.as_str()], query.clone(), json!([{ "created_at": "desc" }]), limit, cursor, now).await?;
+            next_cursor = cursor;
+
+            let post_ids: Vec<String> = hits.iter().map(|hit| hit.source.post_id.clone()).collect();
+            let visible = self.search_ids(self.indices.post.as_str(), json!({
+                "bool": {
+                    "must": [
+                        { "ids": { "values": post_ids } },
+                        { "match": { "published": true } },
+                        { "match": { "deleted": false } },
+                    ]
+                }
+            }), json!([]), limit).await?;
+
+            comments.extend(hits.into_iter()
+                .filter(|hit| visible.contains(&hit.source.post_id))
+                .map(|hit| Comment::from(hit).sanitize(&false)));
+
+            if !comments.is_empty() || next_cursor.is_none() {
+                break;
+            }
+        }
+
+        Ok(Page { items: comments, next_cursor })
+    }
+
+    /**
+     * Get a page of top level comments of a post together with all of their replies.
+     * Only the top level comments are paginated, so a thread is never split across pages.
+     * The time window of the ranking is ignored, since it only makes sense for feeds.
+     */
+    async fn get_comments_by_post_id(&self, post_id: String, show_all: &bool, ranking: &Ranking, limit: u32, cursor: &Option<String>) -> Result<Page<Comment>, AppError> {
+
+        let (cursor, now) = ElasticStore::start_listing(cursor)?;
+
+        let query = json!({
+            "bool": {
+                "must": { "match": { "post_id": post_id } },
+                "must_not": { "exists": { "field": "parent_id" } },
+            }
+        });
+
+        let (hits, next_cursor) = self.search_page::<Comment>(&[self.indices.comment.as_str()], query, ranking.sort(now), limit, cursor, now).await?;
+
+        let mut comments: Vec<Comment> = hits.into_iter().map(|hit| {
+            Comment::from(hit).sanitize(show_all)
+        }).collect();
+
+        let root_ids: Vec<String> = comments.iter().filter_map(|comment| comment.id.clone()).collect();
+        if root_ids.is_empty() {
+            return Ok(Page { items: comments, next_cursor });
         }
-}
\ No newline at end of file
+
+        // Replies are ranked per level once the tree is built. They are read to the end,
+        // a busy thread has more of them than a single search returns.
+        let replies = self.search_all::<Comment>(
+            &[self.indices.comment.as_str()],
+            json!({ "terms": { "root_id.keyword": root_ids } }),
+            json!([{ "created_at": "asc" }]),
+        ).await?;
+
+        comments.extend(replies.into_iter().map(|hit| {
+            Comment::from(hit).sanitize(show_all)
+        }));
+
+        Ok(Page { items: comments, next_cursor })
+    }
+
+    async fn get_comment_by_id(&self, comment_id: String, show_all: &bool) -> Result<Comment, AppError> {
+
+            let client = &self.client;
+
+            let response = client
+                .get(GetParts::IndexId(self.indices.comment.as_str(), &comment_id))
+                .send().await;
+
+            let hit = read::<Hit<Comment>>(response, "Comment not found").await?;
+            Ok(Comment::from(hit).sanitize(show_all))
+    }
+}
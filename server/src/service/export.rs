@@ -0,0 +1,323 @@
+use std::fs::{self, File};
+use std::io::Write;
+use std::sync::Arc;
+
+use actix_web::web::block;
+use serde::Serialize;
+use serde_json::json;
+use zip::{CompressionMethod, ZipWriter};
+use zip::write::FileOptions;
+
+use crate::DbPool;
+use crate::config::StorageConfig;
+use crate::error::AppError;
+use crate::model::data::{Comment, Export, ExportStatus, Post, PostType, User, Vote};
+use crate::service::database;
+use crate::service::store::ContentStore;
+use crate::utils::sanitize::hide_moderators;
+
+/// How long a finished export can be downloaded
+const LIFETIME_DAYS: u32 = 7;
+
+/// How many posts or comments are fetched at a time while collecting an export
+const PAGE_SIZE: u32 = 100;
+
+/// Everything that goes into an export
+struct Archive {
+    user: User,
+    votes: Vec<Vote>,
+    posts: Vec<Post>,
+    comments: Vec<Comment>,
+    /// Path of the avatar file, if the user has one
+    avatar: Option<String>,
+}
+
+/**
+ * The latest export of a user. A new one is started in the background when there is none, the latest one
+ * expired, or `fresh` is asked for. Only the latest export of a user is kept.
+ * @param fresh Start a new export even if there is a ready or failed one, a pending one is never replaced
+ */
+pub async fn request(store: Arc<dyn ContentStore>, pool: DbPool, storage: StorageConfig, user_id: String, fresh: bool) -> Result<Export, AppError> {
+    let exports = database::find_exports_by_user_id(pool.clone(), user_id.clone()).await?;
+
+    let current = exports.first()
+        .filter(|latest| latest.is(ExportStatus::Pending) || (!fresh && !latest.is(ExportStatus::Expired)));
+    if let Some(current) = current {
+        return Ok(current.clone());
+    }
+
+    for export in exports {
+        remove(pool.clone(), &storage, export.id).await?;
+    }
+
+    let export_id = database::save_export(pool.clone(), user_id.clone()).await?;
+    let export = database::find_export(pool.clone(), export_id.clone()).await?;
+
+    actix_web::rt::spawn(async move {
+        // The cause is only logged, like the message of an internal error
+        let error = match build(store.as_ref(), pool.clone(), &storage, &export_id, user_id).await {
+            Ok(_) => None,
+            Err(e) => {
+                eprintln!("Export {} failed: {}", export_id, e);
+                Some("The export failed, please request a new one".to_string())
+            },
+        };
+
+        if let Err(e) = database::finish_export(pool, export_id.clone(), error, LIFETIME_DAYS).await {
+            eprintln!("Failed to finish export {}: {}", export_id, e);
+        }
+    });
+
+    Ok(export)
+}
+
+/**
+ * Find the archive of a ready export of the user
+ * @return The path of the archive
+ */
+pub async fn find_archive(pool: DbPool, storage: &StorageConfig, user_id: &str, export_id: String) -> Result<String, AppError> {
+    let export = database::find_export(pool, export_id).await?;
+
+    // Someone else's export doesn't exist as far as the user is concerned
+    if export.user_id.ne(user_id) {
+        return Err(AppError::NotFound("Export not found".to_string()));
+    }
+
+    if !export.is(ExportStatus::Ready) {
+        return Err(AppError::Conflict(format!("The export is {}", export.status)));
+    }
+
+    Ok(archive_path(storage, &export.id))
+}
+
+/**
+ * Remove every export of a user along with the archives
+ */
+pub async fn remove_all(pool: DbPool, storage: &StorageConfig, user_id: String) -> Result<(), AppError> {
+    for export in database::find_exports_by_user_id(pool.clone(), user_id).await? {
+        remove(pool.clone(), storage, export.id).await?;
+    }
+
+    Ok(())
+}
+
+async fn remove(pool: DbPool, storage: &StorageConfig, export_id: String) -> Result<(), AppError> {
+    // The archive may never have been written
+    let _ = fs::remove_file(archive_path(storage, &export_id));
+
+    database::delete_export(pool, export_id).await
+}
+
+fn archive_path(storage: &StorageConfig, export_id: &str) -> String {
+    format!("{}/{}.zip", storage.export_dir(), export_id)
+}
+
+/**
+ * Collect everything of a user and write the archive
+ */
+async fn build(store: &dyn ContentStore, pool: DbPool, storage: &StorageConfig, export_id: &str, user_id: String) -> Result<(), AppError> {
+    let user = database::find_user_by_id(pool.clone(), user_id.clone()).await?;
+    let votes = database::find_votes_by_user_id(pool, user_id.clone()).await?;
+
+    let mut posts = vec![];
+    let mut cursor = None;
+    loop {
+        let page = store.get_post_sources_by_author_id(user_id.clone(), PAGE_SIZE, &cursor).await?;
+        posts.extend(page.items);
+        cursor = match page.next_cursor {
+            Some(next_cursor) => Some(next_cursor),
+            None => break,
+        };
+    }
+
+    let mut comments = vec![];
+    let mut cursor = None;
+    loop {
+        let page = store.get_comment_sources_by_author_id(user_id.clone(), PAGE_SIZE, &cursor).await?;
+        comments.extend(page.items);
+        cursor = match page.next_cursor {
+            Some(next_cursor) => Some(next_cursor),
+            None => break,
+        };
+    }
+
+    // Who removed or restored something is only for moderators
+    for post in posts.iter_mut() {
+        hide_moderators(&mut post.deletion, &mut post.restoration);
+    }
+    for comment in comments.iter_mut() {
+        hide_moderators(&mut comment.deletion, &mut comment.restoration);
+    }
+
+    let avatar = user.avatar_url.as_ref()
+        .and_then(|avatar_url| avatar_url.split('/').next_back())
+        .map(|file_name| format!("{}/{}", storage.avatar_dir(), file_name));
+
+    let archive = Archive { user, votes, posts, comments, avatar };
+    let path = archive_path(storage, export_id);
+
+    block(move || write_archive(&path, &archive)).await?
+}
+
+/**
+ * Write the archive: everything as JSON, and every post and comment as a Markdown file as well.
+ * It is written under another name first, so a download never gets half an archive.
+ */
+fn write_archive(path: &str, archive: &Archive) -> Result<(), AppError> {
+    let partial = format!("{}.partial", path);
+
+    let mut zip = ZipWriter::new(File::create(&partial)?);
+    let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+
+    // The password hash stays out, it's of no use to anyone but an attacker
+    let profile = json!({
+        "id": archive.user.id,
+        "username": archive.user.username,
+        "avatar_url": archive.user.avatar_url,
+        "role": archive.user.role,
+        "created_at": archive.user.created_at,
+    });
+    write_json(&mut zip, options, "profile.json", &profile)?;
+    write_json(&mut zip, options, "votes.json", &archive.votes)?;
+    write_json(&mut zip, options, "posts.json", &archive.posts)?;
+    write_json(&mut zip, options, "comments.json", &archive.comments)?;
+
+    for post in &archive.posts {
+        zip.start_file(format!("posts/{}.md", file_name(&post.created_at, &post.id)), options)?;
+        zip.write_all(post_markdown(post).as_bytes())?;
+    }
+    for comment in &archive.comments {
+        zip.start_file(format!("comments/{}.md", file_name(&comment.created_at, &comment.id)), options)?;
+        zip.write_all(comment_markdown(comment).as_bytes())?;
+    }
+
+    // An avatar that is gone from disk is left out
+    if let Some(avatar) = &archive.avatar {
+        if let Ok(image) = fs::read(avatar) {
+            let name = avatar.split('/').next_back().unwrap_or_default();
+            zip.start_file(format!("avatar/{}", name), options)?;
+            zip.write_all(&image)?;
+        }
+    }
+
+    zip.finish()?;
+    fs::rename(partial, path)?;
+
+    Ok(())
+}
+
+fn write_json<T: Serialize + ?Sized>(zip: &mut ZipWriter<File>, options: FileOptions, name: &str, value: &T) -> Result<(), AppError> {
+    zip.start_file(name, options)?;
+    zip.write_all(serde_json::to_string_pretty(value)?.as_bytes())?;
+
+    Ok(())
+}
+
+/// Files are named by date, so they sort in the order they were written
+fn file_name(created_at: &str, id: &Option<String>) -> String {
+    let date = created_at.get(..10).unwrap_or(created_at);
+
+    format!("{}-{}", date, id.as_deref().unwrap_or_default())
+}
+
+fn post_markdown(post: &Post) -> String {
+    let post_type = match post.post_type {
+        PostType::Text => "text",
+        PostType::Link => "link",
+    };
+    let status = match (post.deleted, post.published) {
+        (true, _) => "deleted",
+        (false, false) => "draft",
+        (false, true) => "published",
+    };
+
+    format!(
+        "# {}\n\n- Category: {}\n- Type: {}\n- Status: {}\n- Created: {}\n- Updated: {}\n- Votes: +{} / -{}\n\n{}\n",
+        post.title, post.category_name, post_type, status, post.created_at, post.updated_at, post.upvotes, post.downvotes, post.body
+    )
+}
+
+fn comment_markdown(comment: &Comment) -> String {
+    let mut markdown = format!("# Comment on post {}\n\n", comment.post_id);
+
+    if let Some(parent_id) = &comment.parent_id {
+        markdown.push_str(&format!("- In reply to: {}\n", parent_id));
+    }
+    if comment.deleted {
+        markdown.push_str("- Status: deleted\n");
+    }
+    markdown.push_str(&format!(
+        "- Created: {}\n- Edited: {}\n- Votes: +{} / -{}\n\n{}\n",
+        comment.created_at, comment.edited_at.as_deref().unwrap_or("never"), comment.upvotes, comment.downvotes, comment.body
+    ));
+
+    markdown
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use serde_json::Value;
+    use zip::ZipArchive;
+
+    use super::*;
+    use crate::model::data::{Deletion, DeletionKind};
+    use crate::service::store::Index;
+    use crate::test_utils::TestApp;
+
+    fn read_entry(zip: &mut ZipArchive<File>, name: &str) -> String {
+        let mut entry = String::new();
+        zip.by_name(name).unwrap_or_else(|_| panic!("No {} in the archive", name)).read_to_string(&mut entry).unwrap();
+        entry
+    }
+
+    #[actix_web::test]
+    async fn archive_holds_everything_of_the_user_and_nothing_else() {
+        let t = TestApp::new();
+        let alice = t.user("alice", "user").await;
+        let bob = t.user("bob", "user").await;
+        let category = t.category(&bob, "rust").await;
+        let kept = t.post(&alice, &category, "Kept").await;
+        let removed = t.post(&alice, &category, "Removed").await;
+        let others = t.post(&bob, &category, "Not Alice's").await;
+        let comment = t.comment(&alice, &others, None, "Alice's comment").await;
+        t.comment(&bob, &kept, None, "Bob's comment").await;
+        database::set_vote(t.pool.clone(), alice.user.id.clone(), others.clone(), Index::Post.as_str(), 1).await.unwrap();
+        t.store.delete_post(Index::Post, removed.clone(), Deletion::new(DeletionKind::Moderator, Some(bob.user.id.clone()), Some("Spam".to_string()))).await.unwrap();
+
+        fs::create_dir_all(t.config.storage.export_dir()).unwrap();
+        let export_id = database::save_export(t.pool.clone(), alice.user.id.clone()).await.unwrap();
+        build(t.store.as_ref(), t.pool.clone(), &t.config.storage, &export_id, alice.user.id.clone()).await.unwrap();
+
+        let mut zip = ZipArchive::new(File::open(archive_path(&t.config.storage, &export_id)).unwrap()).unwrap();
+
+        let profile: Value = serde_json::from_str(&read_entry(&mut zip, "profile.json")).unwrap();
+        assert_eq!(profile["username"], "alice");
+        assert!(profile.get("password").is_none());
+
+        let votes: Value = serde_json::from_str(&read_entry(&mut zip, "votes.json")).unwrap();
+        assert_eq!(votes.as_array().unwrap().len(), 1);
+
+        // Deleted posts are in there too, without the moderator who removed them
+        let posts: Vec<Post> = serde_json::from_str(&read_entry(&mut zip, "posts.json")).unwrap();
+        let mut post_ids: Vec<_> = posts.iter().filter_map(|post| post.id.clone()).collect();
+        post_ids.sort();
+        let mut expected = vec![kept.clone(), removed.clone()];
+        expected.sort();
+        assert_eq!(post_ids, expected);
+        let deletion = posts.iter().find(|post| post.id.as_ref() == Some(&removed)).and_then(|post| post.deletion.clone()).unwrap();
+        assert_eq!(deletion.reason.as_deref(), Some("Spam"));
+        assert!(deletion.deleted_by.is_none());
+
+        let comments: Vec<Comment> = serde_json::from_str(&read_entry(&mut zip, "comments.json")).unwrap();
+        assert_eq!(comments.len(), 1);
+        assert_eq!(comments[0].id.as_ref(), Some(&comment));
+
+        let markdown: Vec<String> = zip.file_names().filter(|name| name.ends_with(".md")).map(|name| name.to_string()).collect();
+        assert_eq!(markdown.len(), 3);
+        assert!(markdown.iter().any(|name| name.starts_with("comments/") && name.ends_with(&format!("{}.md", comment))));
+        let name = markdown.iter().find(|name| name.ends_with(&format!("{}.md", removed))).unwrap().clone();
+        assert!(read_entry(&mut zip, &name).contains("- Status: deleted"));
+    }
+}
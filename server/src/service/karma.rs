@@ -0,0 +1,76 @@
+use std::collections::HashMap;
+
+use crate::DbPool;
+use crate::error::AppError;
+use crate::service::database;
+use crate::service::store::{ContentStore, Index};
+
+/**
+ * Count the karma of every user again from the votes on their posts and comments in the content store,
+ * for when the incrementally kept karma has drifted. Votes cast while it runs may be lost, run it again if needed.
+ * @return How many users have karma
+ */
+pub async fn recompute(store: &dyn ContentStore, pool: DbPool) -> Result<usize, AppError> {
+    let mut karma: HashMap<String, (i64, i64)> = HashMap::new();
+
+    for (author_id, post_karma) in store.sum_karma(Index::Post).await? {
+        karma.entry(author_id).or_default().0 += post_karma;
+    }
+    for (author_id, comment_karma) in store.sum_karma(Index::Comment).await? {
+        karma.entry(author_id).or_default().1 += comment_karma;
+    }
+
+    let karma = karma.into_iter()
+        .map(|(author_id, (post_karma, comment_karma))| (author_id, post_karma, comment_karma))
+        .collect();
+
+    database::replace_karma(pool, karma).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::vote::cast_vote;
+    use crate::test_utils::{TestApp, TestUser};
+
+    async fn karma(t: &TestApp, user: &TestUser) -> (i64, i64) {
+        let user = t.find_user(&user.user.id).await;
+        (user.post_karma, user.comment_karma)
+    }
+
+    #[actix_web::test]
+    async fn recomputed_karma_matches_the_incremental_one() {
+        let t = TestApp::new();
+        let alice = t.user("alice", "user").await;
+        let bob = t.user("bob", "user").await;
+        let carol = t.user("carol", "user").await;
+        let category = t.category(&alice, "rust").await;
+        let post = t.post(&alice, &category, "Post").await;
+        let comment = t.comment(&bob, &post, None, "Comment").await;
+
+        let votes = [
+            (&bob, &alice, Index::Post, &post, 1),
+            (&carol, &alice, Index::Post, &post, -1),
+            (&carol, &alice, Index::Post, &post, 1),
+            (&alice, &bob, Index::Comment, &comment, 1),
+            (&carol, &bob, Index::Comment, &comment, -1),
+            (&carol, &bob, Index::Comment, &comment, 0),
+        ];
+        for (voter, author, index, id, value) in votes {
+            cast_vote(t.store.as_ref(), t.pool.clone(), voter.user.id.clone(), author.user.id.clone(), index, id.clone(), value).await.unwrap();
+        }
+
+        assert_eq!(karma(&t, &alice).await, (2, 0));
+        assert_eq!(karma(&t, &bob).await, (0, 1));
+
+        // Drift that recomputing has to undo, including karma of someone nobody voted for
+        database::add_karma(t.pool.clone(), alice.user.id.clone(), 5, 5).await.unwrap();
+        database::add_karma(t.pool.clone(), carol.user.id.clone(), -3, 0).await.unwrap();
+
+        assert_eq!(recompute(t.store.as_ref(), t.pool.clone()).await.unwrap(), 2);
+
+        assert_eq!(karma(&t, &alice).await, (2, 0));
+        assert_eq!(karma(&t, &bob).await, (0, 1));
+        assert_eq!(karma(&t, &carol).await, (0, 0));
+    }
+}
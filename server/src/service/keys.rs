@@ -0,0 +1,199 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use jsonwebtoken::jwk::{AlgorithmParameters, CommonParameters, Jwk, JwkSet, PublicKeyUse, RSAKeyParameters, RSAKeyType};
+use rsa::{RsaPrivateKey, RsaPublicKey};
+use rsa::pkcs8::{DecodePublicKey, EncodePrivateKey, EncodePublicKey, LineEnding};
+use rsa::traits::PublicKeyParts;
+
+use crate::config::KeyConfig;
+use crate::error::AppError;
+use crate::model::api::Claims;
+
+/// A key pair is stored as `<kid>.key.pem` (PKCS#8) and `<kid>.pub.pem` (SPKI)
+const PRIVATE_KEY_SUFFIX: &str = ".key.pem";
+const PUBLIC_KEY_SUFFIX: &str = ".pub.pem";
+
+const KEY_BITS: usize = 2048;
+
+/// A public key that tokens are verified with
+struct VerificationKey {
+    kid: String,
+    key: DecodingKey,
+    pem: String,
+}
+
+/**
+ * The keys tokens are signed and verified with, loaded from the keystore directory at startup.
+ * Every public key in the directory is accepted, so tokens signed with an older key stay valid
+ * until its files are removed. New tokens are signed with the newest key that has a private half,
+ * unless `keys.signing_kid` picks another one.
+ */
+pub struct KeyStore {
+    signing_kid: String,
+    signing_key: EncodingKey,
+    verification_keys: Vec<VerificationKey>,
+    jwks: JwkSet,
+}
+
+impl KeyStore {
+
+    pub fn load(config: &KeyConfig) -> Result<KeyStore, String> {
+        let entries = fs::read_dir(&config.dir)
+            .map_err(|e| format!("Failed to read keystore {}: {}", config.dir, e))?;
+
+        let mut kids = entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| entry.file_name().to_str()
+                .and_then(|name| name.strip_suffix(PUBLIC_KEY_SUFFIX))
+                .map(|kid| kid.to_string()))
+            .collect::<Vec<_>>();
+        kids.sort();
+
+        let mut verification_keys = vec![];
+        let mut jwks = vec![];
+        for kid in kids {
+            let path = Path::new(&config.dir).join(format!("{}{}", kid, PUBLIC_KEY_SUFFIX));
+            let pem = fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+
+            let public_key = RsaPublicKey::from_public_key_pem(&pem)
+                .map_err(|e| format!("{} is not an RSA public key: {}", path.display(), e))?;
+            let key = DecodingKey::from_rsa_pem(pem.as_bytes())
+                .map_err(|e| format!("{} is not an RSA public key: {}", path.display(), e))?;
+
+            jwks.push(to_jwk(&kid, &public_key));
+            verification_keys.push(VerificationKey { kid, key, pem });
+        }
+
+        // Newest key that can sign, kids start with their creation time
+        let signing_kid = match &config.signing_kid {
+            Some(kid) => kid.clone(),
+            None => verification_keys.iter().rev()
+                .map(|key| key.kid.clone())
+                .find(|kid| private_key_path(&config.dir, kid).exists())
+                .ok_or(format!("No key pair in {}, create one with `tidder keygen`", config.dir))?,
+        };
+
+        if !verification_keys.iter().any(|key| key.kid.eq(&signing_kid)) {
+            return Err(format!("Signing key {} has no public key in {}", signing_kid, config.dir));
+        }
+
+        let path = private_key_path(&config.dir, &signing_kid);
+        let pem = fs::read(&path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let signing_key = EncodingKey::from_rsa_pem(&pem)
+            .map_err(|e| format!("{} is not an RSA private key: {}", path.display(), e))?;
+
+        Ok(KeyStore {
+            signing_kid,
+            signing_key,
+            verification_keys,
+            jwks: JwkSet { keys: jwks },
+        })
+    }
+
+    /**
+     * Sign the claims with the current signing key, its kid is added to the token header
+     */
+    pub fn sign(&self, claims: &Claims) -> Result<String, AppError> {
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some(self.signing_kid.clone());
+
+        jsonwebtoken::encode(&header, claims, &self.signing_key)
+            .map_err(|e| AppError::Internal(format!("Failed to sign token: {}", e)))
+    }
+
+    /**
+     * Check the signature and expiry of a token.
+     * Tokens without a kid were issued before keys had one, they are checked against every key.
+     */
+    pub fn verify(&self, token: &str) -> Result<Claims, AppError> {
+        let unauthorized = || AppError::Unauthorized("Unauthorized".to_string());
+
+        let header = jsonwebtoken::decode_header(token).map_err(|_| unauthorized())?;
+        let validation = Validation::new(Algorithm::RS256);
+
+        self.verification_keys.iter()
+            .filter(|key| header.kid.as_ref().is_none_or(|kid| key.kid.eq(kid)))
+            .find_map(|key| jsonwebtoken::decode::<Claims>(token, &key.key, &validation).ok())
+            .map(|token_data| token_data.claims)
+            .ok_or_else(unauthorized)
+    }
+
+    /// Every public key that tokens are verified with, as a JWK set
+    pub fn jwks(&self) -> &JwkSet {
+        &self.jwks
+    }
+
+    /// The public key of the current signing key as PEM
+    pub fn signing_public_pem(&self) -> &str {
+        self.verification_keys.iter()
+            .find(|key| key.kid.eq(&self.signing_kid))
+            .map(|key| key.pem.as_str())
+            .unwrap_or_default()
+    }
+}
+
+/**
+ * Create a new key pair in the keystore directory. It becomes the signing key on the next start,
+ * older keys keep verifying tokens until their files are removed.
+ * @return The kid of the new key pair
+ */
+pub fn generate(dir: &str) -> Result<String, String> {
+    fs::create_dir_all(dir).map_err(|e| format!("Failed to create {}: {}", dir, e))?;
+
+    let kid = chrono::Utc::now().format("%Y%m%d%H%M%S").to_string();
+    let private_path = private_key_path(dir, &kid);
+    let public_path = Path::new(dir).join(format!("{}{}", kid, PUBLIC_KEY_SUFFIX));
+    if private_path.exists() || public_path.exists() {
+        return Err(format!("Key {} already exists", kid));
+    }
+
+    let private_key = RsaPrivateKey::new(&mut rand::thread_rng(), KEY_BITS)
+        .map_err(|e| format!("Failed to generate key: {}", e))?;
+    let private_pem = private_key.to_pkcs8_pem(LineEnding::LF)
+        .map_err(|e| format!("Failed to encode private key: {}", e))?;
+    let public_pem = RsaPublicKey::from(&private_key).to_public_key_pem(LineEnding::LF)
+        .map_err(|e| format!("Failed to encode public key: {}", e))?;
+
+    // Only the server's user should be able to read the private key, from the moment the file exists
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    options.open(&private_path)
+        .and_then(|mut file| file.write_all(private_pem.as_bytes()))
+        .map_err(|e| format!("Failed to write {}: {}", private_path.display(), e))?;
+    fs::write(&public_path, public_pem)
+        .map_err(|e| format!("Failed to write {}: {}", public_path.display(), e))?;
+
+    Ok(kid)
+}
+
+fn private_key_path(dir: &str, kid: &str) -> std::path::PathBuf {
+    Path::new(dir).join(format!("{}{}", kid, PRIVATE_KEY_SUFFIX))
+}
+
+fn to_jwk(kid: &str, key: &RsaPublicKey) -> Jwk {
+    Jwk {
+        common: CommonParameters {
+            public_key_use: Some(PublicKeyUse::Signature),
+            algorithm: Some(Algorithm::RS256),
+            key_id: Some(kid.to_string()),
+            ..Default::default()
+        },
+        algorithm: AlgorithmParameters::RSA(RSAKeyParameters {
+            key_type: RSAKeyType::RSA,
+            n: URL_SAFE_NO_PAD.encode(key.n().to_bytes_be()),
+            e: URL_SAFE_NO_PAD.encode(key.e().to_bytes_be()),
+        }),
+    }
+}
@@ -0,0 +1,705 @@
+use std::collections::HashMap;
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use async_trait::async_trait;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::error::AppError;
+use crate::model::data::{Category, Post, PostType, Comment, Deletion, DeletionKind, Restoration, Revision, Page};
+use crate::service::ranking::Ranking;
+use crate::service::store::{ContentStore, SearchIndex, Index, DELETED_AUTHOR};
+use crate::utils::cursor;
+
+/// Content store that keeps everything in memory, for local development without
+/// an Elasticsearch cluster. Nothing survives a restart.
+#[derive(Default)]
+pub struct MemoryStore {
+    data: RwLock<Data>,
+}
+
+#[derive(Default)]
+struct Data {
+    categories: Vec<Category>,
+    posts: Vec<Post>,
+    comments: Vec<Comment>,
+    post_revisions: Vec<Revision>,
+    comment_revisions: Vec<Revision>,
+}
+
+/// Where a listing continues: how many items were already returned and the time the listing started
+#[derive(Debug, Serialize, Deserialize)]
+struct Cursor {
+    offset: usize,
+    now: i64,
+}
+
+impl MemoryStore {
+
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, Data> {
+        self.data.read().expect("Memory store lock poisoned")
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, Data> {
+        self.data.write().expect("Memory store lock poisoned")
+    }
+}
+
+/**
+ * Decode the cursor of a listing request
+ * @return The offset to continue from and the time the listing started in milliseconds
+ */
+fn start_listing(cursor: &Option<String>) -> Result<(usize, i64), AppError> {
+    match cursor {
+        Some(cursor) => {
+            let cursor: Cursor = cursor::decode(cursor)?;
+            Ok((cursor.offset, cursor.now))
+        },
+        None => Ok((0, chrono::Utc::now().timestamp_millis())),
+    }
+}
+
+/**
+ * Cut one page out of a fully ordered listing
+ */
+fn page<T>(items: Vec<T>, limit: u32, offset: usize, now: i64) -> Page<T> {
+    let end = offset + limit as usize;
+    let next_cursor = match items.len() > end {
+        true => Some(cursor::encode(&Cursor { offset: end, now })),
+        false => None,
+    };
+
+    Page {
+        items: items.into_iter().skip(offset).take(limit as usize).collect(),
+        next_cursor,
+    }
+}
+
+fn created_millis(created_at: &str) -> i64 {
+    chrono::DateTime::parse_from_rfc3339(created_at)
+        .map(|date| date.timestamp_millis())
+        .unwrap_or(0)
+}
+
+/// When something was deleted, deletions from before the time was recorded count from the last update
+fn deleted_millis(deletion: &Option<Deletion>, updated_at: &str) -> i64 {
+    match deletion {
+        Some(deletion) => created_millis(&deletion.deleted_at),
+        None => created_millis(updated_at),
+    }
+}
+
+/// The ids of the `limit` oldest deletions before the given time
+fn oldest_deleted(mut deleted: Vec<(i64, String)>, deleted_before: i64, limit: u32) -> Vec<String> {
+    deleted.retain(|(deleted_at, _)| *deleted_at < deleted_before);
+    deleted.sort();
+    deleted.into_iter().take(limit as usize).map(|(_, id)| id).collect()
+}
+
+/**
+ * Drop posts outside the time window of the ranking and order the rest the way Elasticsearch would
+ */
+fn rank_posts(posts: &mut Vec<Post>, ranking: &Ranking, now: i64) {
+    if let Some(since) = ranking.window_start(now) {
+        posts.retain(|post| created_millis(&post.created_at) >= since);
+    }
+
+    posts.sort_by(|a, b| {
+        let score_a = ranking.score(a.upvotes, a.downvotes, &a.created_at, now);
+        let score_b = ranking.score(b.upvotes, b.downvotes, &b.created_at, now);
+        score_b.total_cmp(&score_a).then_with(|| b.created_at.cmp(&a.created_at))
+    });
+}
+
+fn not_found(index: &Index) -> AppError {
+    match index {
+        Index::Post => AppError::NotFound("Post not found".to_string()),
+        Index::Comment => AppError::NotFound("Comment not found".to_string()),
+    }
+}
+
+fn new_id() -> Option<String> {
+    Some(uuid::Uuid::new_v4().to_string())
+}
+
+#[async_trait]
+impl SearchIndex for MemoryStore {
+
+    async fn search(&self, query: &str, limit: u32, cursor: &Option<String>) -> Result<(Vec<Category>, Vec<Post>, Option<String>), AppError> {
+
+        let (offset, now) = start_listing(cursor)?;
+        let query = query.to_lowercase();
+        let data = self.read();
+
+        // Categories come first, then posts, newest first
+        let categories = data.categories.iter()
+            .filter(|category| category.name.to_lowercase().contains(&query))
+            .cloned()
+            .map(|category| (Some(category), None));
+
+        let mut posts: Vec<Post> = data.posts.iter()
+            .filter(|post| post.published && !post.deleted)
+            .filter(|post| {
+                post.title.to_lowercase().contains(&query)
+                    || post.body.to_lowercase().contains(&query)
+                    || post.author_name.to_lowercase().contains(&query)
+            })
+            .cloned()
+            .collect();
+        posts.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+
+        let hits: Vec<(Option<Category>, Option<Post>)> = categories
+            .chain(posts.into_iter().map(|post| (None, Some(post))))
+            .collect();
+
+        let page = page(hits, limit, offset, now);
+
+        let mut categories = Vec::new();
+        let mut posts = Vec::new();
+        for hit in page.items {
+            match hit {
+                (Some(category), _) => categories.push(category),
+                (_, Some(mut post)) => posts.push(post.sanitize(&false)),
+                _ => (),
+            }
+        }
+
+        Ok((categories, posts, page.next_cursor))
+    }
+}
+
+#[async_trait]
+impl ContentStore for MemoryStore {
+
+    async fn get_categories(&self) -> Result<Vec<Category>, AppError> {
+        let data = self.read();
+
+        Ok(data.categories.iter().map(|category| {
+            let count = data.posts.iter()
+                .filter(|post| post.category_id.eq(&category.id) && post.published && !post.deleted)
+                .count();
+
+            Category {
+                posts: Some(count as u64),
+                ..category.clone()
+            }
+        }).collect())
+    }
+
+    async fn get_category_by_id(&self, category_id: &str) -> Result<Category, AppError> {
+        self.read().categories.iter()
+            .find(|category| category.id.eq(category_id))
+            .cloned()
+            .ok_or_else(|| AppError::NotFound("Category not found".to_string()))
+    }
+
+    async fn get_category_by_name(&self, category_name: String) -> Result<Category, AppError> {
+        let normalized = Category::normalize_name(&category_name);
+
+        self.read().categories.iter()
+            .find(|category| Category::normalize_name(&category.name).eq(&normalized))
+            .cloned()
+            .ok_or_else(|| AppError::NotFound("Category not found".to_string()))
+    }
+
+    async fn count_posts_by_category_id(&self, category_id: String) -> Result<u64, AppError> {
+        let count = self.read().posts.iter()
+            .filter(|post| post.category_id.eq(&category_id) && post.published && !post.deleted)
+            .count();
+
+        Ok(count as u64)
+    }
+
+    async fn index_category(&self, category: Category) -> Result<Category, AppError> {
+        let mut data = self.write();
+
+        let normalized = Category::normalize_name(&category.name);
+        if data.categories.iter().any(|existing| Category::normalize_name(&existing.name).eq(&normalized)) {
+            return Err(AppError::Conflict("Category already exists".to_string()));
+        }
+
+        let category = Category {
+            id: new_id().unwrap(),
+            ..category
+        };
+        data.categories.push(category.clone());
+
+        Ok(category)
+    }
+
+    async fn update_category(&self, category_id: String, description: String, rules: String, post_types: Vec<PostType>) -> Result<(), AppError> {
+        match self.write().categories.iter_mut().find(|category| category.id.eq(&category_id)) {
+            Some(category) => {
+                category.description = description;
+                category.rules = rules;
+                category.post_types = post_types;
+                Ok(())
+            },
+            None => Err(AppError::NotFound("Category not found".to_string())),
+        }
+    }
+
+    async fn index_post(&self, post: Post) -> Result<Post, AppError> {
+        let post = Post {
+            id: new_id(),
+            ..post
+        };
+        self.write().posts.push(post.clone());
+
+        Ok(post)
+    }
+
+    async fn publish_post(&self, post_id: String) -> Result<(), AppError> {
+        match self.write().posts.iter_mut().find(|post| post.id.as_ref() == Some(&post_id)) {
+            Some(post) => {
+                post.published = true;
+                Ok(())
+            },
+            None => Err(AppError::NotFound("Post not found".to_string())),
+        }
+    }
+
+    async fn lock_post(&self, post_id: String, locked: bool) -> Result<(), AppError> {
+        match self.write().posts.iter_mut().find(|post| post.id.as_ref() == Some(&post_id)) {
+            Some(post) => {
+                post.locked = locked;
+                Ok(())
+            },
+            None => Err(AppError::NotFound("Post not found".to_string())),
+        }
+    }
+
+    async fn update_post(&self, post_id: String, title: String, body: String, updated_at: String) -> Result<(), AppError> {
+        match self.write().posts.iter_mut().find(|post| post.id.as_ref() == Some(&post_id)) {
+            Some(post) => {
+                post.title = title;
+                post.body = body;
+                post.updated_at = updated_at;
+                Ok(())
+            },
+            None => Err(AppError::NotFound("Post not found".to_string())),
+        }
+    }
+
+    async fn update_comment(&self, comment_id: String, body: String, edited_at: String) -> Result<(), AppError> {
+        match self.write().comments.iter_mut().find(|comment| comment.id.as_ref() == Some(&comment_id)) {
+            Some(comment) => {
+                comment.body = body;
+                comment.updated_at = edited_at.clone();
+                comment.edited_at = Some(edited_at);
+                Ok(())
+            },
+            None => Err(AppError::NotFound("Comment not found".to_string())),
+        }
+    }
+
+    async fn index_revision(&self, index: Index, revision: Revision) -> Result<(), AppError> {
+        let mut data = self.write();
+
+        match index {
+            Index::Post => data.post_revisions.push(revision),
+            Index::Comment => data.comment_revisions.push(revision),
+        }
+
+        Ok(())
+    }
+
+    async fn get_revisions(&self, index: Index, target_id: String) -> Result<Vec<Revision>, AppError> {
+        let data = self.read();
+
+        let revisions = match index {
+            Index::Post => &data.post_revisions,
+            Index::Comment => &data.comment_revisions,
+        };
+
+        let mut revisions: Vec<Revision> = revisions.iter()
+            .filter(|revision| revision.target_id.eq(&target_id))
+            .cloned()
+            .collect();
+        revisions.sort_by(|a, b| a.replaced_at.cmp(&b.replaced_at));
+
+        Ok(revisions)
+    }
+
+    async fn delete_post(&self, index: Index, post_id: String, deletion: Deletion) -> Result<(), AppError> {
+        let mut data = self.write();
+
+        let deleted = match index {
+            Index::Post => data.posts.iter_mut()
+                .find(|post| post.id.as_ref() == Some(&post_id))
+                .map(|post| {
+                    post.deleted = true;
+                    post.deletion = Some(deletion);
+                    post.restoration = None;
+                }),
+            Index::Comment => data.comments.iter_mut()
+                .find(|comment| comment.id.as_ref() == Some(&post_id))
+                .map(|comment| {
+                    comment.deleted = true;
+                    comment.deletion = Some(deletion);
+                    comment.restoration = None;
+                }),
+        };
+
+        deleted.ok_or_else(|| not_found(&index))
+    }
+
+    async fn restore_post(&self, index: Index, post_id: String, restoration: Restoration) -> Result<(), AppError> {
+        let mut data = self.write();
+
+        let restored = match index {
+            Index::Post => data.posts.iter_mut()
+                .find(|post| post.id.as_ref() == Some(&post_id))
+                .map(|post| {
+                    post.deleted = false;
+                    post.restoration = Some(restoration);
+                }),
+            Index::Comment => data.comments.iter_mut()
+                .find(|comment| comment.id.as_ref() == Some(&post_id))
+                .map(|comment| {
+                    comment.deleted = false;
+                    comment.restoration = Some(restoration);
+                }),
+        };
+
+        restored.ok_or_else(|| not_found(&index))
+    }
+
+    async fn find_deleted(&self, index: Index, deleted_before: i64, limit: u32) -> Result<Vec<String>, AppError> {
+        let data = self.read();
+
+        let deleted = match index {
+            Index::Post => data.posts.iter()
+                .filter(|post| post.deleted)
+                .filter_map(|post| post.id.clone().map(|id| (deleted_millis(&post.deletion, &post.updated_at), id)))
+                .collect(),
+            Index::Comment => data.comments.iter()
+                .filter(|comment| comment.deleted && !comment.purged)
+                .filter_map(|comment| comment.id.clone().map(|id| (deleted_millis(&comment.deletion, &comment.updated_at), id)))
+                .collect(),
+        };
+
+        Ok(oldest_deleted(deleted, deleted_before, limit))
+    }
+
+    async fn find_comment_ids_by_post_ids(&self, post_ids: Vec<String>) -> Result<Vec<String>, AppError> {
+        Ok(self.read().comments.iter()
+            .filter(|comment| post_ids.contains(&comment.post_id))
+            .filter_map(|comment| comment.id.clone())
+            .collect())
+    }
+
+    async fn find_replied_comment_ids(&self, comment_ids: Vec<String>) -> Result<Vec<String>, AppError> {
+        let data = self.read();
+
+        Ok(comment_ids.into_iter()
+            .filter(|id| data.comments.iter().any(|comment| comment.parent_id.as_ref() == Some(id)))
+            .collect())
+    }
+
+    async fn purge(&self, index: Index, ids: Vec<String>) -> Result<(), AppError> {
+        let mut data = self.write();
+        let data = &mut *data;
+
+        let is_purged = |id: &Option<String>| id.as_ref().is_some_and(|id| ids.contains(id));
+
+        match index {
+            Index::Post => {
+                data.posts.retain(|post| !is_purged(&post.id));
+                data.post_revisions.retain(|revision| !ids.contains(&revision.target_id));
+            },
+            Index::Comment => {
+                data.comments.retain(|comment| !is_purged(&comment.id));
+                data.comment_revisions.retain(|revision| !ids.contains(&revision.target_id));
+            },
+        }
+
+        Ok(())
+    }
+
+    async fn scrub_comments(&self, comment_ids: Vec<String>) -> Result<(), AppError> {
+        let mut data = self.write();
+        let data = &mut *data;
+
+        let scrubbed = data.comments.iter_mut()
+            .filter(|comment| comment.id.as_ref().is_some_and(|id| comment_ids.contains(id)));
+
+        for comment in scrubbed {
+            comment.body = String::new();
+            comment.author_id = String::new();
+            comment.author_name = DELETED_AUTHOR.to_string();
+            comment.purged = true;
+            if let Some(deletion) = comment.deletion.as_mut().filter(|deletion| deletion.kind == DeletionKind::Author) {
+                deletion.deleted_by = None;
+            }
+        }
+        data.comment_revisions.retain(|revision| !comment_ids.contains(&revision.target_id));
+
+        Ok(())
+    }
+
+    async fn update_votes(&self, index: Index, id: String, upvotes: i64, downvotes: i64) -> Result<(u32, u32), AppError> {
+        let mut data = self.write();
+
+        // The write lock makes the update atomic
+        let counters = match index {
+            Index::Post => data.posts.iter_mut()
+                .find(|post| post.id.as_ref() == Some(&id))
+                .map(|post| (&mut post.upvotes, &mut post.downvotes)),
+            Index::Comment => data.comments.iter_mut()
+                .find(|comment| comment.id.as_ref() == Some(&id))
+                .map(|comment| (&mut comment.upvotes, &mut comment.downvotes)),
+        };
+
+        match counters {
+            Some((up, down)) => {
+                *up = (*up as i64 + upvotes).max(0) as u32;
+                *down = (*down as i64 + downvotes).max(0) as u32;
+                Ok((*up, *down))
+            },
+            None => Err(not_found(&index)),
+        }
+    }
+
+    async fn sum_karma(&self, index: Index) -> Result<Vec<(String, i64)>, AppError> {
+        let data = self.read();
+
+        let votes: Vec<(&String, i64)> = match index {
+            Index::Post => data.posts.iter()
+                .map(|post| (&post.author_id, post.upvotes as i64 - post.downvotes as i64))
+                .collect(),
+            Index::Comment => data.comments.iter()
+                .map(|comment| (&comment.author_id, comment.upvotes as i64 - comment.downvotes as i64))
+                .collect(),
+        };
+
+        let mut karma: HashMap<String, i64> = HashMap::new();
+        for (author_id, votes) in votes.into_iter().filter(|(author_id, _)| !author_id.is_empty()) {
+            *karma.entry(author_id.clone()).or_default() += votes;
+        }
+
+        Ok(karma.into_iter().collect())
+    }
+
+    async fn index_comment(&self, comment: Comment) -> Result<Comment, AppError> {
+        let comment = Comment {
+            id: new_id(),
+            ..comment
+        };
+        self.write().comments.push(comment.clone());
+
+        Ok(comment)
+    }
+
+    async fn rename_author(&self, author_id: String, author_name: String) -> Result<(), AppError> {
+        let mut data = self.write();
+        let data = &mut *data;
+
+        data.posts.iter_mut()
+            .filter(|post| post.author_id.eq(&author_id))
+            .for_each(|post| post.author_name = author_name.clone());
+        data.comments.iter_mut()
+            .filter(|comment| comment.author_id.eq(&author_id))
+            .for_each(|comment| comment.author_name = author_name.clone());
+
+        Ok(())
+    }
+
+    async fn remove_author(&self, author_id: String, remove_content: bool) -> Result<(), AppError> {
+        let mut data = self.write();
+        let data = &mut *data;
+
+        for post in data.posts.iter_mut().filter(|post| post.author_id.eq(&author_id)) {
+            post.author_id = String::new();
+            post.author_name = DELETED_AUTHOR.to_string();
+            if remove_content && !post.deleted {
+                post.deleted = true;
+                post.deletion = Some(Deletion::new(DeletionKind::Author, None, None));
+                post.restoration = None;
+            }
+        }
+        for comment in data.comments.iter_mut().filter(|comment| comment.author_id.eq(&author_id)) {
+            comment.author_id = String::new();
+            comment.author_name = DELETED_AUTHOR.to_string();
+            if remove_content && !comment.deleted {
+                comment.deleted = true;
+                comment.deletion = Some(Deletion::new(DeletionKind::Author, None, None));
+                comment.restoration = None;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn flush_data(&self) -> Result<(), AppError> {
+        *self.write() = Data::default();
+        Ok(())
+    }
+
+    async fn get_post_source_by_id(&self, id: String) -> Result<Post, AppError> {
+        self.read().posts.iter()
+            .find(|post| post.id.as_ref() == Some(&id))
+            .cloned()
+            .ok_or_else(|| AppError::NotFound("Post not found".to_string()))
+    }
+
+    async fn get_comment_source_by_id(&self, comment_id: String) -> Result<Comment, AppError> {
+        self.read().comments.iter()
+            .find(|comment| comment.id.as_ref() == Some(&comment_id))
+            .cloned()
+            .ok_or_else(|| AppError::NotFound("Comment not found".to_string()))
+    }
+
+    async fn get_post_sources_by_author_id(&self, author_id: String, limit: u32, cursor: &Option<String>) -> Result<Page<Post>, AppError> {
+        let (offset, now) = start_listing(cursor)?;
+
+        let mut posts: Vec<Post> = self.read().posts.iter()
+            .filter(|post| post.author_id.eq(&author_id))
+            .cloned()
+            .collect();
+        posts.sort_by(|a, b| a.created_at.cmp(&b.created_at));
+
+        Ok(page(posts, limit, offset, now))
+    }
+
+    async fn get_comment_sources_by_author_id(&self, author_id: String, limit: u32, cursor: &Option<String>) -> Result<Page<Comment>, AppError> {
+        let (offset, now) = start_listing(cursor)?;
+
+        let mut comments: Vec<Comment> = self.read().comments.iter()
+            .filter(|comment| comment.author_id.eq(&author_id))
+            .cloned()
+            .collect();
+        comments.sort_by(|a, b| a.created_at.cmp(&b.created_at));
+
+        Ok(page(comments, limit, offset, now))
+    }
+
+    // NOTE:########################################################//
+    // NOTE:# Sanitize text body in all functions below this point #//
+    // NOTE:########################################################//
+
+    async fn get_posts_by_category_id(&self, category_id: String, show_all: &bool, ranking: &Ranking, limit: u32, cursor: &Option<String>) -> Result<Page<Post>, AppError> {
+        let (offset, now) = start_listing(cursor)?;
+
+        let mut posts: Vec<Post> = self.read().posts.iter()
+            .filter(|post| post.category_id.eq(&category_id))
+            .filter(|post| *show_all || (post.published && !post.deleted))
+            .cloned()
+            .collect();
+        rank_posts(&mut posts, ranking, now);
+
+        let mut page = page(posts, limit, offset, now);
+        page.items = page.items.iter_mut().map(|post| post.sanitize(show_all)).collect();
+
+        Ok(page)
+    }
+
+    async fn get_posts(&self, show_all: &bool, ranking: &Ranking, limit: u32, cursor: &Option<String>) -> Result<Page<Post>, AppError> {
+        let (offset, now) = start_listing(cursor)?;
+
+        let mut posts: Vec<Post> = self.read().posts.iter()
+            .filter(|post| *show_all || (post.published && !post.deleted))
+            .cloned()
+            .collect();
+        rank_posts(&mut posts, ranking, now);
+
+        let mut page = page(posts, limit, offset, now);
+        page.items = page.items.iter_mut().map(|post| post.sanitize(show_all)).collect();
+
+        Ok(page)
+    }
+
+    async fn get_post_by_id(&self, id: String, show_all: &bool) -> Result<Post, AppError> {
+        match self.read().posts.iter().find(|post| post.id.as_ref() == Some(&id)) {
+            Some(post) => Ok(post.clone().sanitize(show_all)),
+            None => Err(AppError::NotFound("Post not found".to_string())),
+        }
+    }
+
+    async fn get_posts_by_user_id(&self, user_id: String, limit: u32, cursor: &Option<String>) -> Result<Page<Post>, AppError> {
+        let (offset, now) = start_listing(cursor)?;
+
+        let mut posts: Vec<Post> = self.read().posts.iter()
+            .filter(|post| post.author_id.eq(&user_id))
+            .cloned()
+            .collect();
+        posts.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+
+        let mut page = page(posts, limit, offset, now);
+        page.items = page.items.iter_mut().map(|post| post.sanitize(&false)).collect();
+
+        Ok(page)
+    }
+
+    async fn get_published_posts_by_author_id(&self, author_id: String, limit: u32, cursor: &Option<String>) -> Result<Page<Post>, AppError> {
+        let (offset, now) = start_listing(cursor)?;
+
+        let mut posts: Vec<Post> = self.read().posts.iter()
+            .filter(|post| post.author_id.eq(&author_id) && post.published && !post.deleted)
+            .cloned()
+            .collect();
+        posts.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+
+        let mut page = page(posts, limit, offset, now);
+        page.items = page.items.iter_mut().map(|post| post.sanitize(&false)).collect();
+
+        Ok(page)
+    }
+
+    async fn get_comments_by_author_id(&self, author_id: String, limit: u32, cursor: &Option<String>) -> Result<Page<Comment>, AppError> {
+        let (offset, now) = start_listing(cursor)?;
+        let data = self.read();
+
+        let visible = |post_id: &String| data.posts.iter()
+            .any(|post| post.id.as_ref() == Some(post_id) && post.published && !post.deleted);
+
+        let mut comments: Vec<Comment> = data.comments.iter()
+            .filter(|comment| comment.author_id.eq(&author_id) && !comment.deleted && visible(&comment.post_id))
+            .cloned()
+            .collect();
+        comments.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+
+        let mut page = page(comments, limit, offset, now);
+        page.items = page.items.iter_mut().map(|comment| comment.sanitize(&false)).collect();
+
+        Ok(page)
+    }
+
+    async fn get_comments_by_post_id(&self, post_id: String, show_all: &bool, ranking: &Ranking, limit: u32, cursor: &Option<String>) -> Result<Page<Comment>, AppError> {
+        let (offset, now) = start_listing(cursor)?;
+        let data = self.read();
+
+        let mut roots: Vec<Comment> = data.comments.iter()
+            .filter(|comment| comment.post_id.eq(&post_id) && comment.parent_id.is_none())
+            .cloned()
+            .collect();
+        roots.sort_by(|a, b| {
+            let score_a = ranking.score(a.upvotes, a.downvotes, &a.created_at, now);
+            let score_b = ranking.score(b.upvotes, b.downvotes, &b.created_at, now);
+            score_b.total_cmp(&score_a).then_with(|| b.created_at.cmp(&a.created_at))
+        });
+
+        let mut page = page(roots, limit, offset, now);
+
+        let root_ids: Vec<&String> = page.items.iter().filter_map(|comment| comment.id.as_ref()).collect();
+        let replies: Vec<Comment> = data.comments.iter()
+            .filter(|comment| comment.root_id.as_ref().is_some_and(|root_id| root_ids.contains(&root_id)))
+            .cloned()
+            .collect();
+
+        page.items.extend(replies);
+        page.items = page.items.iter_mut().map(|comment| comment.sanitize(show_all)).collect();
+
+        Ok(page)
+    }
+
+    async fn get_comment_by_id(&self, comment_id: String, show_all: &bool) -> Result<Comment, AppError> {
+        match self.read().comments.iter().find(|comment| comment.id.as_ref() == Some(&comment_id)) {
+            Some(comment) => Ok(comment.clone().sanitize(show_all)),
+            None => Err(AppError::NotFound("Comment not found".to_string())),
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use r2d2_sqlite::rusqlite::{params, Connection, TransactionBehavior};
+
+use crate::error::AppError;
+
+/// One step of the database schema, the SQL is compiled into the binary
+struct Migration {
+    version: u32,
+    name: &'static str,
+    sql: &'static str,
+}
+
+/**
+ * Every schema change, in the order they are applied. A released migration must never change,
+ * add a new one instead. The first ones only create what is missing, so databases from before
+ * migrations existed are picked up as they are.
+ */
+const MIGRATIONS: &[Migration] = &[
+    Migration { version: 1, name: "users", sql: include_str!("../migrations/0001_users.sql") },
+    Migration { version: 2, name: "votes", sql: include_str!("../migrations/0002_votes.sql") },
+    Migration { version: 3, name: "sessions", sql: include_str!("../migrations/0003_sessions.sql") },
+    Migration { version: 4, name: "login_attempts", sql: include_str!("../migrations/0004_login_attempts.sql") },
+    Migration { version: 5, name: "settings", sql: include_str!("../migrations/0005_settings.sql") },
+    Migration { version: 6, name: "category_moderators", sql: include_str!("../migrations/0006_category_moderators.sql") },
+    Migration { version: 7, name: "reports", sql: include_str!("../migrations/0007_reports.sql") },
+    Migration { version: 8, name: "bans", sql: include_str!("../migrations/0008_bans.sql") },
+    Migration { version: 9, name: "audit_log", sql: include_str!("../migrations/0009_audit_log.sql") },
+    Migration { version: 10, name: "exports", sql: include_str!("../migrations/0010_exports.sql") },
+    Migration { version: 11, name: "karma", sql: include_str!("../migrations/0011_karma.sql") },
+];
+
+/**
+ * Bring the schema up to date. All pending migrations run in one transaction, so a failing one
+ * leaves the database as it was. A database that was migrated by a newer version is refused,
+ * since this version doesn't know what changed.
+ * @return The schema version of the database
+ */
+pub fn run(conn: &mut Connection) -> Result<u32, AppError> {
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            name TEXT NOT NULL,
+            applied_at DATETIME DEFAULT CURRENT_TIMESTAMP
+        )",
+        params![]
+    )?;
+
+    let current: u32 = tx.query_row("SELECT COALESCE(MAX(version), 0) FROM schema_version", params![], |row| row.get(0))?;
+    let latest = MIGRATIONS.last().map_or(0, |migration| migration.version);
+
+    if current > latest {
+        return Err(AppError::Internal(format!("The database has schema version {}, but this version of tidder only knows up to {}", current, latest)));
+    }
+
+    let pending = MIGRATIONS.iter()
+        .filter(|migration| migration.version > current)
+        .collect::<Vec<_>>();
+
+    for migration in &pending {
+        tx.execute_batch(migration.sql)
+            .map_err(|e| AppError::Internal(format!("Migration {} ({}) failed: {}", migration.version, migration.name, e)))?;
+
+        tx.execute(
+            "INSERT INTO schema_version (version, name) VALUES (?, ?)",
+            params![migration.version, migration.name]
+        )?;
+    }
+
+    tx.commit()?;
+
+    for migration in pending {
+        println!("Applied migration {} ({})", migration.version, migration.name);
+    }
+
+    Ok(latest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions_are_contiguous_from_one() {
+        for (i, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version, i as u32 + 1, "migration {} is out of order", migration.name);
+        }
+    }
+
+    #[test]
+    fn names_are_unique() {
+        let mut names: Vec<&str> = MIGRATIONS.iter().map(|migration| migration.name).collect();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), MIGRATIONS.len());
+    }
+
+    #[test]
+    fn applies_every_migration_once() {
+        let mut conn = Connection::open_in_memory().unwrap();
+
+        assert_eq!(run(&mut conn).unwrap(), MIGRATIONS.len() as u32);
+        assert_eq!(run(&mut conn).unwrap(), MIGRATIONS.len() as u32);
+
+        let applied: Vec<u32> = conn.prepare("SELECT version FROM schema_version ORDER BY version").unwrap()
+            .query_map(params![], |row| row.get(0)).unwrap()
+            .collect::<Result<_, _>>().unwrap();
+        let expected: Vec<u32> = MIGRATIONS.iter().map(|migration| migration.version).collect();
+        assert_eq!(applied, expected);
+    }
+
+    #[test]
+    fn refuses_a_newer_schema() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        run(&mut conn).unwrap();
+        conn.execute("INSERT INTO schema_version (version, name) VALUES (?, ?)", params![MIGRATIONS.len() as u32 + 1, "future"]).unwrap();
+
+        assert!(run(&mut conn).is_err());
+    }
+}
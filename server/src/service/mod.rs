@@ -1,3 +1,18 @@
 pub mod elastic;
 pub mod security;
-pub mod database;
\ No newline at end of file
+pub mod database;
+pub mod vote;
+pub mod ranking;
+pub mod store;
+pub mod memory;
+pub mod auth;
+pub mod keys;
+pub mod throttle;
+pub mod migrations;
+pub mod category;
+pub mod report;
+pub mod ban;
+pub mod audit;
+pub mod retention;
+pub mod export;
+pub mod karma;
@@ -0,0 +1,210 @@
+use serde_json::{json, Value};
+
+use crate::error::AppError;
+
+/// Seconds between the Unix epoch and the reference date used by the "hot" ranking.
+/// Only shifts the score, so the exact value doesn't matter as long as it never changes.
+const HOT_EPOCH: i64 = 1134028003;
+
+/// How many seconds it takes for a post to need 10x the score to stay on par with a newer one
+const HOT_DECAY: f64 = 45000.0;
+
+/// z-score for the lower bound of the Wilson score interval (80% confidence)
+const WILSON_Z: f64 = 1.281551565545;
+
+/// How many days back "rising" looks for posts
+const RISING_WINDOW_DAYS: i64 = 1;
+
+/// The order in which a feed is ranked, selected with the `sort` and `t` query parameters
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Ranking {
+    /// Score decayed by age, so new posts with some votes beat old posts with many
+    #[default]
+    Hot,
+    /// Lower bound of the Wilson score confidence interval of the upvote ratio
+    Best,
+    /// Highest score within a time window
+    Top(Window),
+    /// Newest first
+    New,
+    /// Recent posts that gain score fastest relative to their age
+    Rising,
+    /// Many votes, split evenly between up and down
+    Controversial,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Window {
+    Day,
+    Week,
+    Month,
+    All,
+}
+
+impl Ranking {
+    /**
+     * Parse the `sort` and `t` query parameters. Missing parameters fall back to "hot" and "all".
+     */
+    pub fn from_query(sort: &Option<String>, window: &Option<String>) -> Result<Ranking, AppError> {
+        let window = match window.as_deref() {
+            None | Some("all") => Window::All,
+            Some("day") => Window::Day,
+            Some("week") => Window::Week,
+            Some("month") => Window::Month,
+            Some(_) => return Err(AppError::BadRequest("Time window must be one of: day, week, month, all".to_string())),
+        };
+
+        match sort.as_deref() {
+            None | Some("hot") => Ok(Ranking::Hot),
+            Some("best") => Ok(Ranking::Best),
+            Some("top") => Ok(Ranking::Top(window)),
+            Some("new") => Ok(Ranking::New),
+            Some("rising") => Ok(Ranking::Rising),
+            Some("controversial") => Ok(Ranking::Controversial),
+            Some(_) => Err(AppError::BadRequest("Sort must be one of: hot, best, top, new, rising, controversial".to_string())),
+        }
+    }
+
+    /**
+     * Elasticsearch `sort` clause for this ranking.
+     * Every ranking ends with `created_at` so ties come out in a stable order.
+     * @param now Time of the first page in milliseconds, so later pages of a listing are ranked the same way
+     */
+    pub fn sort(&self, now: i64) -> Value {
+        match self {
+            Ranking::New => json!([
+                { "created_at": "desc" },
+            ]),
+            Ranking::Hot => json!([
+                script_sort(&format!(
+                    "double s = doc['upvotes'].value - doc['downvotes'].value;
+                    double order = Math.log10(Math.max(Math.abs(s), 1));
+                    double sign = s > 0 ? 1 : (s < 0 ? -1 : 0);
+                    long seconds = doc['created_at'].value.toInstant().getEpochSecond() - {}L;
+                    return sign * order + seconds / {:.1};",
+                    HOT_EPOCH, HOT_DECAY
+                ), json!({})),
+                { "created_at": "desc" },
+            ]),
+            Ranking::Best => json!([
+                script_sort(
+                    "double n = doc['upvotes'].value + doc['downvotes'].value;
+                    if (n == 0) { return 0; }
+                    double z = params.z;
+                    double p = doc['upvotes'].value / n;
+                    return (p + z * z / (2 * n) - z * Math.sqrt((p * (1 - p) + z * z / (4 * n)) / n)) / (1 + z * z / n);",
+                    json!({ "z": WILSON_Z })
+                ),
+                { "created_at": "desc" },
+            ]),
+            Ranking::Top(_) => json!([
+                script_sort("return doc['upvotes'].value - doc['downvotes'].value;", json!({})),
+                { "upvotes": "desc" },
+                { "created_at": "desc" },
+            ]),
+            Ranking::Rising => json!([
+                script_sort(
+                    "double hours = (params.now - doc['created_at'].value.toInstant().toEpochMilli()) / 3600000.0;
+                    double s = doc['upvotes'].value - doc['downvotes'].value;
+                    return s / Math.pow(Math.max(hours, 0) + 2, 1.5);",
+                    json!({ "now": now })
+                ),
+                { "created_at": "desc" },
+            ]),
+            Ranking::Controversial => json!([
+                script_sort(
+                    "long up = doc['upvotes'].value;
+                    long down = doc['downvotes'].value;
+                    if (up <= 0 || down <= 0) { return 0; }
+                    double balance = up > down ? (double) down / up : (double) up / down;
+                    return Math.pow(up + down, balance);",
+                    json!({})
+                ),
+                { "created_at": "desc" },
+            ]),
+        }
+    }
+
+    /**
+     * Score a single item the same way `sort` ranks documents in Elasticsearch.
+     * Used to order things that are ranked in memory, like the replies of a comment.
+     */
+    pub fn score(&self, upvotes: u32, downvotes: u32, created_at: &str, now: i64) -> f64 {
+        let up = upvotes as f64;
+        let down = downvotes as f64;
+        let s = up - down;
+        let created_at = chrono::DateTime::parse_from_rfc3339(created_at)
+            .map(|date| date.timestamp_millis())
+            .unwrap_or(0);
+
+        match self {
+            Ranking::New => created_at as f64,
+            Ranking::Hot => {
+                let order = s.abs().max(1.0).log10();
+                let sign = if s > 0.0 { 1.0 } else if s < 0.0 { -1.0 } else { 0.0 };
+                let seconds = (created_at / 1000 - HOT_EPOCH) as f64;
+                sign * order + seconds / HOT_DECAY
+            },
+            Ranking::Best => {
+                let n = up + down;
+                if n == 0.0 {
+                    return 0.0;
+                }
+                let z = WILSON_Z;
+                let p = up / n;
+                (p + z * z / (2.0 * n) - z * ((p * (1.0 - p) + z * z / (4.0 * n)) / n).sqrt()) / (1.0 + z * z / n)
+            },
+            Ranking::Top(_) => s,
+            Ranking::Rising => {
+                let hours = (now - created_at) as f64 / 3600000.0;
+                s / (hours.max(0.0) + 2.0).powf(1.5)
+            },
+            Ranking::Controversial => {
+                if upvotes == 0 || downvotes == 0 {
+                    return 0.0;
+                }
+                let balance = if up > down { down / up } else { up / down };
+                (up + down).powf(balance)
+            },
+        }
+    }
+
+    /**
+     * Extra query clause that limits which documents are ranked at all, e.g. the time window of "top"
+     * @param now Time of the first page in milliseconds, so the window doesn't move while paging
+     */
+    pub fn filter(&self, now: i64) -> Option<Value> {
+        self.window_start(now).map(|since| {
+            json!({ "range": { "created_at": { "gte": since, "format": "epoch_millis" } } })
+        })
+    }
+
+    /**
+     * Oldest creation time in milliseconds that is ranked at all, if the ranking has a time window
+     */
+    pub fn window_start(&self, now: i64) -> Option<i64> {
+        let days = match self {
+            Ranking::Top(Window::Day) => 1,
+            Ranking::Top(Window::Week) => 7,
+            Ranking::Top(Window::Month) => 30,
+            Ranking::Rising => RISING_WINDOW_DAYS,
+            _ => return None,
+        };
+
+        Some(now - days * 24 * 60 * 60 * 1000)
+    }
+}
+
+fn script_sort(source: &str, params: Value) -> Value {
+    json!({
+        "_script": {
+            "type": "number",
+            "order": "desc",
+            "script": {
+                "lang": "painless",
+                "source": source,
+                "params": params,
+            }
+        }
+    })
+}
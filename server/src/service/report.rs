@@ -0,0 +1,126 @@
+use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::DbPool;
+use crate::error::AppError;
+use crate::model::api::{ReportAction, ReportQuery};
+use crate::model::data::{Deletion, DeletionKind, Page, ReportItem, ReportStatus};
+use crate::service::{audit, database, security};
+use crate::service::audit::Action;
+use crate::service::auth::AuthUser;
+use crate::service::store::{ContentStore, Index};
+use crate::utils::cursor;
+
+/// Where a page of the moderation queue continues
+#[derive(Debug, Serialize, Deserialize)]
+struct Cursor {
+    offset: u32,
+}
+
+/**
+ * Report a post or comment that still exists. The queue item is filed under the category
+ * of the post, so its moderators get to see it.
+ * @return The number of reports of the item
+ */
+pub async fn report(store: &dyn ContentStore, pool: DbPool, user_id: String, index: Index, target_id: String, reason: String) -> Result<u32, AppError> {
+
+    let category_id = match index {
+        Index::Post => {
+            let post = store.get_post_source_by_id(target_id.clone()).await?;
+            if post.deleted || !post.published {
+                return Err(AppError::NotFound("Post not found".to_string()));
+            }
+            post.category_id
+        },
+        Index::Comment => {
+            let comment = store.get_comment_source_by_id(target_id.clone()).await?;
+            if comment.deleted {
+                return Err(AppError::NotFound("Comment not found".to_string()));
+            }
+            store.get_post_source_by_id(comment.post_id).await?.category_id
+        },
+    };
+
+    database::save_report(pool, user_id, target_id, index.as_str(), category_id, reason).await
+}
+
+/**
+ * A page of the moderation queue. Admins see every category, moderators only the ones they moderate.
+ * @param limit The validated page size
+ */
+pub async fn list(pool: DbPool, user: &AuthUser, query: &ReportQuery, limit: u32) -> Result<Page<ReportItem>, AppError> {
+
+    let category_ids = match (user.is_admin(), &query.category_id) {
+        (true, category_id) => category_id.clone().map(|id| vec![id]),
+        (false, category_id) => {
+            let moderated = database::find_moderated_category_ids(pool.clone(), user.id.clone()).await?;
+            match category_id {
+                _ if moderated.is_empty() => return Err(AppError::Forbidden("Forbidden".to_string())),
+                Some(id) if !moderated.contains(id) => return Err(AppError::Forbidden("Forbidden".to_string())),
+                Some(id) => Some(vec![id.clone()]),
+                None => Some(moderated),
+            }
+        },
+    };
+
+    let target_type = match &query.target_type {
+        Some(target_type) => Some(target_type.parse::<Index>()?.as_str()),
+        None => None,
+    };
+
+    let offset = match &query.cursor {
+        Some(cursor) => cursor::decode::<Cursor>(cursor)?.offset,
+        None => 0,
+    };
+
+    // One more than asked for tells if there is another page
+    let status = query.status.unwrap_or(ReportStatus::Open).as_str();
+    let mut items = database::find_report_items(pool, Some(status), target_type, category_ids, limit + 1, offset).await?;
+
+    let next_cursor = match items.len() > limit as usize {
+        true => {
+            items.truncate(limit as usize);
+            Some(cursor::encode(&Cursor { offset: offset + limit }))
+        },
+        false => None,
+    };
+
+    Ok(Page { items, next_cursor })
+}
+
+/**
+ * Dismiss the reports of an item or remove the reported post or comment.
+ * Only admins and the moderators of its category can.
+ * @param reason Kept with the removal and in the audit log
+ */
+pub async fn resolve(store: &dyn ContentStore, pool: DbPool, user: &AuthUser, target_id: String, action: ReportAction, reason: Option<String>) -> Result<(), AppError> {
+
+    let item = database::find_report_item(pool.clone(), target_id.clone()).await?;
+
+    if !security::can_moderate(pool.clone(), user, &item.category_id).await? {
+        return Err(AppError::Forbidden("Forbidden".to_string()));
+    }
+    if item.status.ne(ReportStatus::Open.as_str()) {
+        return Err(AppError::Conflict("Report already resolved".to_string()));
+    }
+
+    let (status, audited) = match action {
+        ReportAction::Dismiss => (ReportStatus::Dismissed, Action::DismissReport),
+        ReportAction::Remove => {
+            let index = item.target_type.parse::<Index>()?;
+            let removal = match index {
+                Index::Post => Action::RemovePost,
+                Index::Comment => Action::RemoveComment,
+            };
+
+            let deletion = Deletion::new(DeletionKind::Moderator, Some(user.id.clone()), reason.clone());
+            store.delete_post(index, target_id.clone(), deletion).await?;
+            (ReportStatus::Removed, removal)
+        },
+    };
+
+    database::resolve_report_item(pool.clone(), target_id.clone(), status.as_str(), user.id.clone()).await?;
+
+    let details = json!({ "reports": item.report_count });
+    audit::record(pool, &user.id, audited, &target_id, reason, Some(details)).await
+}
@@ -0,0 +1,242 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::rt::time;
+
+use crate::DbPool;
+use crate::config::RetentionConfig;
+use crate::error::AppError;
+use crate::model::data::PurgeReport;
+use crate::service::database;
+use crate::service::store::{ContentStore, Index};
+
+/// How many deleted posts or comments are purged at a time
+const BATCH_SIZE: u32 = 500;
+
+/**
+ * Purge deleted content every `purge_interval_hours`, starting right away.
+ * A failed purge is logged and tried again at the next interval.
+ */
+pub async fn run(store: Arc<dyn ContentStore>, pool: DbPool, config: RetentionConfig) {
+    let mut interval = time::interval(Duration::from_secs(config.purge_interval_hours as u64 * 60 * 60));
+
+    loop {
+        interval.tick().await;
+
+        match purge(store.as_ref(), pool.clone(), config.purge_after_days, false).await {
+            Ok(report) => println!(
+                "Purged {} posts with {} comments, {} deleted comments and scrubbed {} deleted comments with replies",
+                report.posts.len(), report.orphaned_comments.len(), report.comments.len(), report.scrubbed_comments.len()
+            ),
+            Err(e) => eprintln!("Failed to purge deleted content: {}", e),
+        }
+    }
+}
+
+/**
+ * Permanently remove posts and comments that were deleted more than `days` days ago, with their revisions and votes.
+ * A purged post takes all of its comments with it. A deleted comment that still has replies keeps its place
+ * in the thread, only its text and author are removed.
+ * @param dry_run Only report what would be purged. Since nothing is removed, only the first batch of each kind is looked at.
+ */
+pub async fn purge(store: &dyn ContentStore, pool: DbPool, days: u32, dry_run: bool) -> Result<PurgeReport, AppError> {
+    let deleted_before = chrono::Utc::now() - chrono::Duration::days(days as i64);
+
+    let mut report = PurgeReport {
+        dry_run,
+        deleted_before: deleted_before.to_rfc3339(),
+        posts: vec![],
+        orphaned_comments: vec![],
+        comments: vec![],
+        scrubbed_comments: vec![],
+    };
+    let deleted_before = deleted_before.timestamp_millis();
+
+    loop {
+        let post_ids = store.find_deleted(Index::Post, deleted_before, BATCH_SIZE).await?;
+        let comment_ids = store.find_comment_ids_by_post_ids(post_ids.clone()).await?;
+
+        if !dry_run {
+            // Comments before their posts, so an interrupted purge leaves no orphans behind
+            store.purge(Index::Comment, comment_ids.clone()).await?;
+            store.purge(Index::Post, post_ids.clone()).await?;
+            database::delete_votes(pool.clone(), [post_ids.as_slice(), comment_ids.as_slice()].concat()).await?;
+        }
+
+        let done = dry_run || post_ids.len() < BATCH_SIZE as usize;
+        report.posts.extend(post_ids);
+        report.orphaned_comments.extend(comment_ids);
+
+        if done {
+            break;
+        }
+    }
+
+    loop {
+        let mut comment_ids = store.find_deleted(Index::Comment, deleted_before, BATCH_SIZE).await?;
+        let done = dry_run || comment_ids.len() < BATCH_SIZE as usize;
+
+        // In a dry run the comments of the purged posts are still there
+        comment_ids.retain(|id| !report.orphaned_comments.contains(id));
+
+        let replied = store.find_replied_comment_ids(comment_ids.clone()).await?;
+        let (scrubbed, removed): (Vec<String>, Vec<String>) = comment_ids.into_iter()
+            .partition(|id| replied.contains(id));
+
+        if !dry_run {
+            store.scrub_comments(scrubbed.clone()).await?;
+            store.purge(Index::Comment, removed.clone()).await?;
+            database::delete_votes(pool.clone(), removed.clone()).await?;
+        }
+
+        report.comments.extend(removed);
+        report.scrubbed_comments.extend(scrubbed);
+
+        if done {
+            break;
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use r2d2_sqlite::rusqlite::params;
+
+    use super::*;
+    use crate::model::data::{Deletion, DeletionKind};
+    use crate::service::store::DELETED_AUTHOR;
+    use crate::test_utils::{TestApp, TestUser};
+
+    const DAYS: u32 = 30;
+
+    /// Soft delete a post or comment as if it happened `days_ago` days ago
+    async fn delete(t: &TestApp, index: Index, id: &str, by: &TestUser, days_ago: i64) {
+        let mut deletion = Deletion::new(DeletionKind::Author, Some(by.user.id.clone()), None);
+        deletion.deleted_at = (chrono::Utc::now() - chrono::Duration::days(days_ago)).to_rfc3339();
+        t.store.delete_post(index, id.to_string(), deletion).await.unwrap();
+    }
+
+    async fn vote(t: &TestApp, voter: &TestUser, index: Index, id: &str) {
+        database::set_vote(t.pool.clone(), voter.user.id.clone(), id.to_string(), index.as_str(), 1).await.unwrap();
+    }
+
+    async fn votes_on(t: &TestApp, id: &str) -> u32 {
+        let pool = t.pool.clone();
+        let id = id.to_string();
+        actix_web::web::block(move || {
+            pool.get().unwrap().query_row("SELECT COUNT(*) FROM votes WHERE target_id = ?", params![id], |row| row.get(0))
+        }).await.unwrap().unwrap()
+    }
+
+    fn sorted(mut ids: Vec<String>) -> Vec<String> {
+        ids.sort();
+        ids
+    }
+
+    #[actix_web::test]
+    async fn dry_run_only_reports() {
+        let t = TestApp::new();
+        let author = t.user("author", "user").await;
+        let category = t.category(&author, "rust").await;
+        let post_id = t.post(&author, &category, "Old").await;
+        let comment_id = t.comment(&author, &post_id, None, "On the old post").await;
+        let kept_post_id = t.post(&author, &category, "Kept").await;
+        let deleted_comment_id = t.comment(&author, &kept_post_id, None, "Deleted").await;
+        vote(&t, &author, Index::Post, &post_id).await;
+        delete(&t, Index::Post, &post_id, &author, 40).await;
+        delete(&t, Index::Comment, &deleted_comment_id, &author, 40).await;
+
+        let report = purge(t.store.as_ref(), t.pool.clone(), DAYS, true).await.unwrap();
+
+        assert!(report.dry_run);
+        assert_eq!(report.posts, vec![post_id.clone()]);
+        assert_eq!(report.orphaned_comments, vec![comment_id.clone()]);
+        assert_eq!(report.comments, vec![deleted_comment_id.clone()]);
+        assert!(report.scrubbed_comments.is_empty());
+
+        assert!(t.store.get_post_source_by_id(post_id.clone()).await.is_ok());
+        assert!(t.store.get_comment_source_by_id(comment_id).await.is_ok());
+        assert!(t.store.get_comment_source_by_id(deleted_comment_id).await.is_ok());
+        assert_eq!(votes_on(&t, &post_id).await, 1);
+    }
+
+    #[actix_web::test]
+    async fn purges_old_posts_with_their_comments_and_votes() {
+        let t = TestApp::new();
+        let author = t.user("author", "user").await;
+        let voter = t.user("voter", "user").await;
+        let category = t.category(&author, "rust").await;
+        let old_id = t.post(&author, &category, "Old").await;
+        let recent_id = t.post(&author, &category, "Recent").await;
+        let live_id = t.post(&author, &category, "Live").await;
+        let first = t.comment(&voter, &old_id, None, "First").await;
+        let reply = t.comment(&author, &old_id, Some(&first), "Reply").await;
+        let live_comment = t.comment(&voter, &live_id, None, "Live").await;
+        vote(&t, &voter, Index::Post, &old_id).await;
+        vote(&t, &author, Index::Comment, &first).await;
+        vote(&t, &author, Index::Comment, &live_comment).await;
+        delete(&t, Index::Post, &old_id, &author, 40).await;
+        delete(&t, Index::Post, &recent_id, &author, 1).await;
+
+        let report = purge(t.store.as_ref(), t.pool.clone(), DAYS, false).await.unwrap();
+
+        assert!(!report.dry_run);
+        assert_eq!(report.posts, vec![old_id.clone()]);
+        assert_eq!(sorted(report.orphaned_comments), sorted(vec![first.clone(), reply.clone()]));
+
+        // The post and every one of its comments are gone, not just the deleted ones
+        assert!(matches!(t.store.get_post_source_by_id(old_id.clone()).await, Err(AppError::NotFound(_))));
+        assert!(matches!(t.store.get_comment_source_by_id(first.clone()).await, Err(AppError::NotFound(_))));
+        assert!(matches!(t.store.get_comment_source_by_id(reply).await, Err(AppError::NotFound(_))));
+        assert_eq!(votes_on(&t, &old_id).await, 0);
+        assert_eq!(votes_on(&t, &first).await, 0);
+
+        // Content deleted within the retention period and content that isn't deleted stays
+        assert!(t.store.get_post_source_by_id(recent_id).await.is_ok());
+        assert!(t.store.get_comment_source_by_id(live_comment.clone()).await.is_ok());
+        assert_eq!(votes_on(&t, &live_comment).await, 1);
+    }
+
+    #[actix_web::test]
+    async fn deleted_comments_with_replies_are_scrubbed() {
+        let t = TestApp::new();
+        let author = t.user("author", "user").await;
+        let replier = t.user("replier", "user").await;
+        let category = t.category(&author, "rust").await;
+        let post_id = t.post(&author, &category, "Thread").await;
+        let replied = t.comment(&author, &post_id, None, "Has a reply").await;
+        let reply = t.comment(&replier, &post_id, Some(&replied), "The reply").await;
+        let alone = t.comment(&author, &post_id, None, "No replies").await;
+        vote(&t, &replier, Index::Comment, &replied).await;
+        vote(&t, &replier, Index::Comment, &alone).await;
+        delete(&t, Index::Comment, &replied, &author, 40).await;
+        delete(&t, Index::Comment, &alone, &author, 40).await;
+
+        let report = purge(t.store.as_ref(), t.pool.clone(), DAYS, false).await.unwrap();
+
+        assert!(report.posts.is_empty());
+        assert_eq!(report.comments, vec![alone.clone()]);
+        assert_eq!(report.scrubbed_comments, vec![replied.clone()]);
+
+        let scrubbed = t.store.get_comment_source_by_id(replied.clone()).await.unwrap();
+        assert!(scrubbed.purged);
+        assert!(scrubbed.body.is_empty());
+        assert!(scrubbed.author_id.is_empty());
+        assert_eq!(scrubbed.author_name, DELETED_AUTHOR);
+        assert_eq!(votes_on(&t, &replied).await, 1);
+
+        assert!(matches!(t.store.get_comment_source_by_id(alone.clone()).await, Err(AppError::NotFound(_))));
+        assert_eq!(votes_on(&t, &alone).await, 0);
+
+        let reply = t.store.get_comment_source_by_id(reply).await.unwrap();
+        assert_eq!(reply.body, "The reply");
+        assert_eq!(reply.parent_id, Some(replied));
+
+        // A scrubbed comment is done, the next purge leaves it alone
+        let report = purge(t.store.as_ref(), t.pool.clone(), DAYS, false).await.unwrap();
+        assert!(report.comments.is_empty());
+        assert!(report.scrubbed_comments.is_empty());
+    }
+}
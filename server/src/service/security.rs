@@ -1,139 +1,217 @@
 use crate::DbPool;
+use crate::config::{LimitConfig, TokenConfig, UploadConfig};
+use crate::error::AppError;
 use crate::model::api::{Claims, UserRequest, QueryParams};
-use crate::service::database;
-use actix_web::HttpRequest;
-use actix_web::web::Query;
+use crate::model::data::{Deletion, DeletionKind, User};
+use crate::service::{ban, database, throttle};
+use crate::service::auth::{AuthUser, OptionalUser};
+use crate::service::keys::KeyStore;
 use actix_multipart::form::tempfile::TempFile;
-use jsonwebtoken::{encode, Header, DecodingKey, EncodingKey, Algorithm};
 use chrono;
 use bcrypt::verify;
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use rand::Rng;
+use ring::digest;
 use image::io::Reader;
 use std::fs;
 use std::io::Cursor;
 
 
-pub async fn login(pool: DbPool, login: &UserRequest) -> Result<String, String> {
+/// The tokens of a session: a short lived JWT that authenticates requests and an opaque token to get the next one
+pub struct SessionTokens {
+    pub access_token: String,
+    pub refresh_token: String,
+}
 
-    
+/**
+ * Check a username and password
+ * @return The user they belong to
+ */
+pub async fn login(pool: DbPool, login: &UserRequest) -> Result<User, AppError> {
+
+    // Unknown users get the same answer as wrong passwords
     let user = match database::find_user_by_username(pool, login.username.to_string()).await {
         Ok(user) => user,
-        Err(e) => return Err(e.to_string()),
+        Err(AppError::NotFound(_)) => return Err(AppError::Unauthorized("Invalid credentials".to_string())),
+        Err(e) => return Err(e),
     };
     
-    let password_matches = match verify(&login.password, &user.password) {
-        Ok(password_matches) => password_matches,
-        Err(_) => return Err("Invalid credentials".to_string()),
-    };
+    match check_password(&user, &login.password) {
+        true => Ok(user),
+        false => Err(AppError::Unauthorized("Invalid credentials".to_string())),
+    }
+}
 
-    if !password_matches {
-        return Err("Invalid credentials".to_string());
+/**
+ * Ask a logged in user for their password again before changing the account.
+ * Wrong passwords count as failed logins, so a stolen session can't be used to guess it.
+ */
+pub async fn confirm_password(pool: DbPool, limits: &LimitConfig, user: &User, password: &str, ip: &str) -> Result<(), AppError> {
+    throttle::check_login(pool.clone(), limits, &user.username, ip).await?;
+
+    match check_password(user, password) {
+        true => throttle::clear_login_failures(pool, &user.username).await,
+        false => {
+            throttle::record_login_failure(pool, limits, &user.username, ip).await?;
+            Err(AppError::Forbidden("Invalid password".to_string()))
+        },
     }
-    
+}
+
+fn check_password(user: &User, password: &str) -> bool {
+    verify(password, &user.password).unwrap_or(false)
+}
+
+/**
+ * Start a new session for a user that just logged in. Users that are banned from the site can't.
+ * @param user_agent The device the session is for, so users can tell their sessions apart
+ */
+pub async fn start_session(pool: DbPool, keys: &KeyStore, tokens: &TokenConfig, user: &User, user_agent: Option<String>) -> Result<SessionTokens, AppError> {
+    ban::check(pool.clone(), &user.id, None).await?;
+
+    let refresh_token = new_refresh_token();
+
+    let session_id = database::create_session(pool, user.id.clone(), hash_token(&refresh_token), user_agent, tokens.refresh_lifetime_days).await?;
+
+    Ok(SessionTokens {
+        access_token: access_token(keys, tokens, user, session_id)?,
+        refresh_token,
+    })
+}
+
+/**
+ * Trade a refresh token for a new access token and a new refresh token.
+ * The old refresh token stops working, using it again ends the session.
+ */
+pub async fn refresh_session(pool: DbPool, keys: &KeyStore, tokens: &TokenConfig, refresh_token: &str) -> Result<SessionTokens, AppError> {
+    let new_refresh_token = new_refresh_token();
+
+    let session = database::rotate_session(pool.clone(), hash_token(refresh_token), hash_token(&new_refresh_token), tokens.refresh_lifetime_days).await?;
+
+    // Read the user again, the name or role may have changed since the last token
+    let user = match database::find_user_by_id(pool.clone(), session.user_id).await {
+        Ok(user) => user,
+        Err(AppError::NotFound(_)) => return Err(AppError::Unauthorized("User not found".to_string())),
+        Err(e) => return Err(e),
+    };
+
+    // A ban ends the session at the next refresh at the latest
+    ban::check(pool, &user.id, None).await?;
+
+    Ok(SessionTokens {
+        access_token: access_token(keys, tokens, &user, session.id)?,
+        refresh_token: new_refresh_token,
+    })
+}
+
+/**
+ * End the session a refresh token belongs to. Its access token stays valid until it expires.
+ */
+pub async fn end_session(pool: DbPool, refresh_token: &str) -> Result<(), AppError> {
+    database::revoke_session_by_token(pool, hash_token(refresh_token)).await
+}
+
+fn access_token(keys: &KeyStore, tokens: &TokenConfig, user: &User, session_id: String) -> Result<String, AppError> {
+
     // Token claims (payload)
     let claims = Claims {
         sub: user.id.to_string(),
         iss: "tidders".to_string(),
-        exp: (chrono::Utc::now() + chrono::Duration::days(365)).timestamp() as usize,
+        exp: (chrono::Utc::now() + chrono::Duration::minutes(tokens.access_lifetime_minutes)).timestamp() as usize,
         iat: chrono::Utc::now().timestamp() as usize,
         username: user.username.to_string(),
-        role: user.role,
+        role: user.role.to_string(),
+        sid: session_id,
     };
     
     // Create a JWT token
-    let token = encode(
-        &Header::new(Algorithm::RS256), 
-        &claims, 
-        &EncodingKey::from_rsa_pem(include_bytes!("../security/keystore/privkey.pem"))
-            .expect("Failed to create JWT")
-    ).expect("Failed to create JWT");
-
-    Ok(token)
+    keys.sign(&claims)
 }
 
-pub fn verify_user(req: &HttpRequest) -> Result<(String, String), &'static str> {
-    // Get the cookie from the request
-    let cookie = match req.cookie("identity") {
-        Some(cookie) => cookie,
-        None => return Err("Unauthorized"),
-    };
+/// 256 random bits, there is nothing to guess
+fn new_refresh_token() -> String {
+    let bytes: [u8; 32] = rand::thread_rng().gen();
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/**
+ * Refresh tokens are random, so a fast hash is enough to make a leaked sessions table useless
+ */
+fn hash_token(token: &str) -> String {
+    URL_SAFE_NO_PAD.encode(digest::digest(&digest::SHA256, token.as_bytes()))
+}
+
+/**
+ * Check if deleted content should be shown as it is. It has to be asked for, and only moderators get it.
+ * @param category_id The category the content is in, without one only admins qualify
+ */
+pub async fn will_show_all(pool: DbPool, query: &QueryParams, user: &OptionalUser, category_id: Option<&str>) -> Result<bool, AppError> {
+    match (query.show_all.unwrap_or(false), &user.0, category_id) {
+        (false, _, _) | (true, None, _) => Ok(false),
+        (true, Some(user), Some(category_id)) => can_moderate(pool, user, category_id).await,
+        (true, Some(user), None) => Ok(user.is_admin()),
+    }
+}
 
-    // Verify the JWT token
-    let token = cookie.value();
-    let token_data = jsonwebtoken::decode::<Claims>(
-        &token,
-        &DecodingKey::from_rsa_pem(include_bytes!("../security/keystore/pubkey.pem"))
-            .expect("Failed to read public key"),
-        &jsonwebtoken::Validation::new(Algorithm::RS256),
-    );
-
-    match token_data {
-        Ok(token_data) => Ok((token_data.claims.sub, token_data.claims.role)),
-        Err(_) => Err("Unauthorized"),
+/**
+ * Check if a user may moderate a category. Admins moderate every category, moderators the ones they were assigned.
+ */
+pub async fn can_moderate(pool: DbPool, user: &AuthUser, category_id: &str) -> Result<bool, AppError> {
+    match user.is_admin() {
+        true => Ok(true),
+        false => database::is_moderator(pool, user.id.clone(), category_id.to_string()).await,
     }
 }
 
 /**
- * Check if the show_all query parameter is set to true and return true if the user is an admin
- * @param query The query parameters
- * @param req The HTTP request
+ * Check if a user may restore a deleted post or comment. What a moderator removed can be restored by the
+ * moderators of the category, what the author deleted only by admins. Deletions from before the kind was
+ * recorded count as the author's.
  */
-pub fn will_show_all(query: Query<QueryParams>, req: &HttpRequest) -> bool {
-    match query.show_all.unwrap_or(false) {
-        true => match verify_user(req) {
-            Ok((_, role)) => match role.eq("admin") {
-                true => true,
-                false => false,
-            },
-            Err(_) => false,
-        },
-        false => false,
+pub async fn can_restore(pool: DbPool, user: &AuthUser, category_id: &str, deletion: Option<&Deletion>) -> Result<bool, AppError> {
+    match deletion.map(|deletion| deletion.kind) {
+        Some(DeletionKind::Moderator) => can_moderate(pool, user, category_id).await,
+        _ => Ok(user.is_admin()),
     }
 }
 
-pub fn validate_image(image: TempFile, user_id: String) -> Result<(String, Vec<u8>), String> {
+pub fn validate_image(image: TempFile, user_id: String, uploads: &UploadConfig, tmp_dir: &str) -> Result<(String, Vec<u8>), AppError> {
     
     // Validate file size
-    if image.size > 5_000_000 {
-        return Err("File size must be less than 5MB".to_string());
+    if image.size > uploads.max_avatar_size {
+        return Err(AppError::UnsupportedMediaType(format!("File size must be at most {} bytes", uploads.max_avatar_size)));
     }
 
     // Validate file name
     let re = regex::Regex::new(r"^[a-zA-Z0-9_]+\.(png|jpg|jpeg|gif)$").unwrap();
-    if !re.is_match(&image.file_name.unwrap()) {
-        return Err("Illegal file name".to_string());
+    if !re.is_match(&image.file_name.unwrap_or_default()) {
+        return Err(AppError::UnsupportedMediaType("Illegal file name".to_string()));
     };
 
     // Save image in tmp directory so it can be processed as bytes.
     // It is saved with no extension so that the image library can guess the format
-    let tmp_path = format!("tmp/{}", user_id);
-    image.file.persist(&tmp_path).unwrap();
+    let tmp_path = format!("{}/{}", tmp_dir, user_id);
+    image.file.persist(&tmp_path).map_err(|e| AppError::Internal(format!("Failed to save upload: {}", e)))?;
     
     // Read image from tmp directory
-    let img = fs::read(&tmp_path).unwrap();
-    let reader = Reader::new(Cursor::new(&img)).with_guessed_format().unwrap();
+    let img = fs::read(&tmp_path);
+
+    // Delete the image from the tmp directory
+    fs::remove_file(&tmp_path)?;
+
+    let img = img?;
+    let reader = Reader::new(Cursor::new(&img)).with_guessed_format()?;
     
     // Validate mime type
     let extension = match reader.format() {
-        Some(extension) => match extension {
-            image::ImageFormat::Png => "png",
-            image::ImageFormat::Jpeg => "jpeg",
-            image::ImageFormat::Gif => "gif",
-            _ => return {
-                fs::remove_file(&tmp_path).unwrap();
-                Err("Image must be a png, jpg or gif".to_string())
-            },
-        },
-        None => {
-            fs::remove_file(&tmp_path).unwrap();
-            return Err("Image must be a png, jpg or gif".to_string())
-        },
+        Some(image::ImageFormat::Png) => "png",
+        Some(image::ImageFormat::Jpeg) => "jpeg",
+        Some(image::ImageFormat::Gif) => "gif",
+        _ => return Err(AppError::UnsupportedMediaType("Image must be a png, jpg or gif".to_string())),
     };
 
-    // Delete the image from the tmp directory
-    fs::remove_file(&tmp_path).unwrap();
-
     // Add the extension to the file
     let filename = format!("{}.{}", user_id, extension);
 
     Ok((filename, img))
-}
\ No newline at end of file
+}
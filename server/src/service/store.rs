@@ -0,0 +1,163 @@
+use std::str::FromStr;
+
+use async_trait::async_trait;
+
+use crate::error::AppError;
+use crate::model::data::{Category, Post, PostType, Comment, Deletion, Restoration, Revision, Page};
+use crate::service::ranking::Ranking;
+
+/// Shown as the author of posts and comments whose account was deleted
+pub const DELETED_AUTHOR: &str = "[deleted]";
+
+pub enum Index {
+    Post,
+    Comment,
+}
+
+impl Index {
+    /// How posts and comments are told apart in SQLite
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Index::Post => "post",
+            Index::Comment => "comment",
+        }
+    }
+}
+
+impl FromStr for Index {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Index, AppError> {
+        match s {
+            "post" => Ok(Index::Post),
+            "comment" => Ok(Index::Comment),
+            other => Err(AppError::BadRequest(format!("Unknown type \"{}\", expected post or comment", other))),
+        }
+    }
+}
+
+/**
+ * Storage for categories, posts, comments and their edit history.
+ * Listings take a page size and the cursor returned with the previous page.
+ * Posts and comments come back with the body rendered and sanitized,
+ * except from the `*_source_by_id` functions which return them as stored.
+ * Looking up something that doesn't exist fails with `AppError::NotFound`.
+ */
+#[async_trait]
+pub trait ContentStore: Send + Sync {
+
+    async fn get_categories(&self) -> Result<Vec<Category>, AppError>;
+
+    async fn get_category_by_id(&self, category_id: &str) -> Result<Category, AppError>;
+
+    /// Find a category by its normalized name, see `Category::normalize_name`
+    async fn get_category_by_name(&self, category_name: String) -> Result<Category, AppError>;
+
+    async fn count_posts_by_category_id(&self, category_id: String) -> Result<u64, AppError>;
+
+    /// Store a new category, fails with `AppError::Conflict` if the normalized name is taken
+    async fn index_category(&self, category: Category) -> Result<Category, AppError>;
+
+    async fn update_category(&self, category_id: String, description: String, rules: String, post_types: Vec<PostType>) -> Result<(), AppError>;
+
+    async fn index_post(&self, post: Post) -> Result<Post, AppError>;
+
+    async fn publish_post(&self, post_id: String) -> Result<(), AppError>;
+
+    /// Lock or unlock the comments of a post
+    async fn lock_post(&self, post_id: String, locked: bool) -> Result<(), AppError>;
+
+    async fn update_post(&self, post_id: String, title: String, body: String, updated_at: String) -> Result<(), AppError>;
+
+    async fn update_comment(&self, comment_id: String, body: String, edited_at: String) -> Result<(), AppError>;
+
+    /// Store the version of a post or comment that is about to be replaced by an edit
+    async fn index_revision(&self, index: Index, revision: Revision) -> Result<(), AppError>;
+
+    /// Earlier versions of a post or comment, oldest first
+    async fn get_revisions(&self, index: Index, target_id: String) -> Result<Vec<Revision>, AppError>;
+
+    /// Soft delete a post or comment, replacing any earlier deletion and restoration
+    async fn delete_post(&self, index: Index, post_id: String, deletion: Deletion) -> Result<(), AppError>;
+
+    /// Undo the soft delete of a post or comment, the deletion is kept for the record
+    async fn restore_post(&self, index: Index, post_id: String, restoration: Restoration) -> Result<(), AppError>;
+
+    /**
+     * Posts or comments that were deleted before the given time, oldest deletions first.
+     * Purged comments are left out. Deletions from before their time was recorded count from the last update.
+     * @param deleted_before Time in milliseconds
+     * @return The ids of at most `limit` of them
+     */
+    async fn find_deleted(&self, index: Index, deleted_before: i64, limit: u32) -> Result<Vec<String>, AppError>;
+
+    /// The ids of every comment of the given posts
+    async fn find_comment_ids_by_post_ids(&self, post_ids: Vec<String>) -> Result<Vec<String>, AppError>;
+
+    /// The given comments that have at least one reply
+    async fn find_replied_comment_ids(&self, comment_ids: Vec<String>) -> Result<Vec<String>, AppError>;
+
+    /// Permanently remove posts or comments together with their revisions
+    async fn purge(&self, index: Index, ids: Vec<String>) -> Result<(), AppError>;
+
+    /// Remove the text, author and revisions of deleted comments for good, they keep their place in the thread
+    async fn scrub_comments(&self, comment_ids: Vec<String>) -> Result<(), AppError>;
+
+    /// Atomically adjust the vote counters of a post or comment and return the new (upvotes, downvotes)
+    async fn update_votes(&self, index: Index, id: String, upvotes: i64, downvotes: i64) -> Result<(u32, u32), AppError>;
+
+    /// Upvotes minus downvotes of all posts or comments of each author, as (author id, karma)
+    async fn sum_karma(&self, index: Index) -> Result<Vec<(String, i64)>, AppError>;
+
+    async fn index_comment(&self, comment: Comment) -> Result<Comment, AppError>;
+
+    /// Show a new username on every post and comment of a user
+    async fn rename_author(&self, author_id: String, author_name: String) -> Result<(), AppError>;
+
+    /**
+     * Detach every post and comment of a deleted user from the account
+     * @param remove_content Soft delete them as well, as deleted by the author
+     */
+    async fn remove_author(&self, author_id: String, remove_content: bool) -> Result<(), AppError>;
+
+    async fn flush_data(&self) -> Result<(), AppError>;
+
+    async fn get_post_source_by_id(&self, id: String) -> Result<Post, AppError>;
+
+    async fn get_comment_source_by_id(&self, comment_id: String) -> Result<Comment, AppError>;
+
+    /// Every post of an author as stored, drafts and deleted ones included, oldest first
+    async fn get_post_sources_by_author_id(&self, author_id: String, limit: u32, cursor: &Option<String>) -> Result<Page<Post>, AppError>;
+
+    /// Every comment of an author as stored, deleted ones included, oldest first
+    async fn get_comment_sources_by_author_id(&self, author_id: String, limit: u32, cursor: &Option<String>) -> Result<Page<Comment>, AppError>;
+
+    async fn get_posts_by_category_id(&self, category_id: String, show_all: &bool, ranking: &Ranking, limit: u32, cursor: &Option<String>) -> Result<Page<Post>, AppError>;
+
+    async fn get_posts(&self, show_all: &bool, ranking: &Ranking, limit: u32, cursor: &Option<String>) -> Result<Page<Post>, AppError>;
+
+    async fn get_post_by_id(&self, id: String, show_all: &bool) -> Result<Post, AppError>;
+
+    async fn get_posts_by_user_id(&self, user_id: String, limit: u32, cursor: &Option<String>) -> Result<Page<Post>, AppError>;
+
+    /// The published posts of an author that aren't deleted, newest first
+    async fn get_published_posts_by_author_id(&self, author_id: String, limit: u32, cursor: &Option<String>) -> Result<Page<Post>, AppError>;
+
+    /// The comments of an author that aren't deleted, on posts that are published and not deleted, newest first
+    async fn get_comments_by_author_id(&self, author_id: String, limit: u32, cursor: &Option<String>) -> Result<Page<Comment>, AppError>;
+
+    /// A page of top level comments of a post together with all of their replies
+    async fn get_comments_by_post_id(&self, post_id: String, show_all: &bool, ranking: &Ranking, limit: u32, cursor: &Option<String>) -> Result<Page<Comment>, AppError>;
+
+    async fn get_comment_by_id(&self, comment_id: String, show_all: &bool) -> Result<Comment, AppError>;
+}
+
+/**
+ * Full text search over categories and published posts
+ */
+#[async_trait]
+pub trait SearchIndex: Send + Sync {
+
+    /// @return The matching categories and posts of the page and the cursor for the next page
+    async fn search(&self, query: &str, limit: u32, cursor: &Option<String>) -> Result<(Vec<Category>, Vec<Post>, Option<String>), AppError>;
+}
@@ -0,0 +1,165 @@
+use actix_web::HttpRequest;
+
+use crate::DbPool;
+use crate::config::{Backoff, Config, LimitConfig};
+use crate::error::AppError;
+use crate::model::data::LoginAttempts;
+use crate::service::database;
+
+/*
+ * Brute force protection for login and registration. Attempts are counted in SQLite,
+ * so waiting times and locks survive a restart. Every check happens before the password
+ * is hashed or verified, so a client that has to wait doesn't cost any bcrypt rounds.
+ */
+
+/**
+ * The address a request comes from. Forwarded headers are only used when the config trusts them,
+ * otherwise anyone could pick a new address for every attempt.
+ */
+pub fn client_ip(req: &HttpRequest, config: &Config) -> String {
+    let info = req.connection_info();
+    let ip = match config.server.trust_proxy {
+        true => info.realip_remote_addr(),
+        false => info.peer_addr(),
+    };
+
+    ip.unwrap_or("unknown").to_string()
+}
+
+/// The key the failed logins of an account are counted under
+pub fn account_key(username: &str) -> String {
+    format!("login:user:{}", username.to_lowercase())
+}
+
+fn login_address_key(ip: &str) -> String {
+    format!("login:ip:{}", ip)
+}
+
+fn register_address_key(ip: &str) -> String {
+    format!("register:ip:{}", ip)
+}
+
+/**
+ * Check that a login for the username from the address may be attempted right now
+ * @return TooManyRequests with the number of seconds to wait if not
+ */
+pub async fn check_login(pool: DbPool, limits: &LimitConfig, username: &str, ip: &str) -> Result<(), AppError> {
+    check(pool, &limits.login, vec![account_key(username), login_address_key(ip)]).await
+}
+
+/**
+ * Count a failed login for both the account and the address. The account is locked after too many.
+ */
+pub async fn record_login_failure(pool: DbPool, limits: &LimitConfig, username: &str, ip: &str) -> Result<(), AppError> {
+    let now = chrono::Utc::now().timestamp();
+    let reset_after = limits.login.reset_after_minutes * 60;
+
+    database::record_login_attempt(pool.clone(), login_address_key(ip), now, reset_after, None).await?;
+    database::record_login_attempt(pool, account_key(username), now, reset_after, Some((limits.lock_after, limits.lock_minutes * 60))).await
+}
+
+/**
+ * Forget the failed logins of an account after a successful one.
+ * The address keeps its count, otherwise logging into an own account would reset it between guesses.
+ */
+pub async fn clear_login_failures(pool: DbPool, username: &str) -> Result<(), AppError> {
+    database::clear_login_attempts(pool, account_key(username)).await
+}
+
+pub async fn check_register(pool: DbPool, limits: &LimitConfig, ip: &str) -> Result<(), AppError> {
+    check(pool, &limits.register, vec![register_address_key(ip)]).await
+}
+
+/**
+ * Count a registration attempt, whether it succeeds or not
+ */
+pub async fn record_registration(pool: DbPool, limits: &LimitConfig, ip: &str) -> Result<(), AppError> {
+    let now = chrono::Utc::now().timestamp();
+    database::record_login_attempt(pool, register_address_key(ip), now, limits.register.reset_after_minutes * 60, None).await
+}
+
+async fn check(pool: DbPool, backoff: &Backoff, keys: Vec<String>) -> Result<(), AppError> {
+    let now = chrono::Utc::now().timestamp();
+
+    let wait = database::find_login_attempts(pool, keys).await?
+        .iter()
+        .map(|attempts| wait_seconds(attempts, backoff, now))
+        .max()
+        .unwrap_or(0);
+
+    match wait {
+        0 => Ok(()),
+        wait => Err(AppError::TooManyRequests("Too many attempts, try again later".to_string(), wait as u64)),
+    }
+}
+
+/**
+ * How long to wait before the next attempt. A lock always applies, the backoff only while
+ * the attempts are recent: base delay, doubled for every attempt after the free ones, up to the maximum.
+ */
+fn wait_seconds(attempts: &LoginAttempts, backoff: &Backoff, now: i64) -> i64 {
+    let locked = attempts.locked_until.map_or(0, |until| until - now);
+
+    let recent = attempts.last_attempt_at >= now - backoff.reset_after_minutes * 60;
+    let delay = match attempts.attempts.checked_sub(backoff.free_attempts) {
+        Some(extra) if recent => backoff.base_delay_seconds
+            .saturating_mul(1i64 << extra.min(32))
+            .min(backoff.max_delay_seconds),
+        _ => 0,
+    };
+    let backed_off = attempts.last_attempt_at + delay - now;
+
+    locked.max(backed_off).max(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 1_700_000_000;
+
+    fn backoff() -> Backoff {
+        Backoff { free_attempts: 5, base_delay_seconds: 10, max_delay_seconds: 100, reset_after_minutes: 60 }
+    }
+
+    fn attempts(attempts: u32, seconds_ago: i64, locked_until: Option<i64>) -> LoginAttempts {
+        LoginAttempts { attempts, last_attempt_at: NOW - seconds_ago, locked_until }
+    }
+
+    #[test]
+    fn free_attempts_have_no_wait() {
+        assert_eq!(wait_seconds(&attempts(0, 0, None), &backoff(), NOW), 0);
+        assert_eq!(wait_seconds(&attempts(4, 0, None), &backoff(), NOW), 0);
+    }
+
+    #[test]
+    fn wait_doubles_after_the_free_attempts() {
+        assert_eq!(wait_seconds(&attempts(5, 0, None), &backoff(), NOW), 10);
+        assert_eq!(wait_seconds(&attempts(6, 0, None), &backoff(), NOW), 20);
+        assert_eq!(wait_seconds(&attempts(7, 0, None), &backoff(), NOW), 40);
+    }
+
+    #[test]
+    fn wait_is_capped() {
+        assert_eq!(wait_seconds(&attempts(9, 0, None), &backoff(), NOW), 100);
+        assert_eq!(wait_seconds(&attempts(u32::MAX, 0, None), &backoff(), NOW), 100);
+    }
+
+    #[test]
+    fn wait_counts_from_the_last_attempt() {
+        assert_eq!(wait_seconds(&attempts(6, 5, None), &backoff(), NOW), 15);
+        assert_eq!(wait_seconds(&attempts(6, 30, None), &backoff(), NOW), 0);
+    }
+
+    #[test]
+    fn old_attempts_are_forgotten() {
+        assert_eq!(wait_seconds(&attempts(20, 60 * 60 + 1, None), &backoff(), NOW), 0);
+    }
+
+    #[test]
+    fn lock_applies_even_without_recent_attempts() {
+        assert_eq!(wait_seconds(&attempts(20, 2 * 60 * 60, Some(NOW + 300)), &backoff(), NOW), 300);
+        assert_eq!(wait_seconds(&attempts(7, 0, Some(NOW + 5)), &backoff(), NOW), 40);
+        assert_eq!(wait_seconds(&attempts(0, 0, Some(NOW - 5)), &backoff(), NOW), 0);
+    }
+}
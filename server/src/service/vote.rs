@@ -0,0 +1,62 @@
+use crate::DbPool;
+use crate::error::AppError;
+use crate::service::database;
+use crate::service::store::{ContentStore, Index};
+
+/**
+ * Cast, change or clear a user's vote on a post or comment.
+ * The per-user ledger in SQLite decides how the counters move, so voting twice
+ * in the same direction is a no-op. If the counters can't be updated the ledger
+ * is rolled back to the previous vote. The karma of the author moves along with the counters.
+ * @param author_id The author of the post or comment
+ * @param value 1 for an upvote, -1 for a downvote and 0 to clear the vote
+ * @return The new (upvotes, downvotes)
+ */
+pub async fn cast_vote(store: &dyn ContentStore, pool: DbPool, user_id: String, author_id: String, index: Index, target_id: String, value: i8) -> Result<(u32, u32), AppError> {
+
+    let target_type = index.as_str();
+
+    let previous = database::set_vote(pool.clone(), user_id.clone(), target_id.clone(), target_type, value).await?;
+
+    let (upvotes, downvotes) = vote_delta(previous, value);
+
+    let karma = upvotes - downvotes;
+    let (post_karma, comment_karma) = match index {
+        Index::Post => (karma, 0),
+        Index::Comment => (0, karma),
+    };
+
+    match store.update_votes(index, target_id.clone(), upvotes, downvotes).await {
+        Ok(counts) => {
+            // The vote counts either way, karma that drifts is fixed by `tidder recompute-karma`
+            if karma != 0 {
+                if let Err(e) = database::add_karma(pool, author_id, post_karma, comment_karma).await {
+                    eprintln!("Failed to update karma: {}", e);
+                }
+            }
+            Ok(counts)
+        },
+        Err(e) => {
+            if let Err(rollback) = database::set_vote(pool, user_id, target_id, target_type, previous).await {
+                eprintln!("Failed to roll back vote: {}", rollback);
+            }
+            Err(e)
+        }
+    }
+}
+
+/**
+ * Work out how the (upvotes, downvotes) counters change when a vote goes from `previous` to `value`
+ */
+fn vote_delta(previous: i8, value: i8) -> (i64, i64) {
+    let count = |vote: i8| match vote {
+        1 => (1, 0),
+        -1 => (0, 1),
+        _ => (0, 0),
+    };
+
+    let (old_up, old_down) = count(previous);
+    let (new_up, new_down) = count(value);
+
+    (new_up - old_up, new_down - old_down)
+}
@@ -0,0 +1,197 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
+
+use actix_web::cookie::Cookie;
+use actix_web::web;
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+
+use crate::{DbPool, Keys};
+use crate::config::{Config, KeyConfig};
+use crate::model::data::{Category, Comment, Post, PostType, User};
+use crate::service::{category, database, migrations, security};
+use crate::service::keys::{self, KeyStore};
+use crate::service::memory::MemoryStore;
+use crate::service::store::{ContentStore, SearchIndex};
+use crate::service::security::SessionTokens;
+
+/*
+ * What handler tests need to run the real routes: a migrated SQLite database in its own directory,
+ * a key store and an empty memory store. Everything is registered as `app_data` the way main does it,
+ * so a test only adds the services it calls.
+ */
+
+/// Generating an RSA key takes a while without optimizations, so every test signs with the same one
+static KEY_DIR: OnceLock<String> = OnceLock::new();
+
+pub struct TestApp {
+    dir: PathBuf,
+    pub config: Config,
+    pub pool: DbPool,
+    pub keys: Keys,
+    pub store: Arc<MemoryStore>,
+}
+
+/// A user with a live session, the tokens are what the session cookies would hold
+pub struct TestUser {
+    pub user: User,
+    pub tokens: SessionTokens,
+}
+
+impl TestApp {
+    pub fn new() -> TestApp {
+        let dir = std::env::temp_dir().join(format!("tidder-test-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).expect("Failed to create test directory");
+
+        let mut config = Config::default();
+        config.storage.database_dir = dir.join("database").to_string_lossy().to_string();
+        config.storage.public_dir = dir.join("public").to_string_lossy().to_string();
+        config.storage.tmp_dir = dir.join("tmp").to_string_lossy().to_string();
+        config.keys = KeyConfig { dir: key_dir().to_string(), signing_kid: None };
+        // Every request comes from the same address, tests register more users than a client may
+        config.limits.register.free_attempts = 1000;
+        fs::create_dir_all(&config.storage.database_dir).expect("Failed to create database directory");
+
+        let pool = Pool::builder()
+            .build(SqliteConnectionManager::file(config.storage.database_path()))
+            .expect("Failed to create pool");
+        migrations::run(&mut pool.get().expect("Failed to open database")).expect("Failed to migrate database");
+
+        let keys = KeyStore::load(&config.keys).expect("Failed to load test keys");
+
+        TestApp {
+            dir,
+            config,
+            pool: web::Data::new(pool),
+            keys: web::Data::new(keys),
+            store: Arc::new(MemoryStore::new()),
+        }
+    }
+
+    pub fn configure(&self, cfg: &mut web::ServiceConfig) {
+        cfg.app_data(web::Data::new(self.config.clone()))
+            .app_data(self.keys.clone())
+            .app_data(self.pool.clone())
+            .app_data(web::Data::from(self.store.clone() as Arc<dyn ContentStore>))
+            .app_data(web::Data::from(self.store.clone() as Arc<dyn SearchIndex>));
+    }
+
+    /**
+     * Create a user with `PASSWORD` and log them in. The password hash is the cheapest bcrypt allows.
+     */
+    pub async fn user(&self, username: &str, role: &str) -> TestUser {
+        let password = bcrypt::hash(PASSWORD, 4).expect("Failed to hash password");
+        let id = database::save_user(self.pool.clone(), username.to_string(), password).await.expect("Failed to create user");
+
+        if role != "user" {
+            let pool = self.pool.clone();
+            let role = role.to_string();
+            let user_id = id.clone();
+            web::block(move || {
+                pool.get().unwrap().execute("UPDATE users SET role = ? WHERE id = ?", [role, user_id])
+            }).await.unwrap().expect("Failed to set role");
+        }
+
+        let user = database::find_user_by_id(self.pool.clone(), id).await.expect("Failed to read user");
+        let tokens = security::start_session(self.pool.clone(), &self.keys, &self.config.tokens, &user, None).await
+            .expect("Failed to start session");
+
+        TestUser { user, tokens }
+    }
+
+    pub async fn category(&self, owner: &TestUser, name: &str) -> Category {
+        category::create_category(self.store.as_ref(), self.pool.clone(), owner.user.id.clone(), name.to_string(), String::new(), String::new(), PostType::all()).await
+            .expect("Failed to create category")
+    }
+
+    /**
+     * Publish a text post straight to the store
+     * @return The id of the post
+     */
+    pub async fn post(&self, author: &TestUser, category: &Category, title: &str) -> String {
+        let now = chrono::Utc::now().to_rfc3339();
+        let post = Post {
+            id: None,
+            author_id: author.user.id.clone(),
+            author_name: author.user.username.clone(),
+            category_id: category.id.clone(),
+            category_name: category.name.clone(),
+            post_type: PostType::default(),
+            title: title.to_string(),
+            body: String::new(),
+            upvotes: 0,
+            downvotes: 0,
+            published: true,
+            created_at: now.clone(),
+            updated_at: now,
+            deleted: false,
+            deletion: None,
+            restoration: None,
+            locked: false,
+        };
+
+        self.store.index_post(post).await.expect("Failed to create post").id.expect("Post has no id")
+    }
+
+    /**
+     * Comment on a post straight in the store, replies only go to top-level comments
+     * @return The id of the comment
+     */
+    pub async fn comment(&self, author: &TestUser, post_id: &str, parent_id: Option<&str>, body: &str) -> String {
+        let now = chrono::Utc::now().to_rfc3339();
+        let comment = Comment {
+            id: None,
+            author_id: author.user.id.clone(),
+            author_name: author.user.username.clone(),
+            post_id: post_id.to_string(),
+            parent_id: parent_id.map(|id| id.to_string()),
+            root_id: parent_id.map(|id| id.to_string()),
+            depth: parent_id.map_or(0, |_| 1),
+            body: body.to_string(),
+            deleted: false,
+            deletion: None,
+            restoration: None,
+            purged: false,
+            upvotes: 0,
+            downvotes: 0,
+            created_at: now.clone(),
+            updated_at: now,
+            edited_at: None,
+        };
+
+        self.store.index_comment(comment).await.expect("Failed to create comment").id.expect("Comment has no id")
+    }
+
+    pub async fn find_user(&self, id: &str) -> User {
+        database::find_user_by_id(self.pool.clone(), id.to_string()).await.expect("Failed to read user")
+    }
+}
+
+impl Drop for TestApp {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+impl TestUser {
+    pub fn identity(&self) -> Cookie<'static> {
+        Cookie::new("identity", self.tokens.access_token.clone())
+    }
+
+    pub fn refresh(&self) -> Cookie<'static> {
+        Cookie::new("refresh", self.tokens.refresh_token.clone())
+    }
+}
+
+/// Meets every password rule
+pub const PASSWORD: &str = "Passw0rd!";
+
+fn key_dir() -> &'static str {
+    KEY_DIR.get_or_init(|| {
+        let dir = std::env::temp_dir().join(format!("tidder-test-keys-{}", uuid::Uuid::new_v4()));
+        let dir = dir.to_string_lossy().to_string();
+        keys::generate(&dir).expect("Failed to generate test key");
+        dir
+    })
+}
@@ -0,0 +1,134 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::model::data::{Comment, CommentNode};
+use crate::service::ranking::Ranking;
+
+/**
+ * Arrange a flat list of comments into reply trees, ranking every level on its own.
+ * Deleted comments stay in the tree (already reduced to a placeholder by sanitizing)
+ * so their replies keep their context. Replies whose parent is missing are shown at the top level.
+ */
+pub fn build_tree(comments: Vec<Comment>, ranking: &Ranking) -> Vec<CommentNode> {
+    let ids: HashSet<String> = comments.iter().filter_map(|c| c.id.clone()).collect();
+
+    let mut roots = Vec::new();
+    let mut children: HashMap<String, Vec<Comment>> = HashMap::new();
+
+    for comment in comments {
+        match &comment.parent_id {
+            Some(parent_id) if ids.contains(parent_id) => {
+                children.entry(parent_id.clone()).or_default().push(comment);
+            },
+            _ => roots.push(comment),
+        }
+    }
+
+    let now = chrono::Utc::now().timestamp_millis();
+    attach_replies(roots, &mut children, ranking, now)
+}
+
+fn attach_replies(comments: Vec<Comment>, children: &mut HashMap<String, Vec<Comment>>, ranking: &Ranking, now: i64) -> Vec<CommentNode> {
+    let mut nodes: Vec<CommentNode> = comments.into_iter().map(|comment| {
+        let replies = match comment.id.as_ref().and_then(|id| children.remove(id)) {
+            Some(replies) => attach_replies(replies, children, ranking, now),
+            None => vec![],
+        };
+
+        CommentNode { comment, replies }
+    }).collect();
+
+    nodes.sort_by(|a, b| {
+        let score_a = ranking.score(a.comment.upvotes, a.comment.downvotes, &a.comment.created_at, now);
+        let score_b = ranking.score(b.comment.upvotes, b.comment.downvotes, &b.comment.created_at, now);
+        score_b.total_cmp(&score_a).then_with(|| a.comment.created_at.cmp(&b.comment.created_at))
+    });
+
+    nodes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::ranking::Window;
+
+    fn comment(id: &str, parent_id: Option<&str>, upvotes: u32, created_at: &str) -> Comment {
+        Comment {
+            id: Some(id.to_string()),
+            author_name: "author".to_string(),
+            author_id: "author-id".to_string(),
+            post_id: "post".to_string(),
+            parent_id: parent_id.map(|id| id.to_string()),
+            root_id: None,
+            depth: 0,
+            body: id.to_string(),
+            deleted: false,
+            deletion: None,
+            restoration: None,
+            purged: false,
+            upvotes,
+            downvotes: 0,
+            created_at: created_at.to_string(),
+            updated_at: created_at.to_string(),
+            edited_at: None,
+        }
+    }
+
+    fn ids(nodes: &[CommentNode]) -> Vec<&str> {
+        nodes.iter().map(|node| node.comment.id.as_deref().unwrap()).collect()
+    }
+
+    #[test]
+    fn nests_replies_under_their_parents() {
+        let comments = vec![
+            comment("reply", Some("root"), 0, "2023-01-01T00:01:00Z"),
+            comment("root", None, 0, "2023-01-01T00:00:00Z"),
+            comment("nested", Some("reply"), 0, "2023-01-01T00:02:00Z"),
+        ];
+
+        let tree = build_tree(comments, &Ranking::New);
+
+        assert_eq!(ids(&tree), vec!["root"]);
+        assert_eq!(ids(&tree[0].replies), vec!["reply"]);
+        assert_eq!(ids(&tree[0].replies[0].replies), vec!["nested"]);
+        assert!(tree[0].replies[0].replies[0].replies.is_empty());
+    }
+
+    #[test]
+    fn replies_to_missing_parents_are_top_level() {
+        let comments = vec![
+            comment("root", None, 0, "2023-01-01T00:00:00Z"),
+            comment("orphan", Some("gone"), 0, "2023-01-01T00:01:00Z"),
+        ];
+
+        let tree = build_tree(comments, &Ranking::New);
+
+        assert_eq!(ids(&tree), vec!["orphan", "root"]);
+    }
+
+    #[test]
+    fn ranks_every_level_on_its_own() {
+        let comments = vec![
+            comment("low", None, 1, "2023-01-01T00:00:00Z"),
+            comment("high", None, 5, "2023-01-01T00:00:00Z"),
+            comment("low-reply", Some("low"), 0, "2023-01-01T00:00:00Z"),
+            comment("high-reply", Some("low"), 3, "2023-01-01T00:00:00Z"),
+        ];
+
+        let tree = build_tree(comments, &Ranking::Top(Window::All));
+
+        assert_eq!(ids(&tree), vec!["high", "low"]);
+        assert_eq!(ids(&tree[1].replies), vec!["high-reply", "low-reply"]);
+    }
+
+    #[test]
+    fn ties_are_oldest_first() {
+        let comments = vec![
+            comment("second", None, 2, "2023-01-01T00:01:00Z"),
+            comment("first", None, 2, "2023-01-01T00:00:00Z"),
+        ];
+
+        let tree = build_tree(comments, &Ranking::Top(Window::All));
+
+        assert_eq!(ids(&tree), vec!["first", "second"]);
+    }
+}
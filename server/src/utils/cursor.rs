@@ -0,0 +1,37 @@
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::error::AppError;
+
+/**
+ * Encode the state needed to continue a listing into an opaque cursor string
+ */
+pub fn encode<T: Serialize>(state: &T) -> String {
+    URL_SAFE_NO_PAD.encode(serde_json::to_vec(state).expect("Failed to serialize cursor"))
+}
+
+/**
+ * Decode a cursor created by `encode`. Cursors come from clients, so anything can be in there.
+ */
+pub fn decode<T: DeserializeOwned>(cursor: &str) -> Result<T, AppError> {
+    let bytes = URL_SAFE_NO_PAD.decode(cursor).map_err(|_| AppError::BadRequest("Invalid cursor".to_string()))?;
+    serde_json::from_slice(&bytes).map_err(|_| AppError::BadRequest("Invalid cursor".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let state = (String::from("2023-01-01T00:00:00Z"), String::from("id"));
+        let decoded: (String, String) = decode(&encode(&state)).unwrap();
+        assert_eq!(decoded, state);
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert!(matches!(decode::<(String, String)>("not a cursor!"), Err(AppError::BadRequest(_))));
+        assert!(matches!(decode::<(String, String)>(&encode(&42)), Err(AppError::BadRequest(_))));
+    }
+}
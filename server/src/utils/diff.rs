@@ -0,0 +1,202 @@
+use serde_derive::Serialize;
+
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffOp {
+    Equal,
+    Insert,
+    Delete,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DiffLine {
+    pub op: DiffOp,
+    pub line: String,
+}
+
+/// Beyond this many inserted and deleted lines the changed part is shown as replaced as a whole
+const MAX_EDITS: usize = 500;
+
+/**
+ * Line by line diff between two texts, with the fewest inserted and deleted lines (Myers' algorithm).
+ * Unchanged lines at the start and end are skipped first, since edits usually only touch a small part of a post.
+ * When more than `MAX_EDITS` lines changed, the changed part is deleted and inserted as a whole instead,
+ * so a rewrite of a long text can't tie up a worker.
+ */
+pub fn line_diff(old: &str, new: &str) -> Vec<DiffLine> {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+
+    let prefix = old.iter().zip(new.iter()).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..].iter().rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let old_mid = &old[prefix..old.len() - suffix];
+    let new_mid = &new[prefix..new.len() - suffix];
+
+    let mut diff: Vec<DiffLine> = old[..prefix].iter().map(|line| equal(line)).collect();
+
+    match shortest_edit(old_mid, new_mid) {
+        Some(edit) => diff.extend(edit),
+        None => {
+            diff.extend(old_mid.iter().map(|line| DiffLine { op: DiffOp::Delete, line: line.to_string() }));
+            diff.extend(new_mid.iter().map(|line| DiffLine { op: DiffOp::Insert, line: line.to_string() }));
+        },
+    }
+
+    diff.extend(old[old.len() - suffix..].iter().map(|line| equal(line)));
+
+    diff
+}
+
+/**
+ * The shortest edit script from `old` to `new`, or None when it takes more than `MAX_EDITS` edits.
+ * v[k] is the furthest line of `old` reached on diagonal k (old line - new line) with d edits,
+ * the v of every round is kept to walk the path back afterwards.
+ */
+fn shortest_edit(old: &[&str], new: &[&str]) -> Option<Vec<DiffLine>> {
+    let (n, m) = (old.len() as isize, new.len() as isize);
+    let max = (n + m).min(MAX_EDITS as isize);
+
+    // Diagonal k is at v[k + offset], k + 1 is read for k = max
+    let offset = max + 1;
+    let mut v = vec![0isize; 2 * max as usize + 3];
+    let mut trace: Vec<Vec<isize>> = vec![];
+
+    for d in 0..=max {
+        trace.push(v.clone());
+
+        for k in (-d..=d).step_by(2) {
+            let i = (k + offset) as usize;
+            let mut x = if k == -d || (k != d && v[i - 1] < v[i + 1]) {
+                v[i + 1]
+            } else {
+                v[i - 1] + 1
+            };
+            let mut y = x - k;
+
+            while x < n && y < m && old[x as usize] == new[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[i] = x;
+
+            if x >= n && y >= m {
+                return Some(backtrack(old, new, &trace, offset));
+            }
+        }
+    }
+
+    None
+}
+
+/**
+ * Walk the shortest edit back from the end of both texts
+ */
+fn backtrack(old: &[&str], new: &[&str], trace: &[Vec<isize>], offset: isize) -> Vec<DiffLine> {
+    let (mut x, mut y) = (old.len() as isize, new.len() as isize);
+    let mut diff = vec![];
+
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let k = x - y;
+        let i = (k + offset) as usize;
+
+        let previous_k = if k == -d || (k != d && v[i - 1] < v[i + 1]) { k + 1 } else { k - 1 };
+        let previous_x = v[(previous_k + offset) as usize];
+        let previous_y = previous_x - previous_k;
+
+        while x > previous_x && y > previous_y {
+            diff.push(equal(old[x as usize - 1]));
+            x -= 1;
+            y -= 1;
+        }
+
+        if d > 0 {
+            match x == previous_x {
+                true => diff.push(DiffLine { op: DiffOp::Insert, line: new[y as usize - 1].to_string() }),
+                false => diff.push(DiffLine { op: DiffOp::Delete, line: old[x as usize - 1].to_string() }),
+            }
+        }
+
+        x = previous_x;
+        y = previous_y;
+    }
+
+    diff.reverse();
+    diff
+}
+
+fn equal(line: &str) -> DiffLine {
+    DiffLine { op: DiffOp::Equal, line: line.to_string() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(diff: &[DiffLine]) -> Vec<String> {
+        diff.iter().map(|d| {
+            let op = match d.op {
+                DiffOp::Equal => ' ',
+                DiffOp::Insert => '+',
+                DiffOp::Delete => '-',
+            };
+            format!("{}{}", op, d.line)
+        }).collect()
+    }
+
+    #[test]
+    fn same_text_is_all_equal() {
+        assert_eq!(render(&line_diff("a\nb\nc", "a\nb\nc")), vec![" a", " b", " c"]);
+    }
+
+    #[test]
+    fn empty_texts() {
+        assert!(line_diff("", "").is_empty());
+        assert_eq!(render(&line_diff("", "a\nb")), vec!["+a", "+b"]);
+        assert_eq!(render(&line_diff("a\nb", "")), vec!["-a", "-b"]);
+    }
+
+    #[test]
+    fn changed_line_in_the_middle() {
+        assert_eq!(render(&line_diff("a\nb\nc", "a\nx\nc")), vec![" a", "-b", "+x", " c"]);
+    }
+
+    #[test]
+    fn keeps_common_lines_between_changes() {
+        let diff = line_diff("a\nb\nc\nd\ne", "b\nc\nx\nd");
+        assert_eq!(render(&diff), vec!["-a", " b", " c", "+x", " d", "-e"]);
+    }
+
+    #[test]
+    fn finds_the_fewest_edits() {
+        // Myers' classic example, 5 edits is the minimum
+        let diff = line_diff("a\nb\nc\na\nb\nb\na", "c\nb\na\nb\na\nc");
+        let edits = diff.iter().filter(|d| d.op != DiffOp::Equal).count();
+        assert_eq!(edits, 5);
+
+        let old: Vec<&str> = diff.iter().filter(|d| d.op != DiffOp::Insert).map(|d| d.line.as_str()).collect();
+        let new: Vec<&str> = diff.iter().filter(|d| d.op != DiffOp::Delete).map(|d| d.line.as_str()).collect();
+        assert_eq!(old, vec!["a", "b", "c", "a", "b", "b", "a"]);
+        assert_eq!(new, vec!["c", "b", "a", "b", "a", "c"]);
+    }
+
+    #[test]
+    fn large_rewrite_is_replaced_as_a_whole() {
+        let old = (0..MAX_EDITS).map(|i| format!("old {}", i)).collect::<Vec<_>>().join("\n");
+        let new = (0..MAX_EDITS).map(|i| format!("new {}", i)).collect::<Vec<_>>().join("\n");
+        let text = format!("first\n{}\nlast", old);
+        let rewritten = format!("first\n{}\nlast", new);
+
+        let diff = line_diff(&text, &rewritten);
+
+        assert_eq!(diff.len(), 2 * MAX_EDITS + 2);
+        assert_eq!(render(&diff[..1]), vec![" first"]);
+        assert!(diff[1..=MAX_EDITS].iter().all(|d| d.op == DiffOp::Delete));
+        assert!(diff[MAX_EDITS + 1..2 * MAX_EDITS + 1].iter().all(|d| d.op == DiffOp::Insert));
+        assert_eq!(render(&diff[2 * MAX_EDITS + 1..]), vec![" last"]);
+    }
+}
@@ -1,65 +1,259 @@
+
 use actix_web::web;
 use regex;
 
-use crate::model::api::{CreatePostRequest, CreateCommentRequest};
+use crate::error::AppError;
+use crate::model::api::{BanRequest, CreatePostRequest, CreateCommentRequest, ReportRequest, VoteRequest};
+use crate::model::data::{Comment, PostType};
+
+/// Page size of list endpoints when no limit is given
+const DEFAULT_PAGE_SIZE: u32 = 25;
+const MAX_PAGE_SIZE: u32 = 100;
+
+/// Names that are part of the API paths under `/api/user`
+const RESERVED_USERNAMES: &[&str] = &["me"];
+
+
+pub fn validate_username(username: &str) -> Result<(), AppError> {
+    let re_username = regex::Regex::new(r"^[a-zA-Z0-9_]+$").unwrap();
+
+    if !re_username.is_match(username) {
+        return Err(AppError::BadRequest("Username can only contain letters, numbers and underscores".to_string()));
+    }
+
+    // `/api/user/me` is the logged in user, a user called "me" couldn't have a profile
+    if RESERVED_USERNAMES.contains(&username.to_lowercase().as_str()) {
+        return Err(AppError::BadRequest("This username is reserved".to_string()));
+    }
+
+    Ok(())
+}
+
+pub fn validate_password(password: &str) -> Result<(), AppError> {
+    let re_password = regex::RegexSet::new([r"[A-Z]", r"[a-z]", r"\d", r#"[!@#$%^&*(),.?\":{}|<>]"#]).unwrap();
+
+    match 
+        password.len() >= 8 && 
+        password.len() <= 64 &&
+        re_password.matches(password).iter().count() == re_password.len() {
+            true => Ok(()),
+            false => Err(AppError::BadRequest("Password must be between 8 and 64 characters long and contain at least one uppercase letter, one lowercase letter, one digit and one special character".to_string())),
+    }
+}
+
+pub fn validate_category_name(name: &str) -> Result<(), AppError> {
+    let re_name = regex::Regex::new(r"^[a-zA-Z0-9_ ]+$").unwrap();
+
+    // name length too short
+    if name.trim().len() < 3 {
+        return Err(AppError::BadRequest("Category name must be at least 3 characters long".to_string()));
+
+    // name length too long
+    } else if name.len() > 50 {
+        return Err(AppError::BadRequest("Category name must be less than 50 characters long".to_string()));
+
+    // name contains invalid characters
+    } else if !re_name.is_match(name) {
+        return Err(AppError::BadRequest("Category name can only contain letters, numbers, spaces and underscores".to_string()));
+    }
+
+    Ok(())
+}
+
+/**
+ * Check the description and rules of a category and parse its allowed post types
+ * @param post_types Comma separated post types, all of them when left out
+ */
+pub fn validate_category(description: &str, rules: &str, post_types: Option<&str>) -> Result<Vec<PostType>, AppError> {
+    if description.len() > 500 {
+        return Err(AppError::BadRequest("Description can be at most 500 characters long".to_string()));
+    } else if rules.len() > 10000 {
+        return Err(AppError::BadRequest("Rules can be at most 10000 characters long".to_string()));
+    }
+
+    let post_types = match post_types {
+        Some(post_types) => post_types,
+        None => return Ok(PostType::all()),
+    };
+
+    let mut types = vec![];
+    for post_type in post_types.split(',').filter(|post_type| !post_type.trim().is_empty()) {
+        let post_type = post_type.parse::<PostType>()?;
+        if !types.contains(&post_type) {
+            types.push(post_type);
+        }
+    }
+
+    match types.is_empty() {
+        true => Err(AppError::BadRequest("A category must allow at least one post type".to_string())),
+        false => Ok(types),
+    }
+}
+
+pub fn validate_report(form: &ReportRequest) -> Result<(), AppError> {
+    let reason = form.reason.trim();
+
+    if reason.len() < 3 {
+        return Err(AppError::BadRequest("Reason must be at least 3 characters long".to_string()));
+    } else if reason.len() > 500 {
+        return Err(AppError::BadRequest("Reason can be at most 500 characters long".to_string()));
+    }
+
+    Ok(())
+}
+
+pub fn validate_ban(form: &BanRequest) -> Result<(), AppError> {
+    let reason = form.reason.trim();
+
+    if reason.len() < 3 {
+        return Err(AppError::BadRequest("Reason must be at least 3 characters long".to_string()));
+    } else if reason.len() > 500 {
+        return Err(AppError::BadRequest("Reason can be at most 500 characters long".to_string()));
+    } else if form.duration_hours == Some(0) {
+        return Err(AppError::BadRequest("A suspension must last at least one hour".to_string()));
+    }
+
+    Ok(())
+}
+
+/// The reason of a moderator action is optional, a blank one counts as none
+pub fn validate_moderation_reason(reason: Option<String>) -> Result<Option<String>, AppError> {
+    let reason = reason
+        .map(|reason| reason.trim().to_string())
+        .filter(|reason| !reason.is_empty());
 
-pub fn validate_new_post(form: &web::Form<CreatePostRequest>) -> Result<(), &'static str> {
+    if reason.as_ref().is_some_and(|reason| reason.len() > 500) {
+        return Err(AppError::BadRequest("Reason can be at most 500 characters long".to_string()));
+    }
+
+    Ok(reason)
+}
+
+pub fn validate_new_post(form: &CreatePostRequest) -> Result<(), AppError> {
     // title regex
     let re_title = regex::Regex::new(r"^[a-zA-Z0-9_ ]+$").unwrap();
     
     // post length too short
     if form.body.len() < 10 {
-        return Err("Post body must be at least 10 characters long");
+        return Err(AppError::BadRequest("Post body must be at least 10 characters long".to_string()));
 
     // post length too long
     } else if form.body.len() > 10000 {
-        return Err("Post body can be at most 10000 characters long");
+        return Err(AppError::BadRequest("Post body can be at most 10000 characters long".to_string()));
     
     // title contains invalid characters
     } else if !re_title.is_match(&form.title) {
-        return Err("Title can only contain letters, numbers, spaces and underscores");
+        return Err(AppError::BadRequest("Title can only contain letters, numbers, spaces and underscores".to_string()));
 
     // title length too short
     } else if form.title.len() < 5 {
-        return Err("Title must be at least 5 characters long");
+        return Err(AppError::BadRequest("Title must be at least 5 characters long".to_string()));
 
     // title length too long
     } else if form.title.len() > 100 {
-        return Err("Title must be less than 100 characters long");
+        return Err(AppError::BadRequest("Title must be less than 100 characters long".to_string()));
 
     // neither category_id nor new_category is specified
     } else if form.new_category.is_none() && form.category_id.is_none() {
-        return Err("Category is required");
+        return Err(AppError::BadRequest("Category is required".to_string()));
 
     // both category_id and new_category are specified
     } else if form.new_category.is_some() && form.category_id.is_some() {
-        return Err("Cannot specify both category and new_category");
-
-    // new_category length too short
-    } else if form.new_category.is_some() && form.new_category.clone().unwrap().len() < 3 {
-        return Err("New category name must be at least 3 characters long");
+        return Err(AppError::BadRequest("Cannot specify both category and new_category".to_string()));
     
-    // new_category length too long
-    } else if form.new_category.is_some() && form.new_category.clone().unwrap().len() > 50 {
-        return Err("New category name must be less than 50 characters long");
-    
-    // new_category contains invalid characters
-    } else if form.new_category.is_some() && !re_title.is_match(&form.new_category.clone().unwrap()) {
-        return Err("New category name can only contain letters, numbers, spaces and underscores");
+    // link posts are nothing but the link
+    } else if form.post_type == Some(PostType::Link) && !is_web_url(&form.body) {
+        return Err(AppError::BadRequest("Link posts must be an http or https URL".to_string()));
     }
 
-    Ok(())
+    match &form.new_category {
+        Some(name) => validate_category_name(name),
+        None => Ok(()),
+    }
 }
 
-pub fn validate_new_comment(form: &web::Form<CreateCommentRequest>) -> Result<(), &'static str> {
+pub fn validate_new_comment(form: &CreateCommentRequest) -> Result<(), AppError> {
     // comment length too short
-    if form.body.len() < 1 {
-        return Err("Body must be at least 1 characters long");
+    if form.body.is_empty() {
+        return Err(AppError::BadRequest("Body must be at least 1 characters long".to_string()));
 
     // comment length too long
     } else if form.body.len() > 10000 {
-        return Err("Body must be less than 10000 characters long");
+        return Err(AppError::BadRequest("Body must be less than 10000 characters long".to_string()));
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+/**
+ * Convert the vote direction into the value stored in the vote ledger
+ */
+pub fn validate_vote(form: &web::Form<VoteRequest>) -> Result<i8, AppError> {
+    match form.direction.as_str() {
+        "up" => Ok(1),
+        "down" => Ok(-1),
+        "clear" => Ok(0),
+        _ => Err(AppError::BadRequest("Vote direction must be one of: up, down, clear".to_string())),
+    }
+}
+
+/**
+ * Check that a comment can be replied to from the given post
+ * @param max_depth How deep replies can be nested (top level comments have depth 0)
+ * @return The depth of the reply
+ */
+pub fn validate_reply(parent: &Comment, post_id: &String, max_depth: u32) -> Result<u32, AppError> {
+    // parent comment is on another post
+    if !parent.post_id.eq(post_id) {
+        return Err(AppError::BadRequest("Parent comment does not belong to this post".to_string()));
+
+    // parent comment is deleted
+    } else if parent.deleted {
+        return Err(AppError::BadRequest("Cannot reply to a deleted comment".to_string()));
+
+    // thread is already as deep as it can get
+    } else if parent.depth + 1 > max_depth {
+        return Err(AppError::BadRequest("Maximum reply depth reached".to_string()));
+    }
+
+    Ok(parent.depth + 1)
+}
+
+/**
+ * Check the `limit` query parameter of list endpoints
+ * @return The page size to use
+ */
+pub fn validate_limit(limit: Option<u32>) -> Result<u32, AppError> {
+    match limit.unwrap_or(DEFAULT_PAGE_SIZE) {
+        0 => Err(AppError::BadRequest("Limit must be at least 1".to_string())),
+        limit if limit > MAX_PAGE_SIZE => Err(AppError::BadRequest("Limit can be at most 100".to_string())),
+        limit => Ok(limit),
+    }
+}
+
+/**
+ * Check a point in time given as a query parameter, RFC 3339 (`2026-01-01T00:00:00Z`) or UTC as
+ * `YYYY-MM-DD HH:MM:SS` or `YYYY-MM-DD`
+ * @return The time in UTC the way SQLite stores it, so it compares correctly with stored times
+ */
+pub fn validate_time(value: Option<String>, name: &str) -> Result<Option<String>, AppError> {
+    let value = match value {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    let value = value.trim();
+
+    let time = chrono::DateTime::parse_from_rfc3339(value)
+        .map(|time| time.naive_utc())
+        .or_else(|_| chrono::NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S"))
+        .or_else(|_| chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d").map(|date| date.and_hms_opt(0, 0, 0).unwrap_or_default()))
+        .map_err(|_| AppError::BadRequest(format!("{} must be a time like 2026-01-01T00:00:00Z or 2026-01-01 00:00:00", name)))?;
+
+    Ok(Some(time.format("%Y-%m-%d %H:%M:%S").to_string()))
+}
+
+fn is_web_url(url: &str) -> bool {
+    url::Url::parse(url.trim())
+        .map(|url| url.scheme() == "http" || url.scheme() == "https")
+        .unwrap_or(false)
+}
@@ -1,3 +1,6 @@
 pub mod form_validation;
 pub mod sanitize;
-pub mod convert;
\ No newline at end of file
+pub mod convert;
+pub mod comment_tree;
+pub mod diff;
+pub mod cursor;
\ No newline at end of file
@@ -1,7 +1,7 @@
 use comrak::{markdown_to_html_with_plugins, ComrakOptions, ComrakPlugins};
 use comrak::plugins::syntect::SyntectAdapter;
 
-use crate::model::data::{Post, Comment};
+use crate::model::data::{Post, Comment, Deletion, Restoration};
 
 const CODE_BLOCK_THEME: &str = "base16-eighties.dark";
 const DELETED_TEXT: &str = "<p class=\"text-red-500\">deleted</p>";
@@ -27,6 +27,10 @@ pub fn sanitize_post(post: &mut Post, show_all: &bool) -> Post {
         post.body = DELETED_TEXT.to_string();
     }
 
+    if !show_all {
+        hide_moderators(&mut post.deletion, &mut post.restoration);
+    }
+
     post.clone()
 }
 
@@ -49,5 +53,29 @@ pub fn sanitize_comment(comment: &mut Comment, show_all: &bool) -> Comment {
         comment.body = DELETED_TEXT.to_string();
     }
 
+    if !show_all {
+        hide_moderators(&mut comment.deletion, &mut comment.restoration);
+    }
+
     comment.clone()
-}
\ No newline at end of file
+}
+
+/// Everyone may see why something was deleted, but who deleted or restored it is only for those who can see deleted content
+pub fn hide_moderators(deletion: &mut Option<Deletion>, restoration: &mut Option<Restoration>) {
+    if let Some(deletion) = deletion {
+        deletion.deleted_by = None;
+    }
+    *restoration = None;
+}
+
+/**
+ * Render Markdown that isn't a post or comment, like the rules of a category
+ */
+pub fn render_markdown(markdown: &str) -> String {
+    let adapter = SyntectAdapter::new(CODE_BLOCK_THEME);
+    let options = ComrakOptions::default();
+    let mut plugins = ComrakPlugins::default();
+    plugins.render.codefence_syntax_highlighter = Some(&adapter);
+
+    markdown_to_html_with_plugins(markdown, &options, &plugins)
+}